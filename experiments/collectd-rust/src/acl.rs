@@ -0,0 +1,123 @@
+// Source network allowlist from --allow-cidr, checked by every listener. An
+// empty list allows everyone.
+use anyhow::{anyhow, Result};
+use std::net::IpAddr;
+
+pub struct AllowList {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl AllowList {
+    // "10.0.0.0/8", "2001:db8::/32", or a bare address for a single host
+    pub fn parse(cidrs: &[String]) -> Result<AllowList> {
+        let mut networks = Vec::with_capacity(cidrs.len());
+        for cidr in cidrs {
+            let invalid = || anyhow!("Invalid CIDR: {}", cidr);
+            let (addr, prefix) = match cidr.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (cidr.as_str(), None),
+            };
+            let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| invalid())?,
+                None => max,
+            };
+            if prefix > max {
+                return Err(invalid());
+            }
+            networks.push((addr, prefix));
+        }
+        Ok(AllowList { networks })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.networks.is_empty() {
+            return true;
+        }
+        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        self.networks.iter().any(|(network, prefix)| match (network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                same_prefix(u32::from(*network) as u128, u32::from(ip) as u128, 32, *prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => same_prefix(u128::from(*network), u128::from(ip), 128, *prefix),
+            _ => false,
+        })
+    }
+}
+
+fn same_prefix(network: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    network >> shift == ip >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(cidrs: &[&str]) -> AllowList {
+        AllowList::parse(&cidrs.iter().map(|cidr| cidr.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn allows(list: &AllowList, ip: &str) -> bool {
+        list.allows(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_ipv4_prefixes() {
+        let list = list(&["10.0.0.0/8", "192.168.1.5"]);
+        assert!(allows(&list, "10.0.0.1"));
+        assert!(allows(&list, "10.255.255.255"));
+        assert!(!allows(&list, "11.0.0.1"));
+        assert!(allows(&list, "192.168.1.5"));
+        assert!(!allows(&list, "192.168.1.6"));
+    }
+
+    #[test]
+    fn matches_ipv6_prefixes() {
+        let list = list(&["2001:db8::/32", "::1"]);
+        assert!(allows(&list, "2001:db8::1"));
+        assert!(allows(&list, "2001:db8:ffff::1"));
+        assert!(!allows(&list, "2001:db9::1"));
+        assert!(allows(&list, "::1"));
+        assert!(!allows(&list, "10.0.0.1"));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_clients_as_ipv4() {
+        let list = list(&["10.0.0.0/8"]);
+        assert!(allows(&list, "::ffff:10.1.2.3"));
+        assert!(!allows(&list, "::ffff:11.1.2.3"));
+    }
+
+    #[test]
+    fn zero_prefix_matches_its_whole_family() {
+        let ipv4 = list(&["0.0.0.0/0"]);
+        assert!(allows(&ipv4, "1.2.3.4"));
+        assert!(allows(&ipv4, "::ffff:1.2.3.4"));
+        assert!(!allows(&ipv4, "2001:db8::1"));
+        let ipv6 = list(&["::/0"]);
+        assert!(allows(&ipv6, "2001:db8::1"));
+        assert!(!allows(&ipv6, "1.2.3.4"));
+    }
+
+    #[test]
+    fn empty_allows_everyone() {
+        assert!(allows(&list(&[]), "1.2.3.4"));
+    }
+
+    #[test]
+    fn refuses_bad_cidrs() {
+        for cidr in ["10.0.0.0/33", "::/129", "10.0.0.0/x", "example.com"] {
+            assert!(AllowList::parse(&[cidr.to_string()]).is_err(), "{}", cidr);
+        }
+    }
+}
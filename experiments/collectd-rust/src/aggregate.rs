@@ -0,0 +1,215 @@
+// Tumbling-window rollups. Numeric values are folded into min/max/avg/sum/count
+// and percentiles per series (host, plugin, plugin_instance, type, type_instance,
+// dsname, tags) and emitted once per window, stamped with the window start and
+// tagged with the function that produced them. Percentiles come from a DDSketch
+// per series, accurate to 1% of the value. Windows are aligned to the wall clock
+// and bucketed by arrival time, so late or clock-skewed agents still land in the
+// window that is currently open. With match rules only the metrics matching one
+// of them are rolled up, the rest pass through.
+use anyhow::{anyhow, Result};
+use sketches_ddsketch::{Config as SketchConfig, DDSketch};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{interval_at, Instant};
+use tracing::{debug, warn};
+
+use crate::filter::Rule;
+use crate::metric::{MetricId, ProcessedMetric, Tags, Value};
+use crate::queue::{QueueError, QueueSender};
+use crate::telemetry::Telemetry;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Function {
+    Min,
+    Max,
+    Avg,
+    Sum,
+    Count,
+    // The quantile, and the name it was given as, e.g. 0.95 and "p95"
+    Percentile(f64, String),
+}
+
+impl Function {
+    fn name(&self) -> &str {
+        match self {
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Avg => "avg",
+            Function::Sum => "sum",
+            Function::Count => "count",
+            Function::Percentile(_, name) => name,
+        }
+    }
+}
+
+impl FromStr for Function {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Function> {
+        match s {
+            "min" => Ok(Function::Min),
+            "max" => Ok(Function::Max),
+            "avg" => Ok(Function::Avg),
+            "sum" => Ok(Function::Sum),
+            "count" => Ok(Function::Count),
+            other => {
+                let percentile = other
+                    .strip_prefix('p')
+                    .and_then(|p| p.parse::<f64>().ok())
+                    .filter(|p| (0.0..=100.0).contains(p))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Invalid aggregate function: {}, expected min, max, avg, sum, count or a percentile like p99",
+                            other
+                        )
+                    })?;
+                Ok(Function::Percentile(percentile / 100.0, other.to_string()))
+            }
+        }
+    }
+}
+
+type Key = (MetricId, Tags);
+
+struct Accumulator {
+    // Identity fields are copied from the first metric of the window
+    template: ProcessedMetric,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+    // Only kept when a percentile is asked for
+    sketch: Option<DDSketch>,
+}
+
+pub struct Aggregator {
+    window: Duration,
+    functions: Vec<Function>,
+    keep_raw: bool,
+    // Empty rolls up every metric
+    rules: Vec<Rule>,
+    series: Mutex<HashMap<Key, Accumulator>>,
+}
+
+impl Aggregator {
+    pub fn new(window: Duration, functions: Vec<Function>, keep_raw: bool, rules: Vec<Rule>) -> Result<Aggregator> {
+        if window.is_zero() {
+            return Err(anyhow!("Aggregate window must be longer than zero"));
+        }
+        if functions.is_empty() {
+            return Err(anyhow!("At least one aggregate function is required"));
+        }
+        Ok(Aggregator {
+            window,
+            functions,
+            keep_raw,
+            rules,
+            series: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Folds values into the open window. Returns what should still be queued
+    // as-is: everything when raw points are kept, otherwise only the values that
+    // don't match the rules.
+    pub fn add(&self, metrics: Vec<ProcessedMetric>) -> Vec<ProcessedMetric> {
+        let mut series = self.series.lock().unwrap();
+        let mut passthrough = Vec::new();
+        let sketched = self.functions.iter().any(|f| matches!(f, Function::Percentile(..)));
+        for metric in metrics {
+            let value = metric.value.as_f64();
+            if !self.rules.is_empty() && !self.rules.iter().any(|rule| rule.matches(&metric)) {
+                passthrough.push(metric);
+                continue;
+            }
+            let key = (MetricId::of(&metric), metric.tags.clone());
+            let acc = series.entry(key).or_insert_with(|| Accumulator {
+                template: metric.clone(),
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+                sum: 0.0,
+                count: 0,
+                sketch: sketched.then(|| DDSketch::new(SketchConfig::defaults())),
+            });
+            acc.min = acc.min.min(value);
+            acc.max = acc.max.max(value);
+            acc.sum += value;
+            acc.count += 1;
+            if let Some(sketch) = &mut acc.sketch {
+                sketch.add(value);
+            }
+
+            if self.keep_raw {
+                passthrough.push(metric);
+            }
+        }
+        passthrough
+    }
+
+    // Closes the open window and returns its rollups
+    fn drain(&self, window_start: f64) -> Vec<ProcessedMetric> {
+        let series = std::mem::take(&mut *self.series.lock().unwrap());
+        let mut rollups = Vec::with_capacity(series.len() * self.functions.len());
+        for acc in series.into_values() {
+            for function in &self.functions {
+                let value = match function {
+                    Function::Min => Value::from(acc.min),
+                    Function::Max => Value::from(acc.max),
+                    Function::Avg => Value::from(acc.sum / acc.count as f64),
+                    Function::Sum => Value::from(acc.sum),
+                    Function::Count => Value::from(acc.count),
+                    Function::Percentile(quantile, _) => {
+                        match acc.sketch.as_ref().and_then(|sketch| sketch.quantile(*quantile).ok().flatten()) {
+                            Some(v) => Value::from(v),
+                            None => continue,
+                        }
+                    }
+                };
+                rollups.push(ProcessedMetric {
+                    time: Some(window_start),
+                    interval: Some(self.window.as_secs_f64()),
+                    dstype: Some("gauge".to_string()),
+                    aggregation: Some(function.name().to_string()),
+                    value,
+                    ..acc.template.clone()
+                });
+            }
+        }
+        rollups
+    }
+}
+
+// Emits the rollups at every window boundary until the queue closes
+pub async fn run(aggregator: Arc<Aggregator>, sender: QueueSender, telemetry: Arc<Telemetry>) {
+    let window = aggregator.window.as_secs_f64();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    let mut window_start = (now / window).floor() * window;
+    let first_close = Instant::now() + Duration::from_secs_f64(window_start + window - now);
+    let mut ticker = interval_at(first_close, aggregator.window);
+
+    loop {
+        ticker.tick().await;
+        let rollups = aggregator.drain(window_start);
+        window_start += window;
+        if rollups.is_empty() {
+            continue;
+        }
+
+        let count = rollups.len();
+        debug!("Emitting {} rollups", count);
+        match sender.send_batch(rollups).await {
+            Ok(dropped) => telemetry.record_dropped(dropped),
+            Err(QueueError::Full) => {
+                telemetry.record_dropped(count);
+                warn!("Processing queue full, dropping {} rollups", count);
+            }
+            Err(QueueError::Closed) => break,
+        }
+    }
+}
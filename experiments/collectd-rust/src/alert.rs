@@ -0,0 +1,321 @@
+// Threshold alerts evaluated on the metrics as they pass through, for setups
+// where nothing downstream sees the data in time. Rules are
+// `<name>:<match>:<comparison>[:<for>]`, e.g.
+//   cpu_busy:plugin=cpu&type_instance=idle:<10:5m
+//   disk_full:plugin~^df$&type_instance=used:>95
+// where match is one or more of the filter's `<field>=<value>`/`<field>~<regex>`
+// joined by `&`, comparison is one of >, >=, <, <=, ==, != and a number, and
+// for is how long a series has to stay in breach before the alert fires
+// (default 0s). Each series is tracked on its own: a firing event is sent when
+// it has been breaching for long enough, and a resolved event on its first
+// point back within the threshold. Events are JSON objects written to
+// --alert-target, stdout, a udp://host:port or an http(s) URL.
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::config::{self, Config};
+use crate::filter::Rule;
+use crate::metric::{MetricId, ProcessedMetric, Tags};
+use crate::telemetry::Telemetry;
+
+// Events waiting for delivery, beyond this new ones are dropped
+const QUEUE_CAPACITY: usize = 1024;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    // The comparison and the rest of s
+    fn parse(s: &str) -> Option<(Comparison, &str)> {
+        [
+            (">=", Comparison::AtLeast),
+            ("<=", Comparison::AtMost),
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            (">", Comparison::Above),
+            ("<", Comparison::Below),
+        ]
+        .into_iter()
+        .find_map(|(op, comparison)| s.strip_prefix(op).map(|rest| (comparison, rest)))
+    }
+
+    fn breached(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::AtLeast => value >= threshold,
+            Comparison::Below => value < threshold,
+            Comparison::AtMost => value <= threshold,
+            Comparison::Equal => value == threshold,
+            Comparison::NotEqual => value != threshold,
+        }
+    }
+}
+
+struct AlertRule {
+    name: String,
+    matches: Vec<Rule>,
+    comparison: Comparison,
+    threshold: f64,
+    // The comparison as written, for the events
+    condition: String,
+    duration: Duration,
+}
+
+impl AlertRule {
+    fn parse(rule: &str) -> Result<AlertRule> {
+        let invalid = |reason: &str| anyhow!("Invalid alert {:?}: {}", rule, reason);
+        let (name, rest) = rule
+            .split_once(':')
+            .ok_or_else(|| invalid("expected <name>:<match>:<comparison>[:<for>]"))?;
+        if name.is_empty() {
+            return Err(invalid("the name is empty"));
+        }
+        // Parsed from the right, a regex in the match may contain colons
+        let (rest, last) = rest.rsplit_once(':').ok_or_else(|| invalid("missing the comparison"))?;
+        let (expr, condition, duration) = if Comparison::parse(last).is_some() {
+            (rest, last, Duration::ZERO)
+        } else {
+            let duration = config::parse_duration(last).map_err(|e| invalid(&e.to_string()))?;
+            let (expr, condition) = rest.rsplit_once(':').ok_or_else(|| invalid("missing the comparison"))?;
+            (expr, condition, duration)
+        };
+        let (comparison, threshold) = Comparison::parse(condition)
+            .ok_or_else(|| invalid("the comparison must start with >, >=, <, <=, == or !="))?;
+        let threshold: f64 = threshold
+            .trim()
+            .parse()
+            .map_err(|_| invalid("the threshold is not a number"))?;
+        let matches = expr
+            .split('&')
+            .map(Rule::parse)
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| invalid(&e.to_string()))?;
+        Ok(AlertRule {
+            name: name.to_string(),
+            matches,
+            comparison,
+            threshold,
+            condition: condition.to_string(),
+            duration,
+        })
+    }
+
+    fn matches(&self, metric: &ProcessedMetric) -> bool {
+        self.matches.iter().all(|rule| rule.matches(metric))
+    }
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    alert: &'a str,
+    // "firing" or "resolved"
+    state: &'static str,
+    condition: &'a str,
+    // Unix time the series started breaching
+    since: f64,
+    metric: &'a ProcessedMetric,
+}
+
+struct Breach {
+    started: Instant,
+    since: f64,
+    firing: bool,
+}
+
+pub struct Alerts {
+    rules: Vec<AlertRule>,
+    // Series currently breaching, per rule
+    breaches: Mutex<HashMap<(usize, MetricId, Tags), Breach>>,
+    events: mpsc::Sender<Vec<u8>>,
+    telemetry: Arc<Telemetry>,
+}
+
+enum Target {
+    Stdout,
+    Udp(String),
+    Http(reqwest::Url),
+}
+
+impl Target {
+    fn parse(target: &str) -> Result<Target> {
+        if target == "stdout" {
+            return Ok(Target::Stdout);
+        }
+        if let Some(address) = target.strip_prefix("udp://") {
+            return Ok(Target::Udp(address.to_string()));
+        }
+        if target.starts_with("http://") || target.starts_with("https://") {
+            return Ok(Target::Http(reqwest::Url::parse(target)?));
+        }
+        Err(anyhow!(
+            "Invalid --alert-target {:?}, expected stdout, udp://host:port or an http(s) URL",
+            target
+        ))
+    }
+}
+
+// Checks the rules and the target without starting anything
+pub fn validate(config: &Config) -> Result<()> {
+    for rule in &config.alert {
+        AlertRule::parse(rule)?;
+    }
+    Target::parse(&config.alert_target).map(|_| ())
+}
+
+impl Alerts {
+    // None without any --alert. Opens the target and starts delivering events in
+    // the background
+    pub async fn start(config: &Config, telemetry: Arc<Telemetry>) -> Result<Option<Arc<Alerts>>> {
+        if config.alert.is_empty() {
+            return Ok(None);
+        }
+        let rules = config
+            .alert
+            .iter()
+            .map(|rule| AlertRule::parse(rule))
+            .collect::<Result<Vec<_>>>()?;
+        let destination = Destination::open(Target::parse(&config.alert_target)?).await?;
+        let (events, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(deliver(receiver, destination, telemetry.clone()));
+        Ok(Some(Arc::new(Alerts {
+            rules,
+            breaches: Mutex::new(HashMap::new()),
+            events,
+            telemetry,
+        })))
+    }
+
+    pub fn apply(&self, metrics: &[ProcessedMetric]) {
+        let mut breaches = self.breaches.lock().unwrap();
+        let now = Instant::now();
+        for metric in metrics {
+            let value = metric.value.as_f64();
+            for (index, rule) in self.rules.iter().enumerate() {
+                if !rule.matches(metric) {
+                    continue;
+                }
+                let key = (index, MetricId::of(metric), metric.tags.clone());
+                if !rule.comparison.breached(value, rule.threshold) {
+                    if let Some(breach) = breaches.remove(&key) {
+                        if breach.firing {
+                            self.send(rule, "resolved", breach.since, metric);
+                        }
+                    }
+                    continue;
+                }
+                let breach = breaches.entry(key).or_insert_with(|| Breach {
+                    started: now,
+                    since: unix_now(),
+                    firing: false,
+                });
+                if !breach.firing && now.duration_since(breach.started) >= rule.duration {
+                    breach.firing = true;
+                    self.send(rule, "firing", breach.since, metric);
+                }
+            }
+        }
+    }
+
+    fn send(&self, rule: &AlertRule, state: &'static str, since: f64, metric: &ProcessedMetric) {
+        let event = Event {
+            alert: &rule.name,
+            state,
+            condition: &rule.condition,
+            since,
+            metric,
+        };
+        let Ok(event) = serde_json::to_vec(&event) else {
+            return;
+        };
+        if self.events.try_send(event).is_err() {
+            self.telemetry.record_alert_failure();
+            warn!("Alert queue full, dropping {} event for {}", state, rule.name);
+        }
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+// Sends events until every Alerts holding the other end is gone. Delivery is
+// attempted once, a failed event is logged and counted.
+async fn deliver(mut events: mpsc::Receiver<Vec<u8>>, mut destination: Destination, telemetry: Arc<Telemetry>) {
+    while let Some(event) = events.recv().await {
+        match destination.send(event).await {
+            Ok(()) => telemetry.record_alert_sent(),
+            Err(e) => {
+                telemetry.record_alert_failure();
+                warn!("Failed to send alert event: {:#}", e);
+            }
+        }
+    }
+}
+
+enum Destination {
+    Stdout(tokio::io::Stdout),
+    Udp(UdpSocket),
+    Http(reqwest::Client, reqwest::Url),
+}
+
+impl Destination {
+    async fn open(target: Target) -> Result<Destination> {
+        let destination = match target {
+            Target::Stdout => Destination::Stdout(tokio::io::stdout()),
+            Target::Udp(address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(&address).await?;
+                info!("Sending alert events to udp://{}", address);
+                Destination::Udp(socket)
+            }
+            Target::Http(url) => {
+                info!("Sending alert events to {}", url);
+                Destination::Http(reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?, url)
+            }
+        };
+        Ok(destination)
+    }
+
+    async fn send(&mut self, mut event: Vec<u8>) -> Result<()> {
+        match self {
+            Destination::Stdout(stdout) => {
+                event.push(b'\n');
+                stdout.write_all(&event).await?;
+                stdout.flush().await?;
+            }
+            Destination::Udp(socket) => {
+                socket.send(&event).await?;
+            }
+            Destination::Http(client, url) => {
+                let response = client
+                    .post(url.clone())
+                    .header("Content-Type", "application/json")
+                    .body(event)
+                    .send()
+                    .await?;
+                if !response.status().is_success() {
+                    return Err(anyhow!("Alert request failed with {}", response.status()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
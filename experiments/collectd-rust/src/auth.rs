@@ -0,0 +1,147 @@
+// Authentication for the ingest endpoints. A static bearer token, API keys that
+// tag what is sent with them, the basic auth user/password that collectd's
+// write_http plugin can send, or any mix of those.
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::sync::Arc;
+
+use crate::metric::{self, Tags};
+use crate::tenant;
+use crate::AppState;
+
+// Who an authenticated request came from, for handlers that care (the rate
+// limiter, tagging). Only set when the credentials name someone, i.e. basic
+// auth or an API key.
+#[derive(Clone)]
+pub struct Identity {
+    pub name: String,
+    pub tags: Arc<Tags>,
+}
+
+pub struct ApiKey {
+    key: String,
+    identity: Identity,
+}
+
+// --api-key entries, "<key>" or "<key>:<tag>=<value>,...". Keys are named by
+// their position in logs and rate limiting, so the secret never shows up there.
+pub fn parse_api_keys(specs: &[String]) -> Result<Vec<ApiKey>> {
+    let mut keys = Vec::with_capacity(specs.len());
+    for (i, spec) in specs.iter().enumerate() {
+        let (key, tag_list) = spec.split_once(':').unwrap_or((spec, ""));
+        if key.is_empty() {
+            return Err(anyhow!("Empty key in --api-key #{}", i + 1));
+        }
+        let mut tags = Tags::new();
+        for pair in tag_list.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid tag {:?} in --api-key #{}, expected <tag>=<value>", pair, i + 1))?;
+            let name = name.trim();
+            if !metric::valid_tag_name(name) {
+                return Err(anyhow!("Invalid tag name {:?} in --api-key #{}", name, i + 1));
+            }
+            if name == tenant::TAG {
+                tenant::validate_name(value.trim())?;
+            }
+            tags.insert(name.to_string(), value.trim().to_string());
+        }
+        keys.push(ApiKey {
+            key: key.to_string(),
+            identity: Identity {
+                name: format!("key:{}", i + 1),
+                tags: Arc::new(tags),
+            },
+        });
+    }
+    Ok(keys)
+}
+
+pub async fn require_auth(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    match authenticate(&state, authorization) {
+        Auth::Anonymous => next.run(request).await,
+        Auth::Identified(identity) => {
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        Auth::Denied => {
+            let mut response = StatusCode::UNAUTHORIZED.into_response();
+            if state.config.auth_user.is_some() {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Basic realm=\"collectd\""));
+            }
+            response
+        }
+    }
+}
+
+// What an Authorization header value amounts to, shared with the gRPC listener
+pub(crate) enum Auth {
+    // No auth configured, or the static token
+    Anonymous,
+    Identified(Identity),
+    Denied,
+}
+
+pub(crate) fn authenticate(state: &AppState, authorization: &str) -> Auth {
+    let config = &state.config;
+    if config.auth_token.is_none() && config.auth_user.is_none() && state.api_keys.is_empty() {
+        return Auth::Anonymous;
+    }
+
+    if let Some(token) = authorization.strip_prefix("Bearer ").map(str::trim) {
+        if config
+            .auth_token
+            .as_ref()
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+        {
+            return Auth::Anonymous;
+        }
+        // Check every key so the time taken doesn't say which one was close
+        let matched = state.api_keys.iter().fold(None, |matched, api_key| {
+            if constant_time_eq(api_key.key.as_bytes(), token.as_bytes()) {
+                Some(api_key)
+            } else {
+                matched
+            }
+        });
+        if let Some(api_key) = matched {
+            return Auth::Identified(api_key.identity.clone());
+        }
+    }
+    if let (Some(user), Some(encoded)) = (&config.auth_user, authorization.strip_prefix("Basic ")) {
+        let password = config.auth_password.as_deref().unwrap_or_default();
+        let expected = format!("{}:{}", user, password);
+        if STANDARD
+            .decode(encoded.trim())
+            .is_ok_and(|decoded| constant_time_eq(expected.as_bytes(), &decoded))
+        {
+            return Auth::Identified(Identity {
+                name: format!("user:{}", user),
+                tags: Arc::default(),
+            });
+        }
+    }
+    Auth::Denied
+}
+
+// Compare without bailing on the first mismatch, so response timing doesn't leak
+// how much of a guessed secret was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,219 @@
+// Load generator: POSTs synthetic write_http payloads at a fixed request rate
+// and reports the throughput achieved and the latency percentiles. Requests
+// are scheduled open loop, so a slow receiver shows up as latency and missed
+// sends instead of quietly lowering the rate. Without --url a receiver is
+// started in-process with the usual config and benchmarked over loopback.
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tracing::info;
+
+use crate::config::{parse_duration, Config};
+use crate::sources::http::HttpSource;
+use crate::sources::Source;
+
+pub struct BenchOptions {
+    pub url: Option<String>,
+    pub rate: f64,
+    pub duration: String,
+    pub connections: usize,
+    pub hosts: usize,
+    pub values_per_request: usize,
+}
+
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    ok: u64,
+    rate_limited: u64,
+    unavailable: u64,
+    other_status: u64,
+    errors: u64,
+}
+
+pub async fn run(config: &Config, options: BenchOptions) -> Result<()> {
+    if !options.rate.is_finite() || options.rate <= 0.0 {
+        return Err(anyhow!("--rate must be positive"));
+    }
+    if options.connections == 0 || options.hosts == 0 || options.values_per_request == 0 {
+        return Err(anyhow!("--connections, --hosts and --values-per-request must be at least 1"));
+    }
+    let duration = parse_duration(&options.duration)?;
+
+    let url = match &options.url {
+        Some(url) => url.clone(),
+        None => format!("http://{}/collectd", serve_in_process(config).await?),
+    };
+    info!(
+        "Sending {} requests/s of {} value lists for {:?} to {} over {}",
+        options.rate,
+        options.values_per_request,
+        duration,
+        url,
+        if config.http2 { "HTTP/2" } else { "HTTP/1.1" }
+    );
+
+    // With --http2 the requests are sent over HTTP/2 as well, and --connections is
+    // the number of streams in flight on one connection
+    let mut client = reqwest::Client::builder().pool_max_idle_per_host(options.connections);
+    if config.http2 {
+        client = client.http2_prior_knowledge();
+    }
+    let client = client.build()?;
+    let slots = Arc::new(Semaphore::new(options.connections));
+    let results = Arc::new(Mutex::new(Results::default()));
+    let mut generator = Generator::new(options.hosts);
+    let mut ticker = interval(Duration::from_secs_f64(1.0 / options.rate));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+    let started = Instant::now();
+    let (mut sent, mut missed) = (0u64, 0u64);
+    while started.elapsed() < duration {
+        ticker.tick().await;
+        // Every connection busy: the receiver can't keep up with the rate
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            missed += 1;
+            continue;
+        };
+        let body = generator.payload(options.values_per_request);
+        let mut request = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(token) = &config.auth_token {
+            request = request.bearer_auth(token);
+        } else if let Some(user) = &config.auth_user {
+            request = request.basic_auth(user, config.auth_password.as_ref());
+        }
+        let results = results.clone();
+        sent += 1;
+        tokio::spawn(async move {
+            let begun = Instant::now();
+            let response = request.send().await;
+            let latency = begun.elapsed();
+            let mut results = results.lock().unwrap();
+            match response.map(|r| r.status()) {
+                Ok(status) if status.is_success() => {
+                    results.ok += 1;
+                    results.latencies.push(latency);
+                }
+                Ok(status) if status.as_u16() == 429 => results.rate_limited += 1,
+                Ok(status) if status.as_u16() == 503 => results.unavailable += 1,
+                Ok(_) => results.other_status += 1,
+                Err(_) => results.errors += 1,
+            }
+            drop(slot);
+        });
+    }
+    // Let the requests still in flight finish
+    let _ = slots.acquire_many(options.connections as u32).await?;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let mut results = results.lock().unwrap();
+    results.latencies.sort();
+    let percentile = |p: f64| {
+        let latencies = &results.latencies;
+        if latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        latencies[((latencies.len() - 1) as f64 * p).round() as usize]
+    };
+    println!("Requests: {} sent, {} missed (all {} connections busy)", sent, missed, options.connections);
+    println!(
+        "Responses: {} ok, {} rate limited, {} unavailable, {} other status, {} failed",
+        results.ok, results.rate_limited, results.unavailable, results.other_status, results.errors
+    );
+    println!(
+        "Throughput: {:.1} requests/s, {:.1} value lists/s",
+        results.ok as f64 / elapsed,
+        (results.ok * options.values_per_request as u64) as f64 / elapsed
+    );
+    println!(
+        "Latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(1.0)
+    );
+    Ok(())
+}
+
+// The receiver with the usual outputs and HTTP settings, on a loopback port of its own
+async fn serve_in_process(config: &Config) -> Result<SocketAddr> {
+    let state = crate::build(config).await?;
+    let source = HttpSource::open("127.0.0.1:0", config, state.telemetry.clone()).await?;
+    let addr = source.local_addr()?;
+    tokio::spawn(Box::new(source).run(state.pipeline));
+    Ok(addr)
+}
+
+// Value lists shaped like a stock collectd agent's: per-CPU derives, memory
+// gauges, interface counters with rx/tx, load averages. Counters only ever go
+// up, gauges wander, and the hosts take turns.
+struct Generator {
+    hosts: usize,
+    next: usize,
+    counter: u64,
+    seed: u64,
+}
+
+impl Generator {
+    fn new(hosts: usize) -> Generator {
+        Generator {
+            hosts,
+            next: 0,
+            counter: 0,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    // xorshift, enough to make gauges look alive
+    fn random(&mut self) -> f64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed % 10_000) as f64 / 10_000.0
+    }
+
+    fn payload(&mut self, values: usize) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let mut lists = Vec::with_capacity(values);
+        while lists.len() < values {
+            let host = format!("bench-{:04}", self.next % self.hosts);
+            let n = self.next / self.hosts;
+            self.next += 1;
+            self.counter += 1;
+            let list = match n % 4 {
+                0 => serde_json::json!({
+                    "values": [self.counter * 100], "dstypes": ["derive"], "dsnames": ["value"],
+                    "host": host, "plugin": "cpu", "plugin_instance": (n / 4 % 8).to_string(),
+                    "type": "cpu", "type_instance": "user", "time": now, "interval": 10.0,
+                }),
+                1 => serde_json::json!({
+                    "values": [1e9 + self.random() * 4e9], "dstypes": ["gauge"], "dsnames": ["value"],
+                    "host": host, "plugin": "memory", "plugin_instance": "",
+                    "type": "memory", "type_instance": "used", "time": now, "interval": 10.0,
+                }),
+                2 => serde_json::json!({
+                    "values": [self.counter * 1500, self.counter * 900], "dstypes": ["derive", "derive"],
+                    "dsnames": ["rx", "tx"], "host": host, "plugin": "interface", "plugin_instance": "eth0",
+                    "type": "if_octets", "type_instance": "", "time": now, "interval": 10.0,
+                }),
+                _ => serde_json::json!({
+                    "values": [self.random() * 4.0, self.random() * 3.0, self.random() * 2.0],
+                    "dstypes": ["gauge", "gauge", "gauge"], "dsnames": ["shortterm", "midterm", "longterm"],
+                    "host": host, "plugin": "load", "plugin_instance": "",
+                    "type": "load", "type_instance": "", "time": now, "interval": 10.0,
+                }),
+            };
+            lists.push(list);
+        }
+        serde_json::Value::Array(lists).to_string()
+    }
+}
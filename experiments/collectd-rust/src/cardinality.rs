@@ -0,0 +1,103 @@
+// Caps the number of distinct series (identity plus tags) passed on. Once
+// --max-series are tracked, points of any further series are either dropped or
+// moved onto an overflow series, with host, plugin_instance and type_instance
+// set to "overflow", so a runaway plugin_instance can't flood the storage behind
+// the outputs. Overflow series don't count towards the limit. Series not seen
+// within the TTL are evicted and free their slot.
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::metric::{MetricId, ProcessedMetric, Tags};
+
+// Value of host, plugin_instance and type_instance on overflow series
+pub const OVERFLOW: &str = "overflow";
+
+// What happens to points of series beyond the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Drop,
+    Aggregate,
+}
+
+impl FromStr for Overflow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Overflow> {
+        match s {
+            "drop" => Ok(Overflow::Drop),
+            "aggregate" => Ok(Overflow::Aggregate),
+            other => Err(anyhow!("Invalid series overflow action: {}, expected drop or aggregate", other)),
+        }
+    }
+}
+
+// A full table is swept for expired series at most this often
+const FULL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct CardinalityLimiter {
+    max_series: usize,
+    overflow: Overflow,
+    ttl: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    series: HashMap<(MetricId, Tags), Instant>,
+    last_sweep: Instant,
+}
+
+impl CardinalityLimiter {
+    pub fn new(max_series: usize, overflow: Overflow, ttl: Duration) -> CardinalityLimiter {
+        CardinalityLimiter {
+            max_series,
+            overflow,
+            ttl,
+            state: Mutex::new(State {
+                series: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    // Applies the limit in place. Returns the number of points over the limit,
+    // dropped or rewritten, and the number of series now tracked.
+    pub fn apply(&self, metrics: &mut Vec<ProcessedMetric>) -> (usize, usize) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let since_sweep = now.duration_since(state.last_sweep);
+        if since_sweep >= self.ttl || (state.series.len() >= self.max_series && since_sweep >= FULL_SWEEP_INTERVAL) {
+            let ttl = self.ttl;
+            state.series.retain(|_, seen| now.duration_since(*seen) < ttl);
+            state.last_sweep = now;
+        }
+
+        let mut limited = 0;
+        metrics.retain_mut(|metric| {
+            let key = (MetricId::of(metric), metric.tags.clone());
+            if let Some(seen) = state.series.get_mut(&key) {
+                *seen = now;
+                return true;
+            }
+            if state.series.len() < self.max_series {
+                state.series.insert(key, now);
+                return true;
+            }
+            limited += 1;
+            match self.overflow {
+                Overflow::Drop => false,
+                Overflow::Aggregate => {
+                    metric.host = Some(OVERFLOW.into());
+                    metric.plugin_instance = Some(OVERFLOW.into());
+                    metric.type_instance = Some(OVERFLOW.into());
+                    true
+                }
+            }
+        });
+        (limited, state.series.len())
+    }
+}
@@ -0,0 +1,257 @@
+// Decoder for collectd's binary network protocol, as sent by the `network` plugin.
+// See https://collectd.org/wiki/index.php/Binary_protocol
+// Every packet is a sequence of parts. Identity parts (host, plugin, type, ...)
+// update the running state, and each VALUES part emits one metric with that state.
+use anyhow::{anyhow, bail, Result};
+use tracing::debug;
+
+use crate::metric::CollectdMetric;
+
+const PART_HOST: u16 = 0x0000;
+const PART_TIME: u16 = 0x0001;
+const PART_PLUGIN: u16 = 0x0002;
+const PART_PLUGIN_INSTANCE: u16 = 0x0003;
+const PART_TYPE: u16 = 0x0004;
+const PART_TYPE_INSTANCE: u16 = 0x0005;
+const PART_VALUES: u16 = 0x0006;
+const PART_INTERVAL: u16 = 0x0007;
+const PART_TIME_HR: u16 = 0x0008;
+const PART_INTERVAL_HR: u16 = 0x0009;
+const PART_MESSAGE: u16 = 0x0100;
+const PART_SEVERITY: u16 = 0x0101;
+const PART_SIGNATURE: u16 = 0x0200;
+const PART_ENCRYPTION: u16 = 0x0210;
+
+const DS_TYPE_COUNTER: u8 = 0;
+const DS_TYPE_GAUGE: u8 = 1;
+const DS_TYPE_DERIVE: u8 = 2;
+const DS_TYPE_ABSOLUTE: u8 = 3;
+
+// Type and length header in front of every part
+const PART_HEADER_LEN: usize = 4;
+
+#[derive(Default)]
+struct State {
+    host: Option<String>,
+    time: Option<f64>,
+    interval: Option<f64>,
+    plugin: Option<String>,
+    plugin_instance: Option<String>,
+    type_: Option<String>,
+    type_instance: Option<String>,
+}
+
+pub fn parse_packet(mut data: &[u8]) -> Result<Vec<CollectdMetric>> {
+    let mut state = State::default();
+    let mut metrics = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < PART_HEADER_LEN {
+            bail!("Truncated part header ({} bytes left)", data.len());
+        }
+        let part_type = u16::from_be_bytes([data[0], data[1]]);
+        let part_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if part_len < PART_HEADER_LEN || part_len > data.len() {
+            bail!("Invalid length {} for part type {:#06x}", part_len, part_type);
+        }
+        let body = &data[PART_HEADER_LEN..part_len];
+        data = &data[part_len..];
+
+        match part_type {
+            PART_HOST => state.host = Some(parse_string(body)?),
+            PART_PLUGIN => state.plugin = Some(parse_string(body)?),
+            PART_PLUGIN_INSTANCE => state.plugin_instance = Some(parse_string(body)?),
+            PART_TYPE => state.type_ = Some(parse_string(body)?),
+            PART_TYPE_INSTANCE => state.type_instance = Some(parse_string(body)?),
+            PART_TIME => state.time = Some(parse_u64(body)? as f64),
+            // High resolution time is in units of 2^-30 seconds
+            PART_TIME_HR => state.time = Some(parse_u64(body)? as f64 / (1u64 << 30) as f64),
+            PART_INTERVAL => state.interval = Some(parse_u64(body)? as f64),
+            PART_INTERVAL_HR => state.interval = Some(parse_u64(body)? as f64 / (1u64 << 30) as f64),
+            PART_VALUES => {
+                let (values, dstypes) = parse_values(body)?;
+                metrics.push(CollectdMetric {
+                    time: state.time,
+                    interval: state.interval,
+                    host: state.host.clone(),
+                    plugin: state.plugin.clone(),
+                    plugin_instance: state.plugin_instance.clone(),
+                    type_: state.type_.clone(),
+                    type_instance: state.type_instance.clone(),
+                    value: None,
+                    values: Some(values),
+                    dstypes: Some(dstypes),
+                    dsnames: None,
+                })
+            }
+            PART_ENCRYPTION => bail!("Encrypted packets are not supported"),
+            // Not needed for metrics: notifications and signatures
+            // (signed packets still carry their parts in the clear)
+            PART_MESSAGE | PART_SEVERITY | PART_SIGNATURE => {}
+            // Like collectd itself, skip parts from newer agents rather than
+            // losing the values around them
+            other => debug!("Skipping unknown part type {:#06x}", other),
+        }
+    }
+
+    Ok(metrics)
+}
+
+// Strings are null terminated
+fn parse_string(body: &[u8]) -> Result<String> {
+    match body.split_last() {
+        Some((0, s)) => Ok(String::from_utf8_lossy(s).into_owned()),
+        _ => Err(anyhow!("String part is not null terminated")),
+    }
+}
+
+fn parse_u64(body: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] = body
+        .try_into()
+        .map_err(|_| anyhow!("Numeric part has {} bytes, expected 8", body.len()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// Layout: u16 count, count data source types, then count 8 byte values.
+// Gauges are little endian doubles, everything else is big endian.
+fn parse_values(body: &[u8]) -> Result<(Vec<serde_json::Value>, Vec<String>)> {
+    if body.len() < 2 {
+        bail!("Truncated values part");
+    }
+    let count = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let types = &body[2..];
+    if types.len() != count * 9 {
+        bail!("Values part has {} bytes, expected {} for {} values", body.len(), 2 + count * 9, count);
+    }
+    let (types, raw_values) = types.split_at(count);
+
+    let mut values = Vec::with_capacity(count);
+    let mut dstypes = Vec::with_capacity(count);
+    for (ds_type, raw) in types.iter().zip(raw_values.chunks_exact(8)) {
+        let bytes: [u8; 8] = raw.try_into()?;
+        let (value, name) = match *ds_type {
+            DS_TYPE_COUNTER => (serde_json::Value::from(u64::from_be_bytes(bytes)), "counter"),
+            DS_TYPE_ABSOLUTE => (serde_json::Value::from(u64::from_be_bytes(bytes)), "absolute"),
+            DS_TYPE_DERIVE => (serde_json::Value::from(i64::from_be_bytes(bytes)), "derive"),
+            // NaN has no JSON representation and ends up as null
+            DS_TYPE_GAUGE => (serde_json::Value::from(f64::from_le_bytes(bytes)), "gauge"),
+            other => bail!("Unknown data source type {}", other),
+        };
+        values.push(value);
+        dstypes.push(name.to_string());
+    }
+    Ok((values, dstypes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn part(part_type: u16, body: &[u8]) -> Vec<u8> {
+        let mut part = Vec::new();
+        part.extend_from_slice(&part_type.to_be_bytes());
+        part.extend_from_slice(&(body.len() as u16 + 4).to_be_bytes());
+        part.extend_from_slice(body);
+        part
+    }
+
+    fn string_part(part_type: u16, s: &str) -> Vec<u8> {
+        part(part_type, &[s.as_bytes(), &[0]].concat())
+    }
+
+    // host "localhost", high resolution time 1700000000 and interval 10, then
+    // load/load with three gauges and interface-eth0/if_octets with two derives
+    const CAPTURE: &str = "0000000e6c6f63616c686f7374000008000c1954fc40000000000009000c00000002800000000002\
+        00096c6f616400000400096c6f616400000600210003010101000000000000d03f000000000000e03f000000000000f03f\
+        0002000e696e74657266616365000003000965746830000004000e69665f6f637465747300000600180002020200000000\
+        000004d2fffffffffffffffb";
+
+    #[test]
+    fn decodes_capture() {
+        let metrics = parse_packet(&unhex(CAPTURE)).unwrap();
+        assert_eq!(metrics.len(), 2);
+
+        let load = &metrics[0];
+        assert_eq!(load.host.as_deref(), Some("localhost"));
+        assert_eq!(load.time, Some(1700000000.0));
+        assert_eq!(load.interval, Some(10.0));
+        assert_eq!(load.plugin.as_deref(), Some("load"));
+        assert_eq!(load.plugin_instance, None);
+        assert_eq!(load.type_.as_deref(), Some("load"));
+        assert_eq!(load.values, Some(vec![0.25.into(), 0.5.into(), 1.0.into()]));
+        assert_eq!(load.dstypes, Some(vec!["gauge".to_string(); 3]));
+
+        // Identity parts carry over from the previous values part
+        let octets = &metrics[1];
+        assert_eq!(octets.host.as_deref(), Some("localhost"));
+        assert_eq!(octets.time, Some(1700000000.0));
+        assert_eq!(octets.plugin.as_deref(), Some("interface"));
+        assert_eq!(octets.plugin_instance.as_deref(), Some("eth0"));
+        assert_eq!(octets.type_.as_deref(), Some("if_octets"));
+        assert_eq!(octets.values, Some(vec![1234.into(), (-5).into()]));
+        assert_eq!(octets.dstypes, Some(vec!["derive".to_string(); 2]));
+    }
+
+    #[test]
+    fn round_trips_every_data_source_type() {
+        let values = [
+            (DS_TYPE_COUNTER, u64::MAX.to_be_bytes(), serde_json::Value::from(u64::MAX), "counter"),
+            (DS_TYPE_GAUGE, (-1.5f64).to_le_bytes(), (-1.5).into(), "gauge"),
+            (DS_TYPE_DERIVE, i64::MIN.to_be_bytes(), i64::MIN.into(), "derive"),
+            (DS_TYPE_ABSOLUTE, 7u64.to_be_bytes(), 7.into(), "absolute"),
+        ];
+        let mut body = (values.len() as u16).to_be_bytes().to_vec();
+        body.extend(values.iter().map(|(ds_type, ..)| *ds_type));
+        for (_, raw, ..) in &values {
+            body.extend_from_slice(raw);
+        }
+        let packet = [
+            string_part(PART_HOST, "web01"),
+            part(PART_TIME, &1700000000u64.to_be_bytes()),
+            part(PART_INTERVAL, &60u64.to_be_bytes()),
+            string_part(PART_PLUGIN, "test"),
+            string_part(PART_TYPE_INSTANCE, "all"),
+            part(PART_VALUES, &body),
+        ]
+        .concat();
+
+        let metrics = parse_packet(&packet).unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].host.as_deref(), Some("web01"));
+        assert_eq!(metrics[0].time, Some(1700000000.0));
+        assert_eq!(metrics[0].interval, Some(60.0));
+        assert_eq!(metrics[0].type_instance.as_deref(), Some("all"));
+        let expected: Vec<serde_json::Value> = values.iter().map(|(_, _, value, _)| value.clone()).collect();
+        assert_eq!(metrics[0].values, Some(expected));
+        let dstypes: Vec<String> = values.iter().map(|(.., name)| name.to_string()).collect();
+        assert_eq!(metrics[0].dstypes, Some(dstypes));
+    }
+
+    #[test]
+    fn skips_unknown_and_notification_parts() {
+        let mut packet = unhex(CAPTURE);
+        let (head, tail) = packet.split_at(14);
+        packet = [head, &part(0x7777, b"from a newer agent"), &string_part(PART_MESSAGE, "hi"), tail].concat();
+        let metrics = parse_packet(&packet).unwrap();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].host.as_deref(), Some("localhost"));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let capture = unhex(CAPTURE);
+        // Cut inside a part
+        assert!(parse_packet(&capture[..capture.len() - 3]).is_err());
+        // Length shorter than the header
+        assert!(parse_packet(&[0, 0, 0, 2]).is_err());
+        // String without its terminator
+        assert!(parse_packet(&part(PART_HOST, b"web01")).is_err());
+        // Values count that doesn't match the body
+        assert!(parse_packet(&part(PART_VALUES, &[0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0])).is_err());
+        assert!(parse_packet(&part(PART_ENCRYPTION, &[0; 8])).is_err());
+    }
+}
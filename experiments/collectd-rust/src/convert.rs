@@ -0,0 +1,212 @@
+// Offline conversion of an NDJSON archive (the disk output's json/ndjson files,
+// or dead letters) into another format: the record formats of --output-format,
+// InfluxDB line protocol as the influx output writes it, or Parquet for
+// analysis. Records are encoded by the same code the outputs use.
+use anyhow::{anyhow, Result};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::replay::read_archive;
+use crate::sinks::influx::to_line_protocol;
+
+// Rows per Parquet row group, buffered in memory until written
+const ROW_GROUP_SIZE: usize = 100_000;
+
+const PARQUET_SCHEMA: &str = "
+message collectd {
+    optional int64 time (TIMESTAMP(MICROS, true));
+    optional double interval;
+    optional binary host (STRING);
+    optional binary plugin (STRING);
+    optional binary plugin_instance (STRING);
+    optional binary type (STRING);
+    optional binary type_instance (STRING);
+    optional binary dsname (STRING);
+    optional binary dstype (STRING);
+    optional binary aggregation (STRING);
+    optional binary unit (STRING);
+    optional double value;
+}";
+
+enum Target {
+    Records(OutputFormat),
+    Influx,
+    Parquet,
+}
+
+impl Target {
+    // --to if given, otherwise the output file's extension
+    fn pick(to: Option<&str>, output: &str) -> Result<Target> {
+        let name = match to {
+            Some(to) => to,
+            None => output
+                .rsplit_once('.')
+                .map(|(_, extension)| extension)
+                .ok_or_else(|| anyhow!("Can't tell the format of {} from its name, pass --to", output))?,
+        };
+        match name {
+            "influx" | "lp" | "line" => Ok(Target::Influx),
+            "parquet" => Ok(Target::Parquet),
+            "jsonl" => Ok(Target::Records(OutputFormat::Ndjson)),
+            other => Ok(Target::Records(other.parse()?)),
+        }
+    }
+}
+
+// Returns how many metrics were written
+pub fn run(input: &str, output: &str, to: Option<&str>) -> Result<usize> {
+    let target = Target::pick(to, output)?;
+    let file = File::create(output).map_err(|e| anyhow!("Failed to create {}: {}", output, e))?;
+
+    let mut written = 0;
+    let mut failure = None;
+    let skipped = match target {
+        Target::Records(format) => {
+            let mut out = BufWriter::new(file);
+            if let Some(header) = format.file_header() {
+                out.write_all(header.as_bytes())?;
+            }
+            let skipped = read_archive(input, |metric| {
+                match format.encode(&metric).and_then(|record| Ok(out.write_all(&record)?)) {
+                    Ok(()) => written += 1,
+                    Err(e) => failure = Some(e),
+                }
+                failure.is_none()
+            })?;
+            out.flush()?;
+            skipped
+        }
+        Target::Influx => {
+            let mut out = BufWriter::new(file);
+            let skipped = read_archive(input, |metric| {
+                match writeln!(out, "{}", to_line_protocol(&metric)) {
+                    Ok(()) => written += 1,
+                    Err(e) => failure = Some(e.into()),
+                }
+                failure.is_none()
+            })?;
+            out.flush()?;
+            skipped
+        }
+        Target::Parquet => {
+            let mut out = ParquetWriter::new(file)?;
+            let skipped = read_archive(input, |metric| {
+                match out.push(metric) {
+                    Ok(()) => written += 1,
+                    Err(e) => failure = Some(e),
+                }
+                failure.is_none()
+            })?;
+            out.close()?;
+            skipped
+        }
+    };
+    if let Some(e) = failure {
+        return Err(e.context(format!("Failed to write {}", output)));
+    }
+    info!("Converted {} metrics from {} to {}, skipped {} unreadable lines", written, input, output, skipped);
+    Ok(written)
+}
+
+// Snappy compressed Parquet with the CSV columns, tags left out. Times are UTC
+// timestamps.
+struct ParquetWriter {
+    writer: SerializedFileWriter<File>,
+    rows: Vec<ProcessedMetric>,
+}
+
+impl ParquetWriter {
+    fn new(file: File) -> Result<ParquetWriter> {
+        let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        Ok(ParquetWriter {
+            writer: SerializedFileWriter::new(file, schema, Arc::new(properties))?,
+            rows: Vec::with_capacity(ROW_GROUP_SIZE),
+        })
+    }
+
+    fn push(&mut self, metric: ProcessedMetric) -> Result<()> {
+        self.rows.push(metric);
+        if self.rows.len() >= ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn close(mut self) -> Result<()> {
+        if !self.rows.is_empty() {
+            self.write_row_group()?;
+        }
+        self.writer.close()?;
+        Ok(())
+    }
+
+    // Columns in schema order
+    fn write_row_group(&mut self) -> Result<()> {
+        let rows = std::mem::take(&mut self.rows);
+        let text_columns: [fn(&ProcessedMetric) -> Option<&str>; 9] = [
+            |m| m.host.as_deref(),
+            |m| m.plugin.as_deref(),
+            |m| m.plugin_instance.as_deref(),
+            |m| m.type_.as_deref(),
+            |m| m.type_instance.as_deref(),
+            |m| m.dsname.as_deref(),
+            |m| m.dstype.as_deref(),
+            |m| m.aggregation.as_deref(),
+            |m| m.unit.as_deref(),
+        ];
+
+        let mut group = self.writer.next_row_group()?;
+        let mut column = group.next_column()?.ok_or_else(|| anyhow!("Parquet schema is missing time"))?;
+        let (values, levels) = optional(&rows, |m| m.time.map(|t| (t * 1e6) as i64));
+        column.typed::<Int64Type>().write_batch(&values, Some(&levels), None)?;
+        column.close()?;
+
+        let mut column = group.next_column()?.ok_or_else(|| anyhow!("Parquet schema is missing interval"))?;
+        let (values, levels) = optional(&rows, |m| m.interval);
+        column.typed::<DoubleType>().write_batch(&values, Some(&levels), None)?;
+        column.close()?;
+
+        for field in text_columns {
+            let mut column = group.next_column()?.ok_or_else(|| anyhow!("Parquet schema is missing a column"))?;
+            let (values, levels) = optional(&rows, |m| field(m).map(ByteArray::from));
+            column.typed::<ByteArrayType>().write_batch(&values, Some(&levels), None)?;
+            column.close()?;
+        }
+
+        let mut column = group.next_column()?.ok_or_else(|| anyhow!("Parquet schema is missing value"))?;
+        let (values, levels) = optional(&rows, |m| Some(m.value.as_f64()));
+        column.typed::<DoubleType>().write_batch(&values, Some(&levels), None)?;
+        column.close()?;
+
+        group.close()?;
+        Ok(())
+    }
+}
+
+// The present values plus a definition level per row, 1 present and 0 null
+fn optional<T>(rows: &[ProcessedMetric], get: impl Fn(&ProcessedMetric) -> Option<T>) -> (Vec<T>, Vec<i16>) {
+    let mut values = Vec::with_capacity(rows.len());
+    let mut levels = Vec::with_capacity(rows.len());
+    for row in rows {
+        match get(row) {
+            Some(value) => {
+                values.push(value);
+                levels.push(1);
+            }
+            None => levels.push(0),
+        }
+    }
+    (values, levels)
+}
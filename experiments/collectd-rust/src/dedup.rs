@@ -0,0 +1,64 @@
+// Drops exact duplicates: points with the same identity, tags and timestamp as
+// one already seen within the window. Retrying agents and proxies resend whole
+// batches after a 5xx that was only partly processed. A point's entry expires a
+// window after it was first received, later copies don't extend it. Points
+// without a timestamp can't be told apart from a new sample and always pass.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::metric::{MetricId, ProcessedMetric, Tags};
+
+// The timestamp's bits, f64 isn't Hash
+type Key = (MetricId, Tags, u64);
+
+pub struct Deduplicator {
+    window: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    seen: HashMap<Key, Instant>,
+    last_sweep: Instant,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Deduplicator {
+        Deduplicator {
+            window,
+            state: Mutex::new(State {
+                seen: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    // Removes the duplicates in place, returns how many there were
+    pub fn apply(&self, metrics: &mut Vec<ProcessedMetric>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let window = self.window;
+        if now.duration_since(state.last_sweep) >= window {
+            state.seen.retain(|_, first_seen| now.duration_since(*first_seen) < window);
+            state.last_sweep = now;
+        }
+
+        let before = metrics.len();
+        metrics.retain(|metric| {
+            let Some(time) = metric.time else {
+                return true;
+            };
+            let key = (MetricId::of(metric), metric.tags.clone(), time.to_bits());
+            match state.seen.get(&key) {
+                Some(first_seen) if now.duration_since(*first_seen) < window => false,
+                _ => {
+                    state.seen.insert(key, now);
+                    true
+                }
+            }
+        });
+        before - metrics.len()
+    }
+}
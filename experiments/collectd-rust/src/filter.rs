@@ -0,0 +1,125 @@
+// Allow/deny rules applied to every metric before it is queued.
+// Rule syntax is `<allow|deny>:<field>=<value>` for an exact match or
+// `<allow|deny>:<field>~<regex>` for a regex match, e.g.
+//   allow:plugin~^(cpu|memory|df)$
+//   deny:host=test-box
+// With any allow rule present a metric has to match at least one of them,
+// and a metric matching any deny rule is dropped.
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+use crate::metric::ProcessedMetric;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Field {
+    Host,
+    Plugin,
+    PluginInstance,
+    Type,
+    TypeInstance,
+}
+
+impl Field {
+    pub fn parse(name: &str) -> Result<Field> {
+        match name {
+            "host" => Ok(Field::Host),
+            "plugin" => Ok(Field::Plugin),
+            "plugin_instance" => Ok(Field::PluginInstance),
+            "type" => Ok(Field::Type),
+            "type_instance" => Ok(Field::TypeInstance),
+            other => Err(anyhow!("Unknown metric field: {}", other)),
+        }
+    }
+
+    // Missing fields read as empty strings
+    pub fn get<'a>(&self, metric: &'a ProcessedMetric) -> &'a str {
+        let value = match self {
+            Field::Host => &metric.host,
+            Field::Plugin => &metric.plugin,
+            Field::PluginInstance => &metric.plugin_instance,
+            Field::Type => &metric.type_,
+            Field::TypeInstance => &metric.type_instance,
+        };
+        value.as_deref().unwrap_or_default()
+    }
+
+    // An empty value unsets the field
+    pub fn set(&self, metric: &mut ProcessedMetric, value: String) {
+        let field = match self {
+            Field::Host => &mut metric.host,
+            Field::Plugin => &mut metric.plugin,
+            Field::PluginInstance => &mut metric.plugin_instance,
+            Field::Type => &mut metric.type_,
+            Field::TypeInstance => &mut metric.type_instance,
+        };
+        *field = Some(value).filter(|value| !value.is_empty()).map(Into::into);
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Exact(String),
+    Regex(Regex),
+}
+
+// A single `<field>=<value>` or `<field>~<regex>` match, shared with the routing rules
+#[derive(Debug)]
+pub struct Rule {
+    field: Field,
+    matcher: Matcher,
+}
+
+impl Rule {
+    pub fn parse(expr: &str) -> Result<Rule> {
+        let split_at = expr
+            .find(['=', '~'])
+            .ok_or_else(|| anyhow!("Invalid rule {:?}, expected = or ~ after the field name", expr))?;
+        let field = Field::parse(&expr[..split_at])?;
+        let value = &expr[split_at + 1..];
+        let matcher = if expr.as_bytes()[split_at] == b'~' {
+            Matcher::Regex(Regex::new(value).map_err(|e| anyhow!("Invalid regex in rule {:?}: {}", expr, e))?)
+        } else {
+            Matcher::Exact(value.to_string())
+        };
+        Ok(Rule { field, matcher })
+    }
+
+    pub fn matches(&self, metric: &ProcessedMetric) -> bool {
+        let value = self.field.get(metric);
+        match &self.matcher {
+            Matcher::Exact(expected) => value == expected,
+            Matcher::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+impl Filter {
+    pub fn parse(rules: &[String]) -> Result<Filter> {
+        let mut filter = Filter::default();
+        for rule in rules {
+            let (action, expr) = rule
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid filter {:?}, expected <allow|deny>:<field>=<value>", rule))?;
+            let parsed = Rule::parse(expr).map_err(|e| anyhow!("Invalid filter {:?}: {}", rule, e))?;
+            match action {
+                "allow" => filter.allow.push(parsed),
+                "deny" => filter.deny.push(parsed),
+                other => return Err(anyhow!("Invalid filter action {:?} in {:?}", other, rule)),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn allows(&self, metric: &ProcessedMetric) -> bool {
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.matches(metric)) {
+            return false;
+        }
+        !self.deny.iter().any(|rule| rule.matches(metric))
+    }
+}
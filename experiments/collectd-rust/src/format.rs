@@ -0,0 +1,148 @@
+// Record encodings for the outputs that carry whole metrics (disk, UDP, S3,
+// NATS, MQTT, AMQP), picked with --output-format.
+//   json     one JSON object per line in files, a JSON array per datagram or message (the default)
+//   ndjson   one JSON object per line, in files and messages alike
+//   csv      one row per metric, columns in CSV_COLUMNS order. Files start with
+//            a header row, messages don't
+//   msgpack  one MessagePack map per metric, concatenated
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+use crate::metric::ProcessedMetric;
+
+pub const CSV_COLUMNS: &str =
+    "time,interval,host,plugin,plugin_instance,type_,type_instance,dsname,dstype,aggregation,unit,value";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Json,
+    Ndjson,
+    Csv,
+    Msgpack,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" | "json-lines" => Ok(OutputFormat::Ndjson),
+            "csv" => Ok(OutputFormat::Csv),
+            "msgpack" | "messagepack" => Ok(OutputFormat::Msgpack),
+            other => Err(anyhow!("Invalid output format: {}, expected json, ndjson, csv or msgpack", other)),
+        }
+    }
+}
+
+impl OutputFormat {
+    // One record, with its trailing newline for the line-based formats
+    pub fn encode(&self, metric: &ProcessedMetric) -> Result<Vec<u8>> {
+        let mut record = Vec::new();
+        self.encode_into(&mut record, metric)?;
+        Ok(record)
+    }
+
+    // Appends one record to out, for writers that build a batch in one buffer
+    pub fn encode_into(&self, out: &mut Vec<u8>, metric: &ProcessedMetric) -> Result<()> {
+        match self {
+            OutputFormat::Json | OutputFormat::Ndjson => {
+                serde_json::to_writer(&mut *out, metric)?;
+                out.push(b'\n');
+            }
+            OutputFormat::Csv => out.extend_from_slice(csv_row(metric).as_bytes()),
+            OutputFormat::Msgpack => rmp_serde::encode::write_named(out, metric)?,
+        }
+        Ok(())
+    }
+
+    // File extension for objects and files written in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Msgpack => "msgpack",
+        }
+    }
+
+    // For outputs that label their messages
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "application/json",
+            OutputFormat::Ndjson => "application/x-ndjson",
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Msgpack => "application/msgpack",
+        }
+    }
+
+    // Written at the start of every new file
+    pub fn file_header(&self) -> Option<String> {
+        match self {
+            OutputFormat::Csv => Some(format!("{}\n", CSV_COLUMNS)),
+            _ => None,
+        }
+    }
+
+    // How records are framed in a datagram: opening bytes, separator, closing bytes
+    pub fn datagram_framing(&self) -> (&'static [u8], &'static [u8], &'static [u8]) {
+        match self {
+            OutputFormat::Json => (b"[", b",", b"]"),
+            OutputFormat::Ndjson | OutputFormat::Csv | OutputFormat::Msgpack => (b"", b"", b""),
+        }
+    }
+
+    // The record as it goes into a datagram, JSON array elements drop the newline
+    pub fn encode_for_datagram(&self, metric: &ProcessedMetric) -> Result<Vec<u8>> {
+        match self {
+            OutputFormat::Json => Ok(serde_json::to_vec(metric)?),
+            _ => self.encode(metric),
+        }
+    }
+
+    // A whole batch as one message, framed the same way as a datagram
+    pub fn encode_all(&self, metrics: &[ProcessedMetric]) -> Result<Vec<u8>> {
+        let (open, separator, close) = self.datagram_framing();
+        let mut out = open.to_vec();
+        for (i, metric) in metrics.iter().enumerate() {
+            if i > 0 {
+                out.extend_from_slice(separator);
+            }
+            out.extend_from_slice(&self.encode_for_datagram(metric)?);
+        }
+        out.extend_from_slice(close);
+        Ok(out)
+    }
+}
+
+fn csv_row(metric: &ProcessedMetric) -> String {
+    let number = |n: Option<f64>| n.map(|n| n.to_string()).unwrap_or_default();
+    let text = |s: Option<&str>| csv_field(s.unwrap_or_default());
+    let value = metric.value.to_string();
+    let fields = [
+        number(metric.time),
+        number(metric.interval),
+        text(metric.host.as_deref()),
+        text(metric.plugin.as_deref()),
+        text(metric.plugin_instance.as_deref()),
+        text(metric.type_.as_deref()),
+        text(metric.type_instance.as_deref()),
+        text(metric.dsname.as_deref()),
+        text(metric.dstype.as_deref()),
+        text(metric.aggregation.as_deref()),
+        text(metric.unit.as_deref()),
+        value,
+    ];
+    let mut row = fields.join(",");
+    row.push('\n');
+    row
+}
+
+// RFC 4180 quoting, only when the field needs it
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
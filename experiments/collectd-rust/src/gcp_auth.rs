@@ -0,0 +1,177 @@
+// OAuth access tokens from Google application default credentials, looked
+// up the way the client libraries do: $GOOGLE_APPLICATION_CREDENTIALS, then
+// gcloud's application_default_credentials.json, then the metadata server on
+// GCE / GKE. Service account keys are exchanged through a signed JWT, gcloud
+// user credentials through their refresh token.
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use ring::{rand::SystemRandom, signature};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use tracing::info;
+
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CredentialsFile {
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        token_uri: String,
+        project_id: Option<String>,
+    },
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+        quota_project_id: Option<String>,
+    },
+}
+
+enum Source {
+    File(CredentialsFile),
+    Metadata,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+pub struct TokenSource {
+    source: Source,
+    scope: String,
+    client: reqwest::Client,
+    cached: Option<(String, Instant)>,
+}
+
+impl TokenSource {
+    pub fn from_env(scope: &str) -> Result<TokenSource> {
+        let path = match std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => std::env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".config/gcloud/application_default_credentials.json"))
+                .filter(|path| path.exists()),
+        };
+        let source = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(&path)
+                    .map_err(|e| anyhow!("Failed to read Google credentials {}: {}", path.display(), e))?;
+                let file = serde_json::from_str(&text)
+                    .map_err(|e| anyhow!("Unsupported Google credentials in {}: {}", path.display(), e))?;
+                info!("Using Google credentials from {}", path.display());
+                Source::File(file)
+            }
+            None => {
+                info!("No Google credentials file, using the metadata server");
+                Source::Metadata
+            }
+        };
+        Ok(TokenSource {
+            source,
+            scope: scope.to_string(),
+            client: reqwest::Client::new(),
+            cached: None,
+        })
+    }
+
+    // Project of the credentials, if they name one
+    pub fn project_id(&self) -> Option<&str> {
+        match &self.source {
+            Source::File(CredentialsFile::ServiceAccount { project_id, .. }) => project_id.as_deref(),
+            Source::File(CredentialsFile::AuthorizedUser { quota_project_id, .. }) => quota_project_id.as_deref(),
+            Source::Metadata => None,
+        }
+    }
+
+    // A cached token, or a fresh one when it is about to expire
+    pub async fn token(&mut self) -> Result<String> {
+        if let Some((token, expires)) = &self.cached {
+            if Instant::now() + Duration::from_secs(60) < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let response = match &self.source {
+            Source::File(CredentialsFile::ServiceAccount {
+                client_email,
+                private_key,
+                token_uri,
+                ..
+            }) => {
+                let assertion = jwt(client_email, private_key, token_uri, &self.scope)?;
+                self.client
+                    .post(token_uri)
+                    .form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", &assertion)])
+                    .send()
+                    .await?
+            }
+            Source::File(CredentialsFile::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+                ..
+            }) => {
+                self.client
+                    .post("https://oauth2.googleapis.com/token")
+                    .form(&[
+                        ("grant_type", "refresh_token"),
+                        ("client_id", client_id),
+                        ("client_secret", client_secret),
+                        ("refresh_token", refresh_token),
+                    ])
+                    .send()
+                    .await?
+            }
+            Source::Metadata => {
+                self.client
+                    .get(METADATA_TOKEN_URL)
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await?
+            }
+        };
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Google token request failed with {}: {}", status, text));
+        }
+        let response: TokenResponse = serde_json::from_slice(&response.bytes().await?)?;
+        let expires = Instant::now() + Duration::from_secs(response.expires_in);
+        self.cached = Some((response.access_token.clone(), expires));
+        Ok(response.access_token)
+    }
+
+    // Drop the cached token, e.g. after a 401
+    pub fn invalidate(&mut self) {
+        self.cached = None;
+    }
+}
+
+// RS256 signed assertion for the JWT bearer grant
+fn jwt(client_email: &str, private_key: &str, audience: &str, scope: &str) -> Result<String> {
+    let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let header = engine.encode(br#"{"alg":"RS256","typ":"JWT"}"#);
+    let claims = engine.encode(serde_json::to_vec(&serde_json::json!({
+        "iss": client_email,
+        "scope": scope,
+        "aud": audience,
+        "iat": now,
+        "exp": now + 3600,
+    }))?);
+    let message = format!("{}.{}", header, claims);
+
+    // The key is a PKCS#8 PEM block
+    let der: String = private_key.lines().filter(|line| !line.starts_with("-----")).collect();
+    let der = base64::engine::general_purpose::STANDARD.decode(der.trim())?;
+    let key = signature::RsaKeyPair::from_pkcs8(&der).map_err(|e| anyhow!("Invalid service account key: {}", e))?;
+    let mut signature = vec![0; key.public().modulus_len()];
+    key.sign(&signature::RSA_PKCS1_SHA256, &SystemRandom::new(), message.as_bytes(), &mut signature)
+        .map_err(|_| anyhow!("Failed to sign service account JWT"))?;
+    Ok(format!("{}.{}", message, engine.encode(signature)))
+}
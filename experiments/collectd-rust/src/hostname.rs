@@ -0,0 +1,131 @@
+// Normalizes the host field before anything else looks at it, so one machine
+// reporting as both "web1" and "web1.example.com" stays one set of series. In
+// order: a missing host is filled in from the reverse DNS of the sender's
+// address (or the address itself), then the host is lowercased, stripped of its
+// domain and passed through the rewrites, the first matching one winning.
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+use crate::metric::CollectdMetric;
+use crate::relabel::Rewrite;
+
+// How long a reverse lookup, successful or not, is reused
+const LOOKUP_TTL: Duration = Duration::from_secs(300);
+
+// glibc's NI_MAXHOST
+const MAX_HOST_LEN: usize = 1025;
+
+pub struct HostNormalizer {
+    from_peer: bool,
+    lowercase: bool,
+    strip_domain: bool,
+    rewrites: Vec<Rewrite>,
+    lookups: Mutex<HashMap<IpAddr, (String, Instant)>>,
+}
+
+impl HostNormalizer {
+    pub fn new(from_peer: bool, lowercase: bool, strip_domain: bool, rewrites: &[String]) -> Result<HostNormalizer> {
+        let rewrites = rewrites
+            .iter()
+            .map(|rewrite| Rewrite::parse(rewrite).map_err(|e| e.context(format!("Invalid --host-rewrite {:?}", rewrite))))
+            .collect::<Result<_>>()?;
+        Ok(HostNormalizer {
+            from_peer,
+            lowercase,
+            strip_domain,
+            rewrites,
+            lookups: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // The peer is the address the metrics came from, when the source knows it
+    pub async fn apply(&self, metrics: &mut [CollectdMetric], peer: Option<IpAddr>) {
+        if self.from_peer && metrics.iter().any(|metric| is_missing(&metric.host)) {
+            if let Some(peer) = peer {
+                let name = self.peer_name(peer).await;
+                for metric in metrics.iter_mut().filter(|metric| is_missing(&metric.host)) {
+                    metric.host = Some(name.clone());
+                }
+            }
+        }
+        for metric in metrics {
+            if let Some(host) = metric.host.take() {
+                metric.host = Some(self.normalize(host));
+            }
+        }
+    }
+
+    fn normalize(&self, mut host: String) -> String {
+        if self.lowercase {
+            host = host.to_lowercase();
+        }
+        // Addresses have dots too, but no domain
+        if self.strip_domain && host.parse::<IpAddr>().is_err() {
+            if let Some(dot) = host.find('.') {
+                host.truncate(dot);
+            }
+        }
+        for rewrite in &self.rewrites {
+            if let Some(rewritten) = rewrite.apply(&host) {
+                return rewritten;
+            }
+        }
+        host
+    }
+
+    async fn peer_name(&self, peer: IpAddr) -> String {
+        if let Some((name, looked_up)) = self.lookups.lock().unwrap().get(&peer) {
+            if looked_up.elapsed() < LOOKUP_TTL {
+                return name.clone();
+            }
+        }
+        let name = tokio::task::spawn_blocking(move || reverse_lookup(peer))
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| {
+                debug!("No reverse DNS name for {}, using the address as host", peer);
+                peer.to_string()
+            });
+        let mut lookups = self.lookups.lock().unwrap();
+        lookups.retain(|_, (_, looked_up)| looked_up.elapsed() < LOOKUP_TTL);
+        lookups.insert(peer, (name.clone(), Instant::now()));
+        name
+    }
+}
+
+fn is_missing(host: &Option<String>) -> bool {
+    host.as_deref().is_none_or(str::is_empty)
+}
+
+// Blocking PTR lookup through the system resolver
+fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; MAX_HOST_LEN];
+    // Safety: addr points at a sockaddr of addr.len() bytes and host is writable
+    // for its whole length, which getnameinfo NUL-terminates within
+    let result = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr() as *const libc::sockaddr,
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    // Safety: see above, the buffer holds a NUL-terminated string
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    name.to_str().ok().map(str::to_string)
+}
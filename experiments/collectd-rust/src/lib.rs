@@ -0,0 +1,381 @@
+//! Receives collectd metrics over HTTP (write_http JSON) and the binary
+//! network protocol, and forwards them to one or more outputs.
+//!
+//! The `collectd-http-receiver` binary is a thin wrapper around [`run`]. To
+//! embed the receiver, call [`build`] and mount [`server::router`] yourself,
+//! push metrics straight into the returned [`Pipeline`], or run your own
+//! [`Source`] alongside the built-in ones with [`sources::run_all`].
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+pub mod acl;
+pub mod alert;
+pub mod aggregate;
+mod auth;
+pub mod bench;
+pub mod cardinality;
+pub mod collectd_binary;
+pub mod config;
+pub mod convert;
+pub mod dedup;
+pub mod filter;
+pub mod format;
+pub mod gcp_auth;
+pub mod hostname;
+pub mod lookup;
+pub mod metric;
+mod otlp;
+pub mod pipeline;
+pub mod plugin;
+pub mod proto;
+pub mod queue;
+pub mod rate;
+pub mod ratelimit;
+pub mod relabel;
+pub mod reload;
+pub mod replay;
+pub mod retry;
+pub mod routing;
+mod remote_write;
+pub mod rotation;
+pub mod sample;
+pub mod schema;
+pub mod script;
+pub mod server;
+pub mod sigv4;
+pub mod sinks;
+pub mod snmp;
+pub mod sources;
+pub mod systemd;
+pub mod tags;
+pub mod telemetry;
+pub mod tenant;
+pub mod typesdb;
+pub mod units;
+pub mod wal;
+
+pub use config::Config;
+pub use metric::{CollectdMetric, MetricId, ProcessedMetric};
+pub use pipeline::{Pipeline, Stages};
+pub use sinks::Sink;
+pub use sources::Source;
+
+use crate::aggregate::Aggregator;
+use crate::alert::Alerts;
+use crate::cardinality::CardinalityLimiter;
+use crate::dedup::Deduplicator;
+use crate::hostname::HostNormalizer;
+use crate::lookup::HostLookup;
+use crate::plugin::Plugin;
+use crate::queue::{OverflowPolicy, QueueSender};
+use crate::rate::RateTracker;
+use crate::reload::Reloader;
+use crate::sample::Sampler;
+use crate::script::Script;
+use crate::server::AppState;
+use crate::telemetry::Telemetry;
+use crate::typesdb::TypesDb;
+use crate::units::UnitRules;
+
+// Everything build and the listeners would refuse, checked without opening or
+// binding anything. Every problem is reported, not just the first.
+pub fn validate(config: &Config) -> Vec<anyhow::Error> {
+    let mut problems = Vec::new();
+    let mut check = |result: Result<()>| {
+        if let Err(e) = result {
+            problems.push(e);
+        }
+    };
+    check(check_options(config));
+    check(relabel::Relabel::parse(&config.relabel).map(|_| ()));
+    check(tags::TagRules::parse(&config.tag).map(|_| ()));
+    check(filter::Filter::parse(&config.filter).map(|_| ()));
+    check(routing::Routes::parse(&config.route, &config.output_mode).map(|_| ()));
+    check(TypesDb::load(&config.typesdb).map(|_| ()));
+    check(host_normalizer(config).map(|_| ()));
+    check(max_age(config).map(|_| ()));
+    check(host_lookup(config).map(|_| ()));
+    check(unit_rules(config).map(|_| ()));
+    check(script(config).map(|_| ()));
+    check(plugin_transforms(config).map(|_| ()));
+    check(deduplicator(config).map(|_| ()));
+    check(sampler(config).map(|_| ()));
+    check(alert::validate(config));
+    check(rate_tracker(config).map(|_| ()));
+    check(cardinality_limiter(config).map(|_| ()));
+    check(aggregator(config).map(|_| ()));
+    check(config.overflow_policy.parse::<OverflowPolicy>().map(|_| ()));
+    check(sinks::circuit_breaker(config).map(|_| ()));
+    check(acl::AllowList::parse(&config.allow_cidr).map(|_| ()));
+    check(auth::parse_api_keys(&config.api_key).map(|_| ()));
+    check(sources::graphite::Template::parse(&config.graphite_template).map(|_| ()));
+    check(sources::kafka::validate(config));
+    check(sources::tail::validate(config));
+    check(sources::scrape::validate(config));
+    check(sources::host::validate(config));
+    check(sources::http::validate(config));
+    check(sources::snmp::validate(config));
+    check(sources::exec::validate(config));
+    for mode in &config.output_mode {
+        check(sinks::validate(mode, config).map_err(|e| e.context(format!("{} output", mode))));
+    }
+    problems
+}
+
+// Checks that only look at the options themselves
+fn check_options(config: &Config) -> Result<()> {
+    if config.auth_user.is_some() != config.auth_password.is_some() {
+        return Err(anyhow!("--auth-user and --auth-password must be given together"));
+    }
+    for name in &config.tenant {
+        tenant::validate_name(name)?;
+    }
+    sinks::validate_batching(config)?;
+    if config.sink_workers == 0 {
+        return Err(anyhow!("--sink-workers must be at least 1"));
+    }
+    if config.max_metrics_per_sec.is_some_and(|rate| rate <= 0.0) {
+        return Err(anyhow!("--max-metrics-per-sec must be positive"));
+    }
+    for (i, mode) in config.output_mode.iter().enumerate() {
+        if config.output_mode[..i].contains(mode) {
+            return Err(anyhow!("Output mode specified more than once: {}", mode));
+        }
+    }
+    Ok(())
+}
+
+fn host_normalizer(config: &Config) -> Result<Option<Arc<HostNormalizer>>> {
+    if !config.host_from_peer && !config.host_lowercase && !config.host_strip_domain && config.host_rewrite.is_empty() {
+        return Ok(None);
+    }
+    let hosts = HostNormalizer::new(
+        config.host_from_peer,
+        config.host_lowercase,
+        config.host_strip_domain,
+        &config.host_rewrite,
+    )?;
+    Ok(Some(Arc::new(hosts)))
+}
+
+fn max_age(config: &Config) -> Result<Option<Duration>> {
+    let Some(age) = &config.reject_older_than else {
+        return Ok(None);
+    };
+    let age = config::parse_duration(age)?;
+    if age.is_zero() {
+        return Err(anyhow!("--reject-older-than must be longer than zero"));
+    }
+    Ok(Some(age))
+}
+
+fn host_lookup(config: &Config) -> Result<Option<(Arc<HostLookup>, Duration)>> {
+    let Some(path) = &config.lookup_file else {
+        return Ok(None);
+    };
+    let interval = config::parse_duration(&config.lookup_refresh)?;
+    if interval.is_zero() {
+        return Err(anyhow!("--lookup-refresh must be longer than zero"));
+    }
+    Ok(Some((Arc::new(HostLookup::open(path)?), interval)))
+}
+
+fn unit_rules(config: &Config) -> Result<Option<Arc<UnitRules>>> {
+    let units = UnitRules::parse(&config.convert_unit)?;
+    Ok((!units.is_empty()).then(|| Arc::new(units)))
+}
+
+fn script(config: &Config) -> Result<Option<Arc<Script>>> {
+    let Some(path) = &config.script else {
+        return Ok(None);
+    };
+    Ok(Some(Arc::new(Script::load(path, config.script_max_operations)?)))
+}
+
+fn plugin_transforms(config: &Config) -> Result<Vec<Arc<Plugin>>> {
+    let mut transforms = Vec::with_capacity(config.plugin_transform.len());
+    for name in &config.plugin_transform {
+        let plugin = Plugin::load(config, name)?;
+        if !plugin.exports("transform") {
+            return Err(anyhow!("Plugin {} has no transform export", name));
+        }
+        transforms.push(Arc::new(plugin));
+    }
+    Ok(transforms)
+}
+
+fn deduplicator(config: &Config) -> Result<Option<Arc<Deduplicator>>> {
+    let Some(window) = &config.dedup_window else {
+        return Ok(None);
+    };
+    let window = config::parse_duration(window)?;
+    if window.is_zero() {
+        return Err(anyhow!("--dedup-window must be longer than zero"));
+    }
+    Ok(Some(Arc::new(Deduplicator::new(window))))
+}
+
+fn sampler(config: &Config) -> Result<Option<Arc<Sampler>>> {
+    if config.sample.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(Sampler::parse(&config.sample)?)))
+}
+
+fn rate_tracker(config: &Config) -> Result<Option<Arc<RateTracker>>> {
+    if !config.rates {
+        return Ok(None);
+    }
+    let ttl = config::parse_duration(&config.rate_state_ttl)?;
+    Ok(Some(Arc::new(RateTracker::new(ttl, config.rate_wrap.parse()?))))
+}
+
+fn cardinality_limiter(config: &Config) -> Result<Option<Arc<CardinalityLimiter>>> {
+    let Some(max_series) = config.max_series else {
+        return Ok(None);
+    };
+    if max_series == 0 {
+        return Err(anyhow!("--max-series must be at least 1"));
+    }
+    let ttl = config::parse_duration(&config.series_ttl)?;
+    Ok(Some(Arc::new(CardinalityLimiter::new(max_series, config.series_overflow.parse()?, ttl))))
+}
+
+fn aggregator(config: &Config) -> Result<Option<Arc<Aggregator>>> {
+    let Some(window) = &config.aggregate_window else {
+        return Ok(None);
+    };
+    let functions = config
+        .aggregate_functions
+        .iter()
+        .map(|f| f.parse())
+        .collect::<Result<Vec<_>>>()?;
+    let rules = config
+        .aggregate_match
+        .iter()
+        .map(|rule| filter::Rule::parse(rule))
+        .collect::<Result<Vec<_>>>()?;
+    let aggregator = Aggregator::new(config::parse_duration(window)?, functions, config.aggregate_keep_raw, rules)?;
+    Ok(Some(Arc::new(aggregator)))
+}
+
+// The output side on its own, as started by build
+pub struct Outputs {
+    // Metrics sent here go to the outputs as they are, without the pipeline's processing
+    pub sender: QueueSender,
+    pub reloader: Arc<Reloader>,
+    pub telemetry: Arc<Telemetry>,
+    // Finish once every clone of the sender is dropped and the outputs have flushed
+    pub workers: Vec<JoinHandle<()>>,
+}
+
+// Validate the config, start the output workers, and return the state the HTTP
+// router needs. Listeners are started separately, see sources::open_all.
+pub async fn build(config: &Config) -> Result<AppState> {
+    check_options(config)?;
+
+    let types = Arc::new(TypesDb::load(&config.typesdb)?);
+    let lookup = host_lookup(config)?;
+    if let Some((lookup, interval)) = &lookup {
+        tokio::spawn(lookup::watch(lookup.clone(), *interval));
+    }
+    let mut stages = Stages {
+        hosts: host_normalizer(config)?,
+        max_age: max_age(config)?,
+        lookup: lookup.map(|(lookup, _)| lookup),
+        units: unit_rules(config)?,
+        script: script(config)?,
+        plugins: plugin_transforms(config)?,
+        dedup: deduplicator(config)?,
+        sampler: sampler(config)?,
+        alerts: None,
+        rates: rate_tracker(config)?,
+        cardinality: cardinality_limiter(config)?,
+        aggregator: aggregator(config)?,
+    };
+    let outputs = start_outputs(config)?;
+    let telemetry = outputs.telemetry;
+    stages.alerts = Alerts::start(config, telemetry.clone()).await?;
+
+    if let Some(aggregator) = &stages.aggregator {
+        tokio::spawn(aggregate::run(aggregator.clone(), outputs.sender.clone(), telemetry.clone()));
+    }
+
+    let pipeline = Pipeline::new(outputs.sender, outputs.reloader, types, stages, telemetry.clone());
+    AppState::new(pipeline, config, telemetry)
+}
+
+// Start a worker per output mode, behind the fan-out when there is more than one
+pub fn start_outputs(config: &Config) -> Result<Outputs> {
+    check_options(config)?;
+    let overflow_policy: OverflowPolicy = config.overflow_policy.parse()?;
+    // Only the ingest side can answer 503, the fan-out in front of the outputs
+    // blocks instead so a full output queue backs up into the ingest queue
+    let sink_policy = match overflow_policy {
+        OverflowPolicy::Reject => OverflowPolicy::Block,
+        policy => policy,
+    };
+
+    // One queue per output worker, fanned out from the handler's queue
+    let mut telemetry = Telemetry::default();
+    let mut sink_senders = Vec::with_capacity(config.output_mode.len());
+    let mut workers = Vec::with_capacity(config.output_mode.len());
+    for mode in &config.output_mode {
+        sinks::validate(mode, config)?;
+        let mut shards = Vec::new();
+        for worker in 0..sinks::workers(mode, config) {
+            let (sink_tx, sink_rx) = queue::channel(config.queue_capacity, sink_policy);
+            workers.push((mode, worker, sink_rx, telemetry.add_sink(mode)));
+            shards.push(sink_tx);
+        }
+        sink_senders.push((mode.clone(), shards));
+    }
+    let telemetry = Arc::new(telemetry);
+
+    // A single unrouted output with one worker gets the handler's queue directly,
+    // no need for the extra hop
+    let fan_out = workers.len() > 1 || sink_policy != overflow_policy || !config.route.is_empty();
+    let reloader = Arc::new(Reloader::new(config, fan_out, telemetry.clone())?);
+    let mut handles = Vec::with_capacity(workers.len());
+    for (mode, worker, sink_rx, stats) in workers {
+        handles.push(sinks::spawn_sink(mode, worker, sink_rx, reloader.outputs(), stats, telemetry.clone())?);
+    }
+    let sender = if fan_out {
+        let (tx, rx) = queue::channel(config.queue_capacity, overflow_policy);
+        tokio::spawn(sinks::fan_out(rx, sink_senders, reloader.routes(), telemetry.clone()));
+        tx
+    } else {
+        sink_senders.remove(0).1.remove(0)
+    };
+
+    Ok(Outputs {
+        sender,
+        reloader,
+        telemetry,
+        workers: handles,
+    })
+}
+
+// Build everything and receive until one of the listeners fails, or an output
+// fails under --exit-on-sink-failure. SIGHUP reloads the config.
+pub async fn run(config: Config) -> Result<()> {
+    let state = build(&config).await?;
+    let sources = sources::open_all(&config, state.telemetry.clone()).await?;
+    tokio::spawn(reload::on_sighup(state.pipeline.reloader().clone()));
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    systemd::notify("READY=1");
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(systemd::watchdog(interval));
+    }
+    tokio::select! {
+        result = sources::run_all(sources, state.pipeline) => result,
+        _ = state.telemetry.sink_failure() => Err(anyhow!("An output failed, exiting")),
+        _ = terminate.recv() => {
+            tracing::info!("Received SIGTERM, exiting");
+            systemd::notify("STOPPING=1");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Joins every metric's host against a table of per-host fields (rack, role,
+// owner, ...) and adds them as tags. The table is a CSV file with a header row
+// and a "host" column, or a JSON object mapping each host to an object of
+// fields. The file is checked for changes periodically and reloaded; a file
+// that fails to load is logged and the previous table stays in use.
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+use crate::metric::{self, ProcessedMetric, Tags};
+use crate::tenant;
+
+pub struct HostLookup {
+    path: String,
+    table: RwLock<Arc<HashMap<String, Tags>>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl HostLookup {
+    pub fn open(path: &str) -> Result<HostLookup> {
+        let lookup = HostLookup {
+            path: path.to_string(),
+            table: RwLock::new(Arc::new(HashMap::new())),
+            modified: Mutex::new(None),
+        };
+        lookup.refresh()?;
+        Ok(lookup)
+    }
+
+    // Fields of the metric's host, unless the metric already has a tag by that name
+    pub fn apply(&self, metric: &mut ProcessedMetric) {
+        let table = self.table.read().unwrap().clone();
+        let Some(fields) = metric.host.as_deref().and_then(|host| table.get(host)) else {
+            return;
+        };
+        for (name, value) in fields {
+            if !metric.tags.contains_key(name) {
+                metric.tags.insert(name.clone(), value.clone());
+            }
+        }
+    }
+
+    // Reload the file when its modification time changed. A version that fails
+    // to load is reported once, not on every check.
+    fn refresh(&self) -> Result<()> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| anyhow!("Failed to read lookup file {}: {}", self.path, e))?;
+        if self.modified.lock().unwrap().replace(modified) == Some(modified) {
+            return Ok(());
+        }
+        let table = load(&self.path)?;
+        info!("Loaded {} hosts from lookup file {}", table.len(), self.path);
+        *self.table.write().unwrap() = Arc::new(table);
+        Ok(())
+    }
+}
+
+// Checks the file for changes every interval for as long as the receiver runs
+pub async fn watch(lookup: Arc<HostLookup>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = lookup.refresh() {
+            warn!("Keeping the previous lookup table: {:#}", e);
+        }
+    }
+}
+
+pub fn load(path: &str) -> Result<HashMap<String, Tags>> {
+    let contents = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read lookup file {}: {}", path, e))?;
+    let table = if path.ends_with(".json") {
+        load_json(&contents)
+    } else {
+        load_csv(&contents)
+    };
+    table.map_err(|e| e.context(format!("Invalid lookup file {}", path)))
+}
+
+fn load_json(contents: &str) -> Result<HashMap<String, Tags>> {
+    let hosts: HashMap<String, HashMap<String, serde_json::Value>> = serde_json::from_str(contents)?;
+    let mut table = HashMap::with_capacity(hosts.len());
+    for (host, fields) in hosts {
+        let mut tags = Tags::new();
+        for (name, value) in fields {
+            check_name(&name)?;
+            let value = match value {
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Null => continue,
+                other => other.to_string(),
+            };
+            tags.insert(name, value);
+        }
+        table.insert(host, tags);
+    }
+    Ok(table)
+}
+
+fn load_csv(contents: &str) -> Result<HashMap<String, Tags>> {
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+    let header = split_csv(lines.next().ok_or_else(|| anyhow!("the file is empty"))?);
+    let host_column = header
+        .iter()
+        .position(|name| name == "host")
+        .ok_or_else(|| anyhow!("no host column in the header"))?;
+    for (i, name) in header.iter().enumerate() {
+        if i != host_column {
+            check_name(name)?;
+        }
+    }
+
+    let mut table = HashMap::new();
+    for (line_number, line) in lines.enumerate() {
+        let row = split_csv(line);
+        if row.len() != header.len() {
+            return Err(anyhow!(
+                "row {} has {} columns, the header has {}",
+                line_number + 2,
+                row.len(),
+                header.len()
+            ));
+        }
+        let mut tags = Tags::new();
+        for (i, (name, value)) in header.iter().zip(&row).enumerate() {
+            if i != host_column && !value.is_empty() {
+                tags.insert(name.clone(), value.clone());
+            }
+        }
+        table.insert(row[host_column].clone(), tags);
+    }
+    Ok(table)
+}
+
+// Comma separated fields, optionally double quoted with "" for a quote
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if !metric::valid_tag_name(name) || name == tenant::TAG {
+        return Err(anyhow!("invalid field name {:?}", name));
+    }
+    Ok(())
+}
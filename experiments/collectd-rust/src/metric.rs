@@ -0,0 +1,268 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Extra name/value pairs attached at ingest, e.g. by the API key a request used
+pub type Tags = BTreeMap<String, String>;
+
+// Names the metric fields already use, which tags can't shadow
+const RESERVED_TAGS: [&str; 13] = [
+    "time",
+    "interval",
+    "host",
+    "plugin",
+    "plugin_instance",
+    "instance",
+    "type",
+    "type_instance",
+    "dsname",
+    "dstype",
+    "aggregation",
+    "unit",
+    "value",
+];
+
+// Identifier-like and not shadowing a field
+pub fn valid_tag_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !RESERVED_TAGS.contains(&name)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectdMetric {
+    pub time: Option<f64>,
+    // Seconds between reads on the agent
+    pub interval: Option<f64>,
+    pub host: Option<String>,
+    pub plugin: Option<String>,
+    pub plugin_instance: Option<String>,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub type_instance: Option<String>,
+    pub value: Option<serde_json::Value>,
+    pub values: Option<Vec<serde_json::Value>>,
+    // One of "gauge", "counter", "derive" or "absolute" per value
+    pub dstypes: Option<Vec<String>>,
+    pub dsnames: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessedMetric {
+    pub time: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<f64>,
+    // Shared by every value of the collectd metric the row came from
+    pub host: Option<Arc<str>>,
+    pub plugin: Option<Arc<str>>,
+    pub plugin_instance: Option<Arc<str>>,
+    pub type_: Option<Arc<str>>,
+    pub type_instance: Option<Arc<str>>,
+    // Name of the value within a multi-value type, e.g. "rx" or "tx"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dsname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dstype: Option<String>,
+    // Rollup function ("min", "avg", ...) when the value is an aggregate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregation: Option<String>,
+    // Set by the unit conversion rules, e.g. "MiB" or "percent"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    pub value: Value,
+    #[serde(default, skip_serializing_if = "Tags::is_empty")]
+    pub tags: Tags,
+}
+
+// A value as the pipeline carries it. Counters and derives keep their integer
+// type so they stay exact past 2^53, everything else is a float. Serialized as a
+// plain JSON number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    F64(f64),
+    I64(i64),
+    U64(u64),
+}
+
+impl Value {
+    // The number in a collectd value: a JSON number, or a string holding one.
+    // Anything else (null for NaN gauges, bools, objects) has no value.
+    pub fn from_json(value: &serde_json::Value) -> Option<Value> {
+        match value {
+            serde_json::Value::Number(number) => Some(Value::from(number)),
+            serde_json::Value::String(s) => Value::parse(s.trim()),
+            _ => None,
+        }
+    }
+
+    fn parse(s: &str) -> Option<Value> {
+        if let Ok(v) = s.parse::<u64>() {
+            Some(Value::U64(v))
+        } else if let Ok(v) = s.parse::<i64>() {
+            Some(Value::I64(v))
+        } else {
+            s.parse::<f64>().ok().filter(|v| v.is_finite()).map(Value::F64)
+        }
+    }
+
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Value::F64(v) => v,
+            Value::I64(v) => v as f64,
+            Value::U64(v) => v as f64,
+        }
+    }
+
+    // None for floats and for unsigned values past i64::MAX
+    pub fn as_i64(self) -> Option<i64> {
+        match self {
+            Value::F64(_) => None,
+            Value::I64(v) => Some(v),
+            Value::U64(v) => i64::try_from(v).ok(),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Value {
+        Value::F64(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Value {
+        Value::I64(v)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(v: u64) -> Value {
+        Value::U64(v)
+    }
+}
+
+impl From<&serde_json::Number> for Value {
+    fn from(number: &serde_json::Number) -> Value {
+        if let Some(v) = number.as_u64() {
+            Value::U64(v)
+        } else if let Some(v) = number.as_i64() {
+            Value::I64(v)
+        } else {
+            Value::F64(number.as_f64().unwrap_or(f64::NAN))
+        }
+    }
+}
+
+impl From<Value> for serde_json::Value {
+    fn from(value: Value) -> serde_json::Value {
+        match value {
+            Value::F64(v) => serde_json::Value::from(v),
+            Value::I64(v) => serde_json::Value::from(v),
+            Value::U64(v) => serde_json::Value::from(v),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::F64(v) => write!(f, "{}", v),
+            Value::I64(v) => write!(f, "{}", v),
+            Value::U64(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Value::F64(v) => serializer.serialize_f64(v),
+            Value::I64(v) => serializer.serialize_i64(v),
+            Value::U64(v) => serializer.serialize_u64(v),
+        }
+    }
+}
+
+// Accepts what from_json does, so files written before values were typed
+// (numbers, or numeric strings) still read back
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        Value::from_json(&value).ok_or_else(|| serde::de::Error::custom(format!("value is not a number: {}", value)))
+    }
+}
+
+// The identity of a single value: host, plugin, plugin_instance, type,
+// type_instance and dsname. Used as the key of per-series state (rates, rollups)
+// and cheap to clone and hash, since the parts are shared strings: the metric's
+// own for ids of processed metrics, interned ones for ids of raw values.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MetricId {
+    pub host: Option<Arc<str>>,
+    pub plugin: Option<Arc<str>>,
+    pub plugin_instance: Option<Arc<str>>,
+    pub type_: Option<Arc<str>>,
+    pub type_instance: Option<Arc<str>>,
+    pub dsname: Option<Arc<str>>,
+}
+
+impl MetricId {
+    pub fn of(metric: &ProcessedMetric) -> MetricId {
+        MetricId {
+            host: metric.host.clone(),
+            plugin: metric.plugin.clone(),
+            plugin_instance: metric.plugin_instance.clone(),
+            type_: metric.type_.clone(),
+            type_instance: metric.type_instance.clone(),
+            dsname: metric.dsname.as_deref().map(Arc::from),
+        }
+    }
+
+    // The id of the i-th value, the same as the ProcessedMetric the pipeline
+    // makes of it would have
+    pub fn of_value(metric: &CollectdMetric, i: usize) -> MetricId {
+        MetricId {
+            host: intern(metric.host.as_deref()),
+            plugin: intern(metric.plugin.as_deref()),
+            plugin_instance: intern(metric.plugin_instance.as_deref()),
+            type_: intern(metric.type_.as_deref()),
+            type_instance: intern(metric.type_instance.as_deref()),
+            dsname: intern(metric.dsnames.as_ref().and_then(|names| names.get(i)).map(String::as_str)),
+        }
+    }
+}
+
+// Interned strings stay alive while an id refers to them. The table is swept of
+// unreferenced ones whenever it has doubled since the last sweep, so churning
+// hosts don't grow it forever.
+struct Interner {
+    strings: HashSet<Arc<str>>,
+    swept_at: usize,
+}
+
+const MIN_SWEEP_SIZE: usize = 1024;
+
+fn intern(s: Option<&str>) -> Option<Arc<str>> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    let s = s?;
+    let mut interner = INTERNER
+        .get_or_init(|| {
+            Mutex::new(Interner {
+                strings: HashSet::new(),
+                swept_at: MIN_SWEEP_SIZE,
+            })
+        })
+        .lock()
+        .unwrap();
+    if let Some(interned) = interner.strings.get(s) {
+        return Some(interned.clone());
+    }
+    if interner.strings.len() >= interner.swept_at * 2 {
+        interner.strings.retain(|interned| Arc::strong_count(interned) > 1);
+        interner.swept_at = interner.strings.len().max(MIN_SWEEP_SIZE);
+    }
+    let interned: Arc<str> = Arc::from(s);
+    interner.strings.insert(interned.clone());
+    Some(interned)
+}
@@ -0,0 +1,325 @@
+// OpenTelemetry OTLP metrics wire format, for the /v1/metrics endpoint and the
+// otlp output. Like remote_write.rs the messages are hand written with prost
+// derives, and they carry serde derives as well for the OTLP/JSON encoding
+// (camelCase names, 64-bit integers as strings). Only gauges and sums are
+// mapped, histograms and summaries are ignored.
+use anyhow::{anyhow, Result};
+use prost::Message;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::metric::{CollectdMetric, ProcessedMetric};
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScopeMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub version: String,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub unit: String,
+    #[prost(oneof = "Data", tags = "5, 7")]
+    #[serde(flatten)]
+    pub data: Option<Data>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Data {
+    #[prost(message, tag = "5")]
+    Gauge(Gauge),
+    #[prost(message, tag = "7")]
+    Sum(Sum),
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Gauge {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Sum {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+    // 1 is delta, 2 is cumulative
+    #[prost(int32, tag = "2")]
+    pub aggregation_temporality: i32,
+    #[prost(bool, tag = "3")]
+    pub is_monotonic: bool,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(deserialize_with = "de_int")]
+    pub time_unix_nano: u64,
+    #[prost(oneof = "NumberValue", tags = "4, 6")]
+    #[serde(flatten)]
+    pub value: Option<NumberValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NumberValue {
+    #[prost(double, tag = "4")]
+    AsDouble(f64),
+    #[prost(sfixed64, tag = "6")]
+    AsInt(#[serde(deserialize_with = "de_int")] i64),
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AnyValue {
+    #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4")]
+    #[serde(flatten)]
+    pub value: Option<any_value::Value>,
+}
+
+pub mod any_value {
+    use serde::Deserialize;
+
+    #[derive(Clone, PartialEq, prost::Oneof, Deserialize)]
+    pub enum Value {
+        #[prost(string, tag = "1")]
+        #[serde(rename = "stringValue")]
+        String(String),
+        #[prost(bool, tag = "2")]
+        #[serde(rename = "boolValue")]
+        Bool(bool),
+        #[prost(int64, tag = "3")]
+        #[serde(rename = "intValue")]
+        Int(#[serde(deserialize_with = "super::de_int")] i64),
+        #[prost(double, tag = "4")]
+        #[serde(rename = "doubleValue")]
+        Double(f64),
+    }
+}
+
+// OTLP/JSON writes 64-bit integers as strings, but accept plain numbers too
+fn de_int<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr + Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber<T> {
+        String(String),
+        Number(T),
+    }
+    match StringOrNumber::<T>::deserialize(deserializer)? {
+        StringOrNumber::String(s) => s.parse().map_err(|_| serde::de::Error::custom("invalid integer")),
+        StringOrNumber::Number(n) => Ok(n),
+    }
+}
+
+pub fn decode_protobuf(body: &[u8]) -> Result<ExportMetricsServiceRequest> {
+    Ok(ExportMetricsServiceRequest::decode(body)?)
+}
+
+pub fn decode_json(body: &[u8]) -> Result<ExportMetricsServiceRequest> {
+    serde_json::from_slice(body).map_err(|e| anyhow!("Invalid OTLP/JSON: {}", e))
+}
+
+// One collectd metric per data point: host from the `host.name` resource
+// attribute, plugin from the metric name, type from the unit, and the data
+// point attributes as sorted `key=value` pairs in type_instance
+pub fn to_collectd_metrics(request: ExportMetricsServiceRequest) -> Vec<CollectdMetric> {
+    let mut metrics = Vec::new();
+    for resource_metrics in request.resource_metrics {
+        let host = resource_metrics.resource.as_ref().and_then(|resource| {
+            resource
+                .attributes
+                .iter()
+                .find(|attr| attr.key == "host.name")
+                .and_then(|attr| attribute_string(attr.value.as_ref()))
+        });
+
+        for metric in resource_metrics.scope_metrics.into_iter().flat_map(|scope| scope.metrics) {
+            // Only a monotonic cumulative sum is a counter in collectd terms
+            let (data_points, dstype) = match metric.data {
+                Some(Data::Gauge(gauge)) => (gauge.data_points, "gauge"),
+                Some(Data::Sum(sum)) if sum.is_monotonic && sum.aggregation_temporality == 2 => {
+                    (sum.data_points, "derive")
+                }
+                Some(Data::Sum(sum)) => (sum.data_points, "gauge"),
+                None => continue,
+            };
+            for point in data_points {
+                let value = match point.value {
+                    Some(NumberValue::AsDouble(v)) => serde_json::Number::from_f64(v).map(serde_json::Value::Number),
+                    Some(NumberValue::AsInt(v)) => Some(serde_json::Value::from(v)),
+                    None => None,
+                };
+                let Some(value) = value else { continue };
+
+                let mut attributes: Vec<String> = point
+                    .attributes
+                    .iter()
+                    .filter_map(|attr| Some(format!("{}={}", attr.key, attribute_string(attr.value.as_ref())?)))
+                    .collect();
+                attributes.sort();
+
+                metrics.push(CollectdMetric {
+                    time: (point.time_unix_nano > 0).then(|| point.time_unix_nano as f64 / 1e9),
+                    interval: None,
+                    host: host.clone(),
+                    plugin: Some(metric.name.clone()),
+                    plugin_instance: None,
+                    type_: Some(metric.unit.clone()).filter(|unit| !unit.is_empty()),
+                    type_instance: Some(attributes.join(",")).filter(|a| !a.is_empty()),
+                    value: Some(value),
+                    values: None,
+                    dstypes: Some(vec![dstype.to_string()]),
+                    dsnames: None,
+                });
+            }
+        }
+    }
+    metrics
+}
+
+fn attribute_string(value: Option<&AnyValue>) -> Option<String> {
+    match value?.value.as_ref()? {
+        any_value::Value::String(s) => Some(s.clone()),
+        any_value::Value::Bool(b) => Some(b.to_string()),
+        any_value::Value::Int(i) => Some(i.to_string()),
+        any_value::Value::Double(d) => Some(d.to_string()),
+    }
+}
+
+// Builds an ExportMetricsServiceRequest body with one resource per host. Every
+// metric is exported as a gauge named `<plugin>.<type>[.<dsname>]`, with plugin_instance
+// and type_instance as data point attributes. Integers stay integers.
+pub fn encode_batch(metrics: &[ProcessedMetric]) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+
+    let mut by_host: BTreeMap<Option<&str>, Vec<Metric>> = BTreeMap::new();
+    for metric in metrics {
+        let value = match metric.value.as_i64() {
+            Some(v) => NumberValue::AsInt(v),
+            None => NumberValue::AsDouble(metric.value.as_f64()),
+        };
+
+        let plugin = metric.plugin.as_deref().unwrap_or("unknown");
+        let mut name = plugin.to_string();
+        if let Some(type_) = metric.type_.as_deref().filter(|t| !t.is_empty() && *t != plugin) {
+            name.push('.');
+            name.push_str(type_);
+        }
+        if let Some(dsname) = metric.dsname.as_deref().filter(|d| !d.is_empty() && *d != "value") {
+            name.push('.');
+            name.push_str(dsname);
+        }
+
+        let optional_attributes = [
+            ("plugin_instance", metric.plugin_instance.as_deref()),
+            ("type_instance", metric.type_instance.as_deref()),
+            ("aggregation", metric.aggregation.as_deref()),
+        ];
+        let attributes = optional_attributes
+            .into_iter()
+            .filter_map(|(key, value)| Some(string_attribute(key, value.filter(|v| !v.is_empty())?)))
+            .chain(metric.tags.iter().map(|(key, value)| string_attribute(key, value)))
+            .collect();
+
+        let point = NumberDataPoint {
+            attributes,
+            time_unix_nano: metric.time.map(|t| (t * 1e9) as u64).unwrap_or(now),
+            value: Some(value),
+        };
+        by_host.entry(metric.host.as_deref()).or_default().push(Metric {
+            name,
+            unit: metric.unit.clone().unwrap_or_default(),
+            data: Some(Data::Gauge(Gauge {
+                data_points: vec![point],
+            })),
+        });
+    }
+
+    let resource_metrics = by_host
+        .into_iter()
+        .map(|(host, metrics)| ResourceMetrics {
+            resource: Some(Resource {
+                attributes: host.map(|h| string_attribute("host.name", h)).into_iter().collect(),
+            }),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: "collectd".to_string(),
+                    version: String::new(),
+                }),
+                metrics,
+            }],
+        })
+        .collect();
+
+    ExportMetricsServiceRequest { resource_metrics }.encode_to_vec()
+}
+
+fn string_attribute(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::String(value.to_string())),
+        }),
+    }
+}
@@ -0,0 +1,256 @@
+// Everything between parsing a request and handing metrics to the outputs.
+// In order, with the stages that are off unless configured marked (opt):
+//  1. normalize the host (opt) and name values from types.db
+//  2. drop stale metrics (opt) and turn counters into rates (opt)
+//  3. flatten each collectd metric into one row per value
+//  4. relabel, join the host lookup table (opt), add tags, convert units (opt)
+//  5. run the user script and plugin transforms (opt)
+//  6. drop duplicates (opt) and check the alert thresholds (opt)
+//  7. filter, sample (opt) and cap the number of series (opt)
+//  8. fold values into rollups (opt) and enqueue per the overflow policy
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+use crate::aggregate::Aggregator;
+use crate::alert::Alerts;
+use crate::cardinality::CardinalityLimiter;
+use crate::dedup::Deduplicator;
+use crate::hostname::HostNormalizer;
+use crate::lookup::HostLookup;
+use crate::metric::{CollectdMetric, ProcessedMetric, Tags, Value};
+use crate::plugin::Plugin;
+use crate::queue::{QueueError, QueueSender};
+use crate::rate::RateTracker;
+use crate::reload::Reloader;
+use crate::sample::Sampler;
+use crate::script::Script;
+use crate::typesdb::TypesDb;
+use crate::units::UnitRules;
+use crate::telemetry::Telemetry;
+
+#[derive(Clone)]
+pub struct Pipeline {
+    sender: QueueSender,
+    reloader: Arc<Reloader>,
+    types: Arc<TypesDb>,
+    stages: Stages,
+    telemetry: Arc<Telemetry>,
+}
+
+// The optional processing stages, each off when None
+#[derive(Clone, Default)]
+pub struct Stages {
+    pub hosts: Option<Arc<HostNormalizer>>,
+    // Metrics timestamped further than this in the past are dropped
+    pub max_age: Option<Duration>,
+    pub lookup: Option<Arc<HostLookup>>,
+    pub units: Option<Arc<UnitRules>>,
+    pub script: Option<Arc<Script>>,
+    // Plugin transforms, run in order
+    pub plugins: Vec<Arc<Plugin>>,
+    pub dedup: Option<Arc<Deduplicator>>,
+    pub sampler: Option<Arc<Sampler>>,
+    pub alerts: Option<Arc<Alerts>>,
+    pub rates: Option<Arc<RateTracker>>,
+    pub cardinality: Option<Arc<CardinalityLimiter>>,
+    pub aggregator: Option<Arc<Aggregator>>,
+}
+
+impl Pipeline {
+    pub fn new(
+        sender: QueueSender,
+        reloader: Arc<Reloader>,
+        types: Arc<TypesDb>,
+        stages: Stages,
+        telemetry: Arc<Telemetry>,
+    ) -> Pipeline {
+        Pipeline {
+            sender,
+            reloader,
+            types,
+            stages,
+            telemetry,
+        }
+    }
+
+    // Where the relabel, tag and filter rules come from, and how to reload them with the rest
+    pub fn reloader(&self) -> &Arc<Reloader> {
+        &self.reloader
+    }
+
+    // Returns how many metrics were queued. A Full error means the whole batch was
+    // refused under the reject-503 policy.
+    pub async fn ingest(&self, raw_metrics: Vec<CollectdMetric>) -> Result<usize, QueueError> {
+        self.ingest_tagged(raw_metrics, &Tags::new()).await
+    }
+
+    // Same as ingest, with the tags added to every metric
+    pub async fn ingest_tagged(&self, raw_metrics: Vec<CollectdMetric>, tags: &Tags) -> Result<usize, QueueError> {
+        self.ingest_from(raw_metrics, tags, None).await
+    }
+
+    // Same as ingest_tagged, for metrics sent from the peer's address
+    pub async fn ingest_from(
+        &self,
+        mut raw_metrics: Vec<CollectdMetric>,
+        tags: &Tags,
+        peer: Option<IpAddr>,
+    ) -> Result<usize, QueueError> {
+        if let Some(hosts) = &self.stages.hosts {
+            hosts.apply(&mut raw_metrics, peer).await;
+        }
+        for metric in &mut raw_metrics {
+            self.types.annotate(metric);
+        }
+        if let Some(max_age) = self.stages.max_age {
+            self.telemetry.record_too_old(drop_older_than(&mut raw_metrics, max_age));
+        }
+        if let Some(rates) = &self.stages.rates {
+            raw_metrics = rates.apply(raw_metrics);
+        }
+        let mut processed: Vec<ProcessedMetric> = raw_metrics.into_iter().flat_map(process_metric).collect();
+        if !tags.is_empty() {
+            for metric in &mut processed {
+                metric.tags = tags.clone();
+            }
+        }
+        let relabel = self.reloader.relabel();
+        if !relabel.is_empty() {
+            let before = processed.len();
+            processed.retain_mut(|metric| relabel.apply(metric));
+            self.telemetry.record_filtered(before - processed.len());
+        }
+        if let Some(lookup) = &self.stages.lookup {
+            for metric in &mut processed {
+                lookup.apply(metric);
+            }
+        }
+        let tag_rules = self.reloader.tags();
+        if !tag_rules.is_empty() {
+            for metric in &mut processed {
+                tag_rules.apply(metric);
+            }
+        }
+        if let Some(units) = &self.stages.units {
+            for metric in &mut processed {
+                units.apply(metric);
+            }
+        }
+        if let Some(script) = &self.stages.script {
+            let errors;
+            (processed, errors) = script.apply(processed);
+            self.telemetry.record_script_errors(errors);
+        }
+        for plugin in &self.stages.plugins {
+            match plugin.transform(&processed) {
+                Ok(transformed) => processed = transformed,
+                Err(e) => {
+                    self.telemetry.record_plugin_errors(processed.len());
+                    warn!("Passing {} metrics on unchanged: {:#}", processed.len(), e);
+                }
+            }
+        }
+        if let Some(dedup) = &self.stages.dedup {
+            self.telemetry.record_deduplicated(dedup.apply(&mut processed));
+        }
+        if let Some(alerts) = &self.stages.alerts {
+            alerts.apply(&processed);
+        }
+        let before = processed.len();
+        let filter = self.reloader.filter();
+        processed.retain(|metric| filter.allows(metric));
+        self.telemetry.record_filtered(before - processed.len());
+        if let Some(sampler) = &self.stages.sampler {
+            self.telemetry.record_sampled(sampler.apply(&mut processed));
+        }
+        if let Some(cardinality) = &self.stages.cardinality {
+            let (limited, series) = cardinality.apply(&mut processed);
+            self.telemetry.record_series_limited(limited, series);
+        }
+
+        let processed_count = processed.len();
+        if let Some(aggregator) = &self.stages.aggregator {
+            processed = aggregator.add(processed);
+        }
+        let queued_count = processed.len();
+        match self.sender.send_batch(processed).await {
+            Ok(dropped) => self.telemetry.record_dropped(dropped),
+            Err(QueueError::Full) => {
+                self.telemetry.record_dropped(queued_count);
+                return Err(QueueError::Full);
+            }
+            Err(QueueError::Closed) => return Err(QueueError::Closed),
+        }
+
+        self.telemetry.record_received(processed_count);
+        Ok(processed_count)
+    }
+}
+
+// Returns how many values were dropped. Metrics without a timestamp are kept.
+fn drop_older_than(metrics: &mut Vec<CollectdMetric>, max_age: Duration) -> usize {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    let cutoff = now - max_age.as_secs_f64();
+    let mut dropped = 0;
+    metrics.retain(|metric| {
+        if metric.time.is_some_and(|time| time < cutoff) {
+            dropped += metric.values.as_ref().map_or(1, |values| values.len());
+            return false;
+        }
+        true
+    });
+    dropped
+}
+
+pub fn process_metric(metric: CollectdMetric) -> Vec<ProcessedMetric> {
+    let mut processed = Vec::new();
+
+    // Handle both 'values' and 'value' fields
+    let values = if let Some(values_array) = metric.values {
+        values_array
+    } else if let Some(single_value) = metric.value {
+        vec![single_value]
+    } else {
+        return processed;
+    };
+
+    // The fields every value shares, allocated once and shared by the rows
+    let host: Option<Arc<str>> = metric.host.map(Into::into);
+    let plugin: Option<Arc<str>> = metric.plugin.map(Into::into);
+    let plugin_instance: Option<Arc<str>> = metric.plugin_instance.map(Into::into);
+    let type_: Option<Arc<str>> = metric.type_.map(Into::into);
+    let type_instance: Option<Arc<str>> = metric.type_instance.map(Into::into);
+
+    // Create a processed metric for each value that is a flat, eye candy object
+    for (i, value) in values.into_iter().enumerate() {
+        // Non-numeric values (null NaN gauges, garbage) are dropped here rather
+        // than in every sink
+        let Some(value) = Value::from_json(&value) else {
+            debug!("Dropping non-numeric metric value: {}", value);
+            continue;
+        };
+        let processed_metric = ProcessedMetric {
+            time: metric.time,
+            interval: metric.interval,
+            host: host.clone(),
+            plugin: plugin.clone(),
+            plugin_instance: plugin_instance.clone(),
+            type_: type_.clone(),
+            type_instance: type_instance.clone(),
+            dsname: metric.dsnames.as_ref().and_then(|names| names.get(i).cloned()),
+            dstype: metric.dstypes.as_ref().and_then(|types| types.get(i).cloned()),
+            aggregation: None,
+            unit: None,
+            value,
+            tags: Tags::new(),
+        };
+        processed.push(processed_metric);
+    }
+
+    processed
+}
@@ -0,0 +1,188 @@
+// WebAssembly plugins, loaded from --plugin-dir: transforms that rewrite batches
+// in the pipeline (--plugin-transform) and encoders behind the `plugin` output
+// (--plugin-sink). A plugin is a core wasm module (.wasm, or .wat text) named
+// after its file, with this ABI, all integers i32 unless noted:
+//   export memory
+//   export abi_version() -> 1
+//   export alloc(len) -> ptr            room for the host to write an input into
+//   export transform(ptr, len) -> i64   JSON array of metrics in, JSON array out
+//   export encode(ptr, len) -> i64      JSON array of metrics in, bytes to write out
+//   import env.log(level, ptr, len)     optional, 0 error, 1 warn, 2 info, else debug
+// Results are packed as (ptr << 32) | len; a negative result is a failure.
+// Metrics are the JSON output's objects. Plugins get no WASI, so no files,
+// clocks or network; each call has a fuel budget and memory is capped. A call
+// that traps throws the instance away, the next call starts a fresh one.
+use anyhow::{anyhow, Context, Result};
+use std::{path::Path, sync::Mutex};
+use tracing::{debug, error, info, warn};
+use wasmtime::{Caller, Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+
+const ABI_VERSION: i32 = 1;
+
+pub struct Plugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    max_memory: usize,
+    loaded: Mutex<Option<Loaded>>,
+}
+
+struct Loaded {
+    store: Store<Host>,
+    instance: Instance,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+}
+
+struct Host {
+    plugin: String,
+    limits: StoreLimits,
+}
+
+impl Plugin {
+    // The plugin called name in --plugin-dir, checked against the ABI
+    pub fn load(config: &Config, name: &str) -> Result<Plugin> {
+        let dir = config
+            .plugin_dir
+            .as_deref()
+            .ok_or_else(|| anyhow!("--plugin-dir is needed to load plugin {:?}", name))?;
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(anyhow!("Invalid plugin name {:?}", name));
+        }
+        let path = ["wasm", "wat"]
+            .iter()
+            .map(|extension| Path::new(dir).join(format!("{}.{}", name, extension)))
+            .find(|path| path.exists())
+            .ok_or_else(|| anyhow!("No plugin {:?} in {}, expected {}.wasm or {}.wat", name, dir, name, name))?;
+        Plugin::open(name, &path, config.plugin_fuel, config.plugin_max_memory_mb)
+    }
+
+    fn open(name: &str, path: &Path, fuel: u64, max_memory_mb: u64) -> Result<Plugin> {
+        let mut settings = wasmtime::Config::new();
+        settings.consume_fuel(true);
+        let engine = Engine::new(&settings)?;
+        let module = Module::from_file(&engine, path).map_err(|e| anyhow!("Failed to load plugin {}: {:#}", path.display(), e))?;
+        let plugin = Plugin {
+            name: name.to_string(),
+            engine,
+            module,
+            fuel,
+            max_memory: (max_memory_mb as usize).saturating_mul(1024 * 1024),
+            loaded: Mutex::new(None),
+        };
+        let mut loaded = plugin.instantiate()?;
+        let version = loaded
+            .instance
+            .get_typed_func::<(), i32>(&mut loaded.store, "abi_version")
+            .and_then(|abi_version| abi_version.call(&mut loaded.store, ()))
+            .map_err(|e| anyhow!("Plugin {} has no working abi_version() -> i32 export: {:#}", name, e))?;
+        if version != ABI_VERSION {
+            return Err(anyhow!("Plugin {} uses ABI version {}, expected {}", name, version, ABI_VERSION));
+        }
+        *plugin.loaded.lock().unwrap() = Some(loaded);
+        info!("Loaded plugin {} from {}", name, path.display());
+        Ok(plugin)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn exports(&self, function: &str) -> bool {
+        self.module.get_export(function).is_some_and(|export| export.func().is_some())
+    }
+
+    pub fn transform(&self, metrics: &[ProcessedMetric]) -> Result<Vec<ProcessedMetric>> {
+        let output = self.call("transform", &serde_json::to_vec(metrics)?)?;
+        serde_json::from_slice(&output).map_err(|e| anyhow!("Plugin {} returned invalid metrics: {}", self.name, e))
+    }
+
+    pub fn encode(&self, metrics: &[ProcessedMetric]) -> Result<Vec<u8>> {
+        self.call("encode", &serde_json::to_vec(metrics)?)
+    }
+
+    fn instantiate(&self) -> Result<Loaded> {
+        let host = Host {
+            plugin: self.name.clone(),
+            limits: StoreLimitsBuilder::new().memory_size(self.max_memory).build(),
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(self.fuel)?;
+
+        let mut linker = Linker::new(&self.engine);
+        linker.func_wrap("env", "log", log)?;
+        let instance = linker
+            .instantiate(&mut store, &self.module)
+            .map_err(|e| anyhow!("Failed to start plugin {}: {:#}", self.name, e))?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("Plugin {} doesn't export its memory", self.name))?;
+        let alloc = instance
+            .get_typed_func(&mut store, "alloc")
+            .map_err(|e| anyhow!("Plugin {} has no alloc(i32) -> i32 export: {:#}", self.name, e))?;
+        Ok(Loaded {
+            store,
+            instance,
+            memory,
+            alloc,
+        })
+    }
+
+    fn call(&self, function: &str, input: &[u8]) -> Result<Vec<u8>> {
+        let mut slot = self.loaded.lock().unwrap();
+        let loaded = match slot.as_mut() {
+            Some(loaded) => loaded,
+            None => slot.insert(self.instantiate()?),
+        };
+        let result = loaded.call(function, input, self.fuel);
+        if result.is_err() {
+            // The instance may be left in any state after a trap
+            *slot = None;
+        }
+        result.with_context(|| format!("Plugin {} failed in {}", self.name, function))
+    }
+}
+
+impl Loaded {
+    // The fuel budget covers the alloc and the call together
+    fn call(&mut self, function: &str, input: &[u8], fuel: u64) -> Result<Vec<u8>> {
+        let function: TypedFunc<(i32, i32), i64> = self.instance.get_typed_func(&mut self.store, function)?;
+        let len = i32::try_from(input.len()).map_err(|_| anyhow!("input of {} bytes is too large", input.len()))?;
+        self.store.set_fuel(fuel)?;
+
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, input)?;
+        let packed = function.call(&mut self.store, (ptr, len))?;
+        if packed < 0 {
+            return Err(anyhow!("returned error {}", packed));
+        }
+        let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+        let mut output = vec![0; len];
+        self.memory.read(&self.store, ptr, &mut output)?;
+        Ok(output)
+    }
+}
+
+// env.log(level, ptr, len)
+fn log(mut caller: Caller<'_, Host>, level: i32, ptr: i32, len: i32) {
+    let Some(memory) = caller.get_export("memory").and_then(|export| export.into_memory()) else {
+        return;
+    };
+    let mut message = vec![0; len.max(0) as usize];
+    if memory.read(&caller, ptr as u32 as usize, &mut message).is_err() {
+        return;
+    }
+    let message = String::from_utf8_lossy(&message);
+    let plugin = &caller.data().plugin;
+    match level {
+        0 => error!("plugin {}: {}", plugin, message),
+        1 => warn!("plugin {}: {}", plugin, message),
+        2 => info!("plugin {}: {}", plugin, message),
+        _ => debug!("plugin {}: {}", plugin, message),
+    }
+}
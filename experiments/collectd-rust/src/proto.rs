@@ -0,0 +1,97 @@
+// Messages of proto/receiver.proto, the native ingest API for the gRPC listener
+// and application/x-protobuf bodies. Like remote_write.rs they are hand
+// written with prost derives, so building needs no protoc, and Rust emitters
+// can use them as they are.
+use prost::Message;
+
+use crate::metric::CollectdMetric;
+use crate::schema;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub host: String,
+    #[prost(string, tag = "2")]
+    pub plugin: String,
+    #[prost(string, tag = "3")]
+    pub plugin_instance: String,
+    #[prost(string, tag = "4")]
+    pub r#type: String,
+    #[prost(string, tag = "5")]
+    pub type_instance: String,
+    #[prost(double, tag = "6")]
+    pub time: f64,
+    #[prost(double, tag = "7")]
+    pub interval: f64,
+    #[prost(double, repeated, tag = "8")]
+    pub values: Vec<f64>,
+    #[prost(string, repeated, tag = "9")]
+    pub dstypes: Vec<String>,
+    #[prost(string, repeated, tag = "10")]
+    pub dsnames: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Invalid {
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(string, tag = "2")]
+    pub reason: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PushResponse {
+    #[prost(uint64, tag = "1")]
+    pub accepted: u64,
+    #[prost(uint64, tag = "2")]
+    pub rejected: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub invalid: Vec<Invalid>,
+}
+
+impl From<schema::Invalid> for Invalid {
+    fn from(invalid: schema::Invalid) -> Invalid {
+        Invalid {
+            index: invalid.index as u64,
+            reason: invalid.reason,
+        }
+    }
+}
+
+impl Metric {
+    // Proto3 can't tell unset from empty, so empty fields become None. Fails
+    // on values JSON can't hold.
+    pub fn into_collectd(self) -> Result<CollectdMetric, String> {
+        let values = self
+            .values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                serde_json::Number::from_f64(*value)
+                    .map(serde_json::Value::Number)
+                    .ok_or_else(|| format!("value {} is not finite: {}", i, value))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let text = |s: String| (!s.is_empty()).then_some(s);
+        let list = |list: Vec<String>| (!list.is_empty()).then_some(list);
+        Ok(CollectdMetric {
+            time: (self.time != 0.0).then_some(self.time),
+            interval: (self.interval != 0.0).then_some(self.interval),
+            host: text(self.host),
+            plugin: text(self.plugin),
+            plugin_instance: text(self.plugin_instance),
+            type_: text(self.r#type),
+            type_instance: text(self.type_instance),
+            value: None,
+            values: (!values.is_empty()).then_some(values),
+            dstypes: list(self.dstypes),
+            dsnames: list(self.dsnames),
+        })
+    }
+}
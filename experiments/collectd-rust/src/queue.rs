@@ -0,0 +1,277 @@
+// Bounded metric queue between the ingest side and the output workers.
+// tokio's mpsc can't evict from the sender side, which drop-oldest needs, so this
+// is a small VecDeque behind a mutex with Notify for wakeups.
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::metric::ProcessedMetric;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // Wait for the worker to make room, pushing back on the client
+    Block,
+    // Evict the oldest queued metrics to make room
+    DropOldest,
+    // Keep the queue as is and drop the incoming metrics
+    DropNew,
+    // Refuse the whole batch so the handler can answer 503
+    Reject,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-new" => Ok(OverflowPolicy::DropNew),
+            "reject-503" => Ok(OverflowPolicy::Reject),
+            other => Err(anyhow::anyhow!("Invalid overflow policy: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueueError {
+    // The receiving worker is gone
+    Closed,
+    // The batch didn't fit and the policy is Reject
+    Full,
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<ProcessedMetric>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    item_ready: Notify,
+    space_ready: Notify,
+}
+
+pub struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+pub struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+pub fn channel(capacity: usize, policy: OverflowPolicy) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+        capacity: capacity.max(1),
+        policy,
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+        item_ready: Notify::new(),
+        space_ready: Notify::new(),
+    });
+    (
+        QueueSender { shared: shared.clone() },
+        QueueReceiver { shared },
+    )
+}
+
+impl QueueSender {
+    // Enqueue a batch according to the overflow policy, returns how many metrics
+    // were dropped to make it fit. Reject is all-or-nothing so a client retrying
+    // after a 503 doesn't produce duplicates.
+    pub async fn send_batch(&self, metrics: Vec<ProcessedMetric>) -> Result<usize, QueueError> {
+        if self.shared.policy == OverflowPolicy::Block {
+            for metric in metrics {
+                self.send_blocking(metric).await?;
+            }
+            return Ok(0);
+        }
+
+        let mut dropped = 0;
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            if !self.shared.receiver_alive.load(Ordering::Acquire) {
+                return Err(QueueError::Closed);
+            }
+            match self.shared.policy {
+                OverflowPolicy::Reject => {
+                    if queue.len() + metrics.len() > self.shared.capacity {
+                        return Err(QueueError::Full);
+                    }
+                    queue.extend(metrics);
+                }
+                OverflowPolicy::DropNew => {
+                    let room = self.shared.capacity.saturating_sub(queue.len());
+                    dropped = metrics.len().saturating_sub(room);
+                    queue.extend(metrics.into_iter().take(room));
+                }
+                OverflowPolicy::DropOldest => {
+                    queue.extend(metrics);
+                    while queue.len() > self.shared.capacity {
+                        queue.pop_front();
+                        dropped += 1;
+                    }
+                }
+                OverflowPolicy::Block => unreachable!(),
+            }
+        }
+        self.shared.item_ready.notify_one();
+        Ok(dropped)
+    }
+
+    pub async fn send(&self, metric: ProcessedMetric) -> Result<usize, QueueError> {
+        self.send_batch(vec![metric]).await
+    }
+
+    async fn send_blocking(&self, metric: ProcessedMetric) -> Result<(), QueueError> {
+        loop {
+            // Register for the wakeup before checking, so a pop in between isn't missed
+            let notified = self.shared.space_ready.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if !self.shared.receiver_alive.load(Ordering::Acquire) {
+                    return Err(QueueError::Closed);
+                }
+                if queue.len() < self.shared.capacity {
+                    queue.push_back(metric);
+                    drop(queue);
+                    self.shared.item_ready.notify_one();
+                    return Ok(());
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Clone for QueueSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        QueueSender { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Last sender gone, wake the receiver so it can see the queue is closed
+            self.shared.item_ready.notify_one();
+        }
+    }
+}
+
+impl QueueReceiver {
+    // Returns None once every sender is dropped and the queue is drained
+    pub async fn recv(&mut self) -> Option<ProcessedMetric> {
+        loop {
+            let notified = self.shared.item_ready.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(metric) = queue.pop_front() {
+                    drop(queue);
+                    self.shared.space_ready.notify_one();
+                    return Some(metric);
+                }
+                if self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+        self.shared.space_ready.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::process_metric;
+    use std::time::Duration;
+
+    // Metrics told apart by their value
+    fn metrics(values: std::ops::Range<u64>) -> Vec<ProcessedMetric> {
+        values
+            .map(|value| {
+                let raw = serde_json::json!({"values": [value], "dstypes": ["counter"], "host": "web01"});
+                process_metric(serde_json::from_value(raw).unwrap()).remove(0)
+            })
+            .collect()
+    }
+
+    fn drain(receiver: &QueueReceiver) -> Vec<u64> {
+        let mut queue = receiver.shared.queue.lock().unwrap();
+        queue.drain(..).map(|metric| metric.value.as_f64() as u64).collect()
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_evicts_the_front() {
+        let (sender, receiver) = channel(3, OverflowPolicy::DropOldest);
+        assert_eq!(sender.send_batch(metrics(0..2)).await, Ok(0));
+        assert_eq!(sender.send_batch(metrics(2..5)).await, Ok(2));
+        assert_eq!(drain(&receiver), [2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn drop_new_keeps_the_queue() {
+        let (sender, receiver) = channel(3, OverflowPolicy::DropNew);
+        assert_eq!(sender.send_batch(metrics(0..2)).await, Ok(0));
+        assert_eq!(sender.send_batch(metrics(2..5)).await, Ok(2));
+        assert_eq!(sender.send_batch(metrics(5..6)).await, Ok(1));
+        assert_eq!(drain(&receiver), [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn reject_refuses_the_whole_batch() {
+        let (sender, receiver) = channel(3, OverflowPolicy::Reject);
+        assert_eq!(sender.send_batch(metrics(0..2)).await, Ok(0));
+        assert_eq!(sender.send_batch(metrics(2..4)).await, Err(QueueError::Full));
+        // Nothing of the refused batch was queued, what still fits is taken
+        assert_eq!(sender.send_batch(metrics(4..5)).await, Ok(0));
+        assert_eq!(drain(&receiver), [0, 1, 4]);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::Block);
+        let send = tokio::spawn(async move { sender.send_batch(metrics(0..4)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!send.is_finished());
+        assert_eq!(receiver.len(), 2);
+
+        let mut received = Vec::new();
+        while let Some(metric) = receiver.recv().await {
+            received.push(metric.value.as_f64() as u64);
+        }
+        // Nothing lost or reordered, and recv ends once the sender is gone
+        assert_eq!(received, [0, 1, 2, 3]);
+        assert_eq!(send.await.unwrap(), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn closed_once_the_receiver_is_gone() {
+        for policy in [OverflowPolicy::Block, OverflowPolicy::DropOldest, OverflowPolicy::DropNew, OverflowPolicy::Reject] {
+            let (sender, receiver) = channel(2, policy);
+            drop(receiver);
+            assert_eq!(sender.send_batch(metrics(0..1)).await, Err(QueueError::Closed));
+        }
+    }
+}
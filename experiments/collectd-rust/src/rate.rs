@@ -0,0 +1,162 @@
+// Turns counter, derive and absolute values into per-second rates.
+// The previous sample of every value is remembered per identity (host, plugin,
+// plugin_instance, type, type_instance, dsname and value position), the first sample of
+// a series only primes that state and is not emitted. Entries that haven't been
+// updated within the TTL are forgotten, so churning hosts don't leak memory.
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::metric::{CollectdMetric, MetricId};
+
+// How a counter that went down is treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapPolicy {
+    // Assume a 32 or 64 bit rollover, like collectd itself
+    Wrap,
+    // Assume the source restarted and skip the sample
+    Reset,
+}
+
+impl FromStr for WrapPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<WrapPolicy> {
+        match s {
+            "wrap" => Ok(WrapPolicy::Wrap),
+            "reset" => Ok(WrapPolicy::Reset),
+            other => Err(anyhow!("Invalid counter wrap policy: {}, expected wrap or reset", other)),
+        }
+    }
+}
+
+// The position tells values apart when the metric has no dsnames
+type Key = (MetricId, usize);
+
+struct Sample {
+    time: f64,
+    value: f64,
+    seen: Instant,
+}
+
+pub struct RateTracker {
+    ttl: Duration,
+    wrap: WrapPolicy,
+    state: Mutex<State>,
+}
+
+struct State {
+    samples: HashMap<Key, Sample>,
+    last_sweep: Instant,
+}
+
+impl RateTracker {
+    pub fn new(ttl: Duration, wrap: WrapPolicy) -> RateTracker {
+        RateTracker {
+            ttl,
+            wrap,
+            state: Mutex::new(State {
+                samples: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    // Rewrites rate-able values in place and marks them as gauges. A metric is
+    // dropped when any of its values has no usable previous sample yet.
+    pub fn apply(&self, metrics: Vec<CollectdMetric>) -> Vec<CollectdMetric> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(state.last_sweep) >= self.ttl {
+            let ttl = self.ttl;
+            state.samples.retain(|_, sample| now.duration_since(sample.seen) < ttl);
+            state.last_sweep = now;
+        }
+
+        metrics
+            .into_iter()
+            .filter_map(|metric| self.apply_one(&mut state.samples, metric, now))
+            .collect()
+    }
+
+    fn apply_one(&self, samples: &mut HashMap<Key, Sample>, mut metric: CollectdMetric, now: Instant) -> Option<CollectdMetric> {
+        let Some(dstypes) = metric.dstypes.clone() else {
+            return Some(metric);
+        };
+        if !dstypes.iter().any(|t| is_rate_type(t)) {
+            return Some(metric);
+        }
+
+        let time = metric.time.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default()
+        });
+        // A single `value` is treated as a one element `values`
+        if metric.values.is_none() {
+            metric.values = metric.value.take().map(|value| vec![value]);
+        }
+        let ids: Vec<MetricId> = (0..dstypes.len()).map(|i| MetricId::of_value(&metric, i)).collect();
+        let Some(values) = metric.values.as_mut() else {
+            return Some(metric);
+        };
+
+        let mut complete = true;
+        for (i, ((value, dstype), id)) in values.iter_mut().zip(&dstypes).zip(ids).enumerate() {
+            if !is_rate_type(dstype) {
+                continue;
+            }
+            let Some(current) = value.as_f64() else {
+                complete = false;
+                continue;
+            };
+            let previous = samples.insert((id, i), Sample { time, value: current, seen: now });
+            let rate = previous.and_then(|previous| self.rate(dstype, &previous, time, current));
+            match rate.and_then(serde_json::Number::from_f64) {
+                Some(rate) => *value = serde_json::Value::Number(rate),
+                None => complete = false,
+            }
+        }
+        if !complete {
+            return None;
+        }
+
+        metric.dstypes = Some(dstypes.iter().map(|_| "gauge".to_string()).collect());
+        Some(metric)
+    }
+
+    fn rate(&self, dstype: &str, previous: &Sample, time: f64, current: f64) -> Option<f64> {
+        let elapsed = time - previous.time;
+        if elapsed <= 0.0 {
+            return None;
+        }
+        // Absolute values are reset on every read, the value itself is the delta
+        if dstype == "absolute" {
+            return Some(current / elapsed);
+        }
+
+        let delta = if current >= previous.value {
+            current - previous.value
+        } else {
+            match (self.wrap, dstype) {
+                (WrapPolicy::Wrap, "counter") => {
+                    let max = if previous.value <= u32::MAX as f64 { u32::MAX as f64 } else { u64::MAX as f64 };
+                    max - previous.value + current + 1.0
+                }
+                // Derives are signed, going down is a legitimate negative rate
+                (WrapPolicy::Wrap, _) => current - previous.value,
+                (WrapPolicy::Reset, _) => return None,
+            }
+        };
+        Some(delta / elapsed)
+    }
+}
+
+fn is_rate_type(dstype: &str) -> bool {
+    matches!(dstype, "counter" | "derive" | "absolute")
+}
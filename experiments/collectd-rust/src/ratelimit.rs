@@ -0,0 +1,66 @@
+// Per-client token buckets for the ingest endpoints. A client is its auth
+// identity when it has one, otherwise its IP, and each gets
+// --max-metrics-per-sec with bursts of up to --rate-limit-burst metrics.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Idle clients are forgotten after this long without a request
+const IDLE_TTL: Duration = Duration::from_secs(600);
+
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    state: Mutex<State>,
+}
+
+struct State {
+    buckets: HashMap<String, Bucket>,
+    last_sweep: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: Option<f64>) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst: burst.unwrap_or(rate).max(1.0),
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    // Takes `count` tokens from the client's bucket, or says how long to wait.
+    // A request bigger than the burst goes through on a full bucket and leaves
+    // it in debt, so large batches are slowed down rather than refused forever.
+    pub fn check(&self, client: &str, count: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(state.last_sweep) >= IDLE_TTL {
+            state.buckets.retain(|_, bucket| now.duration_since(bucket.updated) < IDLE_TTL);
+            state.last_sweep = now;
+        }
+
+        let bucket = state.buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        let needed = (count as f64).min(self.burst);
+        if bucket.tokens >= needed {
+            bucket.tokens -= count as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((needed - bucket.tokens) / self.rate))
+        }
+    }
+}
@@ -0,0 +1,122 @@
+// Rewrites of the identity fields, applied in order to every metric after it is
+// flattened and before the dedup, filter and later stages see it. Rules:
+//   replace:<field>~<regex>=><replacement>  rewrite a field the regex fully matches,
+//                                           $1 or ${name} insert capture groups
+//   lowercase:<field>                       lowercase a field
+//   keep:<field>=<value>, keep:<field>~<regex>  drop metrics that don't match
+//   drop:<field>=<value>, drop:<field>~<regex>  drop metrics that match
+// e.g.
+//   replace:plugin_instance~eth(\d+)=>nic$1
+//   lowercase:host
+// Fields are host, plugin, plugin_instance, type and type_instance; a missing
+// field reads as empty and a replacement that comes out empty unsets it.
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+use crate::filter::{Field, Rule};
+use crate::metric::ProcessedMetric;
+
+#[derive(Debug)]
+enum Action {
+    Replace { field: Field, rewrite: Rewrite },
+    Lowercase(Field),
+    Keep(Rule),
+    Drop(Rule),
+}
+
+#[derive(Debug, Default)]
+pub struct Relabel {
+    actions: Vec<Action>,
+}
+
+impl Relabel {
+    pub fn parse(rules: &[String]) -> Result<Relabel> {
+        let mut relabel = Relabel::default();
+        for rule in rules {
+            let (action, expr) = rule
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid relabel rule {:?}, expected <action>:<field>...", rule))?;
+            let parsed = match action {
+                "replace" => parse_replace(expr),
+                "lowercase" => Field::parse(expr).map(Action::Lowercase),
+                "keep" => Rule::parse(expr).map(Action::Keep),
+                "drop" => Rule::parse(expr).map(Action::Drop),
+                other => Err(anyhow!("Unknown action {:?}, expected replace, lowercase, keep or drop", other)),
+            };
+            relabel
+                .actions
+                .push(parsed.map_err(|e| anyhow!("Invalid relabel rule {:?}: {}", rule, e))?);
+        }
+        Ok(relabel)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    // Rewrites the metric in place, false when a rule drops it
+    pub fn apply(&self, metric: &mut ProcessedMetric) -> bool {
+        for action in &self.actions {
+            match action {
+                Action::Replace { field, rewrite } => {
+                    if let Some(value) = rewrite.apply(field.get(metric)) {
+                        field.set(metric, value);
+                    }
+                }
+                Action::Lowercase(field) => {
+                    let value = field.get(metric).to_lowercase();
+                    field.set(metric, value);
+                }
+                Action::Keep(rule) => {
+                    if !rule.matches(metric) {
+                        return false;
+                    }
+                }
+                Action::Drop(rule) => {
+                    if rule.matches(metric) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+// <field>~<regex>=><replacement>
+fn parse_replace(expr: &str) -> Result<Action> {
+    let (field, rewrite) = expr
+        .split_once('~')
+        .ok_or_else(|| anyhow!("expected replace:<field>~<regex>=><replacement>"))?;
+    Ok(Action::Replace {
+        field: Field::parse(field)?,
+        rewrite: Rewrite::parse(rewrite)?,
+    })
+}
+
+// "<regex>=><replacement>", the regex anchored at both ends. Shared with --host-rewrite.
+#[derive(Debug)]
+pub struct Rewrite {
+    regex: Regex,
+    replacement: String,
+}
+
+impl Rewrite {
+    pub fn parse(expr: &str) -> Result<Rewrite> {
+        let (regex, replacement) = expr
+            .rsplit_once("=>")
+            .ok_or_else(|| anyhow!("expected => between the regex and the replacement"))?;
+        Ok(Rewrite {
+            regex: Regex::new(&format!("^(?:{})$", regex))?,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    // The replacement with the capture groups filled in, None when the regex doesn't match
+    pub fn apply(&self, value: &str) -> Option<String> {
+        let captures = self.regex.captures(value)?;
+        let mut replaced = String::new();
+        captures.expand(&self.replacement, &mut replaced);
+        Some(replaced)
+    }
+}
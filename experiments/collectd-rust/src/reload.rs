@@ -0,0 +1,187 @@
+// Hot reload of the settings that can change under a running receiver: the
+// relabel, tag, filter and routing rules, and the outputs' own settings. Each
+// lives in a watch channel; the pipeline reads the relabel, tag and filter rules
+// per request, the fan-out reads the routes per metric, and an output worker
+// that sees new settings flushes what it has buffered and reopens its sink.
+// Queues are untouched, so nothing in flight is lost. Listeners, auth and the
+// pipeline options keep their startup values, and the set of outputs and their
+// workers can't change without a restart.
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::relabel::Relabel;
+use crate::routing::Routes;
+use crate::sinks;
+use crate::tags::TagRules;
+use crate::telemetry::Telemetry;
+
+pub struct Reloader {
+    current: Mutex<Config>,
+    // Whether metrics pass through the fan-out, which is where routes apply
+    fan_out: bool,
+    relabel: watch::Sender<Arc<Relabel>>,
+    tags: watch::Sender<Arc<TagRules>>,
+    filter: watch::Sender<Arc<Filter>>,
+    routes: watch::Sender<Arc<Routes>>,
+    outputs: watch::Sender<Arc<Config>>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl Reloader {
+    pub fn new(config: &Config, fan_out: bool, telemetry: Arc<Telemetry>) -> Result<Reloader> {
+        let relabel = Relabel::parse(&config.relabel)?;
+        let tags = TagRules::parse(&config.tag)?;
+        let filter = Filter::parse(&config.filter)?;
+        let routes = Routes::parse(&config.route, &config.output_mode)?;
+        Ok(Reloader {
+            current: Mutex::new(config.clone()),
+            fan_out,
+            relabel: watch::Sender::new(Arc::new(relabel)),
+            tags: watch::Sender::new(Arc::new(tags)),
+            filter: watch::Sender::new(Arc::new(filter)),
+            routes: watch::Sender::new(Arc::new(routes)),
+            outputs: watch::Sender::new(Arc::new(config.clone())),
+            telemetry,
+        })
+    }
+
+    pub fn relabel(&self) -> Arc<Relabel> {
+        self.relabel.borrow().clone()
+    }
+
+    pub fn tags(&self) -> Arc<TagRules> {
+        self.tags.borrow().clone()
+    }
+
+    pub fn filter(&self) -> Arc<Filter> {
+        self.filter.borrow().clone()
+    }
+
+    pub fn routes(&self) -> watch::Receiver<Arc<Routes>> {
+        self.routes.subscribe()
+    }
+
+    // The settings an output worker opens its sink with, changed on reload
+    pub fn outputs(&self) -> watch::Receiver<Arc<Config>> {
+        self.outputs.subscribe()
+    }
+
+    // Re-read the command line and --config file and apply the result
+    pub fn reload(&self) -> Result<()> {
+        let result = Config::load().and_then(|config| self.apply(config));
+        match &result {
+            Ok(()) => self.telemetry.record_reload(),
+            Err(e) => {
+                self.telemetry.record_reload_failure();
+                warn!("Config reload failed, keeping the current settings: {:#}", e);
+            }
+        }
+        result
+    }
+
+    // Everything is checked before anything is swapped, a bad config changes nothing
+    pub fn apply(&self, config: Config) -> Result<()> {
+        let mut current = self.current.lock().unwrap();
+        if config.output_mode != current.output_mode {
+            return Err(anyhow!("Changing --output-mode needs a restart"));
+        }
+        if config.sink_workers != current.sink_workers {
+            return Err(anyhow!("Changing --sink-workers needs a restart"));
+        }
+        let relabel = Relabel::parse(&config.relabel)?;
+        let tags = TagRules::parse(&config.tag)?;
+        let filter = Filter::parse(&config.filter)?;
+        let routes = Routes::parse(&config.route, &config.output_mode)?;
+        if !self.fan_out && !routes.is_empty() {
+            return Err(anyhow!("Adding routes to a single output needs a restart"));
+        }
+        for mode in &config.output_mode {
+            sinks::validate(mode, &config)?;
+        }
+
+        self.relabel.send_replace(Arc::new(relabel));
+        self.tags.send_replace(Arc::new(tags));
+        self.filter.send_replace(Arc::new(filter));
+        self.routes.send_replace(Arc::new(routes));
+        if output_settings(&config) != output_settings(&current) {
+            info!("Output settings changed, reopening the outputs");
+            self.outputs.send_replace(Arc::new(config.clone()));
+        }
+        *current = config;
+        info!("Reloaded the config");
+        Ok(())
+    }
+}
+
+// Settings no output reads: the rules, which apply without touching the outputs,
+// and the listeners, auth, limits and pipeline options, which keep their startup
+// values. A field missing here only costs a needless reopen.
+const NOT_OUTPUT_SETTINGS: &[&str] = &[
+    "relabel", "tag", "filter", "route",
+    "config", "host", "port", "listen_unix", "listen_unix_mode", "http2",
+    "http2_max_concurrent_streams", "http_keep_alive_timeout", "http_max_connections",
+    "collectd_listen", "graphite_listen", "graphite_template", "grpc_listen",
+    "kafka_input_topic", "kafka_input_brokers", "kafka_input_group", "kafka_input_offset_reset",
+    "tail_file", "tail_checkpoint", "tail_poll_interval", "tail_start", "scrape_target",
+    "scrape_interval", "self_metrics", "self_metrics_interval", "snmp_target", "snmp_oid",
+    "snmp_community", "snmp_user", "snmp_auth_protocol", "snmp_auth_password",
+    "snmp_priv_password", "snmp_interval", "snmp_timeout", "exec", "exec_daemon",
+    "exec_interval", "exec_timeout", "exec_restart", "exec_restart_delay",
+    "allow_cidr", "auth_token", "auth_user", "auth_password", "api_key", "tenant_header",
+    "max_body_bytes", "max_metrics_per_request", "strict", "max_metrics_per_sec", "rate_limit_burst",
+    "host_from_peer", "host_lowercase", "host_strip_domain", "host_rewrite", "lookup_file",
+    "lookup_refresh", "sample", "convert_unit", "alert", "alert_target", "typesdb", "script",
+    "script_max_operations", "plugin_transform", "reject_older_than", "dedup_window", "rates",
+    "rate_state_ttl", "max_series", "series_overflow", "series_ttl", "aggregate_window",
+    "aggregate_functions", "aggregate_match", "aggregate_keep_raw", "rate_wrap",
+    "queue_capacity", "overflow_policy",
+];
+
+// What the outputs are opened with, so other changes don't reopen them
+fn output_settings(config: &Config) -> serde_json::Value {
+    let mut value = serde_json::to_value(config).unwrap_or_default();
+    if let Some(fields) = value.as_object_mut() {
+        for name in NOT_OUTPUT_SETTINGS {
+            fields.remove(*name);
+        }
+    }
+    value
+}
+
+// Reload on every SIGHUP for as long as the receiver runs
+pub async fn on_sighup(reloader: Arc<Reloader>) -> Result<()> {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the config");
+        // Failures are logged and counted by reload
+        let _ = reloader.reload();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn names_config_fields() {
+        let config = serde_json::to_value(Config::parse_from(["collectd-http-receiver"])).unwrap();
+        for name in NOT_OUTPUT_SETTINGS {
+            assert!(config.get(name).is_some(), "{} is not a config field", name);
+        }
+    }
+
+    #[test]
+    fn only_output_changes_count() {
+        let config = Config::parse_from(["collectd-http-receiver"]);
+        let auth = Config::parse_from(["collectd-http-receiver", "--auth-token", "secret", "--strict"]);
+        assert_eq!(output_settings(&config), output_settings(&auth));
+        let influx = Config::parse_from(["collectd-http-receiver", "--influx-url", "http://influx:8086"]);
+        assert_ne!(output_settings(&config), output_settings(&influx));
+    }
+}
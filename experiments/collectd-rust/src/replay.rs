@@ -0,0 +1,105 @@
+// Replays an NDJSON file written by the disk output into the configured outputs,
+// for backfilling after an outage or load testing a downstream. Dead-letter
+// files work too, their extra fields are ignored. Metrics go straight to the
+// outputs: the filter, rates and aggregation were applied when they were first
+// received. The original spacing between timestamps is kept, divided by --speed.
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::queue::QueueError;
+
+pub async fn run(config: &Config, path: &str, speed: f64, rewrite_timestamps: bool) -> Result<()> {
+    if !speed.is_finite() || speed < 0.0 {
+        return Err(anyhow!("--speed must be 0 or more"));
+    }
+    // Replaying the disk output's own file into it would never end
+    if config.output_mode.iter().any(|mode| mode == "disk") && same_file(path, &config.output_file) {
+        return Err(anyhow!("{} is the disk output's file, pick another output or --output-file", path));
+    }
+
+    let outputs = crate::start_outputs(config)?;
+    info!("Replaying {} into {}", path, config.output_mode.join(", "));
+
+    let (tx, mut rx) = mpsc::channel(1024);
+    let file = path.to_string();
+    let reader = tokio::task::spawn_blocking(move || read_archive(&file, |metric| tx.blocking_send(metric).is_ok()));
+
+    let started = Instant::now();
+    let mut first_time = None;
+    let (mut sent, mut dropped) = (0, 0);
+    while let Some(mut metric) = rx.recv().await {
+        if let (true, Some(time)) = (speed > 0.0, metric.time) {
+            let first = *first_time.get_or_insert(time);
+            // Out of order timestamps go out right away
+            let offset = ((time - first) / speed).max(0.0);
+            sleep_until(started + Duration::from_secs_f64(offset)).await;
+        }
+        if rewrite_timestamps {
+            metric.time = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or_default(),
+            );
+        }
+        match outputs.sender.send(metric).await {
+            Ok(evicted) => dropped += evicted,
+            Err(QueueError::Full) => dropped += 1,
+            Err(QueueError::Closed) => return Err(anyhow!("The outputs stopped before the replay finished")),
+        }
+        sent += 1;
+    }
+    let skipped = reader.await??;
+
+    // Closing the queue makes every output flush and finish
+    drop(outputs.sender);
+    for worker in outputs.workers {
+        worker.await?;
+    }
+    info!(
+        "Replayed {} metrics from {} ({} dropped by the overflow policy, {} unreadable lines skipped)",
+        sent, path, dropped, skipped
+    );
+    Ok(())
+}
+
+// Hands every metric in an NDJSON archive to `each` until it returns false, and
+// returns how many lines couldn't be parsed. Blocking, shared with convert.
+pub fn read_archive(path: &str, mut each: impl FnMut(ProcessedMetric) -> bool) -> Result<usize> {
+    let file = std::fs::File::open(path).map_err(|e| anyhow!("Failed to open {}: {}", path, e))?;
+    let input: Box<dyn Read> = if path.ends_with(".gz") { Box::new(GzDecoder::new(file)) } else { Box::new(file) };
+    let mut skipped = 0;
+    for (number, line) in BufReader::new(input).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<ProcessedMetric>(&line) {
+            Ok(metric) => {
+                if !each(metric) {
+                    break;
+                }
+            }
+            Err(e) => {
+                skipped += 1;
+                warn!("Skipping line {} of {}: {}", number + 1, path, e);
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
@@ -0,0 +1,215 @@
+// Retry policy for outputs: exponential backoff with jitter, a retry limit,
+// an optional dead-letter file for batches that still fail, and a circuit
+// breaker that stops sending to an output that keeps failing.
+use std::{
+    fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Returned by a sink when the downstream refused the batch itself (e.g. a 400),
+// resending it unchanged can't succeed so it skips straight to the dead letters
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+pub struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { current: initial, max }
+    }
+
+    // The next delay, somewhere between half and all of the current step so
+    // workers that failed together don't retry in lockstep
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current.mul_f64(0.5 + jitter() / 2.0);
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Circuit {
+    // Batches go to the output
+    Closed,
+    // Batches skip the output until the cooldown is over
+    Open,
+    // The cooldown is over, the next attempt is a probe
+    HalfOpen,
+}
+
+impl Circuit {
+    pub fn name(self) -> &'static str {
+        match self {
+            Circuit::Closed => "closed",
+            Circuit::Open => "open",
+            Circuit::HalfOpen => "half-open",
+        }
+    }
+}
+
+// Opens after `threshold` failed attempts in a row (0 never opens). While open
+// nothing is sent; once the cooldown is over one attempt goes through, and its
+// outcome closes the breaker or opens it for another cooldown.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: u32,
+    state: Circuit,
+    probe_at: Instant,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            failures: 0,
+            state: Circuit::Closed,
+            probe_at: Instant::now(),
+        }
+    }
+
+    pub fn state(&self) -> Circuit {
+        self.state
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    // Reloaded settings keep the current state, unless they turn the breaker off
+    pub fn reconfigure(&mut self, threshold: u32, cooldown: Duration) {
+        self.threshold = threshold;
+        self.cooldown = cooldown;
+        if threshold == 0 {
+            self.record_success();
+        }
+    }
+
+    // Whether the next attempt may go to the output, turning an open breaker
+    // half-open once its cooldown is over
+    pub fn allow(&mut self) -> bool {
+        if self.state == Circuit::Open && Instant::now() >= self.probe_at {
+            self.state = Circuit::HalfOpen;
+        }
+        self.state != Circuit::Open
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.state = Circuit::Closed;
+    }
+
+    // True when this failure opened the breaker, which a failed probe always does
+    pub fn record_failure(&mut self) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        let trips = self.threshold > 0 && (self.state == Circuit::HalfOpen || self.failures >= self.threshold);
+        if trips {
+            self.state = Circuit::Open;
+            self.probe_at = Instant::now() + self.cooldown;
+        }
+        trips
+    }
+}
+
+// Good enough randomness for spreading retries, in [0, 1)
+fn jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    // Scramble the low bits, the clock's resolution can be coarse
+    let mixed = nanos.wrapping_mul(2654435761) >> 8;
+    mixed as f64 / (1u32 << 24) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn opens_at_the_threshold() {
+        let mut breaker = CircuitBreaker::new(3, COOLDOWN);
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.state(), Circuit::Closed);
+        assert!(breaker.allow());
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), Circuit::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let mut breaker = CircuitBreaker::new(2, COOLDOWN);
+        breaker.record_failure();
+        breaker.record_success();
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.failures(), 1);
+        assert_eq!(breaker.state(), Circuit::Closed);
+    }
+
+    #[test]
+    fn probes_after_the_cooldown() {
+        let mut breaker = CircuitBreaker::new(1, COOLDOWN);
+        assert!(breaker.record_failure());
+        // Still inside the cooldown
+        assert!(!breaker.allow());
+        assert_eq!(breaker.state(), Circuit::Open);
+
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), Circuit::HalfOpen);
+        breaker.record_success();
+        assert_eq!(breaker.state(), Circuit::Closed);
+        assert_eq!(breaker.failures(), 0);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn failed_probe_opens_again() {
+        let mut breaker = CircuitBreaker::new(3, COOLDOWN);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        std::thread::sleep(COOLDOWN * 2);
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), Circuit::HalfOpen);
+        // One failure is enough, and the cooldown starts over
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), Circuit::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let mut breaker = CircuitBreaker::new(0, COOLDOWN);
+        for _ in 0..10 {
+            assert!(!breaker.record_failure());
+        }
+        assert!(breaker.allow());
+
+        let mut breaker = CircuitBreaker::new(1, COOLDOWN);
+        breaker.record_failure();
+        breaker.reconfigure(0, COOLDOWN);
+        assert_eq!(breaker.state(), Circuit::Closed);
+        assert!(breaker.allow());
+    }
+}
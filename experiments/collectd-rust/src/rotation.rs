@@ -0,0 +1,301 @@
+// Output file for the disk writer that rotates by size and/or age.
+// The active file keeps its configured name; closed segments are renamed to
+// `<name>.<UTC timestamp>` and the oldest ones are deleted past `keep`.
+// With `compress` set, closed segments are gzipped in the background and end
+// up as `<name>.<UTC timestamp>.gz`.
+// With `atomic` set the active file is `<name>.tmp` instead, and closing the
+// file renames it like a rotation does, so a file only ever shows up under a
+// final name once it's complete.
+use anyhow::Result;
+use flate2::{write::GzEncoder, Compression};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    time::Instant,
+};
+use tracing::{info, warn};
+
+#[derive(Clone)]
+pub struct RotationPolicy {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: Option<usize>,
+    pub compress: bool,
+    // fsync a segment before it is renamed away
+    pub sync_on_close: bool,
+    // Write with O_DIRECT
+    pub direct: bool,
+    // Write to <name>.tmp and rename on rotation and close
+    pub atomic: bool,
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: Handle,
+    size: u64,
+    opened_at: Instant,
+    policy: RotationPolicy,
+}
+
+// O_DIRECT only takes block-aligned writes, from aligned memory, at aligned
+// offsets. The direct handle keeps the file's partial last block in memory and
+// rewrites it with the next write, zero padded to a whole block, then truncates
+// the padding off again.
+enum Handle {
+    Buffered(File),
+    Direct { file: Arc<std::fs::File>, tail: Vec<u8> },
+}
+
+// Covers 512 byte and 4K sector devices alike
+const BLOCK: usize = 4096;
+
+impl RotatingFile {
+    // A <name>.tmp left by a crash is appended to, it's still in progress
+    pub async fn open(path: impl Into<PathBuf>, policy: RotationPolicy) -> Result<RotatingFile> {
+        let path = path.into();
+        let (file, size) = open_handle(&active_path(&path, &policy), policy.direct).await?;
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            opened_at: Instant::now(),
+            policy,
+        })
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.file {
+            Handle::Buffered(file) => file.write_all(data).await?,
+            Handle::Direct { file, tail } => {
+                let start = self.size - tail.len() as u64;
+                let len = tail.len() + data.len();
+                let padded = len.next_multiple_of(BLOCK);
+                // Over-allocated by a block so the write can start at an aligned address
+                let mut buf = vec![0u8; padded + BLOCK];
+                let at = buf.as_ptr().align_offset(BLOCK);
+                buf[at..at + tail.len()].copy_from_slice(tail);
+                buf[at + tail.len()..at + len].copy_from_slice(data);
+                let file = file.clone();
+                let buf = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+                    file.write_all_at(&buf[at..at + padded], start)?;
+                    file.set_len(start + len as u64)?;
+                    Ok(buf)
+                })
+                .await??;
+                let whole = len - len % BLOCK;
+                *tail = buf[at + whole..at + len].to_vec();
+            }
+        }
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        if let Handle::Buffered(file) = &mut self.file {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    // Makes what was written so far durable
+    pub async fn sync(&mut self) -> Result<()> {
+        match &mut self.file {
+            Handle::Buffered(file) => file.sync_data().await?,
+            Handle::Direct { file, .. } => {
+                let file = file.clone();
+                tokio::task::spawn_blocking(move || file.sync_data()).await??;
+            }
+        }
+        Ok(())
+    }
+
+    // Flushes (and syncs, if the policy says so) before the file is dropped.
+    // An atomic file gets its final name, or is removed if it's empty.
+    pub async fn close(mut self) -> Result<()> {
+        if !self.policy.atomic {
+            self.flush().await?;
+            if self.policy.sync_on_close {
+                self.sync().await?;
+            }
+            return Ok(());
+        }
+        if self.size == 0 {
+            drop(self.file);
+            tokio::fs::remove_file(active_path(&self.path, &self.policy)).await?;
+            return Ok(());
+        }
+        let closed = self.finish_segment().await?;
+        info!("Closed {} as {}", self.path.display(), closed.display());
+        Ok(())
+    }
+
+    // Rotate if the active file is over the size limit or older than the max age.
+    // Empty files are left alone, there's nothing worth keeping in them.
+    pub async fn rotate_if_due(&mut self) -> Result<Option<PathBuf>> {
+        if self.size == 0 {
+            return Ok(None);
+        }
+        let too_big = self.policy.max_bytes.is_some_and(|max| self.size >= max);
+        let too_old = self.policy.max_age.is_some_and(|max| self.opened_at.elapsed() >= max);
+        if !(too_big || too_old) {
+            return Ok(None);
+        }
+        self.rotate().await.map(Some)
+    }
+
+    async fn rotate(&mut self) -> Result<PathBuf> {
+        let rotated = self.finish_segment().await?;
+        let (file, size) = open_handle(&active_path(&self.path, &self.policy), self.policy.direct).await?;
+        self.file = file;
+        self.size = size;
+        self.opened_at = Instant::now();
+        info!("Rotated {} to {}", self.path.display(), rotated.display());
+        Ok(rotated)
+    }
+
+    // Renames the active file to <name>.<UTC timestamp>, then compresses it
+    // and applies retention as the policy says
+    async fn finish_segment(&mut self) -> Result<PathBuf> {
+        self.flush().await?;
+        if self.policy.sync_on_close {
+            self.sync().await?;
+        }
+
+        let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", timestamp));
+        // A file closed and reopened within the same millisecond mustn't
+        // replace the segment it just finished
+        let mut candidate = PathBuf::from(&rotated);
+        let mut n = 0;
+        while tokio::fs::try_exists(&candidate).await? {
+            n += 1;
+            let mut numbered = rotated.clone();
+            numbered.push(format!("-{}", n));
+            candidate = PathBuf::from(numbered);
+        }
+        let rotated = candidate;
+
+        tokio::fs::rename(active_path(&self.path, &self.policy), &rotated).await?;
+
+        let path = self.path.clone();
+        let keep = self.policy.keep;
+        if self.policy.compress {
+            // Retention runs after compression so it never sees a half written .gz
+            let segment = rotated.clone();
+            tokio::spawn(async move {
+                if let Err(e) = compress_segment(segment).await {
+                    warn!("Failed to compress rotated file: {}", e);
+                }
+                apply_retention(&path, keep).await;
+            });
+        } else {
+            apply_retention(&path, keep).await;
+        }
+        Ok(rotated)
+    }
+}
+
+fn active_path(path: &Path, policy: &RotationPolicy) -> PathBuf {
+    if !policy.atomic {
+        return path.to_path_buf();
+    }
+    let mut active = path.as_os_str().to_owned();
+    active.push(".tmp");
+    PathBuf::from(active)
+}
+
+async fn apply_retention(path: &Path, keep: Option<usize>) {
+    if let Some(keep) = keep {
+        if let Err(e) = remove_old_segments(path, keep).await {
+            warn!("Failed to clean up rotated files: {}", e);
+        }
+    }
+}
+
+// Writes <segment>.gz.tmp, renames it into place, then removes the original
+async fn compress_segment(segment: PathBuf) -> Result<()> {
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut compressed = segment.clone().into_os_string();
+        compressed.push(".gz");
+        let compressed = PathBuf::from(compressed);
+        let mut tmp = compressed.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut input = std::fs::File::open(&segment)?;
+        let mut encoder = GzEncoder::new(std::fs::File::create(&tmp)?, Compression::default());
+        std::io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+
+        std::fs::rename(&tmp, &compressed)?;
+        std::fs::remove_file(&segment)?;
+        info!("Compressed {} to {}", segment.display(), compressed.display());
+        Ok(())
+    })
+    .await?
+}
+
+async fn open_handle(path: &Path, direct: bool) -> Result<(Handle, u64)> {
+    if !direct {
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+        let size = file.metadata().await?.len();
+        return Ok((Handle::Buffered(file), size));
+    }
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        // Not O_APPEND: direct writes go to explicit offsets
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(&path)?;
+        let size = file.metadata()?.len();
+        // Direct reads have the same alignment rules, so the partial last block
+        // is read back through the page cache
+        let tail_len = size % BLOCK as u64;
+        let mut tail = vec![0u8; tail_len as usize];
+        std::fs::File::open(&path)?.read_exact_at(&mut tail, size - tail_len)?;
+        Ok((Handle::Direct { file: Arc::new(file), tail }, size))
+    })
+    .await?
+}
+
+// Rotated names sort by their timestamp, so the oldest come first
+async fn remove_old_segments(path: &Path, keep: usize) -> Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Ok(()),
+    };
+
+    let mut segments = Vec::new();
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // In-progress compressions aren't segments yet
+        if name.starts_with(&prefix) && !name.ends_with(".tmp") {
+            segments.push(entry.path());
+        }
+    }
+    segments.sort();
+
+    let excess = segments.len().saturating_sub(keep);
+    for segment in &segments[..excess] {
+        tokio::fs::remove_file(segment).await?;
+        info!("Removed old rotated file {}", segment.display());
+    }
+    Ok(())
+}
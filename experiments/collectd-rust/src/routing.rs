@@ -0,0 +1,42 @@
+// Per-output routing rules, `<output>:<field>=<value>` or `<output>:<field>~<regex>`,
+// e.g. `udp:plugin=cpu` and `disk:plugin~^(df|disk)$`. An output with rules only
+// receives metrics matching at least one of them, an output without rules
+// receives everything.
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::filter::Rule;
+use crate::metric::ProcessedMetric;
+
+#[derive(Debug, Default)]
+pub struct Routes {
+    rules: HashMap<String, Vec<Rule>>,
+}
+
+impl Routes {
+    pub fn parse(rules: &[String], outputs: &[String]) -> Result<Routes> {
+        let mut routes = Routes::default();
+        for rule in rules {
+            let (output, expr) = rule
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid route {:?}, expected <output>:<field>=<value>", rule))?;
+            if !outputs.iter().any(|o| o == output) {
+                return Err(anyhow!("Route {:?} names output {:?}, which is not enabled", rule, output));
+            }
+            let parsed = Rule::parse(expr).map_err(|e| anyhow!("Invalid route {:?}: {}", rule, e))?;
+            routes.rules.entry(output.to_string()).or_default().push(parsed);
+        }
+        Ok(routes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn accepts(&self, output: &str, metric: &ProcessedMetric) -> bool {
+        match self.rules.get(output) {
+            Some(rules) => rules.iter().any(|rule| rule.matches(metric)),
+            None => true,
+        }
+    }
+}
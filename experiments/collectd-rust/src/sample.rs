@@ -0,0 +1,128 @@
+// Keeps only part of the metrics matching a rule, so high-frequency plugins can
+// be thinned out without touching the rest. Rules are `<rate>:<field>=<value>`
+// or `<rate>:<field>~<regex>` with the filter's match syntax, where rate is
+// either a percentage or `1/N` for every Nth point, e.g.
+//   10%:plugin=interface
+//   1/6:plugin~^(disk|df)$
+//   100%:plugin=cpu
+// The first matching rule decides, metrics matching none are kept. Percentages
+// are sampled on a hash of the series and timestamp, so a resent point gets the
+// same decision; every Nth counts per series, keeping the first of each N.
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::filter::Rule;
+use crate::metric::{MetricId, ProcessedMetric, Tags};
+
+// Counters of series not seen for this long are forgotten
+const IDLE: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy)]
+enum Rate {
+    // Out of u64::MAX
+    Fraction(u64),
+    EveryNth(u64),
+}
+
+impl Rate {
+    fn parse(s: &str) -> Result<Rate> {
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent.parse().map_err(|_| anyhow!("Invalid sample rate {:?}", s))?;
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(anyhow!("Sample rate {:?} must be between 0% and 100%", s));
+            }
+            return Ok(Rate::Fraction((percent / 100.0 * u64::MAX as f64) as u64));
+        }
+        let n = s
+            .strip_prefix("1/")
+            .and_then(|n| n.parse::<u64>().ok())
+            .filter(|n| *n > 0)
+            .ok_or_else(|| anyhow!("Invalid sample rate {:?}, expected a percentage or 1/N", s))?;
+        Ok(Rate::EveryNth(n))
+    }
+}
+
+pub struct Sampler {
+    rules: Vec<(Rate, Rule)>,
+    // Stands in for the timestamp of points without one
+    sequence: AtomicU64,
+    state: Mutex<State>,
+}
+
+struct State {
+    // Points seen per rule and series, and when the series was last seen
+    counters: HashMap<(usize, MetricId, Tags), (u64, Instant)>,
+    last_sweep: Instant,
+}
+
+impl Sampler {
+    pub fn parse(rules: &[String]) -> Result<Sampler> {
+        let mut parsed = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let (rate, expr) = rule
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid sample rule {:?}, expected <rate>:<field>=<value>", rule))?;
+            let rate = Rate::parse(rate).map_err(|e| anyhow!("Invalid sample rule {:?}: {}", rule, e))?;
+            let expr = Rule::parse(expr).map_err(|e| anyhow!("Invalid sample rule {:?}: {}", rule, e))?;
+            parsed.push((rate, expr));
+        }
+        Ok(Sampler {
+            rules: parsed,
+            sequence: AtomicU64::new(0),
+            state: Mutex::new(State {
+                counters: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        })
+    }
+
+    // Removes the points sampled out in place, returns how many there were
+    pub fn apply(&self, metrics: &mut Vec<ProcessedMetric>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(state.last_sweep) >= IDLE {
+            state.counters.retain(|_, (_, seen)| now.duration_since(*seen) < IDLE);
+            state.last_sweep = now;
+        }
+
+        let before = metrics.len();
+        metrics.retain(|metric| {
+            let Some((index, rate)) = self
+                .rules
+                .iter()
+                .enumerate()
+                .find(|(_, (_, rule))| rule.matches(metric))
+                .map(|(index, (rate, _))| (index, *rate))
+            else {
+                return true;
+            };
+            match rate {
+                Rate::Fraction(keep) => {
+                    let mut hasher = DefaultHasher::new();
+                    MetricId::of(metric).hash(&mut hasher);
+                    metric.tags.hash(&mut hasher);
+                    match metric.time {
+                        Some(time) => time.to_bits().hash(&mut hasher),
+                        None => self.sequence.fetch_add(1, Ordering::Relaxed).hash(&mut hasher),
+                    }
+                    hasher.finish() < keep || keep == u64::MAX
+                }
+                Rate::EveryNth(n) => {
+                    let key = (index, MetricId::of(metric), metric.tags.clone());
+                    let (count, seen) = state.counters.entry(key).or_insert((0, now));
+                    let keep = *count % n == 0;
+                    *count += 1;
+                    *seen = now;
+                    keep
+                }
+            }
+        });
+        before - metrics.len()
+    }
+}
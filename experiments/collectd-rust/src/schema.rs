@@ -0,0 +1,47 @@
+// Checks for --strict ingestion: a metric needs a host, a plugin, a time and at
+// least one value, every value has to be a number, and dstypes and dsnames, when
+// given, need one entry per value. Without --strict such metrics pass and end up
+// as rows with empty fields.
+use serde::Serialize;
+
+use crate::metric::CollectdMetric;
+
+// One problem with the metric at index in the request, or the reason it
+// couldn't be parsed
+#[derive(Debug, Serialize)]
+pub struct Invalid {
+    pub index: usize,
+    pub reason: String,
+}
+
+// Every problem with the metric, empty when it is valid
+pub fn check(metric: &CollectdMetric) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (name, field) in [("host", &metric.host), ("plugin", &metric.plugin)] {
+        if field.as_deref().is_none_or(str::is_empty) {
+            problems.push(format!("missing {}", name));
+        }
+    }
+    if metric.time.is_none() {
+        problems.push("missing time".to_string());
+    }
+    let values = match (&metric.values, &metric.value) {
+        (Some(values), _) => values.as_slice(),
+        (None, Some(value)) => std::slice::from_ref(value),
+        (None, None) => &[],
+    };
+    if values.is_empty() {
+        problems.push("no values".to_string());
+    }
+    for (i, value) in values.iter().enumerate() {
+        if !value.is_number() {
+            problems.push(format!("value {} is not a number: {}", i, value));
+        }
+    }
+    for (name, list) in [("dstypes", &metric.dstypes), ("dsnames", &metric.dsnames)] {
+        if let Some(list) = list.as_ref().filter(|list| list.len() != values.len()) {
+            problems.push(format!("{} has {} entries for {} values", name, list.len(), values.len()));
+        }
+    }
+    problems
+}
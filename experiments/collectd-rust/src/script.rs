@@ -0,0 +1,100 @@
+// User transforms in Rhai (https://rhai.rs), run on every metric after the
+// relabel and tag rules. The script sees the metric as a map named `metric`
+// with the same fields as the JSON output (host, plugin, type_, dsname, value,
+// tags, ...) and can change it in place. Its last expression decides what is
+// passed on:
+//   nothing, or true      the metric as changed
+//   false                 nothing, the metric is dropped
+//   a map                 that metric instead
+//   an array of maps      all of them, e.g. to split a metric in two
+// Scripts have no access to files or the network, and each run is capped at
+// --script-max-operations. A run that fails or goes over passes the original
+// metric on unchanged and is counted as a script error.
+use anyhow::{anyhow, Result};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use tracing::{debug, warn};
+
+use crate::metric::ProcessedMetric;
+
+// Caps on what a single run can build, against runaway scripts
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_COLLECTION_SIZE: usize = 10_000;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn load(path: &str, max_operations: u64) -> Result<Script> {
+        if max_operations == 0 {
+            return Err(anyhow!("--script-max-operations must be at least 1"));
+        }
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(max_operations)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_COLLECTION_SIZE)
+            .set_max_map_size(MAX_COLLECTION_SIZE);
+        engine.on_print(|text| debug!("script: {}", text));
+        engine.on_debug(|text, _, position| debug!("script at {}: {}", position, text));
+        let source = std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read script {}: {}", path, e))?;
+        let ast = engine
+            .compile(source)
+            .map_err(|e| anyhow!("Invalid script {}: {}", path, e))?;
+        Ok(Script { engine, ast })
+    }
+
+    // Runs the script over every metric, returns the metrics to pass on and the number of failed runs
+    pub fn apply(&self, metrics: Vec<ProcessedMetric>) -> (Vec<ProcessedMetric>, usize) {
+        let mut output = Vec::with_capacity(metrics.len());
+        let mut errors = 0;
+        for metric in metrics {
+            match self.run(&metric) {
+                Ok(transformed) => output.extend(transformed),
+                Err(e) => {
+                    errors += 1;
+                    warn!("Script failed, passing the metric on unchanged: {:#}", e);
+                    output.push(metric);
+                }
+            }
+        }
+        (output, errors)
+    }
+
+    fn run(&self, metric: &ProcessedMetric) -> Result<Vec<ProcessedMetric>> {
+        let mut value = rhai::serde::to_dynamic(metric).map_err(|e| anyhow!("{}", e))?;
+        // Empty tags aren't serialized, the script should still be able to add some
+        if let Some(mut map) = value.write_lock::<Map>() {
+            map.entry("tags".into()).or_insert_with(|| Map::new().into());
+        }
+        let mut scope = Scope::new();
+        scope.push("metric", value);
+        let result: Dynamic = self
+            .engine
+            .eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| anyhow!("{}", e))?;
+
+        if result.is_unit() || result.as_bool() == Ok(true) {
+            let changed = scope.get_value::<Dynamic>("metric").unwrap_or_default();
+            return Ok(vec![from_dynamic(&changed)?]);
+        }
+        if result.as_bool() == Ok(false) {
+            return Ok(Vec::new());
+        }
+        if result.is_map() {
+            return Ok(vec![from_dynamic(&result)?]);
+        }
+        match result.into_array() {
+            Ok(metrics) => metrics.iter().map(from_dynamic).collect(),
+            Err(type_name) => Err(anyhow!(
+                "the script returned a {}, expected nothing, a bool, a map or an array of maps",
+                type_name
+            )),
+        }
+    }
+}
+
+fn from_dynamic(value: &Dynamic) -> Result<ProcessedMetric> {
+    rhai::serde::from_dynamic(value).map_err(|e| anyhow!("the script produced an invalid metric: {}", e))
+}
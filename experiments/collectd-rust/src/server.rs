@@ -0,0 +1,884 @@
+// HTTP side of the receiver: ingest routes, internal metrics and probes
+use anyhow::Result;
+use axum::{
+    body::{Body, Bytes},
+    extract::{
+        rejection::BytesRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, DefaultBodyLimit, Path, Request, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use flate2::read::GzDecoder;
+use futures_util::StreamExt;
+use prost::Message as _;
+use serde::de::{Deserializer as _, SeqAccess, Visitor};
+use serde_json::value::RawValue;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tracing::{debug, warn};
+
+use crate::acl::AllowList;
+use crate::auth;
+use crate::config::Config;
+use crate::metric::{CollectdMetric, Tags};
+use crate::otlp;
+use crate::pipeline::Pipeline;
+use crate::proto;
+use crate::queue::QueueError;
+use crate::ratelimit::RateLimiter;
+use crate::schema;
+use crate::telemetry::Telemetry;
+use crate::tenant;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub pipeline: Pipeline,
+    pub config: Arc<Config>,
+    pub telemetry: Arc<Telemetry>,
+    pub limiter: Option<Arc<RateLimiter>>,
+    pub allow: Arc<AllowList>,
+    pub(crate) api_keys: Arc<Vec<auth::ApiKey>>,
+}
+
+impl AppState {
+    pub fn new(pipeline: Pipeline, config: &Config, telemetry: Arc<Telemetry>) -> Result<AppState> {
+        let limiter = config
+            .max_metrics_per_sec
+            .map(|rate| Arc::new(RateLimiter::new(rate, config.rate_limit_burst)));
+        Ok(AppState {
+            pipeline,
+            config: Arc::new(config.clone()),
+            telemetry,
+            limiter,
+            allow: Arc::new(AllowList::parse(&config.allow_cidr)?),
+            api_keys: Arc::new(auth::parse_api_keys(&config.api_key)?),
+        })
+    }
+}
+
+// 403 for peers outside --allow-cidr, checked before auth. Without connect
+// info the peer is unknown and only an empty allowlist lets it in.
+async fn require_allowed(
+    State(state): State<AppState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.allow.is_empty() || peer.is_some_and(|ConnectInfo(addr)| state.allow.allows(addr.ip())) {
+        return next.run(request).await;
+    }
+    state.telemetry.record_denied();
+    debug!("Denying request from {:?}, not in --allow-cidr", peer.map(|ConnectInfo(addr)| addr));
+    (StatusCode::FORBIDDEN, "Source address not allowed\n").into_response()
+}
+
+// Who a request counts against for rate limiting: its auth identity, or the
+// peer address. Routers served without connect info lump anonymous clients together.
+pub(crate) fn client_key(identity: Option<&auth::Identity>, peer: Option<ConnectInfo<SocketAddr>>) -> String {
+    match (identity, peer) {
+        (Some(identity), _) => identity.name.clone(),
+        (None, Some(ConnectInfo(addr))) => addr.ip().to_string(),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+// The identity's tags plus the tenant the request names, if any. A tenant
+// fixed by the API key can't be swapped for another one.
+pub(crate) fn request_tags(
+    state: &AppState,
+    identity: Option<&auth::Identity>,
+    path: Option<Path<String>>,
+    headers: &HeaderMap,
+) -> Result<Tags, (StatusCode, String)> {
+    let mut tags = identity.map(|identity| (*identity.tags).clone()).unwrap_or_default();
+    let requested = path.map(|Path(tenant)| tenant).or_else(|| {
+        headers
+            .get(state.config.tenant_header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+    });
+    if let Some(requested) = requested {
+        tenant::validate_name(&requested).map_err(|e| (StatusCode::BAD_REQUEST, format!("{}\n", e)))?;
+        match tags.get(tenant::TAG) {
+            Some(assigned) if *assigned != requested => {
+                return Err((StatusCode::FORBIDDEN, format!("Not allowed to send for tenant {}\n", requested)));
+            }
+            _ => {
+                tags.insert(tenant::TAG.to_string(), requested);
+            }
+        }
+    }
+    // Requests without a tenant count as the default one
+    let name = tags.get(tenant::TAG).map(String::as_str).unwrap_or(tenant::DEFAULT);
+    if !state.config.tenant.is_empty() && !state.config.tenant.iter().any(|allowed| allowed == name) {
+        return Err((StatusCode::FORBIDDEN, format!("Unknown tenant {}\n", name)));
+    }
+    Ok(tags)
+}
+
+// 413 with an explanation, for bodies over --max-body-bytes and requests over
+// --max-metrics-per-request
+fn too_large(message: String) -> Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, format!("{}\n", message)).into_response()
+}
+
+fn body_rejected(state: &AppState, status: StatusCode, error: String) -> Response {
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        warn!("Rejecting request body over --max-body-bytes");
+        return too_large(format!("Request body is larger than {} bytes", state.config.max_body_bytes));
+    }
+    (status, error).into_response()
+}
+
+fn check_count(state: &AppState, count: usize) -> Option<Response> {
+    let max = state.config.max_metrics_per_request?;
+    if count <= max {
+        return None;
+    }
+    warn!("Rejecting request with {} metrics, over --max-metrics-per-request", count);
+    Some(too_large(format!("Request has {} metrics, more than the limit of {}", count, max)))
+}
+
+// Encodings a collectd body can come in, by Content-Type. Anything that isn't
+// MessagePack, CBOR or protobuf is taken for JSON, as before those existed.
+#[derive(Clone, Copy)]
+enum BodyFormat {
+    Json,
+    Msgpack,
+    Cbor,
+    // A PushRequest of proto/receiver.proto
+    Protobuf,
+}
+
+impl BodyFormat {
+    fn of(headers: &HeaderMap) -> BodyFormat {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        match media_type {
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => BodyFormat::Msgpack,
+            "application/cbor" => BodyFormat::Cbor,
+            "application/x-protobuf" | "application/protobuf" => BodyFormat::Protobuf,
+            _ => BodyFormat::Json,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            BodyFormat::Json => "JSON",
+            BodyFormat::Msgpack => "MessagePack",
+            BodyFormat::Cbor => "CBOR",
+            BodyFormat::Protobuf => "protobuf",
+        }
+    }
+
+    fn parse(self, body: &[u8]) -> Result<Parsed> {
+        match self {
+            BodyFormat::Json => Ok(parse_metrics(body)?),
+            BodyFormat::Msgpack => parse_msgpack(body),
+            BodyFormat::Cbor => parse_cbor(body),
+            BodyFormat::Protobuf => {
+                let request = proto::PushRequest::decode(body)?;
+                Ok(each_entry(request.metrics, proto::Metric::into_collectd))
+            }
+        }
+    }
+}
+
+// A single metric object, or an array of them where a bad entry only costs
+// itself. Returns the metrics that parsed, with their index in the request, and
+// why the others didn't. Only a body that isn't JSON at all, or a single metric
+// that doesn't parse, is an error. The bytes are parsed in place, without
+// copying them into a String first.
+pub(crate) type Parsed = (Vec<(usize, CollectdMetric)>, Vec<schema::Invalid>);
+
+pub(crate) fn parse_metrics(body: &[u8]) -> serde_json::Result<Parsed> {
+    if body.trim_ascii_start().starts_with(b"{") {
+        return Ok((vec![(0, serde_json::from_slice(body)?)], Vec::new()));
+    }
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let parsed = deserializer.deserialize_seq(Entries)?;
+    deserializer.end()?;
+    Ok(parsed)
+}
+
+// Reads an array in one pass: each entry is borrowed from the body as raw
+// JSON, then decoded on its own, so a bad entry only fails itself and nothing
+// is parsed twice
+struct Entries;
+
+impl<'de> Visitor<'de> for Entries {
+    type Value = Parsed;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a metric or an array of metrics")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut entries: A) -> std::result::Result<Parsed, A::Error> {
+        let mut metrics = Vec::with_capacity(entries.size_hint().unwrap_or_default());
+        let mut invalid = Vec::new();
+        let mut index = 0;
+        while let Some(entry) = entries.next_element::<&RawValue>()? {
+            match serde_json::from_str(entry.get()) {
+                Ok(metric) => metrics.push((index, metric)),
+                Err(e) => invalid.push(schema::Invalid {
+                    index,
+                    reason: e.to_string(),
+                }),
+            }
+            index += 1;
+        }
+        Ok((metrics, invalid))
+    }
+}
+
+// The same for MessagePack, a map or an array of maps with the JSON field
+// names. Arrays are decoded straight into metrics, and only decoded again
+// entry by entry when one of them is bad.
+fn parse_msgpack(body: &[u8]) -> Result<Parsed> {
+    if matches!(body.first(), Some(0x80..=0x8f | 0xde | 0xdf)) {
+        return Ok((vec![(0, rmp_serde::from_slice(body)?)], Vec::new()));
+    }
+    if let Ok(metrics) = rmp_serde::from_slice::<Vec<CollectdMetric>>(body) {
+        return Ok((metrics.into_iter().enumerate().collect(), Vec::new()));
+    }
+    let entries: Vec<serde_json::Value> = rmp_serde::from_slice(body)?;
+    Ok(each_entry(entries, serde_json::from_value))
+}
+
+// And for CBOR. Encoders write whole numbers as integers, which ciborium
+// won't take for a float field, so a body that doesn't decode straight into
+// metrics goes through JSON values instead.
+fn parse_cbor(body: &[u8]) -> Result<Parsed> {
+    // Major type 5 is a map
+    if matches!(body.first(), Some(0xa0..=0xbf)) {
+        if let Ok(metric) = ciborium::from_reader(body) {
+            return Ok((vec![(0, metric)], Vec::new()));
+        }
+    } else if let Ok(metrics) = ciborium::from_reader::<Vec<CollectdMetric>, _>(body) {
+        return Ok((metrics.into_iter().enumerate().collect(), Vec::new()));
+    }
+    match serde_json::to_value(ciborium::from_reader::<ciborium::Value, _>(body)?)? {
+        serde_json::Value::Array(entries) => Ok(each_entry(entries, serde_json::from_value)),
+        metric => Ok((vec![(0, serde_json::from_value(metric)?)], Vec::new())),
+    }
+}
+
+fn each_entry<T, E: std::fmt::Display>(
+    entries: Vec<T>,
+    decode: impl Fn(T) -> std::result::Result<CollectdMetric, E>,
+) -> Parsed {
+    let mut metrics = Vec::with_capacity(entries.len());
+    let mut invalid = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        match decode(entry) {
+            Ok(metric) => metrics.push((index, metric)),
+            Err(e) => invalid.push(schema::Invalid {
+                index,
+                reason: e.to_string(),
+            }),
+        }
+    }
+    (metrics, invalid)
+}
+
+// Under --strict, moves the metrics failing the schema checks to invalid
+pub(crate) fn check_schema(metrics: &mut Vec<(usize, CollectdMetric)>, invalid: &mut Vec<schema::Invalid>) {
+    metrics.retain(|(index, metric)| {
+        let problems = schema::check(metric);
+        let valid = problems.is_empty();
+        invalid.extend(problems.into_iter().map(|reason| schema::Invalid { index: *index, reason }));
+        valid
+    });
+    invalid.sort_by_key(|invalid| invalid.index);
+}
+
+// The JSON body listing the entries that were refused, for a 207 or 4xx
+fn rejected_body(accepted: usize, invalid: &[schema::Invalid]) -> Json<serde_json::Value> {
+    let rejected = invalid.iter().map(|invalid| invalid.index).collect::<BTreeSet<_>>().len();
+    Json(serde_json::json!({
+        "accepted": accepted,
+        "rejected": rejected,
+        "invalid": invalid,
+    }))
+}
+
+// 429 with Retry-After once the client is over --max-metrics-per-sec
+fn check_rate(state: &AppState, client: &str, count: usize) -> Option<Response> {
+    let limiter = state.limiter.as_ref()?;
+    limiter.check(client, count).err().map(|wait| {
+        state.telemetry.record_rate_limited(count);
+        debug!("Rate limiting {}, {} metrics", client, count);
+        let retry_after = wait.as_secs_f64().ceil().max(1.0).to_string();
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after)],
+            "Rate limit exceeded\n",
+        )
+            .into_response()
+    })
+}
+
+pub fn router(state: AppState) -> Router {
+    // Auth only guards ingest and /-/reload, /metrics, /-/outputs and the probes stay open
+    let ingest = Router::new()
+        .route("/", post(collectd_handler))
+        .route("/collectd", post(collectd_handler))
+        .route("/stream", post(stream_handler))
+        .route("/ws", get(ws_handler))
+        .route("/v1/metrics", post(otlp_handler))
+        .route("/t/:tenant/", post(collectd_handler))
+        .route("/t/:tenant/collectd", post(collectd_handler))
+        .route("/t/:tenant/stream", post(stream_handler))
+        .route("/t/:tenant/ws", get(ws_handler))
+        .route("/t/:tenant/v1/metrics", post(otlp_handler))
+        .route("/-/reload", post(reload_handler))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), auth::require_auth))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), require_allowed))
+        .layer(DefaultBodyLimit::max(state.config.max_body_bytes));
+    Router::new()
+        .merge(ingest)
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/-/outputs", get(outputs_handler))
+        .with_state(state)
+}
+
+// HTTP handler for collectd metrics
+async fn collectd_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<auth::Identity>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    tenant: Option<Path<String>>,
+    headers: HeaderMap,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<impl IntoResponse, Response> {
+    let body = body.map_err(|e| body_rejected(&state, e.status(), e.body_text()))?;
+    let identity = identity.map(|Extension(identity)| identity);
+    let tags = request_tags(&state, identity.as_ref(), tenant, &headers).map_err(IntoResponse::into_response)?;
+    let format = BodyFormat::of(&headers);
+    let (mut raw_metrics, mut invalid) = format.parse(&body).map_err(|e| {
+        state.telemetry.record_parse_failure();
+        warn!("Failed to parse {}: {}", format.name(), e);
+        StatusCode::BAD_REQUEST.into_response()
+    })?;
+
+    // One entry per metric that didn't parse, so far
+    let unparsable = !invalid.is_empty();
+    let entries = raw_metrics.len() + invalid.len();
+    debug!("Received {} metrics", entries);
+    if let Some(response) = check_count(&state, entries) {
+        return Err(response);
+    }
+    if state.config.strict {
+        check_schema(&mut raw_metrics, &mut invalid);
+    }
+    let mut rejected = None;
+    if !invalid.is_empty() {
+        let body = rejected_body(raw_metrics.len(), &invalid);
+        state.telemetry.record_invalid(entries - raw_metrics.len());
+        warn!(
+            "Refusing {} of {} metrics, first at {}: {}",
+            entries - raw_metrics.len(),
+            entries,
+            invalid[0].index,
+            invalid[0].reason
+        );
+        if raw_metrics.is_empty() {
+            // Nothing to keep: 422 when every entry parsed but failed --strict
+            let status = if unparsable {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::UNPROCESSABLE_ENTITY
+            };
+            return Err((status, body).into_response());
+        }
+        rejected = Some(body);
+    }
+    let raw_metrics: Vec<CollectdMetric> = raw_metrics.into_iter().map(|(_, metric)| metric).collect();
+    if let Some(response) = check_rate(&state, &client_key(identity.as_ref(), peer), raw_metrics.len()) {
+        return Err(response);
+    }
+
+    // Process each metric
+    match state.pipeline.ingest_from(raw_metrics, &tags, peer.map(|ConnectInfo(addr)| addr.ip())).await {
+        Ok(processed_count) => {
+            debug!("Processed {} metrics", processed_count);
+            match rejected {
+                // Some were kept, and the client learns which weren't
+                Some(body) => Ok((StatusCode::MULTI_STATUS, body).into_response()),
+                None => Ok("OK\n".into_response()),
+            }
+        }
+        Err(QueueError::Full) => {
+            warn!("Processing queue full, rejecting request");
+            Err(StatusCode::SERVICE_UNAVAILABLE.into_response())
+        }
+        Err(QueueError::Closed) => {
+            warn!("Failed to send metric to processing queue");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+// Refused lines listed in a /stream response, the count covers all of them
+const MAX_LISTED_INVALID: usize = 100;
+
+// Newline-delimited JSON over a long-lived request, one metric object per line.
+// Lines are processed as they arrive, the ones that came in together as one
+// batch, and the body is never buffered whole: --max-body-bytes applies per
+// line. Over the rate limit the stream is slowed down rather than refused.
+// The response comes when the client ends the request and says how many
+// lines were accepted and which were refused, like a 207 from the array
+// endpoint. When the queue refuses a batch under reject-503 the stream stops
+// there with a 503, the accepted count tells the client where to resume.
+async fn stream_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<auth::Identity>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    tenant: Option<Path<String>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, Response> {
+    let identity = identity.map(|Extension(identity)| identity);
+    let tags = request_tags(&state, identity.as_ref(), tenant, &headers).map_err(IntoResponse::into_response)?;
+    let client = client_key(identity.as_ref(), peer);
+    let peer = peer.map(|ConnectInfo(addr)| addr.ip());
+    let max_line = state.config.max_body_bytes;
+
+    let mut chunks = body.into_data_stream();
+    let mut buffer = Vec::new();
+    let mut stream = StreamProgress::default();
+    let mut done = false;
+    while !done {
+        match chunks.next().await {
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                debug!("Stream from {} ended early: {}", client, e);
+                return Err(StatusCode::BAD_REQUEST.into_response());
+            }
+            // The last line doesn't need a newline
+            None => {
+                buffer.push(b'\n');
+                done = true;
+            }
+        }
+        let Some(end) = buffer.iter().rposition(|b| *b == b'\n') else {
+            if buffer.len() > max_line {
+                warn!("Ending stream from {}, line over --max-body-bytes", client);
+                return Err(too_large(format!("Line {} is longer than {} bytes", stream.lines, max_line)));
+            }
+            continue;
+        };
+        let lines: Vec<u8> = buffer.drain(..=end).collect();
+        let rejected = stream.rejected;
+        let metrics = stream.parse(&lines, state.config.strict, max_line);
+        state.telemetry.record_invalid(stream.rejected - rejected);
+        if metrics.is_empty() {
+            continue;
+        }
+        if let Some(limiter) = &state.limiter {
+            while let Err(wait) = limiter.check(&client, metrics.len()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        let count = metrics.len();
+        match state.pipeline.ingest_from(metrics, &tags, peer).await {
+            Ok(_) => stream.accepted += count,
+            Err(QueueError::Full) => {
+                warn!("Processing queue full, ending stream from {}", client);
+                return Err((StatusCode::SERVICE_UNAVAILABLE, stream.summary()).into_response());
+            }
+            Err(QueueError::Closed) => {
+                warn!("Failed to send metric to processing queue");
+                return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        }
+    }
+    debug!("Stream from {} ended after {} lines", client, stream.lines);
+    let status = if stream.rejected == 0 {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    Ok((status, stream.summary()).into_response())
+}
+
+#[derive(Default)]
+struct StreamProgress {
+    lines: usize,
+    accepted: usize,
+    rejected: usize,
+    invalid: Vec<schema::Invalid>,
+}
+
+impl StreamProgress {
+    // The metrics on complete lines, each ending in a newline. Blank lines are
+    // skipped but counted.
+    fn parse(&mut self, lines: &[u8], strict: bool, max_line: usize) -> Vec<CollectdMetric> {
+        let mut metrics = Vec::new();
+        let lines = lines.strip_suffix(b"\n").unwrap_or(lines);
+        for line in lines.split(|b| *b == b'\n') {
+            let index = self.lines;
+            self.lines += 1;
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            let problems = if line.len() > max_line {
+                vec![format!("line is longer than {} bytes", max_line)]
+            } else {
+                match serde_json::from_slice::<CollectdMetric>(line) {
+                    Ok(metric) if strict => {
+                        let problems = schema::check(&metric);
+                        if problems.is_empty() {
+                            metrics.push(metric);
+                        }
+                        problems
+                    }
+                    Ok(metric) => {
+                        metrics.push(metric);
+                        Vec::new()
+                    }
+                    Err(e) => vec![e.to_string()],
+                }
+            };
+            if !problems.is_empty() {
+                self.rejected += 1;
+                let room = MAX_LISTED_INVALID.saturating_sub(self.invalid.len());
+                self.invalid
+                    .extend(problems.into_iter().take(room).map(|reason| schema::Invalid { index, reason }));
+            }
+        }
+        metrics
+    }
+
+    fn summary(&self) -> Json<serde_json::Value> {
+        Json(serde_json::json!({
+            "lines": self.lines,
+            "accepted": self.accepted,
+            "rejected": self.rejected,
+            "invalid": self.invalid,
+        }))
+    }
+}
+
+// Metrics pushed over a WebSocket, each frame a metric or an array of them:
+// JSON in text frames, MessagePack in binary ones. Every frame gets a JSON
+// text frame back with its number (from 0) and what became of it, like the
+// body of a 207, or an error when the frame was refused whole. A full queue
+// under reject-503 refuses the frame but keeps the socket open so the client
+// can resend it. --max-body-bytes limits each frame, and over the rate limit
+// frames are acked late rather than refused.
+async fn ws_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<auth::Identity>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    tenant: Option<Path<String>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, Response> {
+    let identity = identity.map(|Extension(identity)| identity);
+    let tags = request_tags(&state, identity.as_ref(), tenant, &headers).map_err(IntoResponse::into_response)?;
+    let client = client_key(identity.as_ref(), peer);
+    let peer = peer.map(|ConnectInfo(addr)| addr.ip());
+    let max_frame = state.config.max_body_bytes;
+    Ok(ws
+        .max_message_size(max_frame)
+        .max_frame_size(max_frame)
+        .on_upgrade(move |socket| ws_session(state, socket, tags, client, peer)))
+}
+
+async fn ws_session(state: AppState, mut socket: WebSocket, tags: Tags, client: String, peer: Option<IpAddr>) {
+    let mut frame = 0;
+    while let Some(message) = socket.recv().await {
+        let parsed = match message {
+            Ok(Message::Text(text)) => parse_metrics(text.as_bytes()).map_err(|e| e.to_string()),
+            Ok(Message::Binary(bytes)) => parse_msgpack(&bytes).map_err(|e| e.to_string()),
+            // Pings and the close handshake are answered on the next read
+            Ok(Message::Ping(_) | Message::Pong(_) | Message::Close(_)) => continue,
+            Err(e) => {
+                debug!("WebSocket from {} ended: {}", client, e);
+                return;
+            }
+        };
+        let ack = ws_frame(&state, frame, parsed, &tags, &client, peer).await;
+        frame += 1;
+        let Some(ack) = ack else {
+            let _ = socket.close().await;
+            return;
+        };
+        if socket.send(Message::Text(ack.to_string())).await.is_err() {
+            return;
+        }
+    }
+    debug!("WebSocket from {} closed after {} frames", client, frame);
+}
+
+// Ingests one frame and returns its ack, None when the queue has closed
+async fn ws_frame(
+    state: &AppState,
+    frame: usize,
+    parsed: Result<Parsed, String>,
+    tags: &Tags,
+    client: &str,
+    peer: Option<IpAddr>,
+) -> Option<serde_json::Value> {
+    let refused = |error: String| serde_json::json!({"frame": frame, "accepted": 0, "error": error});
+    let (mut metrics, mut invalid) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            state.telemetry.record_parse_failure();
+            warn!("Failed to parse WebSocket frame from {}: {}", client, e);
+            return Some(refused(e));
+        }
+    };
+    let entries = metrics.len() + invalid.len();
+    if let Some(max) = state.config.max_metrics_per_request.filter(|max| entries > *max) {
+        warn!("Refusing WebSocket frame with {} metrics, over --max-metrics-per-request", entries);
+        return Some(refused(format!("Frame has {} metrics, more than the limit of {}", entries, max)));
+    }
+    if state.config.strict {
+        check_schema(&mut metrics, &mut invalid);
+    }
+    state.telemetry.record_invalid(entries - metrics.len());
+    let Json(mut ack) = rejected_body(metrics.len(), &invalid);
+    ack["frame"] = frame.into();
+    if metrics.is_empty() {
+        return Some(ack);
+    }
+    if let Some(limiter) = &state.limiter {
+        while let Err(wait) = limiter.check(client, metrics.len()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+    let metrics = metrics.into_iter().map(|(_, metric)| metric).collect();
+    match state.pipeline.ingest_from(metrics, tags, peer).await {
+        Ok(_) => Some(ack),
+        Err(QueueError::Full) => {
+            warn!("Processing queue full, refusing WebSocket frame from {}", client);
+            Some(refused("queue full".to_string()))
+        }
+        Err(QueueError::Closed) => {
+            warn!("Failed to send metric to processing queue");
+            None
+        }
+    }
+}
+
+// OTLP/HTTP metrics export, protobuf or JSON depending on the content type
+async fn otlp_handler(
+    State(state): State<AppState>,
+    identity: Option<Extension<auth::Identity>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    tenant: Option<Path<String>>,
+    headers: HeaderMap,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<impl IntoResponse, Response> {
+    let body = body.map_err(|e| body_rejected(&state, e.status(), e.body_text()))?;
+    let identity = identity.map(|Extension(identity)| identity);
+    let tags = request_tags(&state, identity.as_ref(), tenant, &headers).map_err(IntoResponse::into_response)?;
+    let header_value = |name| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let content_type = header_value(header::CONTENT_TYPE);
+    let is_json = content_type.starts_with("application/json");
+    if !is_json && !content_type.starts_with("application/x-protobuf") {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
+
+    // OTLP exporters commonly gzip their payloads. The body limit holds for the
+    // decompressed size too, or a small gzip bomb could still exhaust memory.
+    let body = if header_value(header::CONTENT_ENCODING) == "gzip" {
+        let max = state.config.max_body_bytes;
+        let mut decoded = Vec::new();
+        if let Err(e) = GzDecoder::new(&body[..]).take(max as u64 + 1).read_to_end(&mut decoded) {
+            state.telemetry.record_parse_failure();
+            warn!("Failed to gunzip OTLP request: {}", e);
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        }
+        if decoded.len() > max {
+            warn!("Rejecting OTLP request over --max-body-bytes once decompressed");
+            return Err(too_large(format!("Decompressed request body is larger than {} bytes", max)));
+        }
+        Bytes::from(decoded)
+    } else {
+        body
+    };
+
+    let request = if is_json { otlp::decode_json(&body) } else { otlp::decode_protobuf(&body) };
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            state.telemetry.record_parse_failure();
+            warn!("Failed to parse OTLP request: {}", e);
+            return Err(StatusCode::BAD_REQUEST.into_response());
+        }
+    };
+
+    let raw_metrics = otlp::to_collectd_metrics(request);
+    debug!("Received {} OTLP data points", raw_metrics.len());
+    if let Some(response) = check_count(&state, raw_metrics.len()) {
+        return Err(response);
+    }
+    if let Some(response) = check_rate(&state, &client_key(identity.as_ref(), peer), raw_metrics.len()) {
+        return Err(response);
+    }
+
+    match state.pipeline.ingest_from(raw_metrics, &tags, peer.map(|ConnectInfo(addr)| addr.ip())).await {
+        // An empty ExportMetricsServiceResponse, which is "{}" in JSON and zero bytes in protobuf
+        Ok(_) if is_json => Ok(([(header::CONTENT_TYPE, "application/json")], Bytes::from_static(b"{}"))),
+        Ok(_) => Ok(([(header::CONTENT_TYPE, "application/x-protobuf")], Bytes::new())),
+        Err(QueueError::Full) => {
+            warn!("Processing queue full, rejecting request");
+            Err(StatusCode::SERVICE_UNAVAILABLE.into_response())
+        }
+        Err(QueueError::Closed) => {
+            warn!("Failed to send metric to processing queue");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+// Same as SIGHUP, but says whether the new config was taken
+async fn reload_handler(State(state): State<AppState>) -> Response {
+    match state.pipeline.reloader().reload() {
+        Ok(()) => "Reloaded\n".into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("Reload failed: {:#}\n", e)).into_response(),
+    }
+}
+
+// Internal telemetry in the Prometheus text format
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.telemetry.render(),
+    )
+}
+
+// Liveness: the process is up and serving HTTP, and no output worker is down
+async fn healthz_handler(State(state): State<AppState>) -> (StatusCode, String) {
+    let failed = state.telemetry.sinks_failed();
+    if failed.is_empty() {
+        (StatusCode::OK, "OK\n".to_string())
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, format!("Outputs failed: {}\n", failed.join(", ")))
+    }
+}
+
+// Every output worker's state, circuit breaker included
+async fn outputs_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "outputs": state.telemetry.workers() }))
+}
+
+// Readiness: every output worker has its file/socket/client open
+async fn readyz_handler(State(state): State<AppState>) -> (StatusCode, String) {
+    let not_ready = state.telemetry.sinks_not_ready();
+    if not_ready.is_empty() {
+        (StatusCode::OK, "OK\n".to_string())
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, format!("Outputs not ready: {}\n", not_ready.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Stages;
+    use crate::queue::{self, OverflowPolicy, QueueReceiver};
+    use crate::reload::Reloader;
+    use crate::typesdb::TypesDb;
+    use clap::Parser;
+    use tower::ServiceExt;
+
+    const VALID: &str = r#"{"values":[1],"dstypes":["gauge"],"dsnames":["value"],"time":1700000000,"interval":10,"host":"web01","plugin":"cpu","type":"percent"}"#;
+    const UNPARSABLE: &str = r#"{"values":"one"}"#;
+    const INCOMPLETE: &str = r#"{"values":[1]}"#;
+
+    // The router with its queue, and no outputs behind it
+    fn start(args: &[&str]) -> (Router, QueueReceiver) {
+        let config = Config::parse_from(std::iter::once("collectd-http-receiver").chain(args.iter().copied()));
+        let telemetry = Arc::new(Telemetry::default());
+        let (sender, receiver) = queue::channel(100, OverflowPolicy::Reject);
+        let reloader = Arc::new(Reloader::new(&config, false, telemetry.clone()).unwrap());
+        let types = Arc::new(TypesDb::load(&[]).unwrap());
+        let pipeline = Pipeline::new(sender, reloader, types, Stages::default(), telemetry.clone());
+        (router(AppState::new(pipeline, &config, telemetry).unwrap()), receiver)
+    }
+
+    async fn post(app: Router, body: String) -> (StatusCode, Bytes) {
+        let request = Request::post("/collectd").body(axum::body::Body::from(body)).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        (status, axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap())
+    }
+
+    #[test]
+    fn parses_around_bad_entries() {
+        let body = format!("[{},{},{}]", VALID, UNPARSABLE, VALID);
+        let (metrics, invalid) = parse_metrics(body.as_bytes()).unwrap();
+        assert_eq!(metrics.iter().map(|(index, _)| *index).collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].index, 1);
+    }
+
+    #[test]
+    fn refuses_what_isnt_a_metric_or_an_array() {
+        assert!(parse_metrics(b"\"metrics\"").is_err());
+        assert!(parse_metrics(b"42").is_err());
+        assert!(parse_metrics(UNPARSABLE.as_bytes()).is_err());
+        assert!(parse_metrics(b"[{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_valid_entries_with_a_207() {
+        let (app, receiver) = start(&[]);
+        let (status, body) = post(app, format!("[{},{},{}]", VALID, UNPARSABLE, VALID)).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["accepted"], 2);
+        assert_eq!(body["rejected"], 1);
+        assert_eq!(body["invalid"][0]["index"], 1);
+        assert_eq!(receiver.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_all_invalid_array() {
+        // Entries failing --strict leave nothing to keep: 422
+        let (app, receiver) = start(&["--strict"]);
+        let (status, body) = post(app, format!("[{},{}]", INCOMPLETE, INCOMPLETE)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["accepted"], 0);
+        assert_eq!(body["rejected"], 2);
+        assert_eq!(receiver.len(), 0);
+
+        // Entries that don't parse at all: 400, with the same body
+        let (app, receiver) = start(&[]);
+        let (status, body) = post(app, format!("[{},{}]", UNPARSABLE, UNPARSABLE)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["rejected"], 2);
+        assert_eq!(receiver.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_body_that_isnt_an_array() {
+        for body in ["\"metrics\"", "42", "not json"] {
+            let (app, receiver) = start(&[]);
+            let (status, _) = post(app, body.to_string()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+            assert_eq!(receiver.len(), 0);
+        }
+    }
+}
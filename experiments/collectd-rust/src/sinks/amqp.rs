@@ -0,0 +1,99 @@
+// AMQP 0-9-1 (RabbitMQ) publisher, one persistent message per metric to
+// --amqp-exchange with a routing key built from --amqp-routing-key, e.g.
+// "{host}.{plugin}". The channel runs in confirm mode and a batch only counts
+// as sent once the broker has acked every message in it.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lapin::{
+    options::{BasicPublishOptions, ConfirmSelectOptions},
+    BasicProperties, Channel, Connection, ConnectionProperties,
+};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::sinks::template::KeyTemplate;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct AmqpSink {
+    url: String,
+    connection: Connection,
+    channel: Channel,
+    exchange: String,
+    routing_key: KeyTemplate,
+    format: OutputFormat,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    config.output_format.parse::<OutputFormat>()?;
+    KeyTemplate::parse(&config.amqp_routing_key).map(|_| ())
+}
+
+impl AmqpSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<AmqpSink> {
+        info!("Starting AMQP publisher, exchange: {}", config.amqp_exchange);
+
+        let (connection, channel) = connect(&config.amqp_url).await?;
+        stats.set_ready(true);
+        Ok(AmqpSink {
+            url: config.amqp_url.clone(),
+            connection,
+            channel,
+            exchange: config.amqp_exchange.clone(),
+            routing_key: KeyTemplate::parse(&config.amqp_routing_key)?,
+            format: config.output_format.parse()?,
+            stats,
+        })
+    }
+}
+
+async fn connect(url: &str) -> Result<(Connection, Channel)> {
+    let connection = Connection::connect(url, ConnectionProperties::default()).await?;
+    let channel = connection.create_channel().await?;
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+    Ok((connection, channel))
+}
+
+#[async_trait]
+impl Sink for AmqpSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        // A closed channel doesn't come back, start over with a new connection
+        if !self.channel.status().connected() {
+            let _ = self.connection.close(0, "reconnecting").await;
+            let (connection, channel) = connect(&self.url).await?;
+            self.connection = connection;
+            self.channel = channel;
+            info!("Reconnected to the AMQP broker");
+        }
+
+        let properties = BasicProperties::default()
+            .with_content_type(self.format.content_type().into())
+            .with_delivery_mode(2);
+        let mut confirms = Vec::with_capacity(batch.len());
+        let mut bytes = 0;
+        for metric in batch {
+            let payload = self.format.encode_for_datagram(metric)?;
+            bytes += payload.len();
+            // Word separators and wildcards in values would change the routing
+            let routing_key = self.routing_key.render(metric, &['.', '*', '#']);
+            let confirm = self
+                .channel
+                .basic_publish(&self.exchange, &routing_key, BasicPublishOptions::default(), &payload, properties.clone())
+                .await?;
+            confirms.push(confirm);
+        }
+        for confirm in confirms {
+            if confirm.await?.is_nack() {
+                return Err(anyhow!("AMQP broker refused a message"));
+            }
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Published batch of {} metrics to AMQP", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+// ClickHouse writer, INSERT ... FORMAT JSONEachRow over the HTTP interface.
+// Each batch is one insert, so --clickhouse-batch-size should be large: ClickHouse
+// much prefers a few big inserts to many small ones.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::retry::Rejected;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub const COLUMNS: [&str; 12] = [
+    "time",
+    "interval",
+    "host",
+    "plugin",
+    "plugin_instance",
+    "type",
+    "type_instance",
+    "dsname",
+    "dstype",
+    "aggregation",
+    "unit",
+    "value",
+];
+
+pub struct ClickHouseSink {
+    client: reqwest::Client,
+    insert_url: reqwest::Url,
+    columns: Vec<String>,
+    user: Option<String>,
+    password: Option<String>,
+    stats: Arc<SinkStats>,
+}
+
+impl ClickHouseSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<ClickHouseSink> {
+        let insert_url = insert_url(config)?;
+        info!("Starting ClickHouse writer, target: {} table {}", config.clickhouse_url, config.clickhouse_table);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        Ok(ClickHouseSink {
+            client,
+            insert_url,
+            columns: config.clickhouse_columns.clone(),
+            user: config.clickhouse_user.clone(),
+            password: config.clickhouse_password.clone(),
+            stats,
+        })
+    }
+}
+
+// The whole statement goes in the query string, the body is just rows
+pub fn insert_url(config: &Config) -> Result<reqwest::Url> {
+    if config.clickhouse_columns.is_empty() {
+        return Err(anyhow!("--clickhouse-columns must name at least one column"));
+    }
+    for column in &config.clickhouse_columns {
+        if !COLUMNS.contains(&column.as_str()) {
+            return Err(anyhow!("Invalid ClickHouse column: {}, expected one of {}", column, COLUMNS.join(", ")));
+        }
+    }
+    let query = format!(
+        "INSERT INTO {} ({}) FORMAT JSONEachRow",
+        config.clickhouse_table,
+        config.clickhouse_columns.join(", ")
+    );
+    Ok(reqwest::Url::parse_with_params(&config.clickhouse_url, &[("query", query)])?)
+}
+
+#[async_trait]
+impl Sink for ClickHouseSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut body = Vec::new();
+        for metric in batch {
+            let row: serde_json::Map<String, serde_json::Value> = self
+                .columns
+                .iter()
+                .map(|column| (column.clone(), column_value(metric, column)))
+                .collect();
+            serde_json::to_writer(&mut body, &row)?;
+            body.push(b'\n');
+        }
+
+        let bytes = body.len();
+        let mut request = self.client.post(self.insert_url.clone()).body(body);
+        if let Some(user) = &self.user {
+            request = request.header("X-ClickHouse-User", user);
+        }
+        if let Some(password) = &self.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = anyhow!("ClickHouse insert failed with {}: {}", status, text.trim());
+            if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(Rejected(error.to_string()).into());
+            }
+            return Err(error);
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Inserted batch of {} metrics into ClickHouse", batch.len());
+        Ok(())
+    }
+}
+
+fn column_value(metric: &ProcessedMetric, column: &str) -> serde_json::Value {
+    let text = |s: Option<&str>| s.map(serde_json::Value::from).unwrap_or_default();
+    let number = |n: Option<f64>| n.map(serde_json::Value::from).unwrap_or_default();
+    match column {
+        "time" => number(metric.time),
+        "interval" => number(metric.interval),
+        "host" => text(metric.host.as_deref()),
+        "plugin" => text(metric.plugin.as_deref()),
+        "plugin_instance" => text(metric.plugin_instance.as_deref()),
+        "type" => text(metric.type_.as_deref()),
+        "type_instance" => text(metric.type_instance.as_deref()),
+        "dsname" => text(metric.dsname.as_deref()),
+        "dstype" => text(metric.dstype.as_deref()),
+        "aggregation" => text(metric.aggregation.as_deref()),
+        "unit" => text(metric.unit.as_deref()),
+        "value" => metric.value.into(),
+        _ => serde_json::Value::Null,
+    }
+}
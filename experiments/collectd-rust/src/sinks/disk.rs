@@ -0,0 +1,337 @@
+// Disk writer
+// I wanna use this for testing and not having to bring over my dirty little listener
+// If --output-file is a named pipe it is written as a stream instead: no
+// append or rotation, and while no reader has it open batches wait for one.
+// --fsync-policy picks when written batches are made durable: never (the
+// kernel writes them back when it likes), every --fsync-interval, or before
+// each batch is acknowledged.
+// A path with placeholders, e.g. out/{date}/{hour}/{host}.ndjson, partitions the
+// output: every metric goes to the file its path renders to, each rotated on its
+// own, and files nothing was written to for PARTITION_IDLE are closed.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::pipe;
+use tracing::{debug, info, warn};
+
+use crate::config::{parse_duration, Config};
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::rotation::{RotatingFile, RotationPolicy};
+use crate::sinks::template::KeyTemplate;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+// How often to look for a reader on a FIFO nobody has open
+const FIFO_POLL: Duration = Duration::from_millis(500);
+// Largest encoding buffer kept between batches
+const MAX_RETAINED_BUFFER: usize = 16 * 1024 * 1024;
+// How long a partition file stays open without writes
+const PARTITION_IDLE: Duration = Duration::from_secs(300);
+
+pub struct DiskSink {
+    output: Output,
+    format: OutputFormat,
+    // Every batch is encoded into this and written with one call
+    buffer: Vec<u8>,
+    fsync: FsyncPolicy,
+    synced_at: Instant,
+    stats: Arc<SinkStats>,
+}
+
+struct Partition {
+    file: RotatingFile,
+    written_at: Instant,
+    // Written since the last fsync
+    unsynced: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FsyncPolicy {
+    Never,
+    Interval(Duration),
+    PerBatch,
+}
+
+fn fsync_policy(config: &Config) -> Result<FsyncPolicy> {
+    match config.fsync_policy.as_str() {
+        "never" => Ok(FsyncPolicy::Never),
+        "interval" => Ok(FsyncPolicy::Interval(parse_duration(&config.fsync_interval)?)),
+        "per-batch" => Ok(FsyncPolicy::PerBatch),
+        other => Err(anyhow!("Invalid fsync policy: {}, expected never, interval or per-batch", other)),
+    }
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    config.output_format.parse::<OutputFormat>()?;
+    fsync_policy(config)?;
+    if let Some(interval) = &config.rotate_interval {
+        parse_duration(interval).map_err(|e| anyhow!("--rotate-interval: {}", e))?;
+    }
+    KeyTemplate::parse_path(&config.output_file)?;
+    Ok(())
+}
+
+enum Output {
+    Files {
+        path: KeyTemplate,
+        policy: RotationPolicy,
+        // Open files by path, just the one when the path has no placeholders
+        files: HashMap<String, Partition>,
+    },
+    Fifo {
+        path: PathBuf,
+        // None while there is no reader
+        sender: Option<pipe::Sender>,
+    },
+}
+
+impl DiskSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<DiskSink> {
+        info!("Starting disk writer, output: {}", config.output_file);
+        let fsync = fsync_policy(config)?;
+
+        let is_fifo = std::fs::metadata(&config.output_file).is_ok_and(|meta| meta.file_type().is_fifo());
+        let output = if is_fifo {
+            if config.rotate_size_mb.is_some() || config.rotate_interval.is_some() {
+                warn!("{} is a named pipe, ignoring the rotation options", config.output_file);
+            }
+            if fsync != FsyncPolicy::Never || config.direct_io || config.atomic_segments {
+                warn!(
+                    "{} is a named pipe, ignoring --fsync-policy, --direct-io and --atomic-segments",
+                    config.output_file
+                );
+            }
+            // Ready once a reader shows up
+            Output::Fifo {
+                path: PathBuf::from(&config.output_file),
+                sender: None,
+            }
+        } else {
+            let policy = RotationPolicy {
+                max_bytes: config.rotate_size_mb.map(|mb| mb * 1024 * 1024),
+                max_age: config.rotate_interval.as_deref().map(parse_duration).transpose()?,
+                keep: config.rotate_keep,
+                compress: config.compress_rotated,
+                sync_on_close: fsync != FsyncPolicy::Never,
+                direct: config.direct_io,
+                atomic: config.atomic_segments,
+            };
+            let path = KeyTemplate::parse_path(&config.output_file)?;
+            let mut files = HashMap::new();
+            if path.is_literal() {
+                let file = RotatingFile::open(&config.output_file, policy.clone()).await?;
+                files.insert(config.output_file.clone(), Partition::new(file));
+            }
+            stats.set_ready(true);
+            Output::Files { path, policy, files }
+        };
+        Ok(DiskSink {
+            output,
+            format: config.output_format.parse()?,
+            buffer: Vec::new(),
+            fsync,
+            synced_at: Instant::now(),
+            stats,
+        })
+    }
+}
+
+impl Partition {
+    fn new(file: RotatingFile) -> Partition {
+        Partition {
+            file,
+            written_at: Instant::now(),
+            unsynced: false,
+        }
+    }
+
+    // Writes the batch, behind a header if the file is new, in one call
+    async fn write(
+        &mut self,
+        batch: &[&ProcessedMetric],
+        format: OutputFormat,
+        buffer: &mut Vec<u8>,
+        fsync: FsyncPolicy,
+    ) -> Result<usize> {
+        self.file.rotate_if_due().await?;
+        buffer.clear();
+        if self.file.is_empty() {
+            if let Some(header) = format.file_header() {
+                buffer.extend_from_slice(header.as_bytes());
+            }
+        }
+        for metric in batch {
+            format.encode_into(buffer, metric)?;
+        }
+        self.file.write_all(buffer).await?;
+        self.file.flush().await?;
+        self.written_at = Instant::now();
+        self.unsynced = true;
+        if fsync == FsyncPolicy::PerBatch {
+            self.file.sync().await?;
+            self.unsynced = false;
+        }
+        Ok(buffer.len())
+    }
+}
+
+// The file for a path, opened (and its directory created) on first use
+async fn partition<'a>(
+    files: &'a mut HashMap<String, Partition>,
+    path: String,
+    policy: &RotationPolicy,
+) -> Result<&'a mut Partition> {
+    if !files.contains_key(&path) {
+        if let Some(dir) = Path::new(&path).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let file = RotatingFile::open(&path, policy.clone()).await?;
+        debug!("Opened partition {}", path);
+        files.insert(path.clone(), Partition::new(file));
+    }
+    Ok(files.get_mut(&path).expect("partition was just opened"))
+}
+
+// Keeps trying until a reader has the pipe open and takes the whole payload.
+// Whatever a reader that went away hadn't read yet is gone with it, so the
+// payload is written again in full to the next one.
+async fn write_fifo(
+    path: &Path,
+    sender: &mut Option<pipe::Sender>,
+    header: Option<String>,
+    batch: &[u8],
+    stats: &SinkStats,
+) -> Result<usize> {
+    loop {
+        let out = match sender {
+            Some(out) => out,
+            None => match pipe::OpenOptions::new().open_sender(path) {
+                Ok(opened) => {
+                    info!("Reader attached to {}", path.display());
+                    stats.set_ready(true);
+                    // Every reader gets its own csv header
+                    let out = sender.insert(opened);
+                    if let Some(header) = &header {
+                        if let Err(e) = out.write_all(header.as_bytes()).await {
+                            debug!("Failed to write the header to {}: {}", path.display(), e);
+                            *sender = None;
+                            continue;
+                        }
+                    }
+                    out
+                }
+                // Nobody has the pipe open for reading
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                    tokio::time::sleep(FIFO_POLL).await;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+        match out.write_all(batch).await {
+            Ok(()) => return Ok(batch.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                warn!("Reader of {} went away, waiting for the next one", path.display());
+                *sender = None;
+                stats.set_ready(false);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for DiskSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let bytes = match &mut self.output {
+            Output::Files { path, policy, files } => {
+                // Split by path, each keeping the batch's order
+                let mut by_path: Vec<(String, Vec<&ProcessedMetric>)> = Vec::new();
+                if path.is_literal() {
+                    if let Some(first) = batch.first() {
+                        by_path.push((path.render_path(first), batch.iter().collect()));
+                    }
+                } else {
+                    let mut index: HashMap<String, usize> = HashMap::new();
+                    for metric in batch {
+                        let rendered = path.render_path(metric);
+                        let i = *index.entry(rendered.clone()).or_insert_with(|| {
+                            by_path.push((rendered, Vec::new()));
+                            by_path.len() - 1
+                        });
+                        by_path[i].1.push(metric);
+                    }
+                }
+                let mut bytes = 0;
+                for (rendered, metrics) in by_path {
+                    let partition = partition(files, rendered, policy).await?;
+                    bytes += partition.write(&metrics, self.format, &mut self.buffer, self.fsync).await?;
+                }
+                bytes
+            }
+            Output::Fifo { path, sender } => {
+                self.buffer.clear();
+                for metric in batch {
+                    self.format.encode_into(&mut self.buffer, metric)?;
+                }
+                write_fifo(path, sender, self.format.file_header(), &self.buffer, &self.stats).await?
+            }
+        };
+        // Don't hold on to what one outsized batch needed
+        if self.buffer.capacity() > MAX_RETAINED_BUFFER {
+            self.buffer = Vec::new();
+        }
+        self.stats.record_batch(bytes);
+        debug!("Wrote batch to disk");
+        Ok(())
+    }
+
+    // Rotates files that aged out while idle, syncs under the interval policy
+    // and closes partitions that went quiet
+    async fn tick(&mut self) -> Result<()> {
+        let Output::Files { path, files, .. } = &mut self.output else {
+            return Ok(());
+        };
+        let sync_due = matches!(self.fsync, FsyncPolicy::Interval(every) if self.synced_at.elapsed() >= every);
+        for partition in files.values_mut() {
+            if sync_due && partition.unsynced {
+                partition.file.sync().await?;
+                partition.unsynced = false;
+            }
+            partition.file.rotate_if_due().await?;
+        }
+        if sync_due {
+            self.synced_at = Instant::now();
+        }
+        if !path.is_literal() {
+            let idle: Vec<String> = files
+                .iter()
+                .filter(|(_, partition)| partition.written_at.elapsed() >= PARTITION_IDLE)
+                .map(|(rendered, _)| rendered.clone())
+                .collect();
+            for rendered in idle {
+                if let Some(partition) = files.remove(&rendered) {
+                    partition.file.close().await?;
+                    debug!("Closed idle partition {}", rendered);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Gives --atomic-segments files their final names
+    async fn close(&mut self) -> Result<()> {
+        if let Output::Files { files, .. } = &mut self.output {
+            for (_, partition) in files.drain() {
+                partition.file.close().await?;
+            }
+        }
+        Ok(())
+    }
+}
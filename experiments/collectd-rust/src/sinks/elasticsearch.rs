@@ -0,0 +1,199 @@
+// Elasticsearch / OpenSearch writer, batches go to the _bulk API into a
+// date-based index. The bulk API answers per document, so only the documents
+// that failed with a retryable status are resent; mapping errors and the like
+// are logged and counted as failed. Document ids are derived from the series
+// and timestamp, which makes resending a whole batch harmless.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, SecondsFormat, Utc,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::retry::{Backoff, Rejected};
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct ElasticsearchSink {
+    client: reqwest::Client,
+    bulk_url: String,
+    index: String,
+    user: Option<String>,
+    password: Option<String>,
+    api_key: Option<String>,
+    retry_max: u32,
+    retry_initial: Duration,
+    retry_max_backoff: Duration,
+    stats: Arc<SinkStats>,
+}
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    items: Vec<std::collections::HashMap<String, BulkItem>>,
+}
+
+#[derive(Deserialize)]
+struct BulkItem {
+    status: u16,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    reqwest::Url::parse(&config.elasticsearch_url)?;
+    if StrftimeItems::new(&config.elasticsearch_index).any(|item| item == Item::Error) {
+        return Err(anyhow!("Invalid Elasticsearch index pattern: {}", config.elasticsearch_index));
+    }
+    Ok(())
+}
+
+impl ElasticsearchSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<ElasticsearchSink> {
+        validate(config)?;
+        info!("Starting Elasticsearch writer, target: {}", config.elasticsearch_url);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        Ok(ElasticsearchSink {
+            client,
+            bulk_url: format!("{}/_bulk", config.elasticsearch_url.trim_end_matches('/')),
+            index: config.elasticsearch_index.clone(),
+            user: config.elasticsearch_user.clone(),
+            password: config.elasticsearch_password.clone(),
+            api_key: config.elasticsearch_api_key.clone(),
+            retry_max: config.retry_max,
+            retry_initial: Duration::from_millis(config.retry_initial_backoff_ms),
+            retry_max_backoff: Duration::from_millis(config.retry_max_backoff_ms),
+            stats,
+        })
+    }
+
+    // One action line and one source line per document
+    fn encode(&self, metric: &ProcessedMetric) -> Result<Vec<u8>> {
+        let time = metric
+            .time
+            .and_then(|t| DateTime::<Utc>::from_timestamp_millis((t * 1000.0) as i64))
+            .unwrap_or_else(Utc::now);
+        let action = serde_json::json!({
+            "index": { "_index": time.format(&self.index).to_string(), "_id": document_id(metric, time) }
+        });
+        let mut source = serde_json::to_value(metric)?;
+        if let Some(fields) = source.as_object_mut() {
+            fields.insert(
+                "@timestamp".to_string(),
+                time.to_rfc3339_opts(SecondsFormat::Millis, true).into(),
+            );
+        }
+
+        let mut out = serde_json::to_vec(&action)?;
+        out.push(b'\n');
+        serde_json::to_writer(&mut out, &source)?;
+        out.push(b'\n');
+        Ok(out)
+    }
+
+    // Post the documents and return the ones worth sending again
+    async fn bulk(&self, documents: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let body = documents.concat();
+        let bytes = body.len();
+        let mut request = self
+            .client
+            .post(&self.bulk_url)
+            .header("Content-Type", "application/x-ndjson")
+            .body(body);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("Authorization", format!("ApiKey {}", api_key));
+        } else if let Some(user) = &self.user {
+            request = request.basic_auth(user, self.password.as_ref());
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = anyhow!("Elasticsearch bulk request failed with {}: {}", status, text);
+            if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(Rejected(error.to_string()).into());
+            }
+            return Err(error);
+        }
+        self.stats.record_batch(bytes);
+
+        let response: BulkResponse = serde_json::from_slice(&response.bytes().await?)?;
+        if !response.errors {
+            return Ok(Vec::new());
+        }
+        let mut retry = Vec::new();
+        let mut rejected = 0;
+        for (document, item) in documents.into_iter().zip(response.items) {
+            let Some(item) = item.into_values().next() else {
+                continue;
+            };
+            match item.status {
+                200..=299 => {}
+                429 | 500..=599 => retry.push(document),
+                status => {
+                    rejected += 1;
+                    debug!("Elasticsearch rejected a document with {}: {:?}", status, item.error);
+                }
+            }
+        }
+        if rejected > 0 {
+            self.stats.record_failed(rejected);
+            warn!("Elasticsearch rejected {} documents, dropping them", rejected);
+        }
+        Ok(retry)
+    }
+}
+
+#[async_trait]
+impl Sink for ElasticsearchSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut documents = batch.iter().map(|metric| self.encode(metric)).collect::<Result<Vec<_>>>()?;
+
+        let mut backoff = Backoff::new(self.retry_initial, self.retry_max_backoff);
+        let mut attempt = 0;
+        loop {
+            documents = self.bulk(documents).await?;
+            if documents.is_empty() {
+                break;
+            }
+            if self.retry_max > 0 && attempt >= self.retry_max {
+                return Err(anyhow!("{} documents still failing after {} bulk retries", documents.len(), attempt));
+            }
+            attempt += 1;
+            let delay = backoff.next_delay();
+            warn!("{} documents failed, resending them in {:?}", documents.len(), delay);
+            tokio::time::sleep(delay).await;
+        }
+
+        debug!("Indexed batch of {} metrics into Elasticsearch", batch.len());
+        Ok(())
+    }
+}
+
+// Same series and timestamp, same id
+fn document_id(metric: &ProcessedMetric, time: DateTime<Utc>) -> String {
+    let mut hasher = Sha256::new();
+    for field in [
+        metric.host.as_deref(),
+        metric.plugin.as_deref(),
+        metric.plugin_instance.as_deref(),
+        metric.type_.as_deref(),
+        metric.type_instance.as_deref(),
+        metric.dsname.as_deref(),
+        metric.aggregation.as_deref(),
+    ] {
+        hasher.update(field.unwrap_or_default());
+        hasher.update([0]);
+    }
+    hasher.update(time.timestamp_millis().to_be_bytes());
+    hasher.finalize()[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
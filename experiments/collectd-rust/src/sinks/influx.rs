@@ -0,0 +1,198 @@
+// InfluxDB writer, line protocol over the v1 or v2 HTTP write API
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::retry::Rejected;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct InfluxSink {
+    client: reqwest::Client,
+    write_url: reqwest::Url,
+    token: Option<String>,
+    stats: Arc<SinkStats>,
+}
+
+impl InfluxSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<InfluxSink> {
+        let write_url = write_url(config)?;
+        info!("Starting InfluxDB writer, target: {}", write_url);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        Ok(InfluxSink {
+            client,
+            write_url,
+            token: config.influx_token.clone(),
+            stats,
+        })
+    }
+}
+
+pub fn write_url(config: &Config) -> Result<reqwest::Url> {
+    let base = config.influx_url.trim_end_matches('/');
+    let url = match config.influx_version.as_str() {
+        "1" => reqwest::Url::parse_with_params(
+            &format!("{}/write", base),
+            &[("db", config.influx_bucket.as_str()), ("precision", "ns")],
+        )?,
+        "2" => reqwest::Url::parse_with_params(
+            &format!("{}/api/v2/write", base),
+            &[
+                ("org", config.influx_org.as_str()),
+                ("bucket", config.influx_bucket.as_str()),
+                ("precision", "ns"),
+            ],
+        )?,
+        other => return Err(anyhow!("Invalid InfluxDB version: {}", other)),
+    };
+    Ok(url)
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut body = String::new();
+        for metric in batch {
+            body.push_str(&to_line_protocol(metric));
+            body.push('\n');
+        }
+
+        let bytes = body.len();
+        if !body.is_empty() {
+            let mut request = self.client.post(self.write_url.clone()).body(body);
+            if let Some(token) = &self.token {
+                request = request.header("Authorization", format!("Token {}", token));
+            }
+            let response = request.send().await?;
+            if !response.status().is_success() {
+                let status = response.status();
+                let text = response.text().await.unwrap_or_default();
+                let error = anyhow!("InfluxDB write failed with {}: {}", status, text);
+                if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    return Err(Rejected(error.to_string()).into());
+                }
+                return Err(error);
+            }
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Wrote batch of {} metrics to InfluxDB", batch.len());
+        Ok(())
+    }
+}
+
+// Same layout as InfluxDB's own collectd input: measurement "<plugin>_<dsname>"
+// ("<plugin>_value" for unnamed values), tagged with host, instance, type and
+// type_instance, plus aggregation for rollups and unit for converted values
+pub fn to_line_protocol(metric: &ProcessedMetric) -> String {
+    let value = metric.value.as_f64();
+
+    let plugin = metric.plugin.as_deref().unwrap_or("unknown");
+    let dsname = metric.dsname.as_deref().unwrap_or("value");
+    let mut line = escape_measurement(&format!("{}_{}", plugin, dsname));
+
+    let tags = [
+        ("host", metric.host.as_deref()),
+        ("instance", metric.plugin_instance.as_deref()),
+        ("type", metric.type_.as_deref()),
+        ("type_instance", metric.type_instance.as_deref()),
+        ("aggregation", metric.aggregation.as_deref()),
+        ("unit", metric.unit.as_deref()),
+    ];
+    for (key, tag_value) in tags {
+        if let Some(tag_value) = tag_value.filter(|v| !v.is_empty()) {
+            line.push(',');
+            line.push_str(key);
+            line.push('=');
+            line.push_str(&escape_tag(tag_value));
+        }
+    }
+    // An empty tag value isn't valid line protocol
+    for (key, tag_value) in metric.tags.iter().filter(|(_, v)| !v.is_empty()) {
+        line.push_str(&format!(",{}={}", escape_tag(key), escape_tag(tag_value)));
+    }
+
+    line.push_str(&format!(" value={}", value));
+    if let Some(time) = metric.time {
+        line.push_str(&format!(" {}", (time * 1e9) as i64));
+    }
+    line
+}
+
+// Line protocol has no way to carry a line break, so one is written as a
+// literal "\n" rather than letting a field start a new line
+fn escape_measurement(s: &str) -> String {
+    escape(s, &[',', ' '])
+}
+
+fn escape_tag(s: &str) -> String {
+    escape(s, &[',', '=', ' '])
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c if special.contains(&c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::process_metric;
+
+    fn metric(host: &str, plugin: &str) -> ProcessedMetric {
+        let raw = serde_json::json!({
+            "values": [1.5], "dstypes": ["gauge"], "dsnames": ["value"], "time": 1700000000,
+            "host": host, "plugin": plugin, "type": "gauge",
+        });
+        process_metric(serde_json::from_value(raw).unwrap()).remove(0)
+    }
+
+    #[test]
+    fn writes_one_line() {
+        assert_eq!(
+            to_line_protocol(&metric("web01", "cpu")),
+            "cpu_value,host=web01,type=gauge value=1.5 1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn escapes_special_characters() {
+        let line = to_line_protocol(&metric("a b,c=d\\", "my plugin,x"));
+        assert_eq!(line, "my\\ plugin\\,x_value,host=a\\ b\\,c\\=d\\\\,type=gauge value=1.5 1700000000000000000");
+    }
+
+    #[test]
+    fn skips_empty_tags() {
+        let mut metric = metric("web01", "cpu");
+        metric.tags.insert("dc".into(), "".into());
+        metric.tags.insert("team name".into(), "ops".into());
+        assert_eq!(
+            to_line_protocol(&metric),
+            "cpu_value,host=web01,type=gauge,team\\ name=ops value=1.5 1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn line_breaks_cant_start_a_new_line() {
+        let line = to_line_protocol(&metric("web01\ninjected,host=x value=666", "cpu\r\n"));
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.starts_with("cpu\\r\\n_value,host=web01\\ninjected\\,host\\=x\\ value\\=666,"));
+    }
+}
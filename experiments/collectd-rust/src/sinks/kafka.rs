@@ -0,0 +1,92 @@
+// Kafka producer
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rdkafka::{
+    config::ClientConfig,
+    producer::{FutureProducer, FutureRecord},
+};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+    partition_key: String,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    if !matches!(config.kafka_partition_key.as_str(), "host" | "plugin" | "type" | "none") {
+        return Err(anyhow!("Invalid Kafka partition key: {}", config.kafka_partition_key));
+    }
+    if !matches!(config.kafka_acks.as_str(), "0" | "1" | "-1" | "all") {
+        return Err(anyhow!("Invalid --kafka-acks {:?}, expected 0, 1, -1 or all", config.kafka_acks));
+    }
+    Ok(())
+}
+
+impl KafkaSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<KafkaSink> {
+        info!("Starting Kafka producer, brokers: {}, topic: {}", config.kafka_brokers, config.kafka_topic);
+
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &config.kafka_brokers)
+            .set("acks", &config.kafka_acks)
+            .create()?;
+        stats.set_ready(true);
+        Ok(KafkaSink {
+            producer,
+            topic: config.kafka_topic.clone(),
+            partition_key: config.kafka_partition_key.clone(),
+            stats,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for KafkaSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        // Enqueue the whole batch first so librdkafka can pack it, then wait for the acks
+        let mut deliveries = Vec::with_capacity(batch.len());
+        let mut bytes = 0;
+        for metric in batch {
+            let payload = serde_json::to_vec(metric)?;
+            bytes += payload.len();
+            let mut record = FutureRecord::<str, [u8]>::to(&self.topic).payload(&payload);
+            if let Some(key) = partition_key(metric, &self.partition_key) {
+                record = record.key(key);
+            }
+            let delivery = self
+                .producer
+                .send_result(record)
+                .map_err(|(e, _)| anyhow!("Failed to enqueue Kafka record: {}", e))?;
+            deliveries.push(delivery);
+        }
+
+        for delivery in deliveries {
+            match delivery.await {
+                Ok(Ok(_)) => {}
+                Ok(Err((e, _))) => return Err(anyhow!("Kafka delivery failed: {}", e)),
+                Err(_) => return Err(anyhow!("Kafka delivery cancelled")),
+            }
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Published batch of {} metrics to Kafka", batch.len());
+        Ok(())
+    }
+}
+
+fn partition_key<'a>(metric: &'a ProcessedMetric, key: &str) -> Option<&'a str> {
+    match key {
+        "host" => metric.host.as_deref(),
+        "plugin" => metric.plugin.as_deref(),
+        "type" => metric.type_.as_deref(),
+        _ => None,
+    }
+}
@@ -0,0 +1,230 @@
+// Kinesis Data Streams / Firehose writer. Encoded metrics are packed into
+// records of up to 1MB (Firehose concatenates them, so line formats come out
+// as one metric per line), and records into PutRecords / PutRecordBatch calls
+// of at most 500 records. Data stream records are keyed by host, so a host's
+// metrics stay in order on one shard.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::retry::{Backoff, Rejected};
+use crate::sigv4::{Credentials, Signer};
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+const MAX_RECORDS: usize = 500;
+// Firehose takes 1000KiB per record and 4MiB per call, Kinesis 1MiB (data
+// plus partition key) and 5MiB; stick to the smaller of each
+const MAX_RECORD_BYTES: usize = 1000 * 1024;
+const MAX_REQUEST_BYTES: usize = 4 * 1024 * 1024;
+
+pub struct KinesisSink {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    signer: Signer,
+    stream: String,
+    firehose: bool,
+    format: OutputFormat,
+    retry_max: u32,
+    retry_initial: Duration,
+    retry_max_backoff: Duration,
+    stats: Arc<SinkStats>,
+}
+
+struct Record {
+    data: Vec<u8>,
+    partition_key: String,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    config.output_format.parse::<OutputFormat>()?;
+    if config.kinesis_stream.is_empty() {
+        return Err(anyhow!("--kinesis-stream must not be empty"));
+    }
+    if let Some(endpoint) = &config.kinesis_endpoint {
+        reqwest::Url::parse(endpoint)?;
+    }
+    Credentials::from_env("kinesis").map(|_| ())
+}
+
+impl KinesisSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<KinesisSink> {
+        let service = if config.kinesis_firehose { "firehose" } else { "kinesis" };
+        let url = match &config.kinesis_endpoint {
+            Some(endpoint) => reqwest::Url::parse(endpoint)?,
+            None => reqwest::Url::parse(&format!("https://{}.{}.amazonaws.com/", service, config.kinesis_region))?,
+        };
+        info!("Starting {} writer, stream: {}, endpoint: {}", service, config.kinesis_stream, url);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        Ok(KinesisSink {
+            client,
+            url,
+            signer: Signer {
+                credentials: Credentials::from_env("kinesis")?,
+                region: config.kinesis_region.clone(),
+                service: service.to_string(),
+            },
+            stream: config.kinesis_stream.clone(),
+            firehose: config.kinesis_firehose,
+            format: config.output_format.parse()?,
+            retry_max: config.retry_max,
+            retry_initial: Duration::from_millis(config.retry_initial_backoff_ms),
+            retry_max_backoff: Duration::from_millis(config.retry_max_backoff_ms),
+            stats,
+        })
+    }
+
+    // Pack the batch into records, per host for data streams
+    fn records(&self, batch: &[ProcessedMetric]) -> Result<Vec<Record>> {
+        let mut groups: BTreeMap<&str, Vec<&ProcessedMetric>> = BTreeMap::new();
+        for metric in batch {
+            let key = if self.firehose {
+                ""
+            } else {
+                metric.host.as_deref().filter(|h| !h.is_empty()).unwrap_or("unknown")
+            };
+            groups.entry(key).or_default().push(metric);
+        }
+
+        let mut records = Vec::new();
+        for (key, metrics) in groups {
+            let mut data = Vec::new();
+            for metric in metrics {
+                let encoded = self.format.encode(metric)?;
+                if encoded.len() + key.len() > MAX_RECORD_BYTES {
+                    return Err(Rejected(format!("Metric too large for a Kinesis record: {} bytes", encoded.len())).into());
+                }
+                if data.len() + encoded.len() + key.len() > MAX_RECORD_BYTES {
+                    records.push(Record {
+                        data: std::mem::take(&mut data),
+                        partition_key: key.to_string(),
+                    });
+                }
+                data.extend_from_slice(&encoded);
+            }
+            if !data.is_empty() {
+                records.push(Record {
+                    data,
+                    partition_key: key.to_string(),
+                });
+            }
+        }
+        Ok(records)
+    }
+
+    // One PutRecords / PutRecordBatch call, returning the records that failed
+    async fn put(&self, records: Vec<Record>) -> Result<Vec<Record>> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let (target, body) = if self.firehose {
+            let entries: Vec<_> = records
+                .iter()
+                .map(|record| serde_json::json!({ "Data": engine.encode(&record.data) }))
+                .collect();
+            (
+                "Firehose_20150804.PutRecordBatch",
+                serde_json::json!({ "DeliveryStreamName": self.stream, "Records": entries }),
+            )
+        } else {
+            let entries: Vec<_> = records
+                .iter()
+                .map(|record| serde_json::json!({ "Data": engine.encode(&record.data), "PartitionKey": record.partition_key }))
+                .collect();
+            (
+                "Kinesis_20131202.PutRecords",
+                serde_json::json!({ "StreamName": self.stream, "Records": entries }),
+            )
+        };
+        let body = serde_json::to_vec(&body)?;
+
+        let headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("x-amz-target", target.to_string()),
+        ];
+        let mut request = self.client.post(self.url.clone());
+        for (name, value) in self.signer.sign("POST", &self.url, headers, &body) {
+            request = request.header(name, value);
+        }
+        let bytes = body.len();
+        let response = request.body(body).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = anyhow!("{} failed with {}: {}", target, status, text);
+            // Throttling comes back as a 400 too
+            if status.is_client_error() && !text.contains("Throughput") && !text.contains("Throttling") {
+                return Err(Rejected(error.to_string()).into());
+            }
+            return Err(error);
+        }
+
+        // Entries line up with the records sent, failed ones carry an ErrorCode
+        let response: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+        let entries = if self.firehose {
+            &response["RequestResponses"]
+        } else {
+            &response["Records"]
+        };
+        let entries = entries.as_array().cloned().unwrap_or_default();
+        let mut failed = Vec::new();
+        for (record, entry) in records.into_iter().zip(entries) {
+            if let Some(code) = entry["ErrorCode"].as_str() {
+                debug!("Record failed: {} {}", code, entry["ErrorMessage"].as_str().unwrap_or_default());
+                failed.push(record);
+            }
+        }
+        self.stats.record_batch(bytes);
+        Ok(failed)
+    }
+}
+
+#[async_trait]
+impl Sink for KinesisSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut pending = self.records(batch)?;
+
+        let mut backoff = Backoff::new(self.retry_initial, self.retry_max_backoff);
+        let mut attempt = 0;
+        while !pending.is_empty() {
+            let mut failed = Vec::new();
+            while !pending.is_empty() {
+                // Up to MAX_RECORDS and MAX_REQUEST_BYTES per call
+                let mut count = 0;
+                let mut size = 0;
+                for record in pending.iter().take(MAX_RECORDS) {
+                    size += record.data.len() + record.partition_key.len();
+                    if count > 0 && size > MAX_REQUEST_BYTES {
+                        break;
+                    }
+                    count += 1;
+                }
+                let rest = pending.split_off(count);
+                failed.extend(self.put(pending).await?);
+                pending = rest;
+            }
+            if failed.is_empty() {
+                break;
+            }
+            if self.retry_max > 0 && attempt >= self.retry_max {
+                return Err(anyhow!("{} records still failing after {} retries", failed.len(), attempt));
+            }
+            self.stats.record_error();
+            attempt += 1;
+            let delay = backoff.next_delay();
+            warn!("{} records failed, resending them in {:?}", failed.len(), delay);
+            tokio::time::sleep(delay).await;
+            pending = failed;
+        }
+
+        debug!("Put batch of {} metrics to {}", batch.len(), self.stream);
+        Ok(())
+    }
+}
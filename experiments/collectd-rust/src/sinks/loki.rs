@@ -0,0 +1,119 @@
+// Loki push writer, for looking at raw points in Grafana without a TSDB. Every
+// metric becomes a JSON log line in a stream labelled with --loki-labels
+// (host and plugin by default); keep that set small, every combination is a
+// separate stream in Loki.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::retry::Rejected;
+use crate::sinks::template::{field, FIELDS};
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct LokiSink {
+    client: reqwest::Client,
+    push_url: String,
+    labels: Vec<String>,
+    tenant: Option<String>,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    reqwest::Url::parse(&config.loki_url)?;
+    for label in &config.loki_labels {
+        if !FIELDS.contains(&label.as_str()) {
+            return Err(anyhow!("Invalid Loki label: {}, expected one of {}", label, FIELDS.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+impl LokiSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<LokiSink> {
+        validate(config)?;
+        let push_url = format!("{}/loki/api/v1/push", config.loki_url.trim_end_matches('/'));
+        info!("Starting Loki writer, target: {}", push_url);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        Ok(LokiSink {
+            client,
+            push_url,
+            labels: config.loki_labels.clone(),
+            tenant: config.loki_tenant.clone(),
+            stats,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for LokiSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        // Group into streams by label set, entries as [nanosecond timestamp, line]
+        let mut streams: BTreeMap<BTreeMap<&str, &str>, Vec<(i64, String)>> = BTreeMap::new();
+        for metric in batch {
+            let labels = self
+                .labels
+                .iter()
+                .filter_map(|name| Some((name.as_str(), field(metric, name).filter(|v| !v.is_empty())?)))
+                .collect();
+            let time = match metric.time {
+                Some(time) => (time * 1e9) as i64,
+                None => SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as i64)
+                    .unwrap_or_default(),
+            };
+            streams.entry(labels).or_default().push((time, serde_json::to_string(metric)?));
+        }
+
+        let streams: Vec<_> = streams
+            .into_iter()
+            .map(|(labels, mut entries)| {
+                entries.sort_by_key(|(time, _)| *time);
+                // Loki wants at least one label per stream
+                let labels = if labels.is_empty() {
+                    serde_json::json!({ "job": "collectd" })
+                } else {
+                    serde_json::json!(labels)
+                };
+                let values: Vec<_> = entries
+                    .into_iter()
+                    .map(|(time, line)| serde_json::json!([time.to_string(), line]))
+                    .collect();
+                serde_json::json!({ "stream": labels, "values": values })
+            })
+            .collect();
+        let body = serde_json::to_vec(&serde_json::json!({ "streams": streams }))?;
+
+        let bytes = body.len();
+        let mut request = self
+            .client
+            .post(&self.push_url)
+            .header("Content-Type", "application/json")
+            .body(body);
+        if let Some(tenant) = &self.tenant {
+            request = request.header("X-Scope-OrgID", tenant);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = anyhow!("Loki push failed with {}: {}", status, text);
+            if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(Rejected(error.to_string()).into());
+            }
+            return Err(error);
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Pushed batch of {} metrics to Loki", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,799 @@
+// Output side of the receiver. Every output implements Sink; run_sink owns the
+// shared batching loop (flush on batch size or flush interval) so a sink only
+// has to know how to write one batch.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::{path::Path, sync::Arc, time::Duration};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::watch,
+    task::JoinHandle,
+    time::{interval, timeout, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::config::{parse_duration, Config};
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::queue::{QueueError, QueueReceiver, QueueSender};
+use crate::retry::{Backoff, Circuit, CircuitBreaker, Rejected};
+use crate::routing::Routes;
+use crate::telemetry::{SinkStats, Telemetry};
+use crate::wal::Wal;
+
+pub mod amqp;
+pub mod clickhouse;
+pub mod datadog;
+pub mod disk;
+pub mod elasticsearch;
+pub mod influx;
+pub mod kafka;
+pub mod kinesis;
+pub mod loki;
+pub mod mqtt;
+pub mod nats;
+pub mod otlp;
+pub mod plugin;
+pub mod postgres;
+pub mod prometheus;
+pub mod pubsub;
+pub mod s3;
+pub mod stdio;
+pub mod syslog;
+pub mod tcp;
+mod template;
+pub mod tenant;
+pub mod udp;
+pub mod unix;
+pub mod victoriametrics;
+pub mod webhook;
+
+#[async_trait]
+pub trait Sink: Send {
+    // Write one batch. The caller only clears the batch once this returns Ok.
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()>;
+
+    // Called on every flush tick, for housekeeping such as file rotation
+    async fn tick(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Called once the queue has closed, for sinks that hold on to data between batches
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Check the settings for an output mode without opening anything, so bad
+// flags fail at startup instead of inside a worker
+pub fn validate(mode: &str, config: &Config) -> Result<()> {
+    let per_tenant;
+    let config = if crate::tenant::is_templated(mode, config) {
+        per_tenant = crate::tenant::config_for(config, crate::tenant::DEFAULT)?;
+        &per_tenant
+    } else {
+        config
+    };
+    match mode {
+        "disk" => disk::validate(config),
+        "udp" | "nats" | "stdout" | "stderr" => config.output_format.parse::<OutputFormat>().map(|_| ()),
+        "tcp" | "prometheus" | "otlp" => Ok(()),
+        "kafka" => kafka::validate(config),
+        "mqtt" => mqtt::validate(config),
+        "amqp" => amqp::validate(config),
+        "webhook" => webhook::validate(config),
+        "victoriametrics" => victoriametrics::validate(config),
+        "loki" => loki::validate(config),
+        "datadog" => datadog::validate(config),
+        "kinesis" => kinesis::validate(config),
+        "pubsub" => pubsub::validate(config),
+        "syslog" => syslog::validate(config),
+        "unix" => unix::validate(config),
+        "influx" => influx::write_url(config).map(|_| ()),
+        "s3" => s3::validate(config),
+        "clickhouse" => clickhouse::insert_url(config).map(|_| ()),
+        "postgres" => postgres::validate(config),
+        "plugin" => plugin::validate(config),
+        "elasticsearch" => elasticsearch::validate(config),
+        _ => Err(anyhow!("Invalid output mode: {}", mode)),
+    }
+}
+
+pub async fn open(mode: &str, config: &Config, stats: Arc<SinkStats>) -> Result<Box<dyn Sink>> {
+    if crate::tenant::is_templated(mode, config) {
+        return Ok(Box::new(tenant::TenantSink::open(mode, config, stats)));
+    }
+    open_single(mode, config, stats).await
+}
+
+// One instance of the output, with the settings as given
+pub async fn open_single(mode: &str, config: &Config, stats: Arc<SinkStats>) -> Result<Box<dyn Sink>> {
+    let sink: Box<dyn Sink> = match mode {
+        "disk" => Box::new(disk::DiskSink::open(config, stats).await?),
+        "udp" => Box::new(udp::UdpSink::open(config, stats).await?),
+        "tcp" => Box::new(tcp::TcpSink::open(config, stats).await?),
+        "kafka" => Box::new(kafka::KafkaSink::open(config, stats)?),
+        "influx" => Box::new(influx::InfluxSink::open(config, stats)?),
+        "prometheus" => Box::new(prometheus::RemoteWriteSink::open(config, stats)),
+        "otlp" => Box::new(otlp::OtlpSink::open(config, stats)),
+        "s3" => Box::new(s3::S3Sink::open(config, stats)?),
+        "clickhouse" => Box::new(clickhouse::ClickHouseSink::open(config, stats)?),
+        "postgres" => Box::new(postgres::PostgresSink::open(config, stats).await?),
+        "elasticsearch" => Box::new(elasticsearch::ElasticsearchSink::open(config, stats)?),
+        "nats" => Box::new(nats::NatsSink::open(config, stats).await?),
+        "mqtt" => Box::new(mqtt::MqttSink::open(config, stats).await?),
+        "amqp" => Box::new(amqp::AmqpSink::open(config, stats).await?),
+        "webhook" => Box::new(webhook::WebhookSink::open(config, stats)?),
+        "victoriametrics" => Box::new(victoriametrics::VictoriaMetricsSink::open(config, stats)?),
+        "loki" => Box::new(loki::LokiSink::open(config, stats)?),
+        "datadog" => Box::new(datadog::DatadogSink::open(config, stats)?),
+        "kinesis" => Box::new(kinesis::KinesisSink::open(config, stats)?),
+        "pubsub" => Box::new(pubsub::PubSubSink::open(config, stats)?),
+        "syslog" => Box::new(syslog::SyslogSink::open(config, stats).await?),
+        "stdout" | "stderr" => Box::new(stdio::StdioSink::open(mode, config, stats)?),
+        "unix" => Box::new(unix::UnixSink::open(config, stats).await?),
+        "plugin" => Box::new(plugin::PluginSink::open(config, stats).await?),
+        _ => return Err(anyhow!("Invalid output mode: {}", mode)),
+    };
+    Ok(sink)
+}
+
+// Writer workers for the output. Outputs that write one local file or stream,
+// or name their objects by time, can't have several workers writing at once.
+pub fn workers(mode: &str, config: &Config) -> usize {
+    match mode {
+        "disk" | "stdout" | "stderr" | "s3" => 1,
+        _ => config.sink_workers,
+    }
+}
+
+// The output's name in logs and for its WAL and dead-letter files: the mode for
+// the first worker, so a single worker keeps the paths it always had
+fn worker_name(mode: &str, worker: usize) -> String {
+    match worker {
+        0 => mode.to_string(),
+        n => format!("{}.{}", mode, n),
+    }
+}
+
+// The worker of an output that a metric goes to. Hashing the host keeps each
+// host's metrics on one worker, and so in order.
+fn shard(metric: &ProcessedMetric, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    metric.host.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+// Start the supervised worker for one output mode. A worker that errors out or
+// panics is restarted with backoff on the same queue, so nothing queued is lost
+// and ingest never sees a closed queue. While it is down the output is neither
+// ready nor healthy. With --exit-on-sink-failure the failure is reported to
+// run() instead, which shuts the receiver down. The worker reopens its sink
+// whenever the settings in the watch channel change.
+pub fn spawn_sink(
+    mode: &str,
+    worker: usize,
+    rx: QueueReceiver,
+    settings: watch::Receiver<Arc<Config>>,
+    stats: Arc<SinkStats>,
+    telemetry: Arc<Telemetry>,
+) -> Result<JoinHandle<()>> {
+    validate(mode, &settings.borrow())?;
+
+    let mode = mode.to_string();
+    let name = worker_name(&mode, worker);
+    // Each attempt runs in its own task so a panic only takes that task down,
+    // the tokio mutex isn't poisoned and the queue, along with the batch that
+    // was being sent, survives for the next attempt
+    let inbox = Arc::new(tokio::sync::Mutex::new(Inbox {
+        receiver: rx,
+        pending: Vec::new(),
+        breaker: circuit_breaker(&settings.borrow())?,
+    }));
+    Ok(tokio::spawn(async move {
+        let mut backoff = retry_backoff(&settings.borrow());
+        loop {
+            let started = Instant::now();
+            let attempt = tokio::spawn(run_worker(
+                mode.clone(),
+                worker,
+                inbox.clone(),
+                settings.clone(),
+                stats.clone(),
+            ));
+            let result = attempt.await;
+            stats.set_ready(false);
+            let error = match result {
+                // The queue closed, a normal shutdown
+                Ok(Ok(())) => break,
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => format!("worker panicked: {}", e),
+            };
+
+            stats.record_error();
+            stats.set_failed(true);
+            let config = settings.borrow().clone();
+            if config.exit_on_sink_failure {
+                error!("{} output failed: {}", name, error);
+                telemetry.report_sink_failure();
+                break;
+            }
+
+            // A worker that ran for a while before failing starts over from a short delay
+            if started.elapsed() > Duration::from_millis(config.retry_max_backoff_ms) {
+                backoff = retry_backoff(&config);
+            }
+            stats.record_restart();
+            let delay = backoff.next_delay();
+            warn!("{} output failed, restarting in {:?}: {}", name, delay, error);
+            tokio::time::sleep(delay).await;
+        }
+    }))
+}
+
+async fn run_worker(
+    mode: String,
+    worker: usize,
+    inbox: Arc<tokio::sync::Mutex<Inbox>>,
+    mut settings: watch::Receiver<Arc<Config>>,
+    stats: Arc<SinkStats>,
+) -> Result<()> {
+    let mut inbox = inbox.lock().await;
+
+    loop {
+        // Keep trying, a sink that can't start yet (DNS, broker down) is no
+        // reason to give up on the output for good. Retries pick up reloaded settings.
+        let mut backoff = retry_backoff(&settings.borrow());
+        let (mut sink, config) = loop {
+            let config = settings.borrow_and_update().clone();
+            match open(&mode, &config, stats.clone()).await {
+                Ok(sink) => break (sink, config),
+                Err(e) => {
+                    stats.record_error();
+                    let delay = backoff.next_delay();
+                    warn!(
+                        "Failed to start {} output, retrying in {:?}: {}",
+                        worker_name(&mode, worker),
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        };
+        stats.set_failed(false);
+        match run_sink(sink.as_mut(), &mut inbox, &mode, worker, &config, &stats, &mut settings).await? {
+            Stopped::Closed => return Ok(()),
+            Stopped::Reloaded => info!("Reopening {} output with the reloaded settings", worker_name(&mode, worker)),
+        }
+    }
+}
+
+// A worker's queue and the metrics it has taken off it but not delivered yet.
+// Metrics only leave pending once the sink acknowledged them, or they were
+// spilled to the WAL or given up on, so a worker that fails or panics halfway
+// through a batch leaves it for the next attempt rather than losing it.
+// The circuit breaker stays too, a restart or reload doesn't close it.
+pub struct Inbox {
+    pub receiver: QueueReceiver,
+    pub pending: Vec<ProcessedMetric>,
+    pub breaker: CircuitBreaker,
+}
+
+// Why run_sink returned
+pub enum Stopped {
+    // The queue closed, the output is done
+    Closed,
+    // The settings changed, the sink should be reopened with them
+    Reloaded,
+}
+
+// Batch metrics off the queue into the sink until the queue closes, or the
+// settings change. Either way the buffer is flushed and the sink closed first.
+pub async fn run_sink(
+    sink: &mut dyn Sink,
+    inbox: &mut Inbox,
+    mode: &str,
+    worker: usize,
+    config: &Config,
+    stats: &SinkStats,
+    settings: &mut watch::Receiver<Arc<Config>>,
+) -> Result<Stopped> {
+    let name = worker_name(mode, worker);
+    // Spilling a disk output to another disk buys nothing
+    let mut wal = match &config.wal_dir {
+        Some(dir) if mode != "disk" => {
+            let wal = Wal::open(Path::new(dir).join(&name), config.wal_max_mb * 1024 * 1024).await?;
+            stats.set_wal_bytes(wal.bytes());
+            Some(wal)
+        }
+        _ => None,
+    };
+    let dead_letter = match &config.dead_letter_dir {
+        Some(dir) => {
+            tokio::fs::create_dir_all(dir).await?;
+            Some(Path::new(dir).join(format!("{}.ndjson", name)))
+        }
+        None => None,
+    };
+    let Inbox { receiver, pending: buffer, breaker } = inbox;
+    let (threshold, cooldown) = circuit_breaker_settings(config)?;
+    breaker.reconfigure(threshold, cooldown);
+    stats.set_circuit(breaker);
+    let mut output = Output {
+        sink,
+        wal: wal.as_mut(),
+        dead_letter: dead_letter.as_deref(),
+        breaker,
+        config,
+        stats,
+    };
+    let batching = batching(mode, config)?;
+    let max_bytes = batching.max_bytes;
+    let (mut batch_size, mut flush_interval) = (batching.size, batching.flush_interval);
+    // What the buffered metrics take as JSON, only counted under a byte limit
+    let mut buffer_bytes = 0;
+    // Ticks at the shortest interval the batching can adapt to
+    let mut flush_timer = interval(batching.min_flush_interval);
+    let mut last_send = Instant::now();
+
+    // Left behind by an attempt that failed before the sink took it
+    if !buffer.is_empty() {
+        info!("{} output resending {} metrics it hadn't delivered", name, buffer.len());
+        output.deliver(buffer).await?;
+        buffer.clear();
+    }
+
+    loop {
+        tokio::select! {
+            // Receive new metrics
+            metric_opt = receiver.recv() => {
+                match metric_opt {
+                    Some(metric) => {
+                        let backlog = receiver.len();
+                        stats.set_queue_depth(backlog);
+                        // Sized when a batch starts, as the queue drains into it the backlog shrinks
+                        if buffer.is_empty() {
+                            (batch_size, flush_interval) = batching.for_backlog(backlog);
+                        }
+                        if max_bytes > 0 {
+                            // Send what's buffered first if this metric would take it over the limit
+                            let size = json_len(&metric);
+                            if !buffer.is_empty() && buffer_bytes + size > max_bytes {
+                                output.deliver(buffer).await?;
+                                buffer.clear();
+                                buffer_bytes = 0;
+                                last_send = Instant::now();
+                            }
+                            buffer_bytes += size;
+                        }
+                        buffer.push(metric);
+
+                        // Send if buffer is full
+                        if buffer.len() >= batch_size || (max_bytes > 0 && buffer_bytes >= max_bytes) {
+                            output.deliver(buffer).await?;
+                            buffer.clear();
+                            buffer_bytes = 0;
+                            last_send = Instant::now();
+                        }
+                    }
+                    None => {
+                        // Channel closed, flush and exit
+                        if !buffer.is_empty() {
+                            output.deliver(buffer).await?;
+                            buffer.clear();
+                        }
+                        output.sink.close().await?;
+                        info!("Output shutting down");
+                        return Ok(Stopped::Closed);
+                    }
+                }
+            }
+
+            // Reloaded settings. A dropped sender just means they never change.
+            Ok(()) = settings.changed() => {
+                if !buffer.is_empty() {
+                    output.deliver(buffer).await?;
+                    buffer.clear();
+                }
+                output.sink.close().await?;
+                return Ok(Stopped::Reloaded);
+            }
+
+            // Periodic flush
+            _ = flush_timer.tick() => {
+                if !buffer.is_empty() && last_send.elapsed() > flush_interval {
+                    output.deliver(buffer).await?;
+                    buffer.clear();
+                    buffer_bytes = 0;
+                    last_send = Instant::now();
+                }
+                output.sink.tick().await?;
+                output.replay().await?;
+            }
+        }
+    }
+}
+
+// Where a batch goes after the sink: the WAL if there is one, otherwise retries
+// and finally the dead-letter file. While the breaker is open batches go
+// there without trying the sink.
+struct Output<'a> {
+    sink: &'a mut dyn Sink,
+    wal: Option<&'a mut Wal>,
+    dead_letter: Option<&'a Path>,
+    breaker: &'a mut CircuitBreaker,
+    config: &'a Config,
+    stats: &'a SinkStats,
+}
+
+impl Output<'_> {
+    // With a WAL, a batch the sink rejects or doesn't take within the timeout is
+    // spilled, and while older batches are still spilled new ones queue up behind
+    // them to keep the order
+    async fn deliver(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let Some(wal_empty) = self.wal.as_ref().map(|wal| wal.is_empty()) else {
+            return self.send_with_retry(batch).await;
+        };
+
+        if wal_empty && self.allow() {
+            let send_timeout = Duration::from_millis(self.config.wal_send_timeout_ms);
+            let error = match timeout(send_timeout, self.sink.send_batch(batch)).await {
+                Ok(Ok(())) => {
+                    self.succeeded();
+                    return Ok(());
+                }
+                Ok(Err(e)) => e,
+                Err(_) => anyhow!("output did not accept the batch in time"),
+            };
+            self.stats.record_error();
+            self.failed(&error);
+            warn!("Spilling {} metrics to the WAL: {}", batch.len(), error);
+        }
+
+        let Some(wal) = self.wal.as_mut() else {
+            return Ok(());
+        };
+        let lost = wal.append(batch).await?;
+        if lost > 0 {
+            self.stats.record_wal_dropped(lost);
+            warn!("WAL is over its size cap, deleted the oldest {} metrics", lost);
+        }
+        self.stats.set_wal_bytes(wal.bytes());
+        Ok(())
+    }
+
+    // A batch that still fails after the retries is dead-lettered or dropped,
+    // either way the worker moves on to the next one
+    async fn send_with_retry(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        if !self.allow() {
+            self.give_up(batch, anyhow!("circuit breaker is open")).await;
+            return Ok(());
+        }
+        let mut backoff = retry_backoff(self.config);
+        let mut attempt = 0;
+        let error = loop {
+            let error = match self.sink.send_batch(batch).await {
+                Ok(()) => {
+                    self.succeeded();
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            self.stats.record_error();
+            if error.is::<Rejected>() || self.failed(&error) {
+                break error;
+            }
+            if self.config.retry_max > 0 && attempt >= self.config.retry_max {
+                break error;
+            }
+            attempt += 1;
+            let delay = backoff.next_delay();
+            warn!("Batch attempt {} failed, retrying in {:?}: {}", attempt, delay, error);
+            tokio::time::sleep(delay).await;
+        };
+        self.give_up(batch, error).await;
+        Ok(())
+    }
+
+    // Dead-letters or drops the batch. Quietly while the breaker is open, it
+    // already warned once when it opened.
+    async fn give_up(&mut self, batch: &[ProcessedMetric], error: anyhow::Error) {
+        self.stats.record_failed(batch.len());
+        let open = self.breaker.state() != Circuit::Closed;
+        match self.dead_letter {
+            Some(path) => {
+                if open {
+                    debug!("Writing {} metrics to {}: {}", batch.len(), path.display(), error);
+                } else {
+                    warn!("Giving up on {} metrics, writing them to {}: {}", batch.len(), path.display(), error);
+                }
+                match write_dead_letters(path, batch, &error).await {
+                    Ok(()) => self.stats.record_dead_lettered(batch.len()),
+                    Err(e) => error!("Failed to write dead letters to {}, dropping them: {}", path.display(), e),
+                }
+            }
+            None if open => debug!("Dropping {} metrics: {}", batch.len(), error),
+            None => warn!("Giving up on {} metrics: {}", batch.len(), error),
+        }
+    }
+
+    // Published right away so a probe in flight shows as half-open
+    fn allow(&mut self) -> bool {
+        let allowed = self.breaker.allow();
+        self.stats.set_circuit(self.breaker);
+        allowed
+    }
+
+    fn succeeded(&mut self) {
+        if self.breaker.state() != Circuit::Closed {
+            info!("Output recovered, closing its circuit breaker");
+        }
+        self.breaker.record_success();
+        self.stats.set_circuit(self.breaker);
+    }
+
+    // True when the failure opened the breaker. A refused batch says nothing
+    // about the output being down, so it doesn't count.
+    fn failed(&mut self, error: &anyhow::Error) -> bool {
+        if error.is::<Rejected>() {
+            return false;
+        }
+        let probe = self.breaker.state() == Circuit::HalfOpen;
+        let opened = self.breaker.record_failure();
+        if opened {
+            self.stats.record_circuit_open();
+            let cooldown = self.breaker.cooldown();
+            if probe {
+                warn!("Output still failing, probing again in {:?}: {}", cooldown, error);
+            } else {
+                warn!(
+                    "Opening the circuit breaker after {} failures in a row, probing the output in {:?}: {}",
+                    self.breaker.failures(),
+                    cooldown,
+                    error
+                );
+            }
+        }
+        self.stats.set_circuit(self.breaker);
+        opened
+    }
+
+    // Send spilled batches oldest first until the WAL is empty or the sink fails again
+    async fn replay(&mut self) -> Result<()> {
+        let Some(wal) = self.wal.as_mut() else {
+            return Ok(());
+        };
+        let send_timeout = Duration::from_millis(self.config.wal_send_timeout_ms);
+        let mut replayed = 0;
+        let mut failure = None;
+        while let Some(batch) = wal.front().await? {
+            let allowed = self.breaker.allow();
+            self.stats.set_circuit(self.breaker);
+            if !allowed {
+                break;
+            }
+            match timeout(send_timeout, self.sink.send_batch(&batch)).await {
+                Ok(Ok(())) => {
+                    wal.pop_front().await?;
+                    replayed += batch.len();
+                }
+                Ok(Err(e)) => {
+                    failure = Some(e);
+                    break;
+                }
+                Err(_) => {
+                    failure = Some(anyhow!("output did not accept the batch in time"));
+                    break;
+                }
+            }
+        }
+        self.stats.set_wal_bytes(wal.bytes());
+        if replayed > 0 {
+            info!("Replayed {} metrics from the WAL", replayed);
+            self.succeeded();
+        }
+        if let Some(error) = failure {
+            debug!("WAL replay failed, will retry: {}", error);
+            self.failed(&error);
+        }
+        Ok(())
+    }
+}
+
+// One line per metric, the metric's own fields plus "error" and "failed_at",
+// so the file still reads back as metrics
+async fn write_dead_letters(path: &Path, batch: &[ProcessedMetric], error: &anyhow::Error) -> Result<()> {
+    let failed_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let mut data = Vec::new();
+    for metric in batch {
+        let mut line = serde_json::to_value(metric)?;
+        if let Some(fields) = line.as_object_mut() {
+            fields.insert("error".to_string(), format!("{:#}", error).into());
+            fields.insert("failed_at".to_string(), failed_at.clone().into());
+        }
+        serde_json::to_writer(&mut data, &line)?;
+        data.push(b'\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(&data).await?;
+    Ok(())
+}
+
+// When an output sends what it has buffered
+pub struct Batching {
+    pub size: usize,
+    // 0 for no limit
+    pub max_bytes: usize,
+    pub flush_interval: Duration,
+    // The bounds under --adaptive-batching, the same as size and flush_interval without it
+    pub max_size: usize,
+    pub min_flush_interval: Duration,
+}
+
+impl Batching {
+    // The batch size and flush interval for a queue holding backlog metrics. Up to
+    // one batch waiting is no load; from there the batch grows with the backlog,
+    // up to max_size, and the interval shrinks in proportion.
+    pub fn for_backlog(&self, backlog: usize) -> (usize, Duration) {
+        if self.max_size == self.size && self.min_flush_interval == self.flush_interval {
+            return (self.size, self.flush_interval);
+        }
+        let load = backlog.saturating_sub(self.size) as f64 / self.max_size.saturating_sub(self.size).max(1) as f64;
+        let load = load.min(1.0);
+        let size = backlog.clamp(self.size, self.max_size);
+        let flush_interval = self.flush_interval.mul_f64(1.0 - load) + self.min_flush_interval.mul_f64(load);
+        (size, flush_interval)
+    }
+}
+
+// The global thresholds, overridden per output by --sink-batch-size and
+// --sink-batch-max-bytes, and for ClickHouse by its own options
+pub fn batching(mode: &str, config: &Config) -> Result<Batching> {
+    let (size, flush_interval_ms) = match mode {
+        "clickhouse" => (
+            config.clickhouse_batch_size.unwrap_or(config.batch_size),
+            config.clickhouse_flush_interval_ms.unwrap_or(config.flush_interval_ms),
+        ),
+        _ => (config.batch_size, config.flush_interval_ms),
+    };
+    let size = per_output("--sink-batch-size", &config.sink_batch_size, mode)?.unwrap_or(size);
+    let flush_interval = Duration::from_millis(flush_interval_ms);
+    // Bounds on the wrong side of the output's own thresholds leave those fixed
+    let (max_size, min_flush_interval) = if config.adaptive_batching {
+        (
+            config.batch_size_max.max(size),
+            Duration::from_millis(config.flush_interval_min_ms).min(flush_interval),
+        )
+    } else {
+        (size, flush_interval)
+    };
+    Ok(Batching {
+        size,
+        max_bytes: per_output("--sink-batch-max-bytes", &config.sink_batch_max_bytes, mode)?
+            .unwrap_or(config.batch_max_bytes),
+        flush_interval,
+        max_size,
+        min_flush_interval,
+    })
+}
+
+// The value an <output>=<number> list gives the output, the last one if several do
+fn per_output(flag: &str, entries: &[String], mode: &str) -> Result<Option<usize>> {
+    let mut found = None;
+    for entry in entries {
+        let parsed = entry
+            .split_once('=')
+            .and_then(|(name, value)| Some((name.trim(), value.trim().parse::<usize>().ok()?)));
+        let Some((name, value)) = parsed else {
+            return Err(anyhow!("Invalid {} {:?}, expected <output>=<number>", flag, entry));
+        };
+        if name == mode {
+            found = Some(value);
+        }
+    }
+    Ok(found)
+}
+
+// The per-output batch settings parse and name configured outputs
+pub fn validate_batching(config: &Config) -> Result<()> {
+    for (flag, entries) in [
+        ("--sink-batch-size", &config.sink_batch_size),
+        ("--sink-batch-max-bytes", &config.sink_batch_max_bytes),
+    ] {
+        for entry in entries {
+            let name = entry.split_once('=').map_or(entry.as_str(), |(name, _)| name.trim());
+            if !config.output_mode.iter().any(|mode| mode == name) {
+                return Err(anyhow!("{} {:?} is for {}, which isn't an --output-mode", flag, entry, name));
+            }
+        }
+    }
+    if config.adaptive_batching && config.flush_interval_min_ms == 0 {
+        return Err(anyhow!("--flush-interval-min-ms must be at least 1"));
+    }
+    for mode in &config.output_mode {
+        batching(mode, config)?;
+    }
+    Ok(())
+}
+
+// Bytes the metric takes as a line of JSON, what --batch-max-bytes counts. The
+// outputs encode differently, but in proportion.
+fn json_len(metric: &ProcessedMetric) -> usize {
+    struct Counter(usize);
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut counter = Counter(0);
+    let _ = serde_json::to_writer(&mut counter, metric);
+    counter.0 + 1
+}
+
+pub fn circuit_breaker(config: &Config) -> Result<CircuitBreaker> {
+    let (threshold, cooldown) = circuit_breaker_settings(config)?;
+    Ok(CircuitBreaker::new(threshold, cooldown))
+}
+
+fn circuit_breaker_settings(config: &Config) -> Result<(u32, Duration)> {
+    let cooldown = parse_duration(&config.circuit_breaker_cooldown)
+        .map_err(|e| anyhow!("--circuit-breaker-cooldown: {}", e))?;
+    Ok((config.circuit_breaker_failures, cooldown))
+}
+
+fn retry_backoff(config: &Config) -> Backoff {
+    Backoff::new(
+        Duration::from_millis(config.retry_initial_backoff_ms),
+        Duration::from_millis(config.retry_max_backoff_ms),
+    )
+}
+
+// Copies every metric into the queue of each output whose routes accept it, the
+// queue of the output's worker for the metric's host. An output with a worker
+// that has died is dropped from the set so the remaining outputs keep receiving.
+pub async fn fan_out(
+    mut receiver: QueueReceiver,
+    mut senders: Vec<(String, Vec<QueueSender>)>,
+    routes: watch::Receiver<Arc<Routes>>,
+    telemetry: Arc<Telemetry>,
+) {
+    while let Some(metric) = receiver.recv().await {
+        let routes = routes.borrow().clone();
+        let mut closed = Vec::new();
+        let mut routed = false;
+        for (i, (mode, shards)) in senders.iter().enumerate() {
+            if !routes.accepts(mode, &metric) {
+                continue;
+            }
+            routed = true;
+            let sender = match shards.len() {
+                1 => &shards[0],
+                workers => &shards[shard(&metric, workers)],
+            };
+            match sender.send(metric.clone()).await {
+                Ok(dropped) => telemetry.record_dropped(dropped),
+                Err(QueueError::Full) => telemetry.record_dropped(1),
+                Err(QueueError::Closed) => closed.push(i),
+            }
+        }
+        if !routed {
+            telemetry.record_filtered(1);
+        }
+        for i in closed.into_iter().rev() {
+            senders.remove(i);
+        }
+        if senders.is_empty() {
+            warn!("All output workers have stopped");
+            break;
+        }
+    }
+}
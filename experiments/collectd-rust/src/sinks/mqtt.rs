@@ -0,0 +1,128 @@
+// MQTT publisher for edge boxes, one message per metric on a topic built from
+// --mqtt-topic, e.g. "metrics/{host}/{plugin}". rumqttc's event loop runs in its
+// own task and reconnects by itself; while it is down, publishes queue up in the
+// client until that fills and pushes back on the worker.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, TlsConfiguration, Transport};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::sinks::template::KeyTemplate;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct MqttSink {
+    client: AsyncClient,
+    event_loop: JoinHandle<()>,
+    topic: KeyTemplate,
+    qos: QoS,
+    retain: bool,
+    format: OutputFormat,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    config.output_format.parse::<OutputFormat>()?;
+    parse_qos(config.mqtt_qos)?;
+    parse_topic(&config.mqtt_topic).map(|_| ())
+}
+
+fn parse_qos(qos: u8) -> Result<QoS> {
+    match qos {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        other => Err(anyhow!("Invalid MQTT QoS: {}, expected 0, 1 or 2", other)),
+    }
+}
+
+// Topic levels are split on "/", and "+" and "#" are wildcards
+fn parse_topic(template: &str) -> Result<KeyTemplate> {
+    if template.contains(['+', '#']) {
+        return Err(anyhow!("MQTT topic can't contain wildcards: {}", template));
+    }
+    KeyTemplate::parse(template)
+}
+
+impl MqttSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<MqttSink> {
+        info!("Starting MQTT publisher, broker: {}:{}", config.mqtt_host, config.mqtt_port);
+
+        let mut options = MqttOptions::new(&config.mqtt_client_id, &config.mqtt_host, config.mqtt_port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(user) = &config.mqtt_username {
+            options.set_credentials(user, config.mqtt_password.clone().unwrap_or_default());
+        }
+        if config.mqtt_tls {
+            let transport = match &config.mqtt_ca_file {
+                Some(path) => Transport::Tls(TlsConfiguration::Simple {
+                    ca: tokio::fs::read(path).await?,
+                    alpn: None,
+                    client_auth: None,
+                }),
+                None => Transport::tls_with_default_config(),
+            };
+            options.set_transport(transport);
+        }
+
+        let (client, mut event_loop) = AsyncClient::new(options, config.batch_size.max(10));
+        let loop_stats = stats.clone();
+        let event_loop = tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to the MQTT broker");
+                        loop_stats.set_ready(true);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        loop_stats.set_ready(false);
+                        loop_stats.record_error();
+                        warn!("MQTT connection failed, reconnecting: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(MqttSink {
+            client,
+            event_loop,
+            topic: parse_topic(&config.mqtt_topic)?,
+            qos: parse_qos(config.mqtt_qos)?,
+            retain: config.mqtt_retain,
+            format: config.output_format.parse()?,
+            stats,
+        })
+    }
+}
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        self.event_loop.abort();
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut bytes = 0;
+        for metric in batch {
+            let payload = self.format.encode_for_datagram(metric)?;
+            bytes += payload.len();
+            self.client
+                .publish(self.topic.render(metric, &['/', '+', '#']), self.qos, self.retain, payload)
+                .await?;
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Published batch of {} metrics to MQTT", batch.len());
+        Ok(())
+    }
+}
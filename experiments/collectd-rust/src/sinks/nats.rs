@@ -0,0 +1,89 @@
+// NATS publisher. Each batch goes out as one message in --output-format (a JSON
+// array by default), or one message per metric with --nats-per-metric. Plain
+// NATS is fire and forget; with --nats-jetstream every message waits for the
+// stream's ack, so a batch only counts as sent once the server has stored it.
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct NatsSink {
+    client: async_nats::Client,
+    jetstream: Option<async_nats::jetstream::Context>,
+    subject: String,
+    per_metric: bool,
+    format: OutputFormat,
+    stats: Arc<SinkStats>,
+}
+
+impl NatsSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<NatsSink> {
+        info!("Starting NATS publisher, server: {}, subject: {}", config.nats_url, config.nats_subject);
+
+        let mut options = async_nats::ConnectOptions::new();
+        if let Some(path) = &config.nats_credentials {
+            options = options.credentials_file(path).await?;
+        }
+        if let Some(token) = &config.nats_token {
+            options = options.token(token.clone());
+        }
+        let client = options.connect(&config.nats_url).await?;
+        let jetstream = config
+            .nats_jetstream
+            .then(|| async_nats::jetstream::new(client.clone()));
+
+        stats.set_ready(true);
+        Ok(NatsSink {
+            client,
+            jetstream,
+            subject: config.nats_subject.clone(),
+            per_metric: config.nats_per_metric,
+            format: config.output_format.parse()?,
+            stats,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for NatsSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let messages = if self.per_metric {
+            batch
+                .iter()
+                .map(|metric| self.format.encode_for_datagram(metric))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![self.format.encode_all(batch)?]
+        };
+        let bytes = messages.iter().map(Vec::len).sum();
+
+        match &self.jetstream {
+            Some(jetstream) => {
+                // Publish everything first and then collect the acks, instead of a round trip per message
+                let mut acks = Vec::with_capacity(messages.len());
+                for message in messages {
+                    acks.push(jetstream.publish(self.subject.clone(), message.into()).await?);
+                }
+                for ack in acks {
+                    ack.await?;
+                }
+            }
+            None => {
+                for message in messages {
+                    self.client.publish(self.subject.clone(), message.into()).await?;
+                }
+                self.client.flush().await?;
+            }
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Published batch of {} metrics to NATS", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+// OTLP/HTTP exporter, turns the receiver into a collectd to OpenTelemetry bridge
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::retry::Rejected;
+use crate::otlp;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct OtlpSink {
+    client: reqwest::Client,
+    url: String,
+    stats: Arc<SinkStats>,
+}
+
+impl OtlpSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> OtlpSink {
+        info!("Starting OTLP exporter, target: {}", config.otlp_url);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        OtlpSink {
+            client,
+            url: config.otlp_url.clone(),
+            stats,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for OtlpSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let body = otlp::encode_batch(batch);
+        let bytes = body.len();
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/x-protobuf")
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = anyhow!("OTLP export failed with {}: {}", status, text);
+            if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(Rejected(error.to_string()).into());
+            }
+            return Err(error);
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Exported batch of {} metrics via OTLP", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+// Output through a WebAssembly plugin's encode export (see crate::plugin): each
+// batch is turned into bytes by --plugin-sink and appended to the file, or
+// POSTed to the http(s) URL, given as --plugin-target. A batch the plugin fails
+// to encode can't succeed on a retry and goes straight to the dead letters.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::plugin::Plugin;
+use crate::retry::Rejected;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct PluginSink {
+    plugin: Arc<Plugin>,
+    target: Target,
+    stats: Arc<SinkStats>,
+}
+
+enum Target {
+    File(tokio::fs::File),
+    Http { client: reqwest::Client, url: reqwest::Url },
+}
+
+fn sink_plugin(config: &Config) -> Result<Plugin> {
+    let name = config
+        .plugin_sink
+        .as_deref()
+        .ok_or_else(|| anyhow!("--plugin-sink is required with the plugin output"))?;
+    let plugin = Plugin::load(config, name)?;
+    if !plugin.exports("encode") {
+        return Err(anyhow!("Plugin {} has no encode export and can't be used as an output", name));
+    }
+    Ok(plugin)
+}
+
+fn target(config: &Config) -> Result<&str> {
+    config
+        .plugin_target
+        .as_deref()
+        .ok_or_else(|| anyhow!("--plugin-target is required with the plugin output"))
+}
+
+fn is_url(target: &str) -> bool {
+    target.starts_with("http://") || target.starts_with("https://")
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    let target = target(config)?;
+    if is_url(target) {
+        reqwest::Url::parse(target)?;
+    }
+    sink_plugin(config).map(|_| ())
+}
+
+impl PluginSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<PluginSink> {
+        let plugin = Arc::new(sink_plugin(config)?);
+        let target = target(config)?;
+        info!("Starting plugin output {}, target: {}", plugin.name(), target);
+        let target = if is_url(target) {
+            Target::Http {
+                client: reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?,
+                url: reqwest::Url::parse(target)?,
+            }
+        } else {
+            let file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(target)
+                .await
+                .map_err(|e| anyhow!("Failed to open {}: {}", target, e))?;
+            Target::File(file)
+        };
+        stats.set_ready(true);
+        Ok(PluginSink { plugin, target, stats })
+    }
+}
+
+#[async_trait]
+impl Sink for PluginSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let body = self.plugin.encode(batch).map_err(|e| Rejected(format!("{:#}", e)))?;
+        let bytes = body.len();
+        match &mut self.target {
+            Target::File(file) => {
+                file.write_all(&body).await?;
+                file.flush().await?;
+            }
+            Target::Http { client, url } => {
+                let response = client
+                    .post(url.clone())
+                    .header("Content-Type", "application/octet-stream")
+                    .body(body)
+                    .send()
+                    .await?;
+                let status = response.status();
+                if !status.is_success() {
+                    let text = response.text().await.unwrap_or_default();
+                    let error = format!("Plugin output request failed with {}: {}", status, text);
+                    if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                        return Err(Rejected(error).into());
+                    }
+                    return Err(anyhow!(error));
+                }
+            }
+        }
+        self.stats.record_batch(bytes);
+        debug!("Wrote batch of {} metrics through plugin {}", batch.len(), self.plugin.name());
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+// PostgreSQL / TimescaleDB writer, one binary COPY per batch. With
+// --postgres-create-table the table (and optionally the hypertable) is created
+// on startup if it doesn't exist yet.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::pin::pin;
+use std::sync::Arc;
+use tokio_postgres::{binary_copy::BinaryCopyInWriter, error::SqlState, types::Type, Client, NoTls};
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::retry::Rejected;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+const COLUMNS: &str = "time, interval, host, plugin, plugin_instance, type, type_instance, dsname, dstype, aggregation, value";
+const TYPES: [Type; 11] = [
+    Type::TIMESTAMPTZ,
+    Type::FLOAT8,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::FLOAT8,
+];
+
+pub struct PostgresSink {
+    url: String,
+    table: String,
+    client: Client,
+    stats: Arc<SinkStats>,
+}
+
+// Table names go into the SQL as is, so only plain (optionally schema qualified) identifiers
+pub fn validate(config: &Config) -> Result<()> {
+    config.postgres_url.parse::<tokio_postgres::Config>()?;
+    let valid = config
+        .postgres_table
+        .split('.')
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+    if !valid {
+        return Err(anyhow!("Invalid PostgreSQL table name: {}", config.postgres_table));
+    }
+    Ok(())
+}
+
+impl PostgresSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<PostgresSink> {
+        info!("Starting PostgreSQL writer, table: {}", config.postgres_table);
+
+        let client = connect(&config.postgres_url).await?;
+        if config.postgres_create_table {
+            create_table(&client, &config.postgres_table, config.postgres_hypertable).await?;
+        }
+        stats.set_ready(true);
+        Ok(PostgresSink {
+            url: config.postgres_url.clone(),
+            table: config.postgres_table.clone(),
+            client,
+            stats,
+        })
+    }
+}
+
+async fn connect(url: &str) -> Result<Client> {
+    let (client, connection) = tokio_postgres::connect(url, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("PostgreSQL connection closed: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+async fn create_table(client: &Client, table: &str, hypertable: bool) -> Result<()> {
+    client
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                time timestamptz NOT NULL,
+                interval double precision,
+                host text,
+                plugin text,
+                plugin_instance text,
+                type text,
+                type_instance text,
+                dsname text,
+                dstype text,
+                aggregation text,
+                value double precision NOT NULL
+            )",
+            table
+        ))
+        .await?;
+    if hypertable {
+        client
+            .execute("SELECT create_hypertable($1::text::regclass, 'time', if_not_exists => TRUE)", &[&table])
+            .await?;
+    }
+    info!("Created table {} if it was missing", table);
+    Ok(())
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        // The connection task ends on any network error, start over with a new one
+        if self.client.is_closed() {
+            self.client = connect(&self.url).await?;
+            info!("Reconnected to PostgreSQL");
+        }
+
+        let copy = self
+            .client
+            .copy_in(&format!("COPY {} ({}) FROM STDIN BINARY", self.table, COLUMNS))
+            .await
+            .map_err(classify)?;
+        let mut writer = pin!(BinaryCopyInWriter::new(copy, &TYPES));
+        let mut rows = 0;
+        for metric in batch {
+            let value = metric.value.as_f64();
+            let time = metric
+                .time
+                .and_then(|t| DateTime::<Utc>::from_timestamp_millis((t * 1000.0) as i64))
+                .unwrap_or_else(Utc::now);
+            writer
+                .as_mut()
+                .write(&[
+                    &time,
+                    &metric.interval,
+                    &metric.host.as_deref(),
+                    &metric.plugin.as_deref(),
+                    &metric.plugin_instance.as_deref(),
+                    &metric.type_.as_deref(),
+                    &metric.type_instance.as_deref(),
+                    &metric.dsname,
+                    &metric.dstype,
+                    &metric.aggregation,
+                    &value,
+                ])
+                .await
+                .map_err(classify)?;
+            rows += 1;
+        }
+        writer.finish().await.map_err(classify)?;
+
+        // COPY doesn't report what went over the wire, so no bytes for this output
+        self.stats.record_batch(0);
+        debug!("Copied {} rows into PostgreSQL", rows);
+        Ok(())
+    }
+}
+
+// Bad data or a constraint violation fails the same way every time
+fn classify(error: tokio_postgres::Error) -> anyhow::Error {
+    let permanent = error.code().is_some_and(|code| {
+        let class = &code.code()[..2];
+        class == "22" || class == "23" || *code == SqlState::UNDEFINED_COLUMN
+    });
+    if permanent {
+        Rejected(format!("PostgreSQL rejected the batch: {}", error)).into()
+    } else {
+        error.into()
+    }
+}
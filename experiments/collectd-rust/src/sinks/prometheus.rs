@@ -0,0 +1,67 @@
+// Prometheus remote-write sender
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::retry::Rejected;
+use crate::remote_write;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct RemoteWriteSink {
+    client: reqwest::Client,
+    url: String,
+    stats: Arc<SinkStats>,
+}
+
+impl RemoteWriteSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> RemoteWriteSink {
+        info!("Starting Prometheus remote-write sender, target: {}", config.remote_write_url);
+
+        if config.remote_write_max_retries.is_some() {
+            warn!("--remote-write-max-retries is deprecated and ignored, use --retry-max");
+        }
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        RemoteWriteSink {
+            client,
+            url: config.remote_write_url.clone(),
+            stats,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for RemoteWriteSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let body = remote_write::encode_batch(batch)?;
+        let bytes = body.len();
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = anyhow!("Remote write failed with {}: {}", status, text);
+            // Anything but a 5xx or 429 means the payload itself was refused
+            if !(status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS) {
+                return Err(Rejected(error.to_string()).into());
+            }
+            return Err(error);
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Pushed batch of {} metrics via remote write", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+// Google Cloud Pub/Sub publisher, one message per metric via the REST API.
+// With --pubsub-ordering-key messages carry the host as ordering key, so
+// subscriptions with ordering enabled see each host's metrics in order.
+// Authenticates with application default credentials, or not at all against
+// the emulator ($PUBSUB_EMULATOR_HOST).
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::gcp_auth::TokenSource;
+use crate::metric::ProcessedMetric;
+use crate::retry::Rejected;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/pubsub";
+
+// Pub/Sub takes up to 1000 messages and 10MB per publish call
+const MAX_MESSAGES: usize = 1000;
+const MAX_REQUEST_BYTES: usize = 9 * 1024 * 1024;
+
+pub struct PubSubSink {
+    client: reqwest::Client,
+    url: String,
+    tokens: Option<TokenSource>,
+    ordering_key: bool,
+    format: OutputFormat,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    config.output_format.parse::<OutputFormat>()?;
+    if config.pubsub_topic.is_empty() {
+        return Err(anyhow!("--pubsub-topic must not be empty"));
+    }
+    Ok(())
+}
+
+impl PubSubSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<PubSubSink> {
+        let emulator = std::env::var("PUBSUB_EMULATOR_HOST").ok().filter(|host| !host.is_empty());
+        let tokens = match emulator {
+            Some(_) => None,
+            None => Some(TokenSource::from_env(SCOPE)?),
+        };
+
+        // "projects/<project>/topics/<topic>", or a bare topic name in the
+        // credentials' (or $GOOGLE_CLOUD_PROJECT's) project
+        let topic = if config.pubsub_topic.starts_with("projects/") {
+            config.pubsub_topic.clone()
+        } else {
+            let project = config
+                .pubsub_project
+                .clone()
+                .or_else(|| tokens.as_ref().and_then(|t| t.project_id()).map(str::to_string))
+                .or_else(|| std::env::var("GOOGLE_CLOUD_PROJECT").ok())
+                .ok_or_else(|| anyhow!("pubsub output needs --pubsub-project, a full topic path or GOOGLE_CLOUD_PROJECT"))?;
+            format!("projects/{}/topics/{}", project, config.pubsub_topic)
+        };
+        let base = match &emulator {
+            Some(host) => format!("http://{}", host),
+            None => config.pubsub_endpoint.trim_end_matches('/').to_string(),
+        };
+        let url = format!("{}/v1/{}:publish", base, topic);
+        info!("Starting Pub/Sub publisher, topic: {}", topic);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        Ok(PubSubSink {
+            client,
+            url,
+            tokens,
+            ordering_key: config.pubsub_ordering_key,
+            format: config.output_format.parse()?,
+            stats,
+        })
+    }
+
+    async fn publish(&mut self, messages: &[serde_json::Value]) -> Result<usize> {
+        let body = serde_json::to_vec(&serde_json::json!({ "messages": messages }))?;
+        let bytes = body.len();
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(body);
+        if let Some(tokens) = &mut self.tokens {
+            request = request.bearer_auth(tokens.token().await?);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = anyhow!("Pub/Sub publish failed with {}: {}", status, text);
+            // An expired or revoked token is worth another try with a new one
+            if status == reqwest::StatusCode::UNAUTHORIZED {
+                if let Some(tokens) = &mut self.tokens {
+                    tokens.invalidate();
+                }
+                return Err(error);
+            }
+            if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(Rejected(error.to_string()).into());
+            }
+            return Err(error);
+        }
+        Ok(bytes)
+    }
+}
+
+#[async_trait]
+impl Sink for PubSubSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut messages = Vec::new();
+        let mut size = 0;
+        let mut bytes = 0;
+        for metric in batch {
+            let data = engine.encode(self.format.encode_for_datagram(metric)?);
+            if !messages.is_empty() && (messages.len() >= MAX_MESSAGES || size + data.len() > MAX_REQUEST_BYTES) {
+                bytes += self.publish(&messages).await?;
+                messages.clear();
+                size = 0;
+            }
+            size += data.len();
+            let mut message = serde_json::json!({ "data": data });
+            if self.ordering_key {
+                message["orderingKey"] = metric.host.as_deref().unwrap_or_default().into();
+            }
+            messages.push(message);
+        }
+        if !messages.is_empty() {
+            bytes += self.publish(&messages).await?;
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Published batch of {} metrics to Pub/Sub", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+// Archives metrics to an S3-compatible bucket (AWS, MinIO, ...) as gzipped
+// objects bucketed by arrival time:
+//   <prefix>dt=2024-05-01/hour=12/part-<unix ms>.<format>.gz
+// Metrics collect in an in-memory part that is uploaded once it reaches
+// --s3-object-size-mb, gets older than --s3-object-interval, or the hour
+// changes. Requests are signed with SigV4 using the usual AWS_* variables.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::config::{parse_duration, Config};
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::retry::Backoff;
+use crate::sigv4::{uri_encode, Credentials, Signer};
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct S3Sink {
+    client: reqwest::Client,
+    endpoint: reqwest::Url,
+    bucket: String,
+    prefix: String,
+    signer: Signer,
+    format: OutputFormat,
+    max_bytes: usize,
+    max_age: Duration,
+    part: Option<Part>,
+    // A finished part whose upload failed, retried before anything new is accepted
+    sealed: Option<(String, Vec<u8>)>,
+    retry_initial: Duration,
+    retry_max: Duration,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+    stats: Arc<SinkStats>,
+}
+
+struct Part {
+    data: GzEncoder<Vec<u8>>,
+    raw_bytes: usize,
+    opened: DateTime<Utc>,
+    opened_at: Instant,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    config.output_format.parse::<OutputFormat>()?;
+    parse_duration(&config.s3_object_interval)?;
+    reqwest::Url::parse(&config.s3_endpoint)?;
+    if config.s3_bucket.is_empty() {
+        return Err(anyhow!("--s3-bucket must not be empty"));
+    }
+    Credentials::from_env("s3").map(|_| ())
+}
+
+impl S3Sink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<S3Sink> {
+        info!("Starting S3 writer, target: {}/{}", config.s3_endpoint, config.s3_bucket);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        Ok(S3Sink {
+            client,
+            endpoint: reqwest::Url::parse(&config.s3_endpoint)?,
+            bucket: config.s3_bucket.clone(),
+            prefix: config.s3_prefix.clone(),
+            signer: Signer {
+                credentials: Credentials::from_env("s3")?,
+                region: config.s3_region.clone(),
+                service: "s3".to_string(),
+            },
+            format: config.output_format.parse()?,
+            max_bytes: (config.s3_object_size_mb * 1024 * 1024) as usize,
+            max_age: parse_duration(&config.s3_object_interval)?,
+            part: None,
+            sealed: None,
+            retry_initial: Duration::from_millis(config.retry_initial_backoff_ms),
+            retry_max: Duration::from_millis(config.retry_max_backoff_ms),
+            backoff: Backoff::new(
+                Duration::from_millis(config.retry_initial_backoff_ms),
+                Duration::from_millis(config.retry_max_backoff_ms),
+            ),
+            retry_at: None,
+            stats,
+        })
+    }
+
+    // Close the current part if it is full, too old or from an earlier hour
+    fn seal_if_due(&mut self) -> Result<()> {
+        let Some(part) = &self.part else {
+            return Ok(());
+        };
+        let too_big = part.raw_bytes >= self.max_bytes;
+        let too_old = part.opened_at.elapsed() >= self.max_age;
+        let new_hour = part.opened.format("%Y%m%d%H").to_string() != Utc::now().format("%Y%m%d%H").to_string();
+        if too_big || too_old || new_hour {
+            self.seal()?;
+        }
+        Ok(())
+    }
+
+    fn seal(&mut self) -> Result<()> {
+        if let Some(part) = self.part.take() {
+            let key = format!(
+                "{}{}part-{}.{}.gz",
+                self.prefix,
+                part.opened.format("dt=%Y-%m-%d/hour=%H/"),
+                Utc::now().timestamp_millis(),
+                self.format.extension()
+            );
+            self.sealed = Some((key, part.data.finish()?));
+        }
+        Ok(())
+    }
+
+    // Upload the sealed part, if any. On failure it stays sealed for the next try.
+    async fn upload_sealed(&mut self) -> Result<()> {
+        let Some((key, body)) = &self.sealed else {
+            return Ok(());
+        };
+        if let Err(e) = self.put_object(key, body.clone()).await {
+            self.retry_at = Some(Instant::now() + self.backoff.next_delay());
+            return Err(e);
+        }
+        info!("Uploaded s3://{}/{} ({} bytes)", self.bucket, key, body.len());
+        self.stats.record_batch(body.len());
+        self.sealed = None;
+        self.retry_at = None;
+        self.backoff = Backoff::new(self.retry_initial, self.retry_max);
+        Ok(())
+    }
+
+    async fn put_object(&self, key: &str, body: Vec<u8>) -> Result<()> {
+        // Path-style addressing, which every S3-compatible store understands
+        let mut path = self.endpoint.path().trim_end_matches('/').to_string();
+        path.push('/');
+        path.push_str(&uri_encode(&self.bucket, false));
+        path.push('/');
+        path.push_str(&uri_encode(key, true));
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+
+        let headers = vec![("content-type", "application/gzip".to_string())];
+        let mut request = self.client.put(url.clone());
+        for (name, value) in self.signer.sign("PUT", &url, headers, &body) {
+            request = request.header(name, value);
+        }
+        let response = request.body(body).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(anyhow!("S3 upload of {} failed with {}: {}", key, status, text));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for S3Sink {
+    // The batch only goes in once any earlier part is safely uploaded, so a
+    // failing bucket pushes back into the usual retries instead of piling up here
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        self.seal_if_due()?;
+        self.upload_sealed().await?;
+
+        let part = self.part.get_or_insert_with(|| Part {
+            data: GzEncoder::new(Vec::new(), Compression::default()),
+            raw_bytes: 0,
+            opened: Utc::now(),
+            opened_at: Instant::now(),
+        });
+        if part.raw_bytes == 0 {
+            if let Some(header) = self.format.file_header() {
+                part.data.write_all(header.as_bytes())?;
+                part.raw_bytes += header.len();
+            }
+        }
+        for metric in batch {
+            let record = self.format.encode(metric)?;
+            part.data.write_all(&record)?;
+            part.raw_bytes += record.len();
+        }
+        debug!("Added {} metrics to the current S3 part", batch.len());
+
+        // This batch is already in the part, a failed upload is retried from tick
+        if part.raw_bytes >= self.max_bytes {
+            self.seal()?;
+            if let Err(e) = self.upload_sealed().await {
+                self.stats.record_error();
+                warn!("S3 upload failed, will retry: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> Result<()> {
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return Ok(());
+        }
+        self.seal_if_due()?;
+        if let Err(e) = self.upload_sealed().await {
+            self.stats.record_error();
+            warn!("S3 upload failed, will retry: {}", e);
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.seal()?;
+        self.upload_sealed().await
+    }
+}
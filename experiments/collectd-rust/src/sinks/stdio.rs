@@ -0,0 +1,63 @@
+// Writes metrics to stdout or stderr for shell pipelines (`| jq`, `| vector`),
+// one record per line with --output-format json or ndjson. Each batch is
+// written and flushed whole, so a reader never sees half a line.
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct StdioSink {
+    out: Box<dyn AsyncWrite + Send + Sync + Unpin>,
+    format: OutputFormat,
+    // Header still to be written, for csv
+    header: Option<String>,
+    stats: Arc<SinkStats>,
+}
+
+impl StdioSink {
+    pub fn open(mode: &str, config: &Config, stats: Arc<SinkStats>) -> Result<StdioSink> {
+        info!("Starting {} writer", mode);
+
+        let out: Box<dyn AsyncWrite + Send + Sync + Unpin> = if mode == "stderr" {
+            Box::new(tokio::io::stderr())
+        } else {
+            Box::new(tokio::io::stdout())
+        };
+        let format: OutputFormat = config.output_format.parse()?;
+        stats.set_ready(true);
+        Ok(StdioSink {
+            out,
+            header: format.file_header(),
+            format,
+            stats,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for StdioSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut payload = Vec::new();
+        if let Some(header) = &self.header {
+            payload.extend_from_slice(header.as_bytes());
+        }
+        for metric in batch {
+            payload.extend_from_slice(&self.format.encode(metric)?);
+        }
+        // A closed pipe (the reader went away) fails the batch like any other output
+        self.out.write_all(&payload).await?;
+        self.out.flush().await?;
+        self.header = None;
+
+        self.stats.record_batch(payload.len());
+        debug!("Wrote batch of {} metrics", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+// RFC 5424 syslog sender for SIEMs that only take syslog. Each metric becomes
+// one message with its identity and value as structured data and the JSON
+// metric as MSG; with --syslog-per-batch a whole batch goes in one message
+// as a JSON array instead. TCP and TLS use octet-counting framing (RFC 6587 /
+// RFC 5425), UDP one datagram per message.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::rustls::{self, pki_types::ServerName, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+// Structured data ID, under the enterprise number RFC 5612 reserves for examples
+const SD_ID: &str = "metric@32473";
+// Informational
+const SEVERITY: u8 = 6;
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp", "ntp",
+    "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7",
+];
+
+pub struct SyslogSink {
+    target_addr: String,
+    transport: String,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    udp: Option<UdpSocket>,
+    // Dropped on any write error, the next batch reconnects
+    stream: Option<Box<dyn AsyncWrite + Send + Sync + Unpin>>,
+    priority: u8,
+    app_name: String,
+    per_batch: bool,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    facility(&config.syslog_facility)?;
+    match config.syslog_transport.as_str() {
+        "udp" | "tcp" | "tls" => Ok(()),
+        other => Err(anyhow!("Invalid syslog transport: {}, expected udp, tcp or tls", other)),
+    }
+}
+
+fn facility(name: &str) -> Result<u8> {
+    FACILITIES
+        .iter()
+        .position(|facility| *facility == name)
+        .map(|code| code as u8)
+        .ok_or_else(|| anyhow!("Invalid syslog facility: {}, expected one of {}", name, FACILITIES.join(", ")))
+}
+
+impl SyslogSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<SyslogSink> {
+        validate(config)?;
+        let target_addr = format!("{}:{}", config.syslog_host, config.syslog_port);
+        info!("Starting syslog sender, target: {} over {}", target_addr, config.syslog_transport);
+
+        let tls = if config.syslog_transport == "tls" {
+            let mut roots = RootCertStore::empty();
+            match &config.syslog_ca_file {
+                Some(path) => {
+                    let pem = std::fs::read(path)?;
+                    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+                        roots.add(cert?)?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            let tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let server_name = ServerName::try_from(config.syslog_host.clone())?;
+            Some((TlsConnector::from(Arc::new(tls_config)), server_name))
+        } else {
+            None
+        };
+
+        let mut sink = SyslogSink {
+            target_addr,
+            transport: config.syslog_transport.clone(),
+            tls,
+            udp: None,
+            stream: None,
+            priority: facility(&config.syslog_facility)? * 8 + SEVERITY,
+            app_name: config.syslog_app_name.clone(),
+            per_batch: config.syslog_per_batch,
+            stats,
+        };
+        // Connect up front so readiness reflects the collector before the first batch
+        if let Err(e) = sink.connect().await {
+            warn!("Failed to connect to {}, will retry on the first batch: {}", sink.target_addr, e);
+        }
+        Ok(sink)
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        if self.transport == "udp" {
+            if self.udp.is_none() {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(&self.target_addr).await?;
+                self.udp = Some(socket);
+                self.stats.set_ready(true);
+            }
+            return Ok(());
+        }
+        if self.stream.is_some() {
+            return Ok(());
+        }
+        let tcp = TcpStream::connect(&self.target_addr).await?;
+        self.stream = Some(match &self.tls {
+            Some((connector, server_name)) => Box::new(connector.connect(server_name.clone(), tcp).await?),
+            None => Box::new(tcp),
+        });
+        info!("Connected to {}", self.target_addr);
+        self.stats.set_ready(true);
+        Ok(())
+    }
+
+    // HEADER, STRUCTURED-DATA and MSG of one RFC 5424 message
+    fn message(&self, time: Option<f64>, host: Option<&str>, msg_id: &str, data: &str, msg: &str) -> String {
+        let time = time
+            .and_then(|time| DateTime::<Utc>::from_timestamp_millis((time * 1000.0) as i64))
+            .unwrap_or_else(Utc::now)
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        format!(
+            "<{}>1 {} {} {} {} {} {} {}",
+            self.priority,
+            time,
+            header_field(host.unwrap_or_default(), 255),
+            header_field(&self.app_name, 48),
+            std::process::id(),
+            msg_id,
+            data,
+            msg
+        )
+    }
+
+    fn metric_message(&self, metric: &ProcessedMetric) -> Result<String> {
+        let mut data = format!("[{}", SD_ID);
+        for (name, value) in [
+            ("plugin", metric.plugin.as_deref()),
+            ("plugin_instance", metric.plugin_instance.as_deref()),
+            ("type", metric.type_.as_deref()),
+            ("type_instance", metric.type_instance.as_deref()),
+            ("dsname", metric.dsname.as_deref()),
+            ("aggregation", metric.aggregation.as_deref()),
+            ("unit", metric.unit.as_deref()),
+        ] {
+            if let Some(value) = value.filter(|v| !v.is_empty()) {
+                data.push_str(&format!(" {}=\"{}\"", name, param_value(value)));
+            }
+        }
+        for (name, value) in &metric.tags {
+            data.push_str(&format!(" {}=\"{}\"", name, param_value(value)));
+        }
+        data.push_str(&format!(" value=\"{}\"]", param_value(&metric.value.to_string())));
+        let msg = String::from_utf8(OutputFormat::Json.encode_for_datagram(metric)?)?;
+        Ok(self.message(metric.time, metric.host.as_deref(), "metric", &data, &msg))
+    }
+}
+
+// PRINTUSASCII without spaces, "-" when empty
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value.chars().filter(|c| c.is_ascii_graphic()).take(max_len).collect();
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+// '"', '\' and ']' must be escaped inside PARAM-VALUE
+fn param_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+#[async_trait]
+impl Sink for SyslogSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let messages = if self.per_batch {
+            let msg = String::from_utf8(OutputFormat::Json.encode_all(batch)?)?;
+            vec![self.message(None, None, "batch", "-", &msg)]
+        } else {
+            batch.iter().map(|metric| self.metric_message(metric)).collect::<Result<Vec<_>>>()?
+        };
+
+        if let Err(e) = self.connect().await {
+            self.stats.set_ready(false);
+            return Err(anyhow!("Failed to connect to {}: {}", self.target_addr, e));
+        }
+        let mut bytes = 0;
+        if let Some(socket) = &self.udp {
+            for message in &messages {
+                socket.send(message.as_bytes()).await?;
+                bytes += message.len();
+            }
+        } else if let Some(stream) = self.stream.as_mut() {
+            let mut payload = Vec::new();
+            for message in &messages {
+                payload.extend_from_slice(format!("{} {}", message.len(), message).as_bytes());
+            }
+            let written = async {
+                stream.write_all(&payload).await?;
+                stream.flush().await
+            };
+            // A batch interrupted mid-write is resent whole, the collector may see some messages twice
+            if let Err(e) = written.await {
+                self.stream = None;
+                self.stats.set_ready(false);
+                return Err(anyhow!("Lost connection to {}: {}", self.target_addr, e));
+            }
+            bytes = payload.len();
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Sent batch of {} metrics via syslog", batch.len());
+        Ok(())
+    }
+}
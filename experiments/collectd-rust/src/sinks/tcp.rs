@@ -0,0 +1,93 @@
+// TCP sender, newline-delimited JSON over one long-lived connection
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct TcpSink {
+    target_addr: String,
+    // Dropped on any write error, send_batch reconnects
+    stream: Option<TcpStream>,
+    max_backoff: Duration,
+    stats: Arc<SinkStats>,
+}
+
+impl TcpSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<TcpSink> {
+        let target_addr = format!("{}:{}", config.tcp_host, config.tcp_port);
+        info!("Starting TCP sender, target: {}", target_addr);
+
+        // Connect up front so readiness reflects the peer before the first batch
+        let mut stream = None;
+        match TcpStream::connect(&target_addr).await {
+            Ok(connected) => {
+                info!("Connected to {}", target_addr);
+                stream = Some(connected);
+                stats.set_ready(true);
+            }
+            Err(e) => warn!("Failed to connect to {}, will retry on the first batch: {}", target_addr, e),
+        }
+
+        Ok(TcpSink {
+            target_addr,
+            stream,
+            max_backoff: Duration::from_millis(config.tcp_max_backoff_ms),
+            stats,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for TcpSink {
+    // Keeps retrying until the batch is written, so nothing is dropped while the peer is away.
+    // A batch interrupted mid-write is resent whole, the peer may see some lines twice.
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut payload = Vec::new();
+        for metric in batch {
+            serde_json::to_writer(&mut payload, metric)?;
+            payload.push(b'\n');
+        }
+
+        let mut backoff = Duration::from_millis(100);
+        loop {
+            if self.stream.is_none() {
+                match TcpStream::connect(&self.target_addr).await {
+                    Ok(connected) => {
+                        info!("Connected to {}", self.target_addr);
+                        self.stream = Some(connected);
+                        self.stats.set_ready(true);
+                    }
+                    Err(e) => {
+                        self.stats.record_error();
+                        warn!("Failed to connect to {}, retrying in {:?}: {}", self.target_addr, backoff, e);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(self.max_backoff);
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(connected) = self.stream.as_mut() {
+                match connected.write_all(&payload).await {
+                    Ok(()) => break,
+                    Err(e) => {
+                        self.stats.record_error();
+                        warn!("Lost connection to {}: {}", self.target_addr, e);
+                        self.stream = None;
+                        self.stats.set_ready(false);
+                    }
+                }
+            }
+        }
+
+        self.stats.record_batch(payload.len());
+        debug!("Sent batch of {} metrics via TCP", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+// Topic / routing key templates like "metrics/{host}/{plugin}", shared by the
+// outputs that address every metric on its own, and the metric fields they
+// (and label based outputs) can pick from. File paths can also use {date} and
+// {hour} of the metric's time, in UTC.
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+
+use crate::metric::ProcessedMetric;
+
+pub const FIELDS: [&str; 8] = [
+    "host",
+    "plugin",
+    "plugin_instance",
+    "type",
+    "type_instance",
+    "dsname",
+    "aggregation",
+    "unit",
+];
+
+// Placeholder and strftime format of the time parts a path can use
+const TIME_FIELDS: [(&str, &str); 2] = [("date", "%Y-%m-%d"), ("hour", "%H")];
+
+pub struct KeyTemplate {
+    pieces: Vec<Piece>,
+}
+
+enum Piece {
+    Literal(String),
+    Field(String),
+    Time(&'static str),
+}
+
+impl KeyTemplate {
+    // Literal text with {field} placeholders
+    pub fn parse(template: &str) -> Result<KeyTemplate> {
+        KeyTemplate::parse_with(template, false)
+    }
+
+    // A file path, which can also partition by {date} and {hour}
+    pub fn parse_path(template: &str) -> Result<KeyTemplate> {
+        KeyTemplate::parse_with(template, true)
+    }
+
+    fn parse_with(template: &str, time: bool) -> Result<KeyTemplate> {
+        let mut pieces = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .map(|i| start + i)
+                .ok_or_else(|| anyhow!("Unclosed placeholder in {}", template))?;
+            let field = &rest[start + 1..end];
+            let time_format = TIME_FIELDS.iter().find(|(name, _)| time && *name == field).map(|(_, format)| *format);
+            if !FIELDS.contains(&field) && time_format.is_none() {
+                let mut expected = FIELDS.to_vec();
+                if time {
+                    expected.extend(TIME_FIELDS.iter().map(|(name, _)| *name));
+                }
+                return Err(anyhow!("Invalid placeholder {{{}}} in {}, expected one of {}", field, template, expected.join(", ")));
+            }
+            if start > 0 {
+                pieces.push(Piece::Literal(rest[..start].to_string()));
+            }
+            pieces.push(match time_format {
+                Some(format) => Piece::Time(format),
+                None => Piece::Field(field.to_string()),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Literal(rest.to_string()));
+        }
+        Ok(KeyTemplate { pieces })
+    }
+
+    // Fill in the placeholders, with the reserved characters in values (level
+    // separators, wildcards) replaced by "_"
+    pub fn render(&self, metric: &ProcessedMetric, reserved: &[char]) -> String {
+        let mut key = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(text) => key.push_str(text),
+                Piece::Field(name) => {
+                    key.push_str(&field(metric, name).unwrap_or_default().replace(reserved, "_"));
+                }
+                Piece::Time(format) => key.push_str(&metric_time(metric).format(format).to_string()),
+            }
+        }
+        key
+    }
+
+    // The path for a metric. A field can't add directories or climb out of
+    // them: "/" becomes "_", and so do empty, "." and ".." values.
+    pub fn render_path(&self, metric: &ProcessedMetric) -> String {
+        let mut path = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(text) => path.push_str(text),
+                Piece::Field(name) => match field(metric, name).unwrap_or_default() {
+                    "" | "." | ".." => path.push('_'),
+                    value => path.push_str(&value.replace('/', "_")),
+                },
+                Piece::Time(format) => path.push_str(&metric_time(metric).format(format).to_string()),
+            }
+        }
+        path
+    }
+
+    pub fn is_literal(&self) -> bool {
+        self.pieces.iter().all(|piece| matches!(piece, Piece::Literal(_)))
+    }
+}
+
+// When the metric was taken, or now if the agent didn't say
+fn metric_time(metric: &ProcessedMetric) -> DateTime<Utc> {
+    metric
+        .time
+        .and_then(|t| DateTime::from_timestamp_millis((t * 1000.0) as i64))
+        .unwrap_or_else(Utc::now)
+}
+
+// One of FIELDS by name
+pub fn field<'a>(metric: &'a ProcessedMetric, name: &str) -> Option<&'a str> {
+    match name {
+        "host" => metric.host.as_deref(),
+        "plugin" => metric.plugin.as_deref(),
+        "plugin_instance" => metric.plugin_instance.as_deref(),
+        "type" => metric.type_.as_deref(),
+        "type_instance" => metric.type_instance.as_deref(),
+        "dsname" => metric.dsname.as_deref(),
+        "aggregation" => metric.aggregation.as_deref(),
+        "unit" => metric.unit.as_deref(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Component, Path};
+
+    fn metric(host: &str, plugin: &str) -> ProcessedMetric {
+        let raw = serde_json::json!({"values": [1], "time": 1714564800, "host": host, "plugin": plugin});
+        crate::pipeline::process_metric(serde_json::from_value(raw).unwrap()).remove(0)
+    }
+
+    // The rendered path, checked to stay in the template's directory
+    fn render_under(base: &str, metric: &ProcessedMetric) -> String {
+        let path = KeyTemplate::parse_path(&format!("{}/{{host}}/{{plugin}}-{{date}}.out", base))
+            .unwrap()
+            .render_path(metric);
+        assert!(Path::new(&path).starts_with(base), "{} left {}", path, base);
+        assert!(!Path::new(&path).components().any(|c| c == Component::ParentDir), "{} climbs out", path);
+        assert_eq!(Path::new(&path).components().count(), Path::new(base).components().count() + 2);
+        path
+    }
+
+    #[test]
+    fn fills_in_fields_and_date() {
+        assert_eq!(render_under("/var/lib/collectd", &metric("web01", "cpu")), "/var/lib/collectd/web01/cpu-2024-05-01.out");
+    }
+
+    #[test]
+    fn fields_cant_climb_out() {
+        assert_eq!(render_under("/var/lib/collectd", &metric("../../etc", "cpu")), "/var/lib/collectd/.._.._etc/cpu-2024-05-01.out");
+        assert_eq!(render_under("/var/lib/collectd", &metric("..", "cpu")), "/var/lib/collectd/_/cpu-2024-05-01.out");
+        assert_eq!(render_under("/var/lib/collectd", &metric(".", "cpu")), "/var/lib/collectd/_/cpu-2024-05-01.out");
+        assert_eq!(render_under("out", &metric("", "..")), "out/_/_-2024-05-01.out");
+    }
+
+    #[test]
+    fn fields_cant_add_directories() {
+        assert_eq!(render_under("/var/lib/collectd", &metric("web/01", "a/b")), "/var/lib/collectd/web_01/a_b-2024-05-01.out");
+        assert_eq!(render_under("/var/lib/collectd", &metric("/etc/passwd", "cpu")), "/var/lib/collectd/_etc_passwd/cpu-2024-05-01.out");
+    }
+}
@@ -0,0 +1,72 @@
+// Per-tenant instances of an output whose settings contain {tenant}. Each
+// tenant's instance is opened the first time that tenant shows up and kept for
+// the life of the worker. A batch counts as sent once every tenant in it took
+// its share, so a failure retries the whole batch.
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::info;
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::sinks::{open_single, Sink};
+use crate::telemetry::SinkStats;
+use crate::tenant;
+
+pub struct TenantSink {
+    mode: String,
+    config: Config,
+    sinks: HashMap<String, Box<dyn Sink>>,
+    stats: Arc<SinkStats>,
+}
+
+impl TenantSink {
+    pub fn open(mode: &str, config: &Config, stats: Arc<SinkStats>) -> TenantSink {
+        info!("Starting per-tenant {} output", mode);
+        stats.set_ready(true);
+        TenantSink {
+            mode: mode.to_string(),
+            config: config.clone(),
+            sinks: HashMap::new(),
+            stats,
+        }
+    }
+
+    async fn sink(&mut self, name: &str) -> Result<&mut Box<dyn Sink>> {
+        if !self.sinks.contains_key(name) {
+            let config = tenant::config_for(&self.config, name)?;
+            let sink = open_single(&self.mode, &config, self.stats.clone()).await?;
+            self.sinks.insert(name.to_string(), sink);
+        }
+        Ok(self.sinks.get_mut(name).expect("tenant sink was just opened"))
+    }
+}
+
+#[async_trait]
+impl Sink for TenantSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut by_tenant: HashMap<&str, Vec<ProcessedMetric>> = HashMap::new();
+        for metric in batch {
+            by_tenant.entry(tenant::of(metric)).or_default().push(metric.clone());
+        }
+        for (name, metrics) in by_tenant {
+            self.sink(name).await?.send_batch(&metrics).await?;
+        }
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> Result<()> {
+        for sink in self.sinks.values_mut() {
+            sink.tick().await?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        for sink in self.sinks.values_mut() {
+            sink.close().await?;
+        }
+        Ok(())
+    }
+}
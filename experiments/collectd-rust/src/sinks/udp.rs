@@ -0,0 +1,79 @@
+// UDP sender
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct UdpSink {
+    socket: UdpSocket,
+    max_payload: usize,
+    format: OutputFormat,
+    stats: Arc<SinkStats>,
+}
+
+impl UdpSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<UdpSink> {
+        let target_addr = format!("{}:{}", config.udp_host, config.udp_port);
+        info!("Starting UDP sender, target: {}", target_addr);
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(&target_addr).await?;
+        stats.set_ready(true);
+        Ok(UdpSink {
+            socket,
+            max_payload: config.udp_max_payload,
+            format: config.output_format.parse()?,
+            stats,
+        })
+    }
+
+    async fn send_datagram(&self, datagram: &mut Vec<u8>) -> Result<()> {
+        datagram.extend_from_slice(self.format.datagram_framing().2);
+        self.socket.send(datagram).await?;
+        self.stats.record_batch(datagram.len());
+        datagram.clear();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for UdpSink {
+    // Each datagram holds as many metrics as fit in max_payload bytes, a JSON
+    // array by default or records back to back for the other formats
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let (open, separator, close) = self.format.datagram_framing();
+        let mut datagram = Vec::with_capacity(self.max_payload);
+        let mut datagrams = 0;
+        for metric in batch {
+            let record = self.format.encode_for_datagram(metric)?;
+            // The framing plus the metric on its own already don't fit, the OS would
+            // truncate or drop it anyway
+            if open.len() + record.len() + close.len() > self.max_payload {
+                self.stats.record_error();
+                warn!("Dropping metric of {} bytes, larger than --udp-max-payload", record.len());
+                continue;
+            }
+            // One more record needs a separator and the closing bytes
+            if !datagram.is_empty() && datagram.len() + separator.len() + record.len() + close.len() > self.max_payload {
+                self.send_datagram(&mut datagram).await?;
+                datagrams += 1;
+            }
+            datagram.extend_from_slice(if datagram.is_empty() { open } else { separator });
+            datagram.extend_from_slice(&record);
+        }
+        if !datagram.is_empty() {
+            self.send_datagram(&mut datagram).await?;
+            datagrams += 1;
+        }
+
+        debug!("Sent batch of {} metrics via UDP in {} datagrams", batch.len(), datagrams);
+        Ok(())
+    }
+}
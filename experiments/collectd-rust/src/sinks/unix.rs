@@ -0,0 +1,105 @@
+// Unix domain socket sender for local consumers, one record per line over a
+// stream socket, or one record per datagram. A stream connection that fails
+// is dropped and reopened on the next batch, so the consumer can restart
+// without restarting the receiver.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixDatagram, UnixStream};
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct UnixSink {
+    path: PathBuf,
+    datagram: Option<UnixDatagram>,
+    // Stream mode only, None until connected
+    stream: Option<UnixStream>,
+    format: OutputFormat,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    config.output_format.parse::<OutputFormat>()?;
+    match config.unix_socket_type.as_str() {
+        "stream" | "datagram" => Ok(()),
+        other => Err(anyhow!("Invalid Unix socket type: {}, expected stream or datagram", other)),
+    }
+}
+
+impl UnixSink {
+    pub async fn open(config: &Config, stats: Arc<SinkStats>) -> Result<UnixSink> {
+        validate(config)?;
+        info!("Starting Unix socket sender, target: {} ({})", config.unix_path, config.unix_socket_type);
+
+        let datagram = if config.unix_socket_type == "datagram" {
+            Some(UnixDatagram::unbound()?)
+        } else {
+            None
+        };
+        let mut sink = UnixSink {
+            path: PathBuf::from(&config.unix_path),
+            datagram,
+            stream: None,
+            format: config.output_format.parse()?,
+            stats,
+        };
+        if sink.datagram.is_some() {
+            sink.stats.set_ready(true);
+        } else if let Err(e) = sink.connect().await {
+            warn!("Failed to connect to {}, will retry on the first batch: {}", sink.path.display(), e);
+        }
+        Ok(sink)
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(UnixStream::connect(&self.path).await?);
+            info!("Connected to {}", self.path.display());
+            self.stats.set_ready(true);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for UnixSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut bytes = 0;
+        if let Some(socket) = &self.datagram {
+            for metric in batch {
+                let record = self.format.encode(metric)?;
+                socket.send_to(&record, &self.path).await?;
+                bytes += record.len();
+            }
+        } else {
+            let mut payload = Vec::new();
+            for metric in batch {
+                payload.extend_from_slice(&self.format.encode(metric)?);
+            }
+            if let Err(e) = self.connect().await {
+                self.stats.set_ready(false);
+                return Err(anyhow!("Failed to connect to {}: {}", self.path.display(), e));
+            }
+            if let Some(stream) = self.stream.as_mut() {
+                // A batch interrupted mid-write is resent whole, the consumer may see some lines twice
+                if let Err(e) = stream.write_all(&payload).await {
+                    self.stream = None;
+                    self.stats.set_ready(false);
+                    return Err(anyhow!("Lost connection to {}: {}", self.path.display(), e));
+                }
+            }
+            bytes = payload.len();
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Sent batch of {} metrics via Unix socket", batch.len());
+        Ok(())
+    }
+}
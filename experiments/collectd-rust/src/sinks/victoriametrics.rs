@@ -0,0 +1,110 @@
+// VictoriaMetrics writer, either /api/v1/import (JSON lines) or its
+// InfluxDB-compatible /write endpoint. Series are named the same way as for
+// remote write, collectd_<plugin>_<type>[_<dsname>] with host, plugin_instance
+// and type_instance labels, so dashboards work with either output.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+use crate::remote_write;
+use crate::retry::Rejected;
+use crate::sinks::influx::to_line_protocol;
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct VictoriaMetricsSink {
+    client: reqwest::Client,
+    url: String,
+    influx: bool,
+    plugin_labels: bool,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    reqwest::Url::parse(&config.vm_url)?;
+    match config.vm_format.as_str() {
+        "json" | "influx" => Ok(()),
+        other => Err(anyhow!("Invalid VictoriaMetrics format: {}, expected json or influx", other)),
+    }
+}
+
+impl VictoriaMetricsSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<VictoriaMetricsSink> {
+        validate(config)?;
+        let influx = config.vm_format == "influx";
+        let base = config.vm_url.trim_end_matches('/');
+        let url = if influx {
+            format!("{}/write", base)
+        } else {
+            format!("{}/api/v1/import", base)
+        };
+        info!("Starting VictoriaMetrics writer, target: {}", url);
+
+        let client = reqwest::Client::new();
+        stats.set_ready(true);
+        Ok(VictoriaMetricsSink {
+            client,
+            url,
+            influx,
+            plugin_labels: config.vm_plugin_labels,
+            stats,
+        })
+    }
+
+    // One {"metric":{...},"values":[...],"timestamps":[...]} line per metric
+    fn import_line(&self, metric: &ProcessedMetric) -> String {
+        let series = remote_write::to_time_series(metric);
+        let mut labels: serde_json::Map<String, serde_json::Value> =
+            series.labels.into_iter().map(|label| (label.name, label.value.into())).collect();
+        if self.plugin_labels {
+            for (name, value) in [("plugin", &metric.plugin), ("type", &metric.type_)] {
+                if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+                    labels.insert(name.to_string(), value.into());
+                }
+            }
+        }
+        let line = serde_json::json!({
+            "metric": labels,
+            "values": series.samples.iter().map(|s| s.value).collect::<Vec<_>>(),
+            "timestamps": series.samples.iter().map(|s| s.timestamp).collect::<Vec<_>>(),
+        });
+        line.to_string()
+    }
+}
+
+#[async_trait]
+impl Sink for VictoriaMetricsSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        let mut body = String::new();
+        for metric in batch {
+            let line = if self.influx {
+                to_line_protocol(metric)
+            } else {
+                self.import_line(metric)
+            };
+            body.push_str(&line);
+            body.push('\n');
+        }
+
+        let bytes = body.len();
+        if !body.is_empty() {
+            let response = self.client.post(&self.url).body(body).send().await?;
+            if !response.status().is_success() {
+                let status = response.status();
+                let text = response.text().await.unwrap_or_default();
+                let error = anyhow!("VictoriaMetrics write failed with {}: {}", status, text);
+                if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    return Err(Rejected(error.to_string()).into());
+                }
+                return Err(error);
+            }
+        }
+
+        self.stats.record_batch(bytes);
+        debug!("Wrote batch of {} metrics to VictoriaMetrics", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+// Generic HTTP forwarder, POSTs batches to --webhook-url encoded with
+// --output-format (a JSON array by default, or NDJSON). With
+// --webhook-concurrency above 1 each batch is split into that many requests
+// sent in parallel; when only some of them fail, just those are resent.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::format::OutputFormat;
+use crate::metric::ProcessedMetric;
+use crate::retry::{Backoff, Rejected};
+use crate::sinks::Sink;
+use crate::telemetry::SinkStats;
+
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    format: OutputFormat,
+    concurrency: usize,
+    retry_max: u32,
+    retry_initial: Duration,
+    retry_max_backoff: Duration,
+    stats: Arc<SinkStats>,
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    reqwest::Url::parse(&config.webhook_url)?;
+    config.output_format.parse::<OutputFormat>()?;
+    if config.webhook_concurrency == 0 {
+        return Err(anyhow!("--webhook-concurrency must be at least 1"));
+    }
+    headers(config).map(|_| ())
+}
+
+// "Name: value" pairs from --webhook-header
+fn headers(config: &Config) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for header in &config.webhook_header {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid webhook header, expected \"Name: value\": {}", header))?;
+        headers.append(HeaderName::try_from(name.trim())?, HeaderValue::try_from(value.trim())?);
+    }
+    Ok(headers)
+}
+
+impl WebhookSink {
+    pub fn open(config: &Config, stats: Arc<SinkStats>) -> Result<WebhookSink> {
+        info!("Starting webhook forwarder, target: {}", config.webhook_url);
+
+        let client = reqwest::Client::builder()
+            .default_headers(headers(config)?)
+            .timeout(Duration::from_millis(config.webhook_timeout_ms))
+            .build()?;
+        stats.set_ready(true);
+        Ok(WebhookSink {
+            client,
+            url: reqwest::Url::parse(&config.webhook_url)?,
+            format: config.output_format.parse()?,
+            concurrency: config.webhook_concurrency,
+            retry_max: config.retry_max,
+            retry_initial: Duration::from_millis(config.retry_initial_backoff_ms),
+            retry_max_backoff: Duration::from_millis(config.retry_max_backoff_ms),
+            stats,
+        })
+    }
+
+    fn request(&self, body: Vec<u8>) -> reqwest::RequestBuilder {
+        self.client
+            .post(self.url.clone())
+            .header("Content-Type", self.format.content_type())
+            .body(body)
+    }
+
+    // Send the bodies in parallel and return the ones worth sending again,
+    // each with the number of metrics in it
+    async fn post_all(&self, bodies: Vec<(Vec<u8>, usize)>) -> Vec<(Vec<u8>, usize)> {
+        let mut requests = JoinSet::new();
+        for (body, count) in bodies {
+            let request = self.request(body.clone());
+            requests.spawn(async move { (post(request).await, body, count) });
+        }
+
+        let mut failed = Vec::new();
+        while let Some(result) = requests.join_next().await {
+            let Ok((result, body, count)) = result else {
+                continue;
+            };
+            match result {
+                Ok(()) => self.stats.record_batch(body.len()),
+                Err(e) if e.is::<Rejected>() => {
+                    self.stats.record_failed(count);
+                    warn!("Webhook rejected {} metrics, dropping them: {}", count, e);
+                }
+                Err(e) => {
+                    debug!("Webhook request failed: {}", e);
+                    failed.push((body, count));
+                }
+            }
+        }
+        failed
+    }
+}
+
+async fn post(request: reqwest::RequestBuilder) -> Result<()> {
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let error = anyhow!("Webhook request failed with {}: {}", status, text);
+        if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(Rejected(error.to_string()).into());
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn send_batch(&mut self, batch: &[ProcessedMetric]) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let chunk_size = batch.len().div_ceil(self.concurrency);
+        let mut bodies = batch
+            .chunks(chunk_size)
+            .map(|chunk| Ok((self.format.encode_all(chunk)?, chunk.len())))
+            .collect::<Result<Vec<_>>>()?;
+
+        // A single request goes through the usual retries as it is
+        if bodies.len() == 1 {
+            let (body, _) = bodies.remove(0);
+            let bytes = body.len();
+            post(self.request(body)).await?;
+            self.stats.record_batch(bytes);
+            debug!("Forwarded batch of {} metrics", batch.len());
+            return Ok(());
+        }
+
+        let mut backoff = Backoff::new(self.retry_initial, self.retry_max_backoff);
+        let mut attempt = 0;
+        loop {
+            bodies = self.post_all(bodies).await;
+            if bodies.is_empty() {
+                break;
+            }
+            if self.retry_max > 0 && attempt >= self.retry_max {
+                return Err(anyhow!("{} webhook requests still failing after {} retries", bodies.len(), attempt));
+            }
+            self.stats.record_error();
+            attempt += 1;
+            let delay = backoff.next_delay();
+            warn!("{} webhook requests failed, resending them in {:?}", bodies.len(), delay);
+            tokio::time::sleep(delay).await;
+        }
+
+        debug!("Forwarded batch of {} metrics", batch.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,736 @@
+// SNMP GET requests and their responses, BER-encoded by hand: just enough of
+// RFC 3416 for polling, over v2c with a community or v3 with the user-based
+// security model of RFC 3414 (HMAC-MD5-96 or HMAC-SHA-96 authentication) and
+// RFC 3826 AES-128 privacy.
+//
+// A v3 agent is first asked for its engine ID, boots and time with an empty
+// unauthenticated request; the keys derived from the passwords are localized
+// to that engine and every later request carries its clock.
+use aes::cipher::{AsyncStreamCipher, KeyIvInit};
+use anyhow::{anyhow, bail, Result};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use std::time::Instant;
+
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const COUNTER32: u8 = 0x41;
+const GAUGE32: u8 = 0x42;
+const TIME_TICKS: u8 = 0x43;
+const COUNTER64: u8 = 0x46;
+
+const GET_REQUEST: u8 = 0xa0;
+pub const RESPONSE: u8 = 0xa2;
+pub const REPORT: u8 = 0xa8;
+
+const VERSION_2C: i64 = 1;
+const VERSION_3: i64 = 3;
+const USM: i64 = 3;
+// Largest message we take, what fits in a UDP datagram
+pub const MAX_MESSAGE: usize = 65507;
+
+const FLAG_AUTH: u8 = 0x01;
+const FLAG_PRIV: u8 = 0x02;
+const FLAG_REPORTABLE: u8 = 0x04;
+// HMAC-*-96 keeps the first 12 bytes of the digest
+const AUTH_PARAMS_LEN: usize = 12;
+
+type Aes128CfbEnc = cfb_mode::Encryptor<aes::Aes128>;
+type Aes128CfbDec = cfb_mode::Decryptor<aes::Aes128>;
+
+pub type Oid = Vec<u32>;
+
+pub fn parse_oid(s: &str) -> Result<Oid> {
+    let oid = s
+        .trim_start_matches('.')
+        .split('.')
+        .map(|arc| arc.parse::<u32>())
+        .collect::<Result<Oid, _>>()
+        .map_err(|_| anyhow!("Invalid OID {:?}", s))?;
+    // The first two arcs are encoded together as oid[0] * 40 + oid[1], which has to fit
+    if oid.len() < 2 || oid[0] > 2 || (oid[0] < 2 && oid[1] >= 40) || oid[1] > u32::MAX - 80 {
+        bail!("Invalid OID {:?}", s);
+    }
+    Ok(oid)
+}
+
+pub fn format_oid(oid: &[u32]) -> String {
+    oid.iter().map(u32::to_string).collect::<Vec<_>>().join(".")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    // Counter32 and Counter64
+    Counter(u64),
+    // Gauge32, Unsigned32 and TimeTicks
+    Gauge(u64),
+    OctetString(Vec<u8>),
+    // noSuchObject, noSuchInstance, endOfMibView or NULL
+    Missing,
+    // Types that can't be a number, like OIDs and addresses
+    Other,
+}
+
+#[derive(Debug)]
+pub struct Pdu {
+    pub tag: u8,
+    pub request_id: i32,
+    pub error_status: i64,
+    pub error_index: i64,
+    pub varbinds: Vec<(Oid, Value)>,
+}
+
+// Names of the RFC 3416 error-status values
+pub fn error_name(status: i64) -> &'static str {
+    match status {
+        1 => "tooBig",
+        2 => "noSuchName",
+        3 => "badValue",
+        4 => "readOnly",
+        5 => "genErr",
+        6 => "noAccess",
+        13 => "resourceUnavailable",
+        16 => "authorizationError",
+        _ => "error",
+    }
+}
+
+// Names of the usmStats counters an agent reports a refused request with
+pub fn report_name(oid: &[u32]) -> &'static str {
+    match oid {
+        [1, 3, 6, 1, 6, 3, 15, 1, 1, 1, ..] => "unsupported security level",
+        [1, 3, 6, 1, 6, 3, 15, 1, 1, 2, ..] => "not in time window",
+        [1, 3, 6, 1, 6, 3, 15, 1, 1, 3, ..] => "unknown user name",
+        [1, 3, 6, 1, 6, 3, 15, 1, 1, 4, ..] => "unknown engine ID",
+        [1, 3, 6, 1, 6, 3, 15, 1, 1, 5, ..] => "wrong digest",
+        [1, 3, 6, 1, 6, 3, 15, 1, 1, 6, ..] => "decryption error",
+        _ => "report",
+    }
+}
+
+// Reports after which rediscovering the engine and retrying may help
+pub fn is_resync(oid: &[u32]) -> bool {
+    matches!(oid, [1, 3, 6, 1, 6, 3, 15, 1, 1, 2 | 4, ..])
+}
+
+// Encoding
+
+fn push_len(out: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len() + 4);
+    out.push(tag);
+    push_len(&mut out, content.len());
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // Drop leading bytes that only repeat the sign
+    let mut start = 0;
+    while start < 7 {
+        let (byte, next) = (bytes[start], bytes[start + 1]);
+        if (byte == 0 && next & 0x80 == 0) || (byte == 0xff && next & 0x80 != 0) {
+            start += 1;
+        } else {
+            break;
+        }
+    }
+    tlv(INTEGER, &bytes[start..])
+}
+
+fn octets(value: &[u8]) -> Vec<u8> {
+    tlv(OCTET_STRING, value)
+}
+
+fn oid(oid: &[u32]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let first = oid[0] * 40 + oid[1];
+    for &arc in std::iter::once(&first).chain(&oid[2..]) {
+        let mut digits = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            digits.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        content.extend(digits.iter().rev());
+    }
+    tlv(OBJECT_IDENTIFIER, &content)
+}
+
+fn get_request(request_id: i32, oids: &[Oid]) -> Vec<u8> {
+    let varbinds: Vec<u8> = oids
+        .iter()
+        .flat_map(|o| tlv(SEQUENCE, &[oid(o), tlv(NULL, &[])].concat()))
+        .collect();
+    let content = [
+        integer(request_id as i64),
+        integer(0),
+        integer(0),
+        tlv(SEQUENCE, &varbinds),
+    ]
+    .concat();
+    tlv(GET_REQUEST, &content)
+}
+
+pub fn encode_v2c(community: &str, request_id: i32, oids: &[Oid]) -> Vec<u8> {
+    let content = [
+        integer(VERSION_2C),
+        octets(community.as_bytes()),
+        get_request(request_id, oids),
+    ]
+    .concat();
+    tlv(SEQUENCE, &content)
+}
+
+// Decoding
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    fn next(&mut self) -> Result<(u8, &'a [u8])> {
+        let [tag, first, rest @ ..] = self.data else {
+            bail!("Truncated message");
+        };
+        let (len, rest) = if first & 0x80 == 0 {
+            (*first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                bail!("Invalid length");
+            }
+            let len = rest[..count].iter().fold(0usize, |len, b| len << 8 | *b as usize);
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            bail!("Truncated message");
+        }
+        self.data = &rest[len..];
+        Ok((*tag, &rest[..len]))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8]> {
+        let (found, content) = self.next()?;
+        if found != tag {
+            bail!("Expected tag {:#04x}, found {:#04x}", tag, found);
+        }
+        Ok(content)
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        signed(self.expect(INTEGER)?)
+    }
+}
+
+fn signed(content: &[u8]) -> Result<i64> {
+    if content.is_empty() || content.len() > 8 {
+        bail!("Invalid integer");
+    }
+    let sign = if content[0] & 0x80 != 0 { -1i64 } else { 0 };
+    Ok(content.iter().fold(sign, |value, b| value << 8 | *b as i64))
+}
+
+fn unsigned(content: &[u8]) -> Result<u64> {
+    // A leading zero byte keeps the top bit of a 64 bit counter positive
+    let content = content.strip_prefix(&[0]).filter(|rest| !rest.is_empty()).unwrap_or(content);
+    if content.len() > 8 {
+        bail!("Invalid unsigned integer");
+    }
+    Ok(content.iter().fold(0u64, |value, b| value << 8 | *b as u64))
+}
+
+fn decode_oid(content: &[u8]) -> Result<Oid> {
+    let mut arcs = Vec::new();
+    let mut arc: u32 = 0;
+    for b in content {
+        arc = arc.checked_mul(128).ok_or_else(|| anyhow!("OID arc too large"))? | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+    let Some(&first) = arcs.first() else {
+        bail!("Empty OID");
+    };
+    let (a, b) = if first < 80 { (first / 40, first % 40) } else { (2, first - 80) };
+    Ok([a, b].into_iter().chain(arcs.into_iter().skip(1)).collect())
+}
+
+fn decode_value(tag: u8, content: &[u8]) -> Result<Value> {
+    Ok(match tag {
+        INTEGER => Value::Integer(signed(content)?),
+        COUNTER32 | COUNTER64 => Value::Counter(unsigned(content)?),
+        GAUGE32 | TIME_TICKS => Value::Gauge(unsigned(content)?),
+        OCTET_STRING => Value::OctetString(content.to_vec()),
+        NULL | 0x80..=0x82 => Value::Missing,
+        _ => Value::Other,
+    })
+}
+
+fn decode_pdu(tag: u8, content: &[u8]) -> Result<Pdu> {
+    let mut pdu = Reader::new(content);
+    let request_id = pdu.integer()? as i32;
+    let error_status = pdu.integer()?;
+    let error_index = pdu.integer()?;
+    let mut list = Reader::new(pdu.expect(SEQUENCE)?);
+    let mut varbinds = Vec::new();
+    while !list.data.is_empty() {
+        let mut varbind = Reader::new(list.expect(SEQUENCE)?);
+        let name = decode_oid(varbind.expect(OBJECT_IDENTIFIER)?)?;
+        let (tag, value) = varbind.next()?;
+        varbinds.push((name, decode_value(tag, value)?));
+    }
+    Ok(Pdu {
+        tag,
+        request_id,
+        error_status,
+        error_index,
+        varbinds,
+    })
+}
+
+pub fn decode_v2c(packet: &[u8], community: &str) -> Result<Pdu> {
+    let mut message = Reader::new(Reader::new(packet).expect(SEQUENCE)?);
+    if message.integer()? != VERSION_2C {
+        bail!("Not an SNMPv2c message");
+    }
+    if message.expect(OCTET_STRING)? != community.as_bytes() {
+        bail!("Response for another community");
+    }
+    let (tag, content) = message.next()?;
+    decode_pdu(tag, content)
+}
+
+// SNMPv3
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthProtocol {
+    Md5,
+    Sha,
+}
+
+impl AuthProtocol {
+    pub fn parse(s: &str) -> Result<AuthProtocol> {
+        match s.to_ascii_lowercase().as_str() {
+            "md5" => Ok(AuthProtocol::Md5),
+            "sha" | "sha1" => Ok(AuthProtocol::Sha),
+            _ => bail!("Invalid SNMP auth protocol {:?}, expected md5 or sha", s),
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            AuthProtocol::Md5 => Md5::digest(data).to_vec(),
+            AuthProtocol::Sha => Sha1::digest(data).to_vec(),
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            AuthProtocol::Md5 => {
+                let mut mac = Hmac::<Md5>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            AuthProtocol::Sha => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    // RFC 3414 A.2: the digest of a megabyte of the password repeated
+    fn password_to_key(self, password: &str) -> Vec<u8> {
+        const EXPANDED: usize = 1 << 20;
+        let expanded: Vec<u8> = password.bytes().cycle().take(EXPANDED).collect();
+        self.digest(&expanded)
+    }
+
+    fn localize(self, key: &[u8], engine_id: &[u8]) -> Vec<u8> {
+        self.digest(&[key, engine_id, key].concat())
+    }
+}
+
+// A v3 user and the keys derived from its passwords, not yet localized
+pub struct Credentials {
+    pub user: String,
+    auth: Option<(AuthProtocol, Vec<u8>)>,
+    privacy: Option<Vec<u8>>,
+}
+
+impl Credentials {
+    // Privacy needs authentication, the caller checks that
+    pub fn new(user: &str, auth: Option<(AuthProtocol, &str)>, privacy: Option<&str>) -> Credentials {
+        let auth = auth.map(|(protocol, password)| (protocol, protocol.password_to_key(password)));
+        // The privacy key is derived with the authentication hash
+        let privacy = auth
+            .as_ref()
+            .zip(privacy)
+            .map(|((protocol, _), password)| protocol.password_to_key(password));
+        Credentials {
+            user: user.to_string(),
+            auth,
+            privacy,
+        }
+    }
+}
+
+// The agent's engine as learned by discovery, with the keys localized to it
+pub struct Engine {
+    pub id: Vec<u8>,
+    boots: u32,
+    time: u32,
+    discovered: Instant,
+    auth: Option<(AuthProtocol, Vec<u8>)>,
+    privacy: Option<Vec<u8>>,
+}
+
+impl Engine {
+    pub fn new(id: &[u8], boots: u32, time: u32, credentials: &Credentials) -> Engine {
+        let auth = credentials
+            .auth
+            .as_ref()
+            .map(|(protocol, key)| (*protocol, protocol.localize(key, id)));
+        let privacy = credentials
+            .auth
+            .as_ref()
+            .zip(credentials.privacy.as_ref())
+            .map(|((protocol, _), key)| protocol.localize(key, id)[..16].to_vec());
+        Engine {
+            id: id.to_vec(),
+            boots,
+            time,
+            discovered: Instant::now(),
+            auth,
+            privacy,
+        }
+    }
+
+    // The agent's clock now, as far as we can tell
+    fn time(&self) -> u32 {
+        self.time.saturating_add(self.discovered.elapsed().as_secs() as u32)
+    }
+}
+
+fn iv(boots: u32, time: u32, salt: &[u8]) -> [u8; 16] {
+    let mut iv = [0; 16];
+    iv[..4].copy_from_slice(&boots.to_be_bytes());
+    iv[4..8].copy_from_slice(&time.to_be_bytes());
+    iv[8..].copy_from_slice(salt);
+    iv
+}
+
+// An unauthenticated request without varbinds, answered with a report that
+// carries the agent's engine ID, boots and time
+pub fn encode_discovery(message_id: i32, request_id: i32) -> Vec<u8> {
+    let header = tlv(SEQUENCE, &[
+        integer(message_id as i64),
+        integer(MAX_MESSAGE as i64),
+        octets(&[FLAG_REPORTABLE]),
+        integer(USM),
+    ]
+    .concat());
+    let security = tlv(SEQUENCE, &[
+        octets(&[]),
+        integer(0),
+        integer(0),
+        octets(&[]),
+        octets(&[]),
+        octets(&[]),
+    ]
+    .concat());
+    let scoped = tlv(SEQUENCE, &[octets(&[]), octets(&[]), get_request(request_id, &[])].concat());
+    tlv(SEQUENCE, &[integer(VERSION_3), header, octets(&security), scoped].concat())
+}
+
+// salt makes the privacy IV unique, it must not repeat for an engine's boots
+pub fn encode_v3(
+    credentials: &Credentials,
+    engine: &Engine,
+    message_id: i32,
+    request_id: i32,
+    oids: &[Oid],
+    salt: u64,
+) -> Vec<u8> {
+    let time = engine.time();
+    let scoped = tlv(SEQUENCE, &[octets(&engine.id), octets(&[]), get_request(request_id, oids)].concat());
+    let mut flags = FLAG_REPORTABLE;
+    let mut priv_params = Vec::new();
+    let data = match &engine.privacy {
+        Some(key) => {
+            flags |= FLAG_PRIV;
+            priv_params = salt.to_be_bytes().to_vec();
+            let mut encrypted = scoped;
+            Aes128CfbEnc::new(key.as_slice().into(), &iv(engine.boots, time, &priv_params).into())
+                .encrypt(&mut encrypted);
+            octets(&encrypted)
+        }
+        None => scoped,
+    };
+    if engine.auth.is_some() {
+        flags |= FLAG_AUTH;
+    }
+    let auth_params = if engine.auth.is_some() { vec![0; AUTH_PARAMS_LEN] } else { Vec::new() };
+
+    let header = tlv(SEQUENCE, &[
+        integer(message_id as i64),
+        integer(MAX_MESSAGE as i64),
+        octets(&[flags]),
+        integer(USM),
+    ]
+    .concat());
+    let before_auth = [
+        octets(&engine.id),
+        integer(engine.boots as i64),
+        integer(time as i64),
+        octets(credentials.user.as_bytes()),
+    ]
+    .concat();
+    let security_content = [before_auth.clone(), octets(&auth_params), octets(&priv_params)].concat();
+    let security = octets(&tlv(SEQUENCE, &security_content));
+    let body = [integer(VERSION_3), header.clone(), security.clone(), data].concat();
+    let mut message = tlv(SEQUENCE, &body);
+
+    if let Some((protocol, key)) = &engine.auth {
+        // Where the placeholder sits: past every header, then past its own tag and length
+        let offset = (message.len() - body.len())
+            + integer(VERSION_3).len()
+            + header.len()
+            + (security.len() - security_content.len())
+            + before_auth.len()
+            + 2;
+        let digest = protocol.hmac(key, &message);
+        message[offset..offset + AUTH_PARAMS_LEN].copy_from_slice(&digest[..AUTH_PARAMS_LEN]);
+    }
+    message
+}
+
+pub struct Reply {
+    pub engine_id: Vec<u8>,
+    pub boots: u32,
+    pub time: u32,
+    pub pdu: Pdu,
+}
+
+// Decodes a v3 reply, checking its digest and decrypting it when it's
+// authenticated or encrypted. Without an engine only unauthenticated
+// replies, i.e. discovery reports, can be read.
+pub fn decode_v3(packet: &[u8], engine: Option<&Engine>) -> Result<Reply> {
+    let mut message = Reader::new(Reader::new(packet).expect(SEQUENCE)?);
+    if message.integer()? != VERSION_3 {
+        bail!("Not an SNMPv3 message");
+    }
+    let mut header = Reader::new(message.expect(SEQUENCE)?);
+    let _message_id = header.integer()?;
+    let _max_size = header.integer()?;
+    let flags = header.expect(OCTET_STRING)?.first().copied().unwrap_or_default();
+    if header.integer()? != USM {
+        bail!("Unsupported security model");
+    }
+    let mut security = Reader::new(Reader::new(message.expect(OCTET_STRING)?).expect(SEQUENCE)?);
+    let engine_id = security.expect(OCTET_STRING)?.to_vec();
+    let boots = security.integer()? as u32;
+    let time = security.integer()? as u32;
+    let _user = security.expect(OCTET_STRING)?;
+    let auth_params = security.expect(OCTET_STRING)?;
+    let priv_params = security.expect(OCTET_STRING)?;
+
+    if flags & FLAG_AUTH != 0 {
+        let Some((protocol, key)) = engine.and_then(|engine| engine.auth.as_ref()) else {
+            bail!("Authenticated reply without a key to check it");
+        };
+        if auth_params.len() != AUTH_PARAMS_LEN {
+            bail!("Invalid authentication parameters");
+        }
+        let offset = auth_params.as_ptr() as usize - packet.as_ptr() as usize;
+        let mut zeroed = packet.to_vec();
+        zeroed[offset..offset + AUTH_PARAMS_LEN].fill(0);
+        if protocol.hmac(key, &zeroed)[..AUTH_PARAMS_LEN] != *auth_params {
+            bail!("Reply failed authentication");
+        }
+    }
+
+    let scoped = if flags & FLAG_PRIV != 0 {
+        let Some(key) = engine.and_then(|engine| engine.privacy.as_ref()) else {
+            bail!("Encrypted reply without a key to decrypt it");
+        };
+        if priv_params.len() != 8 {
+            bail!("Invalid privacy parameters");
+        }
+        let mut decrypted = message.expect(OCTET_STRING)?.to_vec();
+        Aes128CfbDec::new(key.as_slice().into(), &iv(boots, time, priv_params).into()).decrypt(&mut decrypted);
+        decrypted
+    } else {
+        tlv(SEQUENCE, message.expect(SEQUENCE)?)
+    };
+    let mut scoped = Reader::new(Reader::new(&scoped).expect(SEQUENCE)?);
+    let _context_engine = scoped.expect(OCTET_STRING)?;
+    let _context_name = scoped.expect(OCTET_STRING)?;
+    let (tag, content) = scoped.next()?;
+    Ok(Reply {
+        engine_id,
+        boots,
+        time,
+        pdu: decode_pdu(tag, content)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        let hex: String = hex.split_whitespace().collect();
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn encodes_v2c_get_like_net_snmp() {
+        // snmpget -v2c -c public host sysDescr.0, request ID 1
+        let expected = unhex("30 26 02 01 01 04 06 70 75 62 6c 69 63 a0 19 02 01 01 02 01 00 02 01 00 30 0e 30 0c 06 08 2b 06 01 02 01 01 01 00 05 00");
+        assert_eq!(encode_v2c("public", 1, &[parse_oid("1.3.6.1.2.1.1.1.0").unwrap()]), expected);
+    }
+
+    #[test]
+    fn decodes_v2c_response() {
+        // Response to request 0x12345678: sysUpTime.0 TimeTicks, ifInOctets.1
+        // Counter32, ifHCInOctets.1 Counter64 with its top bit set, ifSpeed.1
+        // Gauge32, a negative Integer, sysName.0 and a noSuchObject
+        let packet = unhex(
+            "30 81 a4 02 01 01 04 06 70 75 62 6c 69 63 a2 81 96 02 04 12 34 56 78 02 01 00 02 01 00 30 81 87
+             30 10 06 08 2b 06 01 02 01 01 03 00 43 04 00 bc 61 4e
+             30 11 06 0a 2b 06 01 02 01 02 02 01 0a 01 41 03 01 00 00
+             30 18 06 0b 2b 06 01 02 01 1f 01 01 01 06 01 46 09 00 80 00 00 00 00 00 00 01
+             30 12 06 0a 2b 06 01 02 01 02 02 01 05 01 42 04 3b 9a ca 00
+             30 0f 06 0a 2b 06 01 04 01 81 9d 0e 01 02 02 01 ff
+             30 12 06 08 2b 06 01 02 01 01 05 00 04 06 72 6f 75 74 65 72
+             30 0d 06 09 2b 06 01 04 01 81 9d 0e 02 80 00",
+        );
+        let pdu = decode_v2c(&packet, "public").unwrap();
+        assert_eq!(pdu.tag, RESPONSE);
+        assert_eq!(pdu.request_id, 0x12345678);
+        assert_eq!(pdu.error_status, 0);
+        let values: Vec<(String, Value)> = pdu.varbinds.into_iter().map(|(oid, value)| (format_oid(&oid), value)).collect();
+        assert_eq!(
+            values,
+            vec![
+                ("1.3.6.1.2.1.1.3.0".to_string(), Value::Gauge(12345678)),
+                ("1.3.6.1.2.1.2.2.1.10.1".to_string(), Value::Counter(65536)),
+                ("1.3.6.1.2.1.31.1.1.1.6.1".to_string(), Value::Counter(0x8000_0000_0000_0001)),
+                ("1.3.6.1.2.1.2.2.1.5.1".to_string(), Value::Gauge(1_000_000_000)),
+                ("1.3.6.1.4.1.20110.1.2".to_string(), Value::Integer(-1)),
+                ("1.3.6.1.2.1.1.5.0".to_string(), Value::OctetString(b"router".to_vec())),
+                ("1.3.6.1.4.1.20110.2".to_string(), Value::Missing),
+            ]
+        );
+        assert!(decode_v2c(&packet, "private").is_err());
+        assert!(decode_v2c(&packet[..packet.len() - 1], "public").is_err());
+    }
+
+    #[test]
+    fn round_trips_oids_and_integers() {
+        for s in ["1.3.6.1.2.1.1.3.0", "0.39", "2.999.1", "1.3.6.1.4.1.4294967295", "2.4294967215"] {
+            let encoded = oid(&parse_oid(s).unwrap());
+            let (tag, content) = Reader::new(&encoded).next().unwrap();
+            assert_eq!(tag, OBJECT_IDENTIFIER);
+            assert_eq!(format_oid(&decode_oid(content).unwrap()), s);
+        }
+        for s in ["2.4294967295", "2.4294967216", "1.40", "3.1", "1", "1.3.x"] {
+            assert!(parse_oid(s).is_err(), "{}", s);
+        }
+        for value in [0, 1, -1, 127, 128, -128, -129, 255, 256, i32::MAX as i64, i64::MIN, i64::MAX] {
+            let encoded = integer(value);
+            assert_eq!(Reader::new(&encoded).integer().unwrap(), value);
+        }
+        // Minimal encodings
+        assert_eq!(integer(127), [INTEGER, 1, 0x7f]);
+        assert_eq!(integer(128), [INTEGER, 2, 0x00, 0x80]);
+        assert_eq!(integer(-129), [INTEGER, 2, 0xff, 0x7f]);
+        // Long form lengths
+        let long = octets(&[0; 300]);
+        assert_eq!(&long[..4], [OCTET_STRING, 0x82, 0x01, 0x2c]);
+        assert_eq!(Reader::new(&long).expect(OCTET_STRING).unwrap().len(), 300);
+    }
+
+    // RFC 3414 A.3.1 and A.3.2
+    #[test]
+    fn derives_keys_like_rfc_3414() {
+        let engine_id = unhex("00 00 00 00 00 00 00 00 00 00 00 02");
+        let md5 = AuthProtocol::Md5.password_to_key("maplesyrup");
+        assert_eq!(md5, unhex("9f af 32 83 88 4e 92 83 4e bc 98 47 d8 ed d9 63"));
+        assert_eq!(
+            AuthProtocol::Md5.localize(&md5, &engine_id),
+            unhex("52 6f 5e ed 9f cc e2 6f 89 64 c2 93 07 87 d8 2b")
+        );
+        let sha = AuthProtocol::Sha.password_to_key("maplesyrup");
+        assert_eq!(sha, unhex("9f b5 cc 03 81 49 7b 37 93 52 89 39 ff 78 8d 5d 79 14 52 11"));
+        assert_eq!(
+            AuthProtocol::Sha.localize(&sha, &engine_id),
+            unhex("66 95 fe bc 92 88 e3 62 82 23 5f c7 15 1f 12 84 97 b3 8f 3f")
+        );
+    }
+
+    #[test]
+    fn round_trips_discovery() {
+        let packet = encode_discovery(7, 7);
+        let reply = decode_v3(&packet, None).unwrap();
+        assert!(reply.engine_id.is_empty());
+        assert_eq!(reply.pdu.tag, GET_REQUEST);
+        assert_eq!(reply.pdu.request_id, 7);
+        assert!(reply.pdu.varbinds.is_empty());
+    }
+
+    #[test]
+    fn round_trips_authenticated_and_encrypted_v3() {
+        let engine_id = unhex("80 00 1f 88 80 59 dc 48 61 45 a2 63 22");
+        let oids = vec![parse_oid("1.3.6.1.2.1.1.3.0").unwrap(), parse_oid("1.3.6.1.2.1.1.5.0").unwrap()];
+        for protocol in [AuthProtocol::Md5, AuthProtocol::Sha] {
+            for privacy in [None, Some("privpass1")] {
+                let credentials = Credentials::new("monitor", Some((protocol, "authpass1")), privacy);
+                let engine = Engine::new(&engine_id, 3, 1000, &credentials);
+                let packet = encode_v3(&credentials, &engine, 42, 43, &oids, 0x0102030405060708);
+
+                let reply = decode_v3(&packet, Some(&engine)).unwrap();
+                assert_eq!(reply.engine_id, engine_id);
+                assert_eq!(reply.boots, 3);
+                assert_eq!(reply.pdu.request_id, 43);
+                let names: Vec<Oid> = reply.pdu.varbinds.into_iter().map(|(oid, _)| oid).collect();
+                assert_eq!(names, oids);
+
+                // The digest covers the whole message, the user name included
+                let mut tampered = packet.clone();
+                let user = tampered.windows(7).position(|w| w == b"monitor").unwrap();
+                tampered[user] = b'M';
+                let error = decode_v3(&tampered, Some(&engine)).err().unwrap();
+                assert_eq!(error.to_string(), "Reply failed authentication");
+
+                // Keys localized to another engine don't check out
+                let other = Engine::new(b"other engine", 3, 1000, &credentials);
+                assert!(decode_v3(&packet, Some(&other)).is_err());
+                assert!(decode_v3(&packet, None).is_err());
+            }
+        }
+    }
+}
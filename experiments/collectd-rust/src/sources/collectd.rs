@@ -0,0 +1,70 @@
+// UDP listener for the collectd `network` plugin
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::acl::AllowList;
+use crate::collectd_binary::parse_packet;
+use crate::metric::Tags;
+use crate::pipeline::Pipeline;
+use crate::queue::QueueError;
+use crate::sources::Source;
+use crate::telemetry::Telemetry;
+
+pub struct CollectdSource {
+    socket: UdpSocket,
+    addr: String,
+    allow: Arc<AllowList>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl CollectdSource {
+    pub async fn open(addr: &str, allow: Arc<AllowList>, telemetry: Arc<Telemetry>) -> Result<CollectdSource> {
+        let socket = UdpSocket::bind(addr).await?;
+        info!("Listening for collectd binary protocol on udp://{}", addr);
+        Ok(CollectdSource {
+            socket,
+            addr: addr.to_string(),
+            allow,
+            telemetry,
+        })
+    }
+}
+
+#[async_trait]
+impl Source for CollectdSource {
+    fn name(&self) -> String {
+        format!("udp://{}", self.addr)
+    }
+
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        // Largest possible UDP payload, collectd itself defaults to 1452 byte packets
+        let mut buf = vec![0u8; 65535];
+
+        loop {
+            let (len, peer) = self.socket.recv_from(&mut buf).await?;
+            if !self.allow.allows(peer.ip()) {
+                self.telemetry.record_denied();
+                debug!("Ignoring collectd packet from {}, not in --allow-cidr", peer);
+                continue;
+            }
+            let raw_metrics = match parse_packet(&buf[..len]) {
+                Ok(metrics) => metrics,
+                Err(e) => {
+                    self.telemetry.record_parse_failure();
+                    warn!("Failed to parse collectd packet from {}: {}", peer, e);
+                    continue;
+                }
+            };
+
+            debug!("Received {} metrics from {}", raw_metrics.len(), peer);
+
+            // There's no one to answer a 503 to, a full queue just drops the packet
+            if let Err(QueueError::Closed) = pipeline.ingest_from(raw_metrics, &Tags::new(), Some(peer.ip())).await {
+                bail!("Processing queue closed");
+            }
+        }
+    }
+}
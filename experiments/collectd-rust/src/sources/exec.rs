@@ -0,0 +1,354 @@
+// Exec input: runs external commands and reads metrics from their stdout, one
+// per line, in the PUTVAL format of collectd's exec plugin or as collectd JSON
+// (a metric object or array of them). --exec commands are run every
+// --exec-interval and killed after --exec-timeout, in place of cron jobs piped
+// into curl. --exec-daemon commands keep running and print as they go, like
+// collectd's exec scripts, and are started again after they exit as
+// --exec-restart says. Commands run through `sh -c` with COLLECTD_HOSTNAME and
+// COLLECTD_INTERVAL set, and their stderr is logged.
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use futures_util::future::{try_join_all, BoxFuture, FutureExt};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sysinfo::System;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{ChildStdout, Command};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use crate::config::{self, Config};
+use crate::metric::{CollectdMetric, Tags};
+use crate::pipeline::Pipeline;
+use crate::queue::QueueError;
+use crate::server;
+use crate::sources::Source;
+use crate::telemetry::Telemetry;
+
+// Ingest at least this often while a command keeps printing
+const MAX_LINES_PER_INGEST: usize = 1000;
+// How long to wait before asking a full queue again
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Restart {
+    Always,
+    OnFailure,
+    Never,
+}
+
+impl Restart {
+    fn parse(s: &str) -> Result<Restart> {
+        match s {
+            "always" => Ok(Restart::Always),
+            "on-failure" => Ok(Restart::OnFailure),
+            "never" => Ok(Restart::Never),
+            _ => bail!("Invalid --exec-restart {:?}, expected always, on-failure or never", s),
+        }
+    }
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    Restart::parse(&config.exec_restart)?;
+    for (flag, value) in [
+        ("--exec-interval", &config.exec_interval),
+        ("--exec-timeout", &config.exec_timeout),
+        ("--exec-restart-delay", &config.exec_restart_delay),
+    ] {
+        if config::parse_duration(value)?.is_zero() {
+            bail!("{} must be positive", flag);
+        }
+    }
+    Ok(())
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+// "plugin-instance" or "type-instance", split at the first dash
+fn split_instance(name: &str) -> (Option<String>, Option<String>) {
+    match name.split_once('-') {
+        Some((name, instance)) => (Some(name.to_string()), Some(instance.to_string())),
+        None => (Some(name.to_string()), None),
+    }
+}
+
+// `PUTVAL <host>/<plugin>[-<instance>]/<type>[-<instance>] [interval=<seconds>] <time>:<value>[:<value>...] ...`
+// with the identifier optionally quoted, N for the time meaning now and one
+// metric per value list. Value lists with an unknown (U) value are skipped.
+fn parse_putval(args: &str, now: f64) -> Result<Vec<CollectdMetric>> {
+    let args = args.trim_start();
+    let (identifier, rest) = match args.strip_prefix('"') {
+        Some(quoted) => quoted.split_once('"').ok_or_else(|| anyhow!("Unterminated identifier"))?,
+        None => args.split_once(char::is_whitespace).unwrap_or((args, "")),
+    };
+    let [host, plugin, type_] = identifier.splitn(3, '/').collect::<Vec<_>>()[..] else {
+        bail!("Invalid identifier {:?}, expected host/plugin/type", identifier);
+    };
+    if host.is_empty() || plugin.is_empty() || type_.is_empty() {
+        bail!("Invalid identifier {:?}, expected host/plugin/type", identifier);
+    }
+    let (plugin, plugin_instance) = split_instance(plugin);
+    let (type_, type_instance) = split_instance(type_);
+
+    let mut interval = None;
+    let mut value_lists = Vec::new();
+    for field in rest.split_whitespace() {
+        match field.split_once('=') {
+            Some(("interval", seconds)) => {
+                interval = Some(seconds.parse().map_err(|_| anyhow!("Invalid interval {:?}", seconds))?)
+            }
+            // Other options, like meta data, aren't kept
+            Some(_) => {}
+            None => value_lists.push(field),
+        }
+    }
+    if value_lists.is_empty() {
+        bail!("Missing values");
+    }
+
+    let mut metrics = Vec::new();
+    for list in value_lists {
+        let mut fields = list.split(':');
+        let time = match fields.next() {
+            Some("N") => now,
+            Some(time) => time.parse().map_err(|_| anyhow!("Invalid time {:?}", time))?,
+            None => now,
+        };
+        let fields: Vec<&str> = fields.collect();
+        if fields.is_empty() {
+            bail!("Invalid value list {:?}, expected <time>:<value>", list);
+        }
+        if fields.contains(&"U") {
+            debug!("Skipping value list {:?} with an unknown value", list);
+            continue;
+        }
+        let values = fields
+            .iter()
+            .map(|value| serde_json::from_str::<serde_json::Number>(value).map(serde_json::Value::Number))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("Invalid value list {:?}", list))?;
+        metrics.push(CollectdMetric {
+            time: Some(time),
+            interval,
+            host: Some(host.to_string()),
+            plugin: plugin.clone(),
+            plugin_instance: plugin_instance.clone(),
+            type_: type_.clone(),
+            type_instance: type_instance.clone(),
+            value: None,
+            values: Some(values),
+            // Filled in from --typesdb
+            dstypes: None,
+            dsnames: None,
+        });
+    }
+    Ok(metrics)
+}
+
+pub struct ExecSource {
+    commands: Vec<String>,
+    daemons: Vec<String>,
+    interval: Duration,
+    timeout: Duration,
+    restart: Restart,
+    restart_delay: Duration,
+    hostname: String,
+    max_line: usize,
+    strict: bool,
+    telemetry: Arc<Telemetry>,
+}
+
+impl ExecSource {
+    pub fn open(config: &Config, telemetry: Arc<Telemetry>) -> Result<ExecSource> {
+        validate(config)?;
+        let commands = config.exec.iter().chain(&config.exec_daemon);
+        info!("Running {}", commands.cloned().collect::<Vec<_>>().join(", "));
+        Ok(ExecSource {
+            commands: config.exec.clone(),
+            daemons: config.exec_daemon.clone(),
+            interval: config::parse_duration(&config.exec_interval)?,
+            timeout: config::parse_duration(&config.exec_timeout)?,
+            restart: Restart::parse(&config.exec_restart)?,
+            restart_delay: config::parse_duration(&config.exec_restart_delay)?,
+            hostname: System::host_name().unwrap_or_else(|| "localhost".to_string()),
+            max_line: config.max_body_bytes,
+            strict: config.strict,
+            telemetry,
+        })
+    }
+
+    // The metrics on one line of output, problems are logged and counted
+    fn parse(&self, command: &str, line: &str) -> Vec<CollectdMetric> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("PUTNOTIF") {
+            return Vec::new();
+        }
+        if line.len() > self.max_line {
+            self.telemetry.record_invalid(1);
+            warn!("Skipping a line of {:?} over {} bytes", command, self.max_line);
+            return Vec::new();
+        }
+        let parsed = match line.strip_prefix("PUTVAL ") {
+            Some(args) => parse_putval(args, now()).map(|metrics| (metrics.into_iter().enumerate().collect(), Vec::new())),
+            None if line.starts_with(['{', '[']) => server::parse_metrics(line.as_bytes()).map_err(Into::into),
+            None => Err(anyhow!("expected PUTVAL or JSON")),
+        };
+        let (mut metrics, mut invalid) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.telemetry.record_parse_failure();
+                warn!("Failed to parse output of {:?}: {}", command, e);
+                return Vec::new();
+            }
+        };
+        if self.strict {
+            server::check_schema(&mut metrics, &mut invalid);
+        }
+        if let Some(first) = invalid.first() {
+            self.telemetry.record_invalid(invalid.len());
+            warn!("Skipping a metric from {:?}: {}", command, first.reason);
+        }
+        metrics.into_iter().map(|(_, metric)| metric).collect()
+    }
+
+    // The command waits on its pipe while the queue is full
+    async fn ingest(&self, pipeline: &Pipeline, metrics: Vec<CollectdMetric>) -> Result<()> {
+        loop {
+            match pipeline.ingest_from(metrics.clone(), &Tags::new(), None).await {
+                Ok(_) => return Ok(()),
+                Err(QueueError::Full) => tokio::time::sleep(RETRY_DELAY).await,
+                Err(QueueError::Closed) => bail!("Processing queue closed"),
+            }
+        }
+    }
+
+    // Lines already sitting in the read buffer are ingested together
+    async fn read_output(&self, command: &str, stdout: ChildStdout, pipeline: &Pipeline) -> Result<()> {
+        let mut lines = BufReader::new(stdout).lines();
+        let mut batch = Vec::new();
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Failed to read output of {:?}: {}", command, e);
+                    break;
+                }
+            };
+            batch.extend(self.parse(command, &line));
+            if !batch.is_empty() && (lines.get_ref().buffer().is_empty() || batch.len() >= MAX_LINES_PER_INGEST) {
+                self.ingest(pipeline, std::mem::take(&mut batch)).await?;
+            }
+        }
+        if !batch.is_empty() {
+            self.ingest(pipeline, batch).await?;
+        }
+        Ok(())
+    }
+
+    // Runs the command to its exit. The outer error is a closed queue, the
+    // inner one a command that couldn't be started or waited for.
+    async fn run_command(&self, command: &str, pipeline: &Pipeline) -> Result<std::io::Result<ExitStatus>> {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("COLLECTD_HOSTNAME", &self.hostname)
+            .env("COLLECTD_INTERVAL", self.interval.as_secs().to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // A command that timed out is killed with its future
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => return Ok(Err(e)),
+        };
+        if let Some(stderr) = child.stderr.take() {
+            let command = command.to_string();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    warn!("{:?}: {}", command, line);
+                }
+            });
+        }
+        if let Some(stdout) = child.stdout.take() {
+            self.read_output(command, stdout, pipeline).await?;
+        }
+        Ok(child.wait().await)
+    }
+
+    async fn run_periodic(&self, command: &str, pipeline: &Pipeline) -> Result<()> {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let failure = match tokio::time::timeout(self.timeout, self.run_command(command, pipeline)).await {
+                Ok(status) => match status? {
+                    Ok(status) if status.success() => continue,
+                    Ok(status) => format!("exited with {}", status),
+                    Err(e) => format!("failed: {}", e),
+                },
+                Err(_) => format!("killed after --exec-timeout of {:?}", self.timeout),
+            };
+            self.telemetry.record_exec_failure();
+            warn!("Command {:?} {}", command, failure);
+        }
+    }
+
+    async fn run_daemon(&self, command: &str, pipeline: &Pipeline) -> Result<()> {
+        loop {
+            let failed = match self.run_command(command, pipeline).await? {
+                Ok(status) if status.success() => {
+                    info!("Command {:?} exited", command);
+                    false
+                }
+                Ok(status) => {
+                    warn!("Command {:?} exited with {}", command, status);
+                    true
+                }
+                Err(e) => {
+                    warn!("Command {:?} failed: {}", command, e);
+                    true
+                }
+            };
+            if failed {
+                self.telemetry.record_exec_failure();
+            }
+            if self.restart == Restart::Never || (self.restart == Restart::OnFailure && !failed) {
+                return Ok(());
+            }
+            debug!("Restarting {:?} in {:?}", command, self.restart_delay);
+            tokio::time::sleep(self.restart_delay).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Source for ExecSource {
+    fn name(&self) -> String {
+        "exec".to_string()
+    }
+
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        let source = &*self;
+        let pipeline = &pipeline;
+        let mut tasks: Vec<BoxFuture<Result<()>>> = Vec::new();
+        for command in &source.commands {
+            tasks.push(source.run_periodic(command, pipeline).boxed());
+        }
+        for command in &source.daemons {
+            tasks.push(source.run_daemon(command, pipeline).boxed());
+        }
+        try_join_all(tasks).await?;
+        // Every daemon exited for good, the other inputs keep the receiver going
+        std::future::pending().await
+    }
+}
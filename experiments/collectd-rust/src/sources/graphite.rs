@@ -0,0 +1,232 @@
+// Graphite plaintext listener, for carbon-relay and other legacy clients.
+// Each line is `<dotted.path> <value> [<unix timestamp>]`. The template maps
+// path segments onto collectd fields, e.g. `host.plugin.type*` turns
+// `web01.cpu.idle.percent` into host=web01, plugin=cpu, type=idle.percent.
+// `_` skips a segment and a trailing `*` takes the rest of the path.
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+use crate::filter::Field;
+use crate::metric::{CollectdMetric, Tags};
+use crate::acl::AllowList;
+use crate::pipeline::Pipeline;
+use crate::queue::QueueError;
+use crate::sources::Source;
+use crate::telemetry::Telemetry;
+
+// Flush a connection's lines to the pipeline at least this often
+const MAX_LINES_PER_INGEST: usize = 1000;
+
+// A client sending a longer line is dropped rather than buffered without end
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    // None skips the segment
+    segments: Vec<Option<Field>>,
+    // The last field swallows any remaining segments
+    greedy: bool,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template> {
+        let parts: Vec<&str> = template.split('.').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        let mut greedy = false;
+        for (i, part) in parts.iter().enumerate() {
+            let name = match part.strip_suffix('*') {
+                Some(name) if i == parts.len() - 1 => {
+                    greedy = true;
+                    name
+                }
+                Some(_) => bail!("Only the last segment of a Graphite template can end in *: {}", template),
+                None => part,
+            };
+            if name == "_" {
+                if greedy {
+                    bail!("The greedy segment of a Graphite template needs a field: {}", template);
+                }
+                segments.push(None);
+            } else {
+                segments.push(Some(Field::parse(name)?));
+            }
+        }
+        Ok(Template { segments, greedy })
+    }
+
+    // Paths with more segments than the template drop the extra ones unless
+    // the template is greedy
+    fn apply(&self, path: &str, metric: &mut CollectdMetric) {
+        let parts: Vec<&str> = path.split('.').collect();
+        for (i, field) in self.segments.iter().enumerate() {
+            let Some(field) = field else { continue };
+            let value = if self.greedy && i == self.segments.len() - 1 {
+                parts.get(i..).map(|rest| rest.join("."))
+            } else {
+                parts.get(i).map(|part| part.to_string())
+            };
+            let Some(value) = value.filter(|v| !v.is_empty()) else { continue };
+            let slot = match field {
+                Field::Host => &mut metric.host,
+                Field::Plugin => &mut metric.plugin,
+                Field::PluginInstance => &mut metric.plugin_instance,
+                Field::Type => &mut metric.type_,
+                Field::TypeInstance => &mut metric.type_instance,
+            };
+            *slot = Some(value);
+        }
+    }
+}
+
+pub fn parse_line(line: &str, template: &Template) -> Result<CollectdMetric> {
+    let mut parts = line.split_whitespace();
+    let path = parts.next().ok_or_else(|| anyhow!("Empty line"))?;
+    let value: f64 = parts
+        .next()
+        .ok_or_else(|| anyhow!("Missing value"))?
+        .parse()
+        .map_err(|_| anyhow!("Invalid value"))?;
+    let value = serde_json::Number::from_f64(value).ok_or_else(|| anyhow!("Value is not finite"))?;
+    // carbon treats -1 as "now", leave it to the pipeline like a missing timestamp
+    let time = match parts.next() {
+        Some(ts) => {
+            let ts: f64 = ts.parse().map_err(|_| anyhow!("Invalid timestamp"))?;
+            (ts >= 0.0).then_some(ts)
+        }
+        None => None,
+    };
+
+    let mut metric = CollectdMetric {
+        time,
+        interval: None,
+        host: None,
+        plugin: None,
+        plugin_instance: None,
+        type_: None,
+        type_instance: None,
+        value: Some(serde_json::Value::Number(value)),
+        values: None,
+        dstypes: None,
+        dsnames: None,
+    };
+    template.apply(path, &mut metric);
+    Ok(metric)
+}
+
+pub struct GraphiteSource {
+    listener: TcpListener,
+    addr: String,
+    template: Arc<Template>,
+    allow: Arc<AllowList>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl GraphiteSource {
+    pub async fn open(
+        addr: &str,
+        template: &str,
+        allow: Arc<AllowList>,
+        telemetry: Arc<Telemetry>,
+    ) -> Result<GraphiteSource> {
+        let template = Arc::new(Template::parse(template)?);
+        let listener = TcpListener::bind(addr).await?;
+        info!("Listening for Graphite plaintext on tcp://{}", addr);
+        Ok(GraphiteSource {
+            listener,
+            addr: addr.to_string(),
+            template,
+            allow,
+            telemetry,
+        })
+    }
+}
+
+#[async_trait]
+impl Source for GraphiteSource {
+    fn name(&self) -> String {
+        format!("graphite://{}", self.addr)
+    }
+
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        loop {
+            let (stream, peer) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Out of file descriptors and the like, wait for some to be freed
+                    warn!("Failed to accept a Graphite connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            if !self.allow.allows(peer.ip()) {
+                self.telemetry.record_denied();
+                debug!("Closing Graphite connection from {}, not in --allow-cidr", peer);
+                continue;
+            }
+            debug!("Graphite connection from {}", peer);
+            let pipeline = pipeline.clone();
+            let template = self.template.clone();
+            let telemetry = self.telemetry.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, peer.ip(), pipeline, &template, &telemetry).await {
+                    warn!("Graphite connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+// Lines already sitting in the read buffer are ingested together, so a busy
+// relay doesn't pay one queue round trip per line
+async fn handle_connection(
+    stream: TcpStream,
+    peer: IpAddr,
+    pipeline: Pipeline,
+    template: &Template,
+    telemetry: &Telemetry,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut buffer = Vec::new();
+    let mut batch = Vec::new();
+    loop {
+        buffer.clear();
+        if (&mut reader).take(MAX_LINE_BYTES as u64 + 1).read_until(b'\n', &mut buffer).await? == 0 {
+            break;
+        }
+        if !buffer.ends_with(b"\n") && buffer.len() > MAX_LINE_BYTES {
+            telemetry.record_parse_failure();
+            bail!("Line is longer than {} bytes", MAX_LINE_BYTES);
+        }
+        let line = std::str::from_utf8(&buffer)?.trim_end_matches(['\n', '\r']);
+        if !line.trim().is_empty() {
+            match parse_line(line, template) {
+                Ok(metric) => batch.push(metric),
+                Err(e) => {
+                    telemetry.record_parse_failure();
+                    warn!("Failed to parse Graphite line {:?}: {}", line, e);
+                }
+            }
+        }
+
+        if !batch.is_empty() && (reader.buffer().is_empty() || batch.len() >= MAX_LINES_PER_INGEST) {
+            // Like the UDP listener there's no one to answer a 503 to, a full queue drops the lines
+            if let Err(QueueError::Closed) = pipeline.ingest_from(std::mem::take(&mut batch), &Tags::new(), Some(peer)).await {
+                bail!("Processing queue closed");
+            }
+        }
+    }
+    if !batch.is_empty() {
+        if let Err(QueueError::Closed) = pipeline.ingest_from(batch, &Tags::new(), Some(peer)).await {
+            bail!("Processing queue closed");
+        }
+    }
+    Ok(())
+}
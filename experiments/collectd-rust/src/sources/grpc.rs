@@ -0,0 +1,294 @@
+// gRPC listener serving the Receiver service of proto/receiver.proto: a unary
+// PushMetrics and a client-streaming StreamMetrics that queues each message as
+// it arrives. The service is written out by hand rather than generated, the
+// same way the messages are, so there's no build script. Auth, tenants, the
+// allowlist, limits and --strict work as on the HTTP routes, with the
+// credentials and tenant taken from the call's metadata.
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::extract::ConnectInfo;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tonic::codegen::{empty_body, http, tokio_stream::wrappers::TcpListenerStream, Body, BoxFuture, Service, StdError};
+use tonic::codegen::{Context, Poll};
+use tonic::server::{ClientStreamingService, Grpc, NamedService, UnaryService};
+use tonic::{Code, Status, Streaming};
+use tracing::{debug, info, warn};
+
+use crate::auth::{self, Auth};
+use crate::config::Config;
+use crate::metric::Tags;
+use crate::pipeline::Pipeline;
+use crate::proto::{PushRequest, PushResponse};
+use crate::queue::QueueError;
+use crate::schema;
+use crate::server::{self, AppState};
+use crate::sources::Source;
+use crate::telemetry::Telemetry;
+
+const SERVICE: &str = "collectd.receiver.v1.Receiver";
+// Refused metrics listed in a response, the count covers all of them
+const MAX_LISTED_INVALID: usize = 100;
+
+pub struct GrpcSource {
+    listener: TcpListener,
+    addr: String,
+    config: Arc<Config>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl GrpcSource {
+    pub async fn open(addr: &str, config: &Config, telemetry: Arc<Telemetry>) -> Result<GrpcSource> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Listening for gRPC on {}", addr);
+        Ok(GrpcSource {
+            listener,
+            addr: addr.to_string(),
+            config: Arc::new(config.clone()),
+            telemetry,
+        })
+    }
+}
+
+#[async_trait]
+impl Source for GrpcSource {
+    fn name(&self) -> String {
+        format!("grpc://{}", self.addr)
+    }
+
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        let state = AppState::new(pipeline, &self.config, self.telemetry)?;
+        let server = ReceiverServer {
+            receiver: Arc::new(Receiver { state }),
+        };
+        tonic::transport::Server::builder()
+            .add_service(server)
+            .serve_with_incoming(TcpListenerStream::new(self.listener))
+            .await?;
+        Ok(())
+    }
+}
+
+// Where a call's metrics go and who they count against
+struct Caller {
+    tags: Tags,
+    client: String,
+    peer: Option<IpAddr>,
+}
+
+struct Receiver {
+    state: AppState,
+}
+
+impl Receiver {
+    // The allowlist, auth and tenant checks of the HTTP routes. Status is
+    // large, but it's what the handlers return anyway.
+    #[allow(clippy::result_large_err)]
+    fn admit<T>(&self, request: &tonic::Request<T>) -> Result<Caller, Status> {
+        let state = &self.state;
+        let peer = request.remote_addr();
+        if !state.allow.is_empty() && !peer.is_some_and(|addr| state.allow.allows(addr.ip())) {
+            state.telemetry.record_denied();
+            debug!("Denying gRPC call from {:?}, not in --allow-cidr", peer);
+            return Err(Status::permission_denied("Source address not allowed"));
+        }
+        let headers = request.metadata().clone().into_headers();
+        let authorization = headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let identity = match auth::authenticate(state, authorization) {
+            Auth::Anonymous => None,
+            Auth::Identified(identity) => Some(identity),
+            Auth::Denied => return Err(Status::unauthenticated("Missing or invalid credentials")),
+        };
+        let tags = server::request_tags(state, identity.as_ref(), None, &headers).map_err(|(status, message)| {
+            let message = message.trim_end();
+            match status.as_u16() {
+                403 => Status::permission_denied(message),
+                _ => Status::invalid_argument(message),
+            }
+        })?;
+        Ok(Caller {
+            tags,
+            client: server::client_key(identity.as_ref(), peer.map(ConnectInfo)),
+            peer: peer.map(|addr: SocketAddr| addr.ip()),
+        })
+    }
+
+    // Queues one message's metrics and adds them to the response. index is
+    // where the message starts, counted in metrics. Over the rate limit a
+    // stream waits and a unary call is refused.
+    async fn push(
+        &self,
+        caller: &Caller,
+        request: PushRequest,
+        index: usize,
+        wait: bool,
+        response: &mut PushResponse,
+    ) -> Result<(), Status> {
+        let state = &self.state;
+        let entries = request.metrics.len();
+        if let Some(max) = state.config.max_metrics_per_request.filter(|max| entries > *max) {
+            warn!("Refusing gRPC message with {} metrics, over --max-metrics-per-request", entries);
+            return Err(Status::resource_exhausted(format!(
+                "Message has {} metrics, more than the limit of {}",
+                entries, max
+            )));
+        }
+        let mut metrics = Vec::with_capacity(entries);
+        let mut invalid = Vec::new();
+        for (i, metric) in request.metrics.into_iter().enumerate() {
+            match metric.into_collectd() {
+                Ok(metric) => metrics.push((index + i, metric)),
+                Err(reason) => invalid.push(schema::Invalid { index: index + i, reason }),
+            }
+        }
+        if state.config.strict {
+            server::check_schema(&mut metrics, &mut invalid);
+        }
+        let rejected = entries - metrics.len();
+        state.telemetry.record_invalid(rejected);
+        response.rejected += rejected as u64;
+        let room = MAX_LISTED_INVALID.saturating_sub(response.invalid.len());
+        response.invalid.extend(invalid.into_iter().take(room).map(Into::into));
+        if metrics.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(limiter) = &state.limiter {
+            while let Err(wait_for) = limiter.check(&caller.client, metrics.len()) {
+                if !wait {
+                    state.telemetry.record_rate_limited(metrics.len());
+                    debug!("Rate limiting {}, {} metrics", caller.client, metrics.len());
+                    return Err(Status::resource_exhausted("Rate limit exceeded"));
+                }
+                tokio::time::sleep(wait_for).await;
+            }
+        }
+        let count = metrics.len();
+        let metrics = metrics.into_iter().map(|(_, metric)| metric).collect();
+        match state.pipeline.ingest_from(metrics, &caller.tags, caller.peer).await {
+            Ok(_) => {
+                response.accepted += count as u64;
+                Ok(())
+            }
+            Err(QueueError::Full) => {
+                warn!("Processing queue full, refusing gRPC call from {}", caller.client);
+                Err(Status::unavailable(format!(
+                    "Processing queue full after {} accepted metrics",
+                    response.accepted
+                )))
+            }
+            Err(QueueError::Closed) => {
+                warn!("Failed to send metric to processing queue");
+                Err(Status::internal("Processing queue closed"))
+            }
+        }
+    }
+
+    // Like a 4xx from the array endpoint, a call that kept nothing fails
+    async fn push_metrics(&self, request: tonic::Request<PushRequest>) -> Result<tonic::Response<PushResponse>, Status> {
+        let caller = self.admit(&request)?;
+        let mut response = PushResponse::default();
+        self.push(&caller, request.into_inner(), 0, false, &mut response).await?;
+        if response.accepted == 0 {
+            if let Some(first) = response.invalid.first() {
+                return Err(Status::invalid_argument(format!(
+                    "All {} metrics refused, first at {}: {}",
+                    response.rejected, first.index, first.reason
+                )));
+            }
+        }
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn stream_metrics(
+        &self,
+        request: tonic::Request<Streaming<PushRequest>>,
+    ) -> Result<tonic::Response<PushResponse>, Status> {
+        let caller = self.admit(&request)?;
+        let mut messages = request.into_inner();
+        let mut response = PushResponse::default();
+        let mut index = 0;
+        while let Some(message) = messages.message().await? {
+            let count = message.metrics.len();
+            self.push(&caller, message, index, true, &mut response).await?;
+            index += count;
+        }
+        debug!("gRPC stream from {} ended after {} metrics", caller.client, index);
+        Ok(tonic::Response::new(response))
+    }
+}
+
+// What tonic-build would generate for the service: routes calls by path
+#[derive(Clone)]
+struct ReceiverServer {
+    receiver: Arc<Receiver>,
+}
+
+impl NamedService for ReceiverServer {
+    const NAME: &'static str = SERVICE;
+}
+
+struct PushMetrics(Arc<Receiver>);
+
+impl UnaryService<PushRequest> for PushMetrics {
+    type Response = PushResponse;
+    type Future = BoxFuture<tonic::Response<PushResponse>, Status>;
+
+    fn call(&mut self, request: tonic::Request<PushRequest>) -> Self::Future {
+        let receiver = self.0.clone();
+        Box::pin(async move { receiver.push_metrics(request).await })
+    }
+}
+
+struct StreamMetrics(Arc<Receiver>);
+
+impl ClientStreamingService<PushRequest> for StreamMetrics {
+    type Response = PushResponse;
+    type Future = BoxFuture<tonic::Response<PushResponse>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Streaming<PushRequest>>) -> Self::Future {
+        let receiver = self.0.clone();
+        Box::pin(async move { receiver.stream_metrics(request).await })
+    }
+}
+
+impl<B> Service<http::Request<B>> for ReceiverServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let receiver = self.receiver.clone();
+        // --max-body-bytes limits each message
+        let max_message = receiver.state.config.max_body_bytes;
+        let method = request.uri().path().rsplit_once('/').map(|(_, method)| method.to_string());
+        Box::pin(async move {
+            let codec = tonic::codec::ProstCodec::default();
+            let mut grpc = Grpc::new(codec).max_decoding_message_size(max_message);
+            let response = match method.as_deref().unwrap_or_default() {
+                "PushMetrics" => grpc.unary(PushMetrics(receiver), request).await,
+                "StreamMetrics" => grpc.client_streaming(StreamMetrics(receiver), request).await,
+                _ => {
+                    let mut response = http::Response::new(empty_body());
+                    let headers = response.headers_mut();
+                    headers.insert(Status::GRPC_STATUS, (Code::Unimplemented as i32).into());
+                    headers.insert(http::header::CONTENT_TYPE, tonic::metadata::GRPC_CONTENT_TYPE);
+                    response
+                }
+            };
+            Ok(response)
+        })
+    }
+}
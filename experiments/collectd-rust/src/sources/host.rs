@@ -0,0 +1,173 @@
+// Built-in host metrics: with --self-metrics the receiver reads cpu, load,
+// memory, disk and network figures of the machine it runs on every
+// --self-metrics-interval, so on a small edge device it can stand in for
+// collectd itself. Metrics have plugin "self", the area in plugin_instance and
+// types named after collectd's own (percent, load, memory, df, disk_octets,
+// if_octets, ...). Byte and packet totals are derives, --rates turns them into
+// rates.
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sysinfo::{Disks, Networks, System};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info};
+
+use crate::config::{self, Config};
+use crate::metric::{CollectdMetric, Tags};
+use crate::pipeline::Pipeline;
+use crate::queue::QueueError;
+use crate::sources::Source;
+
+pub fn validate(config: &Config) -> Result<()> {
+    if config::parse_duration(&config.self_metrics_interval)?.is_zero() {
+        bail!("--self-metrics-interval must be positive");
+    }
+    Ok(())
+}
+
+pub struct HostSource {
+    system: System,
+    disks: Disks,
+    networks: Networks,
+    host: Option<String>,
+    interval: Duration,
+}
+
+impl HostSource {
+    pub fn open(config: &Config) -> Result<HostSource> {
+        validate(config)?;
+        let interval = config::parse_duration(&config.self_metrics_interval)?;
+        let host = System::host_name();
+        info!(
+            "Collecting host metrics for {} every {}",
+            host.as_deref().unwrap_or("this host"),
+            config.self_metrics_interval
+        );
+        let mut system = System::new();
+        // CPU usage is measured between two refreshes, this is the first
+        system.refresh_cpu_usage();
+        Ok(HostSource {
+            system,
+            disks: Disks::new_with_refreshed_list(),
+            networks: Networks::new_with_refreshed_list(),
+            host,
+            interval,
+        })
+    }
+
+    // One metric of the current reading, values all of the same dstype
+    fn metric(
+        &self,
+        time: f64,
+        area: &str,
+        type_: &str,
+        type_instance: Option<&str>,
+        dstype: &str,
+        values: &[(&str, f64)],
+    ) -> Option<CollectdMetric> {
+        let numbers = values
+            .iter()
+            .map(|(_, value)| serde_json::Number::from_f64(*value).map(serde_json::Value::Number))
+            .collect::<Option<Vec<_>>>()?;
+        Some(CollectdMetric {
+            time: Some(time),
+            interval: Some(self.interval.as_secs_f64()),
+            host: self.host.clone(),
+            plugin: Some("self".to_string()),
+            plugin_instance: Some(area.to_string()),
+            type_: Some(type_.to_string()),
+            type_instance: type_instance.map(str::to_string),
+            value: None,
+            values: Some(numbers),
+            dstypes: Some(vec![dstype.to_string(); values.len()]),
+            dsnames: Some(values.iter().map(|(name, _)| name.to_string()).collect()),
+        })
+    }
+
+    fn collect(&mut self) -> Vec<CollectdMetric> {
+        self.system.refresh_cpu_usage();
+        self.system.refresh_memory();
+        self.disks.refresh(true);
+        self.networks.refresh(true);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+
+        let mut metrics = Vec::new();
+        let usage = self.system.global_cpu_usage() as f64;
+        metrics.extend(self.metric(time, "cpu", "percent", Some("total"), "gauge", &[("value", usage)]));
+        for cpu in self.system.cpus() {
+            let usage = cpu.cpu_usage() as f64;
+            metrics.extend(self.metric(time, "cpu", "percent", Some(cpu.name()), "gauge", &[("value", usage)]));
+        }
+
+        let load = System::load_average();
+        metrics.extend(self.metric(time, "load", "load", None, "gauge", &[
+            ("shortterm", load.one),
+            ("midterm", load.five),
+            ("longterm", load.fifteen),
+        ]));
+
+        let memory = [
+            ("used", self.system.used_memory()),
+            ("available", self.system.available_memory()),
+            ("total", self.system.total_memory()),
+            ("swap_used", self.system.used_swap()),
+            ("swap_total", self.system.total_swap()),
+        ];
+        for (name, bytes) in memory {
+            metrics.extend(self.metric(time, "memory", "memory", Some(name), "gauge", &[("value", bytes as f64)]));
+        }
+
+        for disk in self.disks.list() {
+            let mount = disk.mount_point().to_string_lossy();
+            let used = disk.total_space().saturating_sub(disk.available_space());
+            metrics.extend(self.metric(time, "disk", "df", Some(&mount), "gauge", &[
+                ("used", used as f64),
+                ("free", disk.available_space() as f64),
+            ]));
+            let io = disk.usage();
+            metrics.extend(self.metric(time, "disk", "disk_octets", Some(&mount), "derive", &[
+                ("read", io.total_read_bytes as f64),
+                ("write", io.total_written_bytes as f64),
+            ]));
+        }
+
+        for (interface, data) in self.networks.list() {
+            let totals = [
+                ("if_octets", data.total_received(), data.total_transmitted()),
+                ("if_packets", data.total_packets_received(), data.total_packets_transmitted()),
+                ("if_errors", data.total_errors_on_received(), data.total_errors_on_transmitted()),
+            ];
+            for (type_, rx, tx) in totals {
+                metrics.extend(self.metric(time, "network", type_, Some(interface), "derive", &[
+                    ("rx", rx as f64),
+                    ("tx", tx as f64),
+                ]));
+            }
+        }
+        metrics
+    }
+}
+
+#[async_trait]
+impl Source for HostSource {
+    fn name(&self) -> String {
+        "self".to_string()
+    }
+
+    async fn run(mut self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + self.interval, self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let metrics = self.collect();
+            debug!("Collected {} host metrics", metrics.len());
+            // The next reading brings fresh values, a full queue just loses this one
+            if let Err(QueueError::Closed) = pipeline.ingest_from(metrics, &Tags::new(), None).await {
+                bail!("Processing queue closed");
+            }
+        }
+    }
+}
@@ -0,0 +1,201 @@
+// HTTP listener: write_http JSON ingest plus /metrics and the probes, on a TCP
+// address, with --listen-unix on a Unix domain socket, or on the socket systemd
+// passed when socket activated
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use axum::{extract::ConnectInfo, http::Request, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::Semaphore;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use crate::config::{parse_duration, Config};
+use crate::pipeline::Pipeline;
+use crate::server::{self, AppState};
+use crate::sources::Source;
+use crate::systemd::Inherited;
+use crate::telemetry::Telemetry;
+
+pub fn validate(config: &Config) -> Result<()> {
+    unix_mode(&config.listen_unix_mode)?;
+    parse_duration(&config.http_keep_alive_timeout)?;
+    if config.http2_max_concurrent_streams == 0 {
+        bail!("--http2-max-concurrent-streams must be at least 1");
+    }
+    Ok(())
+}
+
+fn unix_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| anyhow!("Invalid --listen-unix-mode {:?}, expected octal permissions like 660", mode))
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+pub struct HttpSource {
+    listener: Listener,
+    addr: String,
+    config: Arc<Config>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl HttpSource {
+    pub async fn open(addr: &str, config: &Config, telemetry: Arc<Telemetry>) -> Result<HttpSource> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Listening on http://{}", addr);
+        Ok(HttpSource {
+            listener: Listener::Tcp(listener),
+            addr: addr.to_string(),
+            config: Arc::new(config.clone()),
+            telemetry,
+        })
+    }
+
+    // A socket left behind by an earlier run is replaced, any other file is an error
+    pub fn open_unix(path: &str, config: &Config, telemetry: Arc<Telemetry>) -> Result<HttpSource> {
+        let mode = unix_mode(&config.listen_unix_mode)?;
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => bail!("--listen-unix {} exists and isn't a socket", path),
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        info!("Listening on unix:{} with mode {:o}", path, mode);
+        Ok(HttpSource {
+            listener: Listener::Unix(listener),
+            addr: path.to_string(),
+            config: Arc::new(config.clone()),
+            telemetry,
+        })
+    }
+
+    pub fn inherit(inherited: Inherited, config: &Config, telemetry: Arc<Telemetry>) -> Result<HttpSource> {
+        let (listener, addr) = match inherited {
+            Inherited::Tcp(listener) => {
+                let listener = TcpListener::from_std(listener)?;
+                let addr = listener.local_addr()?.to_string();
+                info!("Listening on http://{} from systemd", addr);
+                (Listener::Tcp(listener), addr)
+            }
+            Inherited::Unix(listener) => {
+                let listener = UnixListener::from_std(listener)?;
+                let addr = listener.local_addr()?;
+                let addr = addr.as_pathname().map_or("(unnamed)".into(), |path| path.display().to_string());
+                info!("Listening on unix:{} from systemd", addr);
+                (Listener::Unix(listener), addr)
+            }
+        };
+        Ok(HttpSource {
+            listener,
+            addr,
+            config: Arc::new(config.clone()),
+            telemetry,
+        })
+    }
+
+    // The bound TCP address, e.g. when opened on port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?),
+            Listener::Unix(_) => bail!("unix:{} has no TCP address", self.addr),
+        }
+    }
+}
+
+// One HTTP/1.1 or, with --http2, HTTP/2 connection builder for all clients
+fn builder(config: &Config) -> Result<Builder<TokioExecutor>> {
+    let keep_alive = parse_duration(&config.http_keep_alive_timeout)?;
+    let mut builder = Builder::new(TokioExecutor::new());
+    // hyper applies the header timeout from the end of one request to the
+    // headers of the next, so it is also how long an idle connection is kept
+    builder.http1().timer(TokioTimer::new()).header_read_timeout(keep_alive);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(config.http2_max_concurrent_streams)
+        .keep_alive_interval(keep_alive);
+    Ok(if config.http2 { builder } else { builder.http1_only() })
+}
+
+// What the ConnectInfo extractor reads, which axum::serve would insert
+fn with_peer<B>(peer: SocketAddr) -> impl Fn(Request<B>) -> Request<B> + Clone {
+    move |mut request| {
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request
+    }
+}
+
+async fn serve(listener: Listener, app: Router, config: &Config, telemetry: Arc<Telemetry>) -> Result<()> {
+    let builder = Arc::new(builder(config)?);
+    let slots = Arc::new(Semaphore::new(match config.http_max_connections {
+        0 => Semaphore::MAX_PERMITS,
+        max => max,
+    }));
+    loop {
+        // At the limit, new clients wait in the listen backlog
+        let slot = slots.clone().acquire_owned().await?;
+        let accepted = match &listener {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, peer)| (Box::new(stream) as Box<dyn Stream>, peer)),
+            // Clients of the socket are on this host: to --allow-cidr, the rate
+            // limiter and --host-from-peer they come from the loopback address
+            Listener::Unix(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| (Box::new(stream) as Box<dyn Stream>, SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))),
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Out of file descriptors and the like, wait for some to be freed
+                warn!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone().map_request(with_peer(peer)));
+        let (builder, telemetry) = (builder.clone(), telemetry.clone());
+        telemetry.record_connection_opened();
+        tokio::spawn(async move {
+            if let Err(e) = builder.serve_connection_with_upgrades(TokioIo::new(stream), service).await {
+                debug!("Connection from {} failed: {}", peer, e);
+            }
+            telemetry.record_connection_closed();
+            drop(slot);
+        });
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Stream for T {}
+
+#[async_trait]
+impl Source for HttpSource {
+    fn name(&self) -> String {
+        match self.listener {
+            Listener::Tcp(_) => format!("http://{}", self.addr),
+            Listener::Unix(_) => format!("unix:{}", self.addr),
+        }
+    }
+
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        let state = AppState::new(pipeline, &self.config, self.telemetry.clone())?;
+        serve(self.listener, server::router(state), &self.config, self.telemetry).await
+    }
+}
@@ -0,0 +1,148 @@
+// Kafka consumer input: reads collectd JSON (a metric object or an array of
+// them per record) from --kafka-input-topic in a consumer group, so data that
+// already sits in Kafka can go through the filters and out to any sink. A
+// record's offset is stored once its metrics are queued and librdkafka commits
+// stored offsets in the background, so after a restart the group resumes
+// where it left off and at worst sees a few seconds of records again.
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    message::BorrowedMessage,
+    Message,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::metric::{CollectdMetric, Tags};
+use crate::pipeline::Pipeline;
+use crate::queue::QueueError;
+use crate::server;
+use crate::sources::Source;
+use crate::telemetry::Telemetry;
+
+// How long to wait before asking a full queue again, or after a consumer error
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+pub fn validate(config: &Config) -> Result<()> {
+    if !matches!(config.kafka_input_offset_reset.as_str(), "earliest" | "latest") {
+        return Err(anyhow!(
+            "Invalid --kafka-input-offset-reset {:?}, expected earliest or latest",
+            config.kafka_input_offset_reset
+        ));
+    }
+    Ok(())
+}
+
+pub struct KafkaSource {
+    consumer: StreamConsumer,
+    brokers: String,
+    topics: Vec<String>,
+    strict: bool,
+    telemetry: Arc<Telemetry>,
+}
+
+impl KafkaSource {
+    pub fn open(config: &Config, telemetry: Arc<Telemetry>) -> Result<KafkaSource> {
+        validate(config)?;
+        let brokers = config.kafka_input_brokers.as_ref().unwrap_or(&config.kafka_brokers);
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", &config.kafka_input_group)
+            .set("auto.offset.reset", &config.kafka_input_offset_reset)
+            .set("enable.auto.commit", "true")
+            // Offsets are stored by hand, once the record is queued
+            .set("enable.auto.offset.store", "false")
+            .create()?;
+        let topics: Vec<&str> = config.kafka_input_topic.iter().map(String::as_str).collect();
+        consumer.subscribe(&topics)?;
+        info!(
+            "Consuming Kafka topics {} from {} as group {}",
+            topics.join(","),
+            brokers,
+            config.kafka_input_group
+        );
+        Ok(KafkaSource {
+            consumer,
+            brokers: brokers.clone(),
+            topics: config.kafka_input_topic.clone(),
+            strict: config.strict,
+            telemetry,
+        })
+    }
+
+    fn parse(&self, message: &BorrowedMessage) -> Vec<CollectdMetric> {
+        let Some(payload) = message.payload() else {
+            return Vec::new();
+        };
+        let parsed = server::parse_metrics(payload).map_err(|e| e.to_string());
+        let (mut metrics, mut invalid) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.telemetry.record_parse_failure();
+                warn!(
+                    "Failed to parse Kafka record {}/{}@{}: {}",
+                    message.topic(),
+                    message.partition(),
+                    message.offset(),
+                    e
+                );
+                return Vec::new();
+            }
+        };
+        if self.strict {
+            server::check_schema(&mut metrics, &mut invalid);
+        }
+        if let Some(first) = invalid.first() {
+            let entries = metrics.len() + invalid.len();
+            self.telemetry.record_invalid(entries - metrics.len());
+            warn!(
+                "Skipping {} metrics of Kafka record {}/{}@{}, first at {}: {}",
+                entries - metrics.len(),
+                message.topic(),
+                message.partition(),
+                message.offset(),
+                first.index,
+                first.reason
+            );
+        }
+        metrics.into_iter().map(|(_, metric)| metric).collect()
+    }
+}
+
+#[async_trait]
+impl Source for KafkaSource {
+    fn name(&self) -> String {
+        format!("kafka://{}/{}", self.brokers, self.topics.join(","))
+    }
+
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        loop {
+            let message = match self.consumer.recv().await {
+                Ok(message) => message,
+                Err(e) => {
+                    // Broker trouble is retried by librdkafka, keep asking
+                    warn!("Kafka consumer error: {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let metrics = self.parse(&message);
+            if !metrics.is_empty() {
+                debug!("Received {} metrics from Kafka", metrics.len());
+                // Unlike a client, a topic can wait: hold the record until the queue takes it
+                loop {
+                    match pipeline.ingest_from(metrics.clone(), &Tags::new(), None).await {
+                        Ok(_) => break,
+                        Err(QueueError::Full) => tokio::time::sleep(RETRY_DELAY).await,
+                        Err(QueueError::Closed) => bail!("Processing queue closed"),
+                    }
+                }
+            }
+            self.consumer.store_offset_from_message(&message)?;
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// Input side of the receiver. Every listener implements Source and feeds the
+// same Pipeline, so any mix of them can run in one process.
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tracing::warn;
+
+use crate::acl::AllowList;
+use crate::config::Config;
+use crate::pipeline::Pipeline;
+use crate::systemd;
+use crate::telemetry::Telemetry;
+
+pub mod collectd;
+pub mod exec;
+pub mod graphite;
+pub mod grpc;
+pub mod host;
+pub mod http;
+pub mod kafka;
+pub mod scrape;
+pub mod snmp;
+pub mod tail;
+
+#[async_trait]
+pub trait Source: Send {
+    // Shown in logs, e.g. "http://0.0.0.0:8080"
+    fn name(&self) -> String;
+
+    // Receive until the listener fails. Sources are bound before this is called,
+    // so address errors surface at startup.
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()>;
+}
+
+// Bind every listener the config asks for
+pub async fn open_all(config: &Config, telemetry: Arc<Telemetry>) -> Result<Vec<Box<dyn Source>>> {
+    let mut sources: Vec<Box<dyn Source>> = Vec::new();
+    let allow = Arc::new(AllowList::parse(&config.allow_cidr)?);
+    // A socket passed by systemd takes the place of --host/--port and --listen-unix
+    if let Some(inherited) = systemd::listen_socket()? {
+        sources.push(Box::new(http::HttpSource::inherit(inherited, config, telemetry.clone())?));
+    } else {
+        match &config.listen_unix {
+            Some(path) => sources.push(Box::new(http::HttpSource::open_unix(path, config, telemetry.clone())?)),
+            None => {
+                let http_addr = format!("{}:{}", config.host, config.port);
+                sources.push(Box::new(http::HttpSource::open(&http_addr, config, telemetry.clone()).await?));
+            }
+        }
+    }
+    for addr in &config.collectd_listen {
+        sources.push(Box::new(
+            collectd::CollectdSource::open(addr, allow.clone(), telemetry.clone()).await?,
+        ));
+    }
+    for addr in &config.graphite_listen {
+        sources.push(Box::new(
+            graphite::GraphiteSource::open(addr, &config.graphite_template, allow.clone(), telemetry.clone()).await?,
+        ));
+    }
+    for addr in &config.grpc_listen {
+        sources.push(Box::new(grpc::GrpcSource::open(addr, config, telemetry.clone()).await?));
+    }
+    if !config.kafka_input_topic.is_empty() {
+        sources.push(Box::new(kafka::KafkaSource::open(config, telemetry.clone())?));
+    }
+    if !config.tail_file.is_empty() {
+        sources.push(Box::new(tail::TailSource::open(config, telemetry.clone())?));
+    }
+    if !config.scrape_target.is_empty() {
+        sources.push(Box::new(scrape::ScrapeSource::open(config, telemetry.clone())?));
+    }
+    if config.self_metrics {
+        sources.push(Box::new(host::HostSource::open(config)?));
+    }
+    if !config.snmp_target.is_empty() {
+        sources.push(Box::new(snmp::SnmpSource::open(config, telemetry.clone()).await?));
+    }
+    if !config.exec.is_empty() || !config.exec_daemon.is_empty() {
+        sources.push(Box::new(exec::ExecSource::open(config, telemetry.clone())?));
+    }
+    Ok(sources)
+}
+
+// Run all sources concurrently. Returns as soon as any of them stops, a
+// receiver that silently lost one of its inputs is worse than one that exits.
+pub async fn run_all(sources: Vec<Box<dyn Source>>, pipeline: Pipeline) -> Result<()> {
+    let mut tasks = JoinSet::new();
+    for source in sources {
+        let pipeline = pipeline.clone();
+        tasks.spawn(async move {
+            let name = source.name();
+            (name, source.run(pipeline).await)
+        });
+    }
+
+    match tasks.join_next().await {
+        Some(Ok((name, Ok(())))) => Err(anyhow!("Source {} stopped", name)),
+        Some(Ok((name, Err(e)))) => {
+            warn!("Source {} failed: {}", name, e);
+            Err(e)
+        }
+        Some(Err(e)) => Err(e.into()),
+        None => Err(anyhow!("No sources configured")),
+    }
+}
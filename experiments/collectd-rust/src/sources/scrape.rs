@@ -0,0 +1,234 @@
+// Prometheus scrape input: fetches every --scrape-target each --scrape-interval
+// and forwards the samples of its text exposition page, for exporters on hosts
+// that can't run an agent. Samples map the way OTLP data points do: host from
+// the target URL, plugin from the sample name and the labels as sorted
+// `key=value` pairs in type_instance. Counters, and the buckets, sums and
+// counts of histograms and summaries, become derives so --rates can turn them
+// into rates; everything else is a gauge. NaN and infinite samples are
+// skipped, JSON can't carry them.
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use crate::config::{self, Config};
+use crate::metric::{CollectdMetric, Tags};
+use crate::pipeline::Pipeline;
+use crate::queue::QueueError;
+use crate::sources::Source;
+use crate::telemetry::Telemetry;
+
+pub fn validate(config: &Config) -> Result<()> {
+    for target in &config.scrape_target {
+        let url = reqwest::Url::parse(target).map_err(|e| anyhow!("Invalid --scrape-target {:?}: {}", target, e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Invalid --scrape-target {:?}, expected an http(s) URL", target);
+        }
+    }
+    if config::parse_duration(&config.scrape_interval)?.is_zero() {
+        bail!("--scrape-interval must be positive");
+    }
+    Ok(())
+}
+
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+    // Milliseconds since the epoch
+    timestamp: Option<i64>,
+}
+
+// `name{label="value",...} value [timestamp]`
+fn parse_sample(line: &str) -> Result<Sample> {
+    let end = line.find(|c: char| c == '{' || c.is_whitespace()).unwrap_or(line.len());
+    let (name, mut rest) = line.split_at(end);
+    if name.is_empty() {
+        bail!("Missing metric name");
+    }
+    let mut labels = Vec::new();
+    if let Some(mut s) = rest.strip_prefix('{') {
+        loop {
+            s = s.trim_start();
+            if let Some(after) = s.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (label, after) = s.split_once('=').ok_or_else(|| anyhow!("Invalid label"))?;
+            let after = after
+                .trim_start()
+                .strip_prefix('"')
+                .ok_or_else(|| anyhow!("Unquoted label value"))?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let close = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, c)) => value.push(c),
+                        None => bail!("Unterminated label value"),
+                    },
+                    Some((_, c)) => value.push(c),
+                    None => bail!("Unterminated label value"),
+                }
+            };
+            labels.push((label.trim().to_string(), value));
+            s = after[close + 1..].trim_start();
+            s = s.strip_prefix(',').unwrap_or(s);
+        }
+    }
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().ok_or_else(|| anyhow!("Missing value"))?;
+    let value: f64 = match value {
+        "NaN" => f64::NAN,
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        value => value.parse().map_err(|_| anyhow!("Invalid value {:?}", value))?,
+    };
+    let timestamp = match fields.next() {
+        Some(ts) => Some(ts.parse().map_err(|_| anyhow!("Invalid timestamp {:?}", ts))?),
+        None => None,
+    };
+    Ok(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+// Whether the sample counts up, from the # TYPE of its family
+fn is_cumulative(sample: &Sample, types: &HashMap<String, String>) -> bool {
+    if let Some(kind) = types.get(&sample.name) {
+        return kind == "counter";
+    }
+    ["_bucket", "_sum", "_count"].iter().any(|suffix| {
+        sample
+            .name
+            .strip_suffix(suffix)
+            .and_then(|family| types.get(family))
+            .is_some_and(|kind| kind == "histogram" || kind == "summary")
+    })
+}
+
+// Samples of one page as metrics from host, stamped with now when they carry
+// no timestamp. Lines that don't parse are an error, a page is all or nothing.
+fn parse_page(page: &str, host: Option<&str>, now: f64) -> Result<Vec<CollectdMetric>> {
+    let mut types = HashMap::new();
+    let mut metrics = Vec::new();
+    for (number, line) in page.lines().enumerate() {
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut words = comment.split_whitespace();
+            if let (Some("TYPE"), Some(name), Some(kind)) = (words.next(), words.next(), words.next()) {
+                types.insert(name.to_string(), kind.to_string());
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let mut sample = parse_sample(line).map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+        let Some(value) = serde_json::Number::from_f64(sample.value) else {
+            continue;
+        };
+        let dstype = if is_cumulative(&sample, &types) { "derive" } else { "gauge" };
+        sample.labels.sort();
+        let labels: Vec<String> = sample.labels.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        metrics.push(CollectdMetric {
+            time: Some(sample.timestamp.map_or(now, |ms| ms as f64 / 1000.0)),
+            interval: None,
+            host: host.map(str::to_string),
+            plugin: Some(sample.name),
+            plugin_instance: None,
+            type_: None,
+            type_instance: Some(labels.join(",")).filter(|labels| !labels.is_empty()),
+            value: Some(serde_json::Value::Number(value)),
+            values: None,
+            dstypes: Some(vec![dstype.to_string()]),
+            dsnames: None,
+        });
+    }
+    Ok(metrics)
+}
+
+pub struct ScrapeSource {
+    client: reqwest::Client,
+    targets: Vec<reqwest::Url>,
+    interval: Duration,
+    telemetry: Arc<Telemetry>,
+}
+
+impl ScrapeSource {
+    pub fn open(config: &Config, telemetry: Arc<Telemetry>) -> Result<ScrapeSource> {
+        validate(config)?;
+        let interval = config::parse_duration(&config.scrape_interval)?;
+        info!("Scraping {} every {}", config.scrape_target.join(", "), config.scrape_interval);
+        Ok(ScrapeSource {
+            // A scrape that takes longer than the interval is abandoned
+            client: reqwest::Client::builder().timeout(interval).build()?,
+            targets: config
+                .scrape_target
+                .iter()
+                .map(|target| reqwest::Url::parse(target))
+                .collect::<Result<_, _>>()?,
+            interval,
+            telemetry,
+        })
+    }
+
+    async fn scrape(&self, target: &reqwest::Url) -> Result<Vec<CollectdMetric>> {
+        let response = self
+            .client
+            .get(target.clone())
+            .header("Accept", "text/plain;version=0.0.4")
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Scrape failed with {}", response.status());
+        }
+        let page = response.text().await?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        parse_page(&page, target.host_str(), now)
+    }
+}
+
+#[async_trait]
+impl Source for ScrapeSource {
+    fn name(&self) -> String {
+        let targets: Vec<&str> = self.targets.iter().map(reqwest::Url::as_str).collect();
+        format!("scrape {}", targets.join(","))
+    }
+
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let scrapes = join_all(self.targets.iter().map(|target| self.scrape(target))).await;
+            for (target, scraped) in self.targets.iter().zip(scrapes) {
+                let metrics = match scraped {
+                    Ok(metrics) => metrics,
+                    Err(e) => {
+                        self.telemetry.record_scrape_failure();
+                        warn!("Failed to scrape {}: {:#}", target, e);
+                        continue;
+                    }
+                };
+                debug!("Scraped {} samples from {}", metrics.len(), target);
+                // The next scrape brings fresh values, a full queue just loses this one
+                if let Err(QueueError::Closed) = pipeline.ingest_from(metrics, &Tags::new(), None).await {
+                    bail!("Processing queue closed");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,392 @@
+// SNMP polling input: GETs every --snmp-oid from each --snmp-target every
+// --snmp-interval, for devices like switches that can't run an agent. With
+// --snmp-user the targets are polled over v3, otherwise over v2c with
+// --snmp-community. Each value becomes a metric with plugin "snmp", the target
+// as host and the OID's name in type_instance. Counter32 and Counter64 values
+// are counters so --rates handles their wrap, integers, gauges and time ticks
+// are gauges, and strings holding a number (like UCD's laLoad) are gauges too.
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use futures_util::future::try_join_all;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use crate::config::{self, Config};
+use crate::metric::{CollectdMetric, Tags};
+use crate::pipeline::Pipeline;
+use crate::queue::QueueError;
+use crate::snmp::{self, AuthProtocol, Credentials, Engine, Oid, Pdu, Value};
+use crate::sources::Source;
+use crate::telemetry::Telemetry;
+
+const DEFAULT_PORT: u16 = 161;
+// Agents refuse requests whose response wouldn't fit, keep them small
+const MAX_OIDS_PER_REQUEST: usize = 32;
+
+// An OID to poll and the type_instance its values get
+struct Field {
+    oid: Oid,
+    name: String,
+}
+
+// "<oid>" or "<oid>=<name>"
+fn parse_field(field: &str) -> Result<Field> {
+    let (oid, name) = match field.split_once('=') {
+        Some((oid, name)) => (oid.trim(), Some(name.trim())),
+        None => (field.trim(), None),
+    };
+    let oid = snmp::parse_oid(oid)?;
+    let name = match name {
+        Some("") => bail!("Invalid --snmp-oid {:?}, empty name", field),
+        Some(name) => name.to_string(),
+        None => snmp::format_oid(&oid),
+    };
+    Ok(Field { oid, name })
+}
+
+// The host a target's metrics get and the address to poll: "<host>",
+// "<host>:<port>" or an IPv6 address, in brackets when it has a port
+fn parse_target(target: &str) -> Result<(String, String)> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok((addr.ip().to_string(), target.to_string()));
+    }
+    if let Ok(ip) = target.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return Ok((ip.to_string(), SocketAddr::new(ip, DEFAULT_PORT).to_string()));
+    }
+    let (host, addr) = match target.split_once(':') {
+        Some((host, port)) => {
+            port.parse::<u16>()
+                .map_err(|_| anyhow!("Invalid --snmp-target {:?}, bad port", target))?;
+            (host, target.to_string())
+        }
+        None => (target, format!("{}:{}", target, DEFAULT_PORT)),
+    };
+    if host.is_empty() {
+        bail!("Invalid --snmp-target {:?}, expected <host>[:<port>]", target);
+    }
+    Ok((host.to_string(), addr))
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    if !config.snmp_target.is_empty() && config.snmp_oid.is_empty() {
+        bail!("--snmp-target needs at least one --snmp-oid");
+    }
+    for target in &config.snmp_target {
+        parse_target(target)?;
+    }
+    for field in &config.snmp_oid {
+        parse_field(field)?;
+    }
+    AuthProtocol::parse(&config.snmp_auth_protocol)?;
+    let passwords = [&config.snmp_auth_password, &config.snmp_priv_password];
+    if config.snmp_user.is_none() && passwords.iter().any(|password| password.is_some()) {
+        bail!("--snmp-auth-password and --snmp-priv-password need --snmp-user");
+    }
+    if config.snmp_priv_password.is_some() && config.snmp_auth_password.is_none() {
+        bail!("--snmp-priv-password needs --snmp-auth-password");
+    }
+    // RFC 3414 11.2
+    if passwords.iter().any(|password| password.as_ref().is_some_and(|p| p.len() < 8)) {
+        bail!("SNMP passwords must be at least 8 characters");
+    }
+    if config::parse_duration(&config.snmp_interval)?.is_zero() {
+        bail!("--snmp-interval must be positive");
+    }
+    if config::parse_duration(&config.snmp_timeout)?.is_zero() {
+        bail!("--snmp-timeout must be positive");
+    }
+    Ok(())
+}
+
+enum Security {
+    Community(String),
+    Usm(Credentials),
+}
+
+struct Target {
+    name: String,
+    host: String,
+    socket: UdpSocket,
+    // Learned on the first v3 poll, and again when the agent reboots
+    engine: Option<Engine>,
+    next_id: i32,
+    salt: u64,
+}
+
+impl Target {
+    fn next_id(&mut self) -> i32 {
+        self.next_id = self.next_id.wrapping_add(1) & i32::MAX;
+        self.next_id
+    }
+
+    fn next_salt(&mut self) -> u64 {
+        self.salt = self.salt.wrapping_add(1);
+        self.salt
+    }
+}
+
+pub struct SnmpSource {
+    targets: Vec<Target>,
+    fields: Vec<Field>,
+    security: Security,
+    interval: Duration,
+    timeout: Duration,
+    telemetry: Arc<Telemetry>,
+}
+
+impl SnmpSource {
+    pub async fn open(config: &Config, telemetry: Arc<Telemetry>) -> Result<SnmpSource> {
+        validate(config)?;
+        let random = SystemRandom::new();
+        let mut targets = Vec::new();
+        for target in &config.snmp_target {
+            let (host, addr) = parse_target(target)?;
+            let peer = tokio::net::lookup_host(&addr)
+                .await?
+                .next()
+                .ok_or_else(|| anyhow!("No address for --snmp-target {:?}", target))?;
+            let local = if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(peer).await?;
+            // Request IDs and IVs start at random, replies from an earlier run don't match
+            let mut seed = [0; 12];
+            random
+                .fill(&mut seed)
+                .map_err(|_| anyhow!("Failed to seed SNMP request IDs"))?;
+            targets.push(Target {
+                name: target.clone(),
+                host,
+                socket,
+                engine: None,
+                next_id: i32::from_be_bytes(seed[..4].try_into()?) & i32::MAX,
+                salt: u64::from_be_bytes(seed[4..].try_into()?),
+            });
+        }
+        let security = match &config.snmp_user {
+            Some(user) => {
+                let protocol = AuthProtocol::parse(&config.snmp_auth_protocol)?;
+                let auth = config.snmp_auth_password.as_deref().map(|password| (protocol, password));
+                Security::Usm(Credentials::new(user, auth, config.snmp_priv_password.as_deref()))
+            }
+            None => Security::Community(config.snmp_community.clone()),
+        };
+        info!(
+            "Polling {} OIDs from {} over SNMP {} every {}",
+            config.snmp_oid.len(),
+            config.snmp_target.join(", "),
+            if config.snmp_user.is_some() { "v3" } else { "v2c" },
+            config.snmp_interval
+        );
+        Ok(SnmpSource {
+            targets,
+            fields: config.snmp_oid.iter().map(|field| parse_field(field)).collect::<Result<_>>()?,
+            security,
+            interval: config::parse_duration(&config.snmp_interval)?,
+            timeout: config::parse_duration(&config.snmp_timeout)?,
+            telemetry,
+        })
+    }
+
+    // Sends a request and waits for the reply decode picks out, skipping late
+    // replies to earlier requests and datagrams that don't decode (stray
+    // packets, another community, a stale digest). Only the timeout ends the wait.
+    async fn exchange<T>(
+        &self,
+        socket: &UdpSocket,
+        request: &[u8],
+        decode: impl Fn(&[u8]) -> Result<Option<T>>,
+    ) -> Result<T> {
+        socket.send(request).await?;
+        let mut buf = vec![0; snmp::MAX_MESSAGE];
+        let mut skipped = None;
+        let reply = async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                match decode(&buf[..len]) {
+                    Ok(Some(reply)) => return Ok(reply),
+                    Ok(None) => {}
+                    Err(e) => {
+                        debug!("Skipping a {} byte reply: {}", len, e);
+                        skipped = Some(e);
+                    }
+                }
+            }
+        };
+        let result = tokio::time::timeout(self.timeout, reply).await;
+        result.map_err(|_| match skipped {
+            // Likely why, e.g. a wrong password fails every reply's digest
+            Some(e) => anyhow!("No usable reply within {:?}, the last one: {}", self.timeout, e),
+            None => anyhow!("No reply within {:?}", self.timeout),
+        })?
+    }
+
+    async fn discover(&self, target: &mut Target, credentials: &Credentials) -> Result<Engine> {
+        let id = target.next_id();
+        let request = snmp::encode_discovery(id, id);
+        let reply = self
+            .exchange(&target.socket, &request, |packet| {
+                let reply = snmp::decode_v3(packet, None)?;
+                Ok((reply.pdu.tag == snmp::REPORT).then_some(reply))
+            })
+            .await?;
+        if reply.engine_id.is_empty() {
+            bail!("Agent didn't report its engine ID");
+        }
+        debug!("{} has engine boots {} and time {}", target.name, reply.boots, reply.time);
+        Ok(Engine::new(&reply.engine_id, reply.boots, reply.time, credentials))
+    }
+
+    async fn get(&self, target: &mut Target, oids: &[Oid]) -> Result<Pdu> {
+        let credentials = match &self.security {
+            Security::Community(community) => {
+                let id = target.next_id();
+                let request = snmp::encode_v2c(community, id, oids);
+                return self
+                    .exchange(&target.socket, &request, |packet| {
+                        let pdu = snmp::decode_v2c(packet, community)?;
+                        Ok((pdu.tag == snmp::RESPONSE && pdu.request_id == id).then_some(pdu))
+                    })
+                    .await;
+            }
+            Security::Usm(credentials) => credentials,
+        };
+        // An agent that rebooted or whose clock drifted says so in a report,
+        // rediscover it and try once more
+        let mut retried = false;
+        loop {
+            if target.engine.is_none() {
+                target.engine = Some(self.discover(target, credentials).await?);
+            }
+            let (id, salt) = (target.next_id(), target.next_salt());
+            let Some(engine) = &target.engine else {
+                unreachable!("engine was just discovered");
+            };
+            let request = snmp::encode_v3(credentials, engine, id, id, oids, salt);
+            let reply = self
+                .exchange(&target.socket, &request, |packet| {
+                    let reply = snmp::decode_v3(packet, Some(engine))?;
+                    // A report may not know the request it answers
+                    Ok((reply.pdu.tag == snmp::REPORT || reply.pdu.request_id == id).then_some(reply))
+                })
+                .await?;
+            if reply.pdu.tag != snmp::REPORT {
+                return Ok(reply.pdu);
+            }
+            let cause = reply.pdu.varbinds.first().map(|(oid, _)| oid.as_slice()).unwrap_or_default();
+            target.engine = None;
+            if retried || !snmp::is_resync(cause) {
+                bail!("Agent refused the request: {}", snmp::report_name(cause));
+            }
+            retried = true;
+        }
+    }
+
+    async fn poll(&self, target: &mut Target) -> Result<Vec<(Oid, Value)>> {
+        let mut values = Vec::with_capacity(self.fields.len());
+        let oids: Vec<Oid> = self.fields.iter().map(|field| field.oid.clone()).collect();
+        for oids in oids.chunks(MAX_OIDS_PER_REQUEST) {
+            let pdu = self.get(target, oids).await?;
+            if pdu.error_status != 0 {
+                bail!(
+                    "Agent answered {} for OID {}",
+                    snmp::error_name(pdu.error_status),
+                    pdu.error_index
+                );
+            }
+            if pdu.varbinds.len() != oids.len() {
+                bail!("Agent answered {} OIDs of {}", pdu.varbinds.len(), oids.len());
+            }
+            values.extend(pdu.varbinds);
+        }
+        Ok(values)
+    }
+
+    fn metrics(&self, target: &Target, values: Vec<(Oid, Value)>) -> Vec<CollectdMetric> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let mut metrics = Vec::new();
+        for (field, (oid, value)) in self.fields.iter().zip(values) {
+            let (number, dstype) = match value {
+                Value::Counter(n) => (n as f64, "counter"),
+                Value::Gauge(n) => (n as f64, "gauge"),
+                Value::Integer(n) => (n as f64, "gauge"),
+                Value::OctetString(s) => match std::str::from_utf8(&s).ok().and_then(|s| s.trim().parse().ok()) {
+                    Some(n) => (n, "gauge"),
+                    None => {
+                        debug!("Skipping {} of {}, not a number", field.name, target.name);
+                        continue;
+                    }
+                },
+                Value::Missing => {
+                    warn!("{} has no value for {} ({})", target.name, field.name, snmp::format_oid(&oid));
+                    continue;
+                }
+                Value::Other => {
+                    debug!("Skipping {} of {}, not a number", field.name, target.name);
+                    continue;
+                }
+            };
+            let Some(number) = serde_json::Number::from_f64(number) else {
+                continue;
+            };
+            metrics.push(CollectdMetric {
+                time: Some(time),
+                interval: Some(self.interval.as_secs_f64()),
+                host: Some(target.host.clone()),
+                plugin: Some("snmp".to_string()),
+                plugin_instance: None,
+                type_: None,
+                type_instance: Some(field.name.clone()),
+                value: Some(serde_json::Value::Number(number)),
+                values: None,
+                dstypes: Some(vec![dstype.to_string()]),
+                dsnames: None,
+            });
+        }
+        metrics
+    }
+
+    async fn poll_target(&self, mut target: Target, pipeline: &Pipeline) -> Result<()> {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let values = match self.poll(&mut target).await {
+                Ok(values) => values,
+                Err(e) => {
+                    self.telemetry.record_snmp_failure();
+                    warn!("Failed to poll {}: {:#}", target.name, e);
+                    continue;
+                }
+            };
+            let metrics = self.metrics(&target, values);
+            debug!("Polled {} values from {}", metrics.len(), target.name);
+            // The next poll brings fresh values, a full queue just loses this one
+            if let Err(QueueError::Closed) = pipeline.ingest_from(metrics, &Tags::new(), None).await {
+                bail!("Processing queue closed");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Source for SnmpSource {
+    fn name(&self) -> String {
+        let targets: Vec<&str> = self.targets.iter().map(|target| target.name.as_str()).collect();
+        format!("snmp://{}", targets.join(","))
+    }
+
+    async fn run(mut self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        let targets = std::mem::take(&mut self.targets);
+        let source = &*self;
+        try_join_all(targets.into_iter().map(|target| source.poll_target(target, &pipeline))).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+// File tail input: follows NDJSON files, one collectd metric (or array of
+// them) per line, like `tail -F`. Patterns may use * and ? in the file name,
+// e.g. /var/log/metrics/*.ndjson, and are expanded again on every poll so new
+// files are picked up. A file is told apart by its device and inode, not its
+// path: a file renamed by a rotation keeps its offset under the new name if
+// that still matches, and otherwise the rest of it is read through the handle
+// still open on it before the file that took its place is read from the
+// start. A file that shrinks was truncated and is read again from the start.
+//
+// With --tail-checkpoint the offset reached in every file is saved after each
+// poll, once its lines are queued, and a restart resumes from there for the
+// files still around, under whatever name they have by then.
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tracing::{debug, info, warn};
+
+use crate::config::{self, Config};
+use crate::metric::{CollectdMetric, Tags};
+use crate::pipeline::Pipeline;
+use crate::queue::QueueError;
+use crate::server;
+use crate::sources::Source;
+use crate::telemetry::Telemetry;
+
+// Read at most this much of a file at a time
+const CHUNK: usize = 1 << 20;
+// How long to wait before asking a full queue again
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// A path whose file name may hold wildcards
+struct Pattern {
+    dir: PathBuf,
+    name: Regex,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Pattern> {
+        let path = Path::new(pattern);
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|name| name.to_str())) else {
+            bail!("Invalid --tail-file {:?}, expected a file path", pattern);
+        };
+        if dir.to_string_lossy().contains(['*', '?']) {
+            bail!("Invalid --tail-file {:?}, wildcards are only allowed in the file name", pattern);
+        }
+        let mut regex = String::from("^");
+        for c in name.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        Ok(Pattern {
+            dir: dir.to_path_buf(),
+            name: Regex::new(&regex)?,
+        })
+    }
+
+    // The regular files matching, a missing directory matches nothing
+    async fn expand(&self, paths: &mut Vec<PathBuf>) {
+        let Ok(mut entries) = fs::read_dir(&self.dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let matches = entry.file_name().to_str().is_some_and(|name| self.name.is_match(name));
+            if matches && fs::metadata(entry.path()).await.is_ok_and(|meta| meta.is_file()) {
+                paths.push(entry.path());
+            }
+        }
+    }
+}
+
+pub fn validate(config: &Config) -> Result<()> {
+    for pattern in &config.tail_file {
+        Pattern::parse(pattern)?;
+    }
+    if config::parse_duration(&config.tail_poll_interval)?.is_zero() {
+        bail!("--tail-poll-interval must be positive");
+    }
+    if !matches!(config.tail_start.as_str(), "beginning" | "end") {
+        bail!("Invalid --tail-start {:?}, expected beginning or end", config.tail_start);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+struct FileId {
+    dev: u64,
+    inode: u64,
+}
+
+impl FileId {
+    fn of(meta: &std::fs::Metadata) -> FileId {
+        FileId {
+            dev: meta.dev(),
+            inode: meta.ino(),
+        }
+    }
+}
+
+// A checkpoint entry. The path is the one the file had when it was saved,
+// only there for whoever reads the file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Position {
+    #[serde(flatten)]
+    id: FileId,
+    path: PathBuf,
+    offset: u64,
+}
+
+type Checkpoint = Vec<Position>;
+
+struct Tailed {
+    file: File,
+    // Where the file was found on the last poll
+    path: PathBuf,
+    offset: u64,
+    // Inside a line over --max-body-bytes, dropped up to its newline
+    skipping: bool,
+}
+
+pub struct TailSource {
+    patterns: Vec<Pattern>,
+    names: Vec<String>,
+    checkpoint: Option<PathBuf>,
+    interval: Duration,
+    from_start: bool,
+    max_line: usize,
+    strict: bool,
+    telemetry: Arc<Telemetry>,
+}
+
+impl TailSource {
+    pub fn open(config: &Config, telemetry: Arc<Telemetry>) -> Result<TailSource> {
+        validate(config)?;
+        info!("Tailing {}", config.tail_file.join(", "));
+        Ok(TailSource {
+            patterns: config
+                .tail_file
+                .iter()
+                .map(|pattern| Pattern::parse(pattern))
+                .collect::<Result<_>>()?,
+            names: config.tail_file.clone(),
+            checkpoint: config.tail_checkpoint.as_ref().map(PathBuf::from),
+            interval: config::parse_duration(&config.tail_poll_interval)?,
+            from_start: config.tail_start == "beginning",
+            max_line: config.max_body_bytes,
+            strict: config.strict,
+            telemetry,
+        })
+    }
+
+    async fn load_checkpoint(&self) -> Result<Checkpoint> {
+        let Some(path) = &self.checkpoint else {
+            return Ok(Checkpoint::new());
+        };
+        match fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data).with_context(|| format!("Invalid checkpoint {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Checkpoint::new()),
+            Err(e) => Err(anyhow!("Failed to read checkpoint {}: {}", path.display(), e)),
+        }
+    }
+
+    // Written to a temporary file first, a crash mid-write keeps the old one
+    async fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let Some(path) = &self.checkpoint else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(checkpoint)?).await?;
+        fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    // Queues what was appended since the last poll. Read errors are logged
+    // and the file retried on the next poll, only a closed queue is an error.
+    async fn drain(&self, tailed: &mut Tailed, pipeline: &Pipeline) -> Result<()> {
+        let path = tailed.path.clone();
+        let path = path.as_path();
+        loop {
+            let metrics = match self.read_chunk(path, tailed).await {
+                Ok(Some(metrics)) => metrics,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    warn!("Failed to read {}: {}", path.display(), e);
+                    return Ok(());
+                }
+            };
+            if metrics.is_empty() {
+                continue;
+            }
+            debug!("Read {} metrics from {}", metrics.len(), path.display());
+            // The file isn't going anywhere, wait for the queue to take the lines
+            loop {
+                match pipeline.ingest_from(metrics.clone(), &Tags::new(), None).await {
+                    Ok(_) => break,
+                    Err(QueueError::Full) => tokio::time::sleep(RETRY_DELAY).await,
+                    Err(QueueError::Closed) => bail!("Processing queue closed"),
+                }
+            }
+        }
+    }
+
+    // The metrics on the complete lines of the next chunk, None at the end of
+    // the file or of what has been written of its last line
+    async fn read_chunk(&self, path: &Path, tailed: &mut Tailed) -> std::io::Result<Option<Vec<CollectdMetric>>> {
+        // Room for the longest line allowed and its newline
+        let size = CHUNK.max(self.max_line + 1);
+        let mut chunk = Vec::with_capacity(size);
+        tailed.file.seek(SeekFrom::Start(tailed.offset)).await?;
+        let len = (&mut tailed.file).take(size as u64).read_to_end(&mut chunk).await?;
+        if len == 0 {
+            return Ok(None);
+        }
+        let Some(end) = chunk.iter().rposition(|b| *b == b'\n') else {
+            // An unfinished line, unless it's already too long to keep waiting for
+            if !tailed.skipping && len <= self.max_line {
+                return Ok(None);
+            }
+            if !tailed.skipping {
+                self.telemetry.record_invalid(1);
+                warn!("Skipping a line of {} over {} bytes", path.display(), self.max_line);
+            }
+            tailed.skipping = true;
+            tailed.offset += len as u64;
+            return Ok(Some(Vec::new()));
+        };
+        tailed.offset += end as u64 + 1;
+        Ok(Some(self.parse(path, &chunk[..end], &mut tailed.skipping)))
+    }
+
+    // The metrics on complete lines
+    fn parse(&self, path: &Path, lines: &[u8], skipping: &mut bool) -> Vec<CollectdMetric> {
+        let mut metrics = Vec::new();
+        for line in lines.split(|b| *b == b'\n') {
+            // The end of a line that was too long
+            if std::mem::take(skipping) || line.trim_ascii().is_empty() {
+                continue;
+            }
+            if line.len() > self.max_line {
+                self.telemetry.record_invalid(1);
+                warn!("Skipping a line of {} over {} bytes", path.display(), self.max_line);
+                continue;
+            }
+            let parsed = server::parse_metrics(line).map_err(|e| e.to_string());
+            let (mut parsed, mut invalid) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    self.telemetry.record_parse_failure();
+                    warn!("Failed to parse a line of {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if self.strict {
+                server::check_schema(&mut parsed, &mut invalid);
+            }
+            if let Some(first) = invalid.first() {
+                self.telemetry.record_invalid(invalid.len());
+                warn!("Skipping a metric in {}: {}", path.display(), first.reason);
+            }
+            metrics.extend(parsed.into_iter().map(|(_, metric)| metric));
+        }
+        metrics
+    }
+}
+
+#[async_trait]
+impl Source for TailSource {
+    fn name(&self) -> String {
+        format!("tail://{}", self.names.join(","))
+    }
+
+    async fn run(self: Box<Self>, pipeline: Pipeline) -> Result<()> {
+        let mut checkpoint = self.load_checkpoint().await?;
+        let mut tailed: HashMap<FileId, Tailed> = HashMap::new();
+        let mut first_poll = true;
+        loop {
+            let mut paths = Vec::new();
+            for pattern in &self.patterns {
+                pattern.expand(&mut paths).await;
+            }
+            paths.sort();
+            paths.dedup();
+            // The first path wins for a file linked under several
+            let mut found: Vec<(FileId, PathBuf, u64)> = Vec::with_capacity(paths.len());
+            for path in paths {
+                if let Ok(meta) = fs::metadata(&path).await {
+                    let id = FileId::of(&meta);
+                    if !found.iter().any(|(seen, ..)| *seen == id) {
+                        found.push((id, path, meta.len()));
+                    }
+                }
+            }
+
+            // Files deleted, or renamed to a name that doesn't match, are read
+            // to the end one last time and forgotten. This comes first so a
+            // rotated file is finished before the one that replaced it.
+            let gone: Vec<FileId> = tailed.keys().filter(|id| !found.iter().any(|(seen, ..)| seen == *id)).copied().collect();
+            for id in gone {
+                if let Some(mut file) = tailed.remove(&id) {
+                    self.drain(&mut file, &pipeline).await?;
+                    debug!("Stopped tailing {}", file.path.display());
+                }
+            }
+
+            for (id, path, len) in found {
+                if let Some(file) = tailed.get_mut(&id) {
+                    if file.path != path {
+                        info!("{} was renamed to {}, still reading it", file.path.display(), path.display());
+                        file.path = path;
+                    }
+                    if len < file.offset {
+                        info!("{} was truncated, reading it from the start", file.path.display());
+                        file.offset = 0;
+                        file.skipping = false;
+                    }
+                } else {
+                    let file = match File::open(&path).await {
+                        Ok(file) => file,
+                        Err(e) => {
+                            warn!("Failed to open {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+                    // Replaced between the listing and the open, it's picked up on the next poll
+                    if !file.metadata().await.is_ok_and(|meta| FileId::of(&meta) == id) {
+                        continue;
+                    }
+                    let offset = match checkpoint.iter().find(|position| position.id == id) {
+                        Some(position) if position.offset <= len => position.offset,
+                        // The inode was reused by a smaller file while the receiver was down
+                        Some(_) => 0,
+                        // Files that show up later are new, read them whole
+                        None if first_poll && !self.from_start => len,
+                        None => 0,
+                    };
+                    debug!("Tailing {} from offset {}", path.display(), offset);
+                    tailed.insert(id, Tailed {
+                        file,
+                        path,
+                        offset,
+                        skipping: false,
+                    });
+                }
+                if let Some(file) = tailed.get_mut(&id) {
+                    self.drain(file, &pipeline).await?;
+                }
+            }
+
+            let mut positions: Checkpoint = tailed
+                .iter()
+                .map(|(id, file)| Position {
+                    id: *id,
+                    path: file.path.clone(),
+                    offset: file.offset,
+                })
+                .collect();
+            positions.sort_by(|a, b| a.path.cmp(&b.path));
+            if positions != checkpoint {
+                if let Err(e) = self.save_checkpoint(&positions).await {
+                    warn!("Failed to save the tail checkpoint: {}", e);
+                }
+                checkpoint = positions;
+            }
+            first_poll = false;
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// systemd integration, all of it a no-op outside a systemd service. Socket
+// activation: the HTTP listener is inherited from a .socket unit (LISTEN_FDS),
+// so connections queue up in the kernel across a restart instead of being
+// refused. sd_notify: READY=1 once listening, STOPPING=1 on SIGTERM and
+// WATCHDOG=1 pings for WatchdogSec.
+use anyhow::{bail, Result};
+use std::ffi::OsStr;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+// sd_listen_fds(3): passed sockets start right after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Inherited {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+// The variable, if it was meant for this process rather than a parent's
+fn for_this_process(pid_var: &str) -> bool {
+    match std::env::var(pid_var) {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => true,
+    }
+}
+
+// The first socket systemd passed, when socket activated. The variables are
+// removed so commands run by --exec don't think they were activated too.
+pub fn listen_socket() -> Result<Option<Inherited>> {
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let ours = std::env::var_os("LISTEN_PID").is_some() && for_this_process("LISTEN_PID");
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let count: RawFd = match fds {
+        Some(fds) if ours => fds.parse()?,
+        _ => return Ok(None),
+    };
+    if count < 1 {
+        return Ok(None);
+    }
+    if count > 1 {
+        warn!("systemd passed {} sockets, only the first one is served", count);
+    }
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // Safety: fcntl on a descriptor number has no memory effects
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    let fd = LISTEN_FDS_START;
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safety: kind and len are valid for writes of the sizes given
+    let found = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, (&mut kind as *mut libc::c_int).cast(), &mut len) };
+    if found != 0 {
+        bail!("systemd passed fd {} that isn't a socket: {}", fd, std::io::Error::last_os_error());
+    }
+    if kind != libc::SOCK_STREAM {
+        bail!("systemd passed a datagram socket, the HTTP listener needs ListenStream=");
+    }
+    // Safety: an all-zero sockaddr_storage is valid, and getsockname writes at most len bytes
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, (&mut addr as *mut libc::sockaddr_storage).cast(), &mut len) } != 0 {
+        bail!("Failed to read the address of the systemd socket: {}", std::io::Error::last_os_error());
+    }
+    // Safety: systemd handed the descriptor over, nothing else in the process owns it
+    let inherited = match addr.ss_family as libc::c_int {
+        libc::AF_INET | libc::AF_INET6 => {
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Inherited::Tcp(listener)
+        }
+        libc::AF_UNIX => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Inherited::Unix(listener)
+        }
+        family => bail!("systemd passed a socket of unsupported family {}", family),
+    };
+    Ok(Some(inherited))
+}
+
+// Tells systemd about a state change, see sd_notify(3). Without
+// NOTIFY_SOCKET, i.e. outside a Type=notify service, nothing is sent.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    match send(&path, state) {
+        Ok(()) => debug!("Notified systemd: {}", state),
+        Err(e) => warn!("Failed to notify systemd of {}: {}", state, e),
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> std::io::Result<()> {
+    // A leading @ is a socket in the abstract namespace
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+// How often to ping the watchdog: half of WatchdogSec, as sd_watchdog_enabled(3)
+// advises. None when the unit has no watchdog.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if usec == 0 || !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+// Pings for as long as the runtime keeps scheduling tasks, a wedged receiver
+// misses them and systemd restarts it
+pub async fn watchdog(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
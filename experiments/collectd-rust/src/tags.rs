@@ -0,0 +1,134 @@
+// Tags added to every metric from --tag, "<tag>=<value>". The value can refer to
+// the metric's fields: {field} inserts a field, {field:split('<sep>',<n>)} the
+// n-th piece of it (from 0), e.g.
+//   env=prod
+//   dc={host:split('-',0)}
+//   source={plugin}/{type}
+// Fields are host, plugin, plugin_instance, type and type_instance. A tag whose
+// template refers to a missing field or piece is left out of that metric. Tags
+// a metric already carries, e.g. from its API key, are kept as they are.
+use anyhow::{anyhow, Result};
+
+use crate::filter::Field;
+use crate::metric::{self, ProcessedMetric};
+use crate::tenant;
+
+#[derive(Debug)]
+enum Part {
+    Literal(String),
+    Field(Field),
+    Split { field: Field, separator: String, index: usize },
+}
+
+#[derive(Debug)]
+struct Tag {
+    name: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Default)]
+pub struct TagRules {
+    tags: Vec<Tag>,
+}
+
+impl TagRules {
+    pub fn parse(specs: &[String]) -> Result<TagRules> {
+        let mut rules = TagRules::default();
+        for spec in specs {
+            let (name, template) = spec
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid tag {:?}, expected <tag>=<value>", spec))?;
+            if !metric::valid_tag_name(name) || name == tenant::TAG {
+                return Err(anyhow!("Invalid tag name {:?} in --tag {:?}", name, spec));
+            }
+            let parts = parse_template(template).map_err(|e| anyhow!("Invalid tag {:?}: {}", spec, e))?;
+            rules.tags.push(Tag {
+                name: name.to_string(),
+                parts,
+            });
+        }
+        Ok(rules)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn apply(&self, metric: &mut ProcessedMetric) {
+        for tag in &self.tags {
+            if metric.tags.contains_key(&tag.name) {
+                continue;
+            }
+            if let Some(value) = render(&tag.parts, metric) {
+                metric.tags.insert(tag.name.clone(), value);
+            }
+        }
+    }
+}
+
+fn render(parts: &[Part], metric: &ProcessedMetric) -> Option<String> {
+    let mut value = String::new();
+    for part in parts {
+        match part {
+            Part::Literal(text) => value.push_str(text),
+            Part::Field(field) => value.push_str(non_empty(field.get(metric))?),
+            Part::Split { field, separator, index } => {
+                value.push_str(non_empty(field.get(metric).split(separator.as_str()).nth(*index)?)?)
+            }
+        }
+    }
+    Some(value)
+}
+
+fn non_empty(s: &str) -> Option<&str> {
+    Some(s).filter(|s| !s.is_empty())
+}
+
+fn parse_template(template: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unclosed {{ in {:?}", template))?;
+        parts.push(parse_placeholder(&rest[start + 1..start + end])?);
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+// "field" or "field:split('<sep>',<n>)"
+fn parse_placeholder(placeholder: &str) -> Result<Part> {
+    let Some((field, function)) = placeholder.split_once(':') else {
+        return Ok(Part::Field(Field::parse(placeholder)?));
+    };
+    let field = Field::parse(field)?;
+    let args = function
+        .strip_prefix("split(")
+        .and_then(|args| args.strip_suffix(')'))
+        .ok_or_else(|| anyhow!("unknown function {:?}, expected split('<sep>',<n>)", function))?;
+    let (separator, index) = args
+        .rsplit_once(',')
+        .ok_or_else(|| anyhow!("expected split('<sep>',<n>), got {:?}", function))?;
+    let separator = separator
+        .trim()
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| anyhow!("the split separator must be a non-empty quoted string, got {:?}", separator))?;
+    let index = index
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("the split index must be a number, got {:?}", index))?;
+    Ok(Part::Split {
+        field,
+        separator: separator.to_string(),
+        index,
+    })
+}
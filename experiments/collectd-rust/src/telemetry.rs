@@ -0,0 +1,573 @@
+// Internal counters for the receiver itself, rendered in the Prometheus text
+// format on GET /metrics
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+use crate::retry::{Circuit, CircuitBreaker};
+
+#[derive(Default)]
+pub struct Telemetry {
+    pub metrics_received: AtomicU64,
+    pub parse_failures: AtomicU64,
+    pub metrics_invalid: AtomicU64,
+    pub metrics_dropped: AtomicU64,
+    pub metrics_filtered: AtomicU64,
+    pub metrics_rate_limited: AtomicU64,
+    pub metrics_deduplicated: AtomicU64,
+    pub metrics_sampled: AtomicU64,
+    pub metrics_too_old: AtomicU64,
+    pub script_errors: AtomicU64,
+    pub plugin_errors: AtomicU64,
+    pub alerts_sent: AtomicU64,
+    pub alerts_failed: AtomicU64,
+    pub scrape_failures: AtomicU64,
+    pub snmp_failures: AtomicU64,
+    pub exec_failures: AtomicU64,
+    pub series: AtomicU64,
+    pub metrics_over_series_limit: AtomicU64,
+    pub requests_denied: AtomicU64,
+    pub config_reloads: AtomicU64,
+    pub config_reload_failures: AtomicU64,
+    pub http_connections: AtomicU64,
+    pub http_connections_accepted: AtomicU64,
+    sinks: Vec<(String, Arc<SinkStats>)>,
+    // Signalled when an output fails under --exit-on-sink-failure
+    sink_failure: Notify,
+}
+
+// Per-output counters, shared between the worker and the /metrics handler
+#[derive(Default)]
+pub struct SinkStats {
+    pub queue_depth: AtomicU64,
+    pub batches_flushed: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub errors: AtomicU64,
+    pub metrics_failed: AtomicU64,
+    pub metrics_dead_lettered: AtomicU64,
+    pub restarts: AtomicU64,
+    pub wal_bytes: AtomicU64,
+    pub wal_dropped: AtomicU64,
+    // Set once the worker has opened its output, cleared when it disconnects or dies
+    pub ready: AtomicBool,
+    // Set while the worker is down, between a failure and its restart
+    pub failed: AtomicBool,
+    // 1 while the worker's circuit breaker is open or half-open
+    pub circuit_open: AtomicU64,
+    pub circuit_half_open: AtomicBool,
+    pub circuit_opens: AtomicU64,
+    // Failed attempts in a row, reset by the next success
+    pub consecutive_failures: AtomicU64,
+}
+
+// One output worker's state, as listed by GET /-/outputs
+#[derive(Serialize)]
+pub struct WorkerStatus<'a> {
+    pub output: &'a str,
+    pub worker: usize,
+    pub ready: bool,
+    pub failed: bool,
+    pub circuit: &'static str,
+    pub consecutive_failures: u64,
+    pub queue_depth: u64,
+}
+
+impl Telemetry {
+    // Called once per output worker at startup, before the telemetry is shared.
+    // The workers of one output are added one after the other under its name.
+    pub fn add_sink(&mut self, name: &str) -> Arc<SinkStats> {
+        let stats = Arc::new(SinkStats::default());
+        self.sinks.push((name.to_string(), stats.clone()));
+        stats
+    }
+
+    pub fn sinks_failed(&self) -> Vec<&str> {
+        self.sinks
+            .iter()
+            .filter(|(_, stats)| stats.failed.load(Ordering::Relaxed))
+            .map(|(name, _)| name.as_str())
+            .fold(Vec::new(), push_once)
+    }
+
+    pub fn report_sink_failure(&self) {
+        self.sink_failure.notify_one();
+    }
+
+    // Resolves once an output has reported a failure
+    pub async fn sink_failure(&self) {
+        self.sink_failure.notified().await;
+    }
+
+    pub fn sinks_not_ready(&self) -> Vec<&str> {
+        self.sinks
+            .iter()
+            .filter(|(_, stats)| !stats.ready.load(Ordering::Relaxed))
+            .map(|(name, _)| name.as_str())
+            .fold(Vec::new(), push_once)
+    }
+
+    pub fn workers(&self) -> Vec<WorkerStatus<'_>> {
+        let mut workers: Vec<WorkerStatus> = Vec::with_capacity(self.sinks.len());
+        for (name, stats) in &self.sinks {
+            let worker = match workers.last() {
+                Some(last) if last.output == name => last.worker + 1,
+                _ => 0,
+            };
+            workers.push(WorkerStatus {
+                output: name,
+                worker,
+                ready: stats.ready.load(Ordering::Relaxed),
+                failed: stats.failed.load(Ordering::Relaxed),
+                circuit: stats.circuit().name(),
+                consecutive_failures: stats.consecutive_failures.load(Ordering::Relaxed),
+                queue_depth: stats.queue_depth.load(Ordering::Relaxed),
+            });
+        }
+        workers
+    }
+
+    // One sample per output, summed over its workers
+    fn per_sink(&self, field: impl Fn(&SinkStats) -> &AtomicU64) -> Vec<(&str, AtomicU64)> {
+        let mut samples: Vec<(&str, AtomicU64)> = Vec::new();
+        for (name, stats) in &self.sinks {
+            let value = field(stats).load(Ordering::Relaxed);
+            match samples.last_mut() {
+                Some((last, total)) if last == name => *total.get_mut() += value,
+                _ => samples.push((name, AtomicU64::new(value))),
+            }
+        }
+        samples
+    }
+
+    pub fn record_received(&self, count: usize) {
+        self.metrics_received.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_parse_failure(&self) {
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_invalid(&self, count: usize) {
+        self.metrics_invalid.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self, count: usize) {
+        self.metrics_dropped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_filtered(&self, count: usize) {
+        self.metrics_filtered.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self, count: usize) {
+        self.metrics_rate_limited.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_deduplicated(&self, count: usize) {
+        self.metrics_deduplicated.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_sampled(&self, count: usize) {
+        self.metrics_sampled.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_too_old(&self, count: usize) {
+        self.metrics_too_old.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_script_errors(&self, count: usize) {
+        self.script_errors.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_plugin_errors(&self, count: usize) {
+        self.plugin_errors.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_alert_sent(&self) {
+        self.alerts_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_alert_failure(&self) {
+        self.alerts_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_scrape_failure(&self) {
+        self.scrape_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_snmp_failure(&self) {
+        self.snmp_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_exec_failure(&self) {
+        self.exec_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Series is the number currently tracked by the cardinality limiter
+    pub fn record_series_limited(&self, count: usize, series: usize) {
+        self.metrics_over_series_limit.fetch_add(count as u64, Ordering::Relaxed);
+        self.series.store(series as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_denied(&self) {
+        self.requests_denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reload(&self) {
+        self.config_reloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reload_failure(&self) {
+        self.config_reload_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_opened(&self) {
+        self.http_connections.fetch_add(1, Ordering::Relaxed);
+        self.http_connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_closed(&self) {
+        self.http_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_received_total",
+            "counter",
+            "Metrics accepted by the ingest endpoints",
+            [("", &self.metrics_received)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_parse_failures_total",
+            "counter",
+            "Requests or packets that could not be parsed",
+            [("", &self.parse_failures)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_invalid_total",
+            "counter",
+            "Metrics refused because they didn't parse or failed the --strict checks",
+            [("", &self.metrics_invalid)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_dropped_total",
+            "counter",
+            "Metrics dropped or rejected because a queue was full",
+            [("", &self.metrics_dropped)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_filtered_total",
+            "counter",
+            "Metrics dropped by the relabel, filter or routing rules",
+            [("", &self.metrics_filtered)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_rate_limited_total",
+            "counter",
+            "Metrics refused with 429 because the client was over --max-metrics-per-sec",
+            [("", &self.metrics_rate_limited)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_deduplicated_total",
+            "counter",
+            "Duplicate metrics dropped within --dedup-window",
+            [("", &self.metrics_deduplicated)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_sampled_total",
+            "counter",
+            "Metrics dropped by --sample rules",
+            [("", &self.metrics_sampled)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_too_old_total",
+            "counter",
+            "Metrics dropped because their timestamp was older than --reject-older-than",
+            [("", &self.metrics_too_old)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_script_errors_total",
+            "counter",
+            "Metrics passed on unchanged because --script failed or ran out of operations",
+            [("", &self.script_errors)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_plugin_errors_total",
+            "counter",
+            "Metrics passed on unchanged because a --plugin-transform failed",
+            [("", &self.plugin_errors)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_alert_events_total",
+            "counter",
+            "Alert events sent to --alert-target",
+            [("", &self.alerts_sent)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_alert_event_failures_total",
+            "counter",
+            "Alert events dropped because the queue was full or sending failed",
+            [("", &self.alerts_failed)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_scrape_failures_total",
+            "counter",
+            "Scrapes of a --scrape-target that failed or returned an unparsable page",
+            [("", &self.scrape_failures)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_snmp_failures_total",
+            "counter",
+            "Polls of an --snmp-target that timed out or were refused",
+            [("", &self.snmp_failures)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_exec_failures_total",
+            "counter",
+            "Runs of an --exec or --exec-daemon command that failed, timed out or exited with an error",
+            [("", &self.exec_failures)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_series",
+            "gauge",
+            "Distinct series tracked against --max-series",
+            [("", &self.series)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_metrics_over_series_limit_total",
+            "counter",
+            "Metrics of series beyond --max-series, dropped or moved to an overflow series",
+            [("", &self.metrics_over_series_limit)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_requests_denied_total",
+            "counter",
+            "Requests, packets or connections refused because the source is not in --allow-cidr",
+            [("", &self.requests_denied)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_config_reloads_total",
+            "counter",
+            "Config reloads applied, on SIGHUP or POST /-/reload",
+            [("", &self.config_reloads)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_config_reload_failures_total",
+            "counter",
+            "Config reloads refused because the new config was invalid",
+            [("", &self.config_reload_failures)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_http_connections",
+            "gauge",
+            "HTTP connections currently open",
+            [("", &self.http_connections)],
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_http_connections_accepted_total",
+            "counter",
+            "HTTP connections accepted; a fast rise means clients reconnect instead of keeping connections alive",
+            [("", &self.http_connections_accepted)],
+        );
+
+        write_metric(
+            &mut out,
+            "collectd_receiver_queue_depth",
+            "gauge",
+            "Metrics waiting in the output queue",
+            self.per_sink(|stats| &stats.queue_depth).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_batches_flushed_total",
+            "counter",
+            "Batches written by the output",
+            self.per_sink(|stats| &stats.batches_flushed).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_bytes_written_total",
+            "counter",
+            "Bytes written by the output",
+            self.per_sink(|stats| &stats.bytes_written).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_sink_errors_total",
+            "counter",
+            "Errors reported by the output",
+            self.per_sink(|stats| &stats.errors).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_sink_restarts_total",
+            "counter",
+            "Times the output worker was restarted after a failure",
+            self.per_sink(|stats| &stats.restarts).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_sink_circuit_open",
+            "gauge",
+            "Output workers whose circuit breaker is open or probing",
+            self.per_sink(|stats| &stats.circuit_open).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_sink_circuit_opens_total",
+            "counter",
+            "Times the output's circuit breaker opened, failed probes included",
+            self.per_sink(|stats| &stats.circuit_opens).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_sink_metrics_failed_total",
+            "counter",
+            "Metrics the output gave up on after its retries, dead-lettered when configured",
+            self.per_sink(|stats| &stats.metrics_failed).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_sink_metrics_dead_lettered_total",
+            "counter",
+            "Failed metrics written to the output's dead-letter file",
+            self.per_sink(|stats| &stats.metrics_dead_lettered).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_wal_bytes",
+            "gauge",
+            "Bytes spilled to the output's write-ahead buffer, waiting for replay",
+            self.per_sink(|stats| &stats.wal_bytes).iter().map(|(sink, total)| (*sink, total)),
+        );
+        write_metric(
+            &mut out,
+            "collectd_receiver_wal_dropped_total",
+            "counter",
+            "Metrics deleted from the write-ahead buffer to stay under its size cap",
+            self.per_sink(|stats| &stats.wal_dropped).iter().map(|(sink, total)| (*sink, total)),
+        );
+
+        out
+    }
+}
+
+impl SinkStats {
+    // Sampled by the worker whenever it takes a metric off its queue
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn set_failed(&self, failed: bool) {
+        self.failed.store(failed, Ordering::Relaxed);
+    }
+
+    // Mirrors the worker's breaker after every attempt
+    pub fn set_circuit(&self, breaker: &CircuitBreaker) {
+        let state = breaker.state();
+        self.circuit_open.store((state != Circuit::Closed) as u64, Ordering::Relaxed);
+        self.circuit_half_open.store(state == Circuit::HalfOpen, Ordering::Relaxed);
+        self.consecutive_failures.store(breaker.failures() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_circuit_open(&self) {
+        self.circuit_opens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn circuit(&self) -> Circuit {
+        match (self.circuit_open.load(Ordering::Relaxed), self.circuit_half_open.load(Ordering::Relaxed)) {
+            (0, _) => Circuit::Closed,
+            (_, true) => Circuit::HalfOpen,
+            _ => Circuit::Open,
+        }
+    }
+
+    pub fn record_restart(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_batch(&self, bytes: usize) {
+        self.batches_flushed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failed(&self, count: usize) {
+        self.metrics_failed.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_dead_lettered(&self, count: usize) {
+        self.metrics_dead_lettered.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_wal_bytes(&self, bytes: u64) {
+        self.wal_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_wal_dropped(&self, count: usize) {
+        self.wal_dropped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+}
+
+// Adds the output to the list unless it's there already, as it is when
+// another of its workers was added before
+fn push_once<'a>(mut names: Vec<&'a str>, name: &'a str) -> Vec<&'a str> {
+    if names.last() != Some(&name) {
+        names.push(name);
+    }
+    names
+}
+
+// An empty sink name means the sample has no labels
+fn write_metric<'a>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (&'a str, &'a AtomicU64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (sink, value) in samples {
+        let value = value.load(Ordering::Relaxed);
+        if sink.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{sink=\"{}\"}} {}", name, sink, value);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// Tenants: which team a metric belongs to, carried as the "tenant" tag. It comes
+// from the API key a request used, the /t/<tenant>/ path prefix or a header.
+// An output setting may contain "{tenant}", which makes that output open one
+// instance per tenant with the placeholder filled in, e.g. a file or topic each.
+use anyhow::{anyhow, Result};
+
+use crate::config::Config;
+use crate::metric::ProcessedMetric;
+
+pub const TAG: &str = "tenant";
+// Stands in for {tenant} on metrics that arrived without one
+pub const DEFAULT: &str = "default";
+const PLACEHOLDER: &str = "{tenant}";
+
+// Tenant names end up in file names, topics and URLs, so keep them plain
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(anyhow!(
+            "Invalid tenant {:?}, expected up to 64 letters, digits, '-', '_' or '.'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+pub fn of(metric: &ProcessedMetric) -> &str {
+    metric.tags.get(TAG).map(String::as_str).unwrap_or(DEFAULT)
+}
+
+// Whether any of the output's own settings (the ones named after it) asks for
+// per-tenant instances
+pub fn is_templated(mode: &str, config: &Config) -> bool {
+    let prefix = match mode {
+        "disk" => "output_file".to_string(),
+        "prometheus" => "remote_write_".to_string(),
+        "victoriametrics" => "vm_".to_string(),
+        mode => format!("{}_", mode),
+    };
+    let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(config) else {
+        return false;
+    };
+    fields
+        .iter()
+        .any(|(name, value)| name.starts_with(&prefix) && value.to_string().contains(PLACEHOLDER))
+}
+
+// The config with every {tenant} replaced, for one tenant's instance of an output
+pub fn config_for(config: &Config, tenant: &str) -> Result<Config> {
+    let mut value = serde_json::to_value(config)?;
+    substitute(&mut value, tenant);
+    Ok(serde_json::from_value(value)?)
+}
+
+fn substitute(value: &mut serde_json::Value, tenant: &str) {
+    match value {
+        serde_json::Value::String(s) if s.contains(PLACEHOLDER) => *s = s.replace(PLACEHOLDER, tenant),
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|item| substitute(item, tenant)),
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(|field| substitute(field, tenant)),
+        _ => {}
+    }
+}
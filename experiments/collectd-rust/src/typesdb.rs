@@ -0,0 +1,87 @@
+// collectd's types.db: the data sources of every type, e.g.
+//   if_octets  rx:DERIVE:0:U, tx:DERIVE:0:U
+// Used to name the values of multi-value metrics (rx/tx, read/write, ...) and
+// to fill in data source types for clients that don't send them.
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+
+use crate::metric::CollectdMetric;
+
+#[derive(Debug, Clone)]
+pub struct DataSource {
+    pub name: String,
+    // Lowercased to match the dstypes write_http sends, e.g. "derive"
+    pub dstype: String,
+}
+
+#[derive(Debug, Default)]
+pub struct TypesDb {
+    types: HashMap<String, Vec<DataSource>>,
+}
+
+impl TypesDb {
+    // Later files override types defined by earlier ones, like collectd's TypesDB option
+    pub fn load(paths: &[String]) -> Result<TypesDb> {
+        let mut db = TypesDb::default();
+        for path in paths {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read types.db {}: {}", path, e))?;
+            for (i, line) in contents.lines().enumerate() {
+                db.parse_line(line)
+                    .map_err(|e| anyhow!("{}:{}: {}", path, i + 1, e))?;
+            }
+        }
+        Ok(db)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some((type_name, sources)) = line.split_once(char::is_whitespace) else {
+            if line.is_empty() {
+                return Ok(());
+            }
+            bail!("Type {} has no data sources", line);
+        };
+
+        let mut data_sources = Vec::new();
+        for source in sources.split(',') {
+            let mut parts = source.trim().split(':');
+            let (Some(name), Some(dstype)) = (parts.next(), parts.next()) else {
+                bail!("Invalid data source {:?}", source.trim());
+            };
+            let dstype = dstype.to_ascii_lowercase();
+            if !matches!(dstype.as_str(), "gauge" | "counter" | "derive" | "absolute") {
+                bail!("Unknown data source type {:?}", dstype);
+            }
+            data_sources.push(DataSource {
+                name: name.to_string(),
+                dstype,
+            });
+        }
+        self.types.insert(type_name.to_string(), data_sources);
+        Ok(())
+    }
+
+    // Fills in dsnames, and dstypes when missing, for metrics whose type is known
+    // and whose value count matches. Anything else is left as it came in.
+    pub fn annotate(&self, metric: &mut CollectdMetric) {
+        let Some(sources) = metric.type_.as_deref().and_then(|t| self.types.get(t)) else {
+            return;
+        };
+        let count = match (&metric.values, &metric.value) {
+            (Some(values), _) => values.len(),
+            (None, Some(_)) => 1,
+            (None, None) => return,
+        };
+        if count != sources.len() {
+            return;
+        }
+
+        if metric.dsnames.is_none() {
+            metric.dsnames = Some(sources.iter().map(|ds| ds.name.clone()).collect());
+        }
+        if metric.dstypes.is_none() {
+            metric.dstypes = Some(sources.iter().map(|ds| ds.dstype.clone()).collect());
+        }
+    }
+}
@@ -0,0 +1,172 @@
+// Unit conversion rules, `<from>-><to>:<match>` where match is one or more of
+// the filter's `<field>=<value>`/`<field>~<regex>` joined by `&`, e.g.
+//   bytes->MiB:plugin=memory
+//   celsius->fahrenheit:plugin=sensors&type=temperature
+//   jiffies->percent:plugin=cpu
+// The first matching rule converts the value and sets the metric's unit to the
+// target unit. Scaled units convert within their family (bytes, time), the
+// temperatures between each other. Jiffies become percent of one CPU assuming
+// 100 jiffies a second, which needs the value as a rate (a gauge, e.g. after
+// --rates) or per interval (absolute, with an interval), and turns it into a
+// gauge; running counters are left alone.
+use anyhow::{anyhow, Result};
+
+use crate::filter::Rule;
+use crate::metric::{ProcessedMetric, Value};
+
+// Jiffies per second, USER_HZ on practically every Linux
+const JIFFIES_PER_SECOND: f64 = 100.0;
+
+// Units that convert by a factor, with their size in the family's base unit
+const SCALED: [(&str, &str, f64); 16] = [
+    ("bits", "bytes", 0.125),
+    ("bytes", "bytes", 1.0),
+    ("kB", "bytes", 1e3),
+    ("MB", "bytes", 1e6),
+    ("GB", "bytes", 1e9),
+    ("TB", "bytes", 1e12),
+    ("KiB", "bytes", 1024.0),
+    ("MiB", "bytes", 1048576.0),
+    ("GiB", "bytes", 1073741824.0),
+    ("TiB", "bytes", 1099511627776.0),
+    ("ns", "s", 1e-9),
+    ("us", "s", 1e-6),
+    ("ms", "s", 1e-3),
+    ("s", "s", 1.0),
+    ("min", "s", 60.0),
+    ("h", "s", 3600.0),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Temperature {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl Temperature {
+    fn parse(name: &str) -> Option<Temperature> {
+        match name {
+            "celsius" => Some(Temperature::Celsius),
+            "fahrenheit" => Some(Temperature::Fahrenheit),
+            "kelvin" => Some(Temperature::Kelvin),
+            _ => None,
+        }
+    }
+
+    fn to_celsius(self, value: f64) -> f64 {
+        match self {
+            Temperature::Celsius => value,
+            Temperature::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+            Temperature::Kelvin => value - 273.15,
+        }
+    }
+
+    fn of_celsius(self, value: f64) -> f64 {
+        match self {
+            Temperature::Celsius => value,
+            Temperature::Fahrenheit => value * 9.0 / 5.0 + 32.0,
+            Temperature::Kelvin => value + 273.15,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Conversion {
+    Scale(f64),
+    Temperature(Temperature, Temperature),
+    JiffiesToPercent,
+}
+
+impl Conversion {
+    fn parse(from: &str, to: &str) -> Result<Conversion> {
+        if (from, to) == ("jiffies", "percent") {
+            return Ok(Conversion::JiffiesToPercent);
+        }
+        if let (Some(from), Some(to)) = (Temperature::parse(from), Temperature::parse(to)) {
+            return Ok(Conversion::Temperature(from, to));
+        }
+        let scaled = |name: &str| SCALED.iter().find(|(unit, _, _)| *unit == name);
+        match (scaled(from), scaled(to)) {
+            (Some((_, from_family, from_size)), Some((_, to_family, to_size))) if from_family == to_family => {
+                Ok(Conversion::Scale(from_size / to_size))
+            }
+            _ => Err(anyhow!("Can't convert {} to {}", from, to)),
+        }
+    }
+
+    // None when the value can't be converted
+    fn apply(&self, value: f64, metric: &ProcessedMetric) -> Option<f64> {
+        match self {
+            Conversion::Scale(factor) => Some(value * factor),
+            Conversion::Temperature(from, to) => Some(to.of_celsius(from.to_celsius(value))),
+            Conversion::JiffiesToPercent => {
+                let per_second = match metric.dstype.as_deref() {
+                    None | Some("gauge") => value,
+                    Some("absolute") => value / metric.interval.filter(|interval| *interval > 0.0)?,
+                    Some(_) => return None,
+                };
+                Some(per_second / JIFFIES_PER_SECOND * 100.0)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UnitRule {
+    matches: Vec<Rule>,
+    conversion: Conversion,
+    unit: String,
+}
+
+#[derive(Debug, Default)]
+pub struct UnitRules {
+    rules: Vec<UnitRule>,
+}
+
+impl UnitRules {
+    pub fn parse(rules: &[String]) -> Result<UnitRules> {
+        let mut parsed = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let invalid = |e: anyhow::Error| anyhow!("Invalid unit conversion {:?}: {}", rule, e);
+            let (units, expr) = rule
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid unit conversion {:?}, expected <from>-><to>:<match>", rule))?;
+            let (from, to) = units
+                .split_once("->")
+                .ok_or_else(|| anyhow!("Invalid unit conversion {:?}, expected <from>-><to>:<match>", rule))?;
+            let conversion = Conversion::parse(from.trim(), to.trim()).map_err(invalid)?;
+            let matches = expr.split('&').map(Rule::parse).collect::<Result<Vec<_>>>().map_err(invalid)?;
+            parsed.push(UnitRule {
+                matches,
+                conversion,
+                unit: to.trim().to_string(),
+            });
+        }
+        Ok(UnitRules { rules: parsed })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Converts the metric in place when a rule matches it
+    pub fn apply(&self, metric: &mut ProcessedMetric) {
+        let value = metric.value.as_f64();
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches.iter().all(|expr| expr.matches(metric)))
+        else {
+            return;
+        };
+        if let Some(converted) = rule.conversion.apply(value, metric) {
+            metric.value = Value::F64(converted);
+            metric.unit = Some(rule.unit.clone());
+            // A share of the CPU is a gauge, whatever the jiffies were
+            if matches!(rule.conversion, Conversion::JiffiesToPercent) {
+                metric.dstype = Some("gauge".to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// Write-ahead buffer for an output whose downstream is down or too slow.
+// Each spilled batch becomes one NDJSON segment, `<seq>.ndjson`, in the
+// output's own directory. Segments are replayed oldest first once the output
+// accepts batches again, and survive restarts. When the directory grows past
+// its cap the oldest segments are deleted.
+use anyhow::Result;
+use std::{collections::VecDeque, path::PathBuf};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::metric::ProcessedMetric;
+
+struct Segment {
+    seq: u64,
+    bytes: u64,
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    max_bytes: u64,
+    segments: VecDeque<Segment>,
+    bytes: u64,
+}
+
+impl Wal {
+    // Picks up segments left behind by a previous run
+    pub async fn open(dir: PathBuf, max_bytes: u64) -> Result<Wal> {
+        fs::create_dir_all(&dir).await?;
+
+        let mut segments = Vec::new();
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(seq) = name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".ndjson"))
+                .and_then(|n| n.parse().ok())
+            else {
+                continue;
+            };
+            segments.push(Segment {
+                seq,
+                bytes: entry.metadata().await?.len(),
+            });
+        }
+        segments.sort_by_key(|s| s.seq);
+
+        let bytes = segments.iter().map(|s| s.bytes).sum();
+        if !segments.is_empty() {
+            info!("Found {} WAL segments ({} bytes) in {}", segments.len(), bytes, dir.display());
+        }
+        Ok(Wal {
+            dir,
+            max_bytes,
+            segments: segments.into(),
+            bytes,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.ndjson", seq))
+    }
+
+    // Returns how many metrics were lost to the size cap
+    pub async fn append(&mut self, batch: &[ProcessedMetric]) -> Result<usize> {
+        let mut data = Vec::new();
+        for metric in batch {
+            serde_json::to_writer(&mut data, metric)?;
+            data.push(b'\n');
+        }
+
+        let seq = self.segments.back().map(|s| s.seq + 1).unwrap_or_default();
+        // Written under a temporary name so a crash never leaves a torn segment
+        let tmp = self.dir.join(format!("{:020}.ndjson.tmp", seq));
+        fs::write(&tmp, &data).await?;
+        fs::rename(&tmp, self.path(seq)).await?;
+        self.segments.push_back(Segment {
+            seq,
+            bytes: data.len() as u64,
+        });
+        self.bytes += data.len() as u64;
+
+        let mut lost = 0;
+        while self.bytes > self.max_bytes && self.segments.len() > 1 {
+            if let Some(batch) = self.front().await? {
+                lost += batch.len();
+            }
+            self.pop_front().await?;
+        }
+        Ok(lost)
+    }
+
+    // The oldest spilled batch. A segment that can't be read back is deleted
+    // rather than blocking everything behind it.
+    pub async fn front(&mut self) -> Result<Option<Vec<ProcessedMetric>>> {
+        while let Some(segment) = self.segments.front() {
+            let path = self.path(segment.seq);
+            let parsed = match fs::read(&path).await {
+                Ok(data) => data
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(serde_json::from_slice)
+                    .collect::<Result<Vec<ProcessedMetric>, _>>()
+                    .map_err(anyhow::Error::from),
+                Err(e) => Err(e.into()),
+            };
+            match parsed {
+                Ok(batch) => return Ok(Some(batch)),
+                Err(e) => {
+                    warn!("Discarding unreadable WAL segment {}: {}", path.display(), e);
+                    self.pop_front().await?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn pop_front(&mut self) -> Result<()> {
+        if let Some(segment) = self.segments.pop_front() {
+            self.bytes -= segment.bytes;
+            match fs::remove_file(self.path(segment.seq)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}